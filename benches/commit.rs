@@ -0,0 +1,108 @@
+#[macro_use]
+extern crate criterion;
+extern crate rsm;
+#[macro_use]
+extern crate slog;
+
+use criterion::Criterion;
+use rsm::primitives::event::*;
+use rsm::raft::config::RaftConfig;
+use rsm::raft::kv::{self, KV, Op};
+use rsm::raft::protocol::{Raft, Role};
+use rsm::raft::snapshot::FileSnapshotStore;
+use slog::{Discard, Logger};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+type Registry = Arc<RwLock<HashMap<[u8; 32], Arc<Raft>>>>;
+
+fn host(tag: &str) -> [u8; 32] {
+    let mut buf = [0; 32];
+    buf[..tag.len()].copy_from_slice(tag.as_bytes());
+    buf
+}
+
+fn cluster(guard: &Arc<Guard>) -> Vec<Arc<Raft>> {
+
+    //
+    // - spawn 3 peers wired in-process: each outgoing buffer is fed right away to the
+    //   destination peer, e.g no network and no delay
+    // - the registry is filled once all the peers are up
+    //
+    let hosts = vec!["bench-0", "bench-1", "bench-2"];
+    let registry: Registry = Arc::new(RwLock::new(HashMap::new()));
+    let mut rafts = Vec::new();
+    for id in 0..3u16 {
+        let peers: HashMap<_, _> = hosts.iter().enumerate().map(|(n, h)| (n as u16, *h)).collect();
+        let routes = registry.clone();
+        let (raft, _, _) = rsm::raft::spawn::<_, _, KV, _>(
+            guard,
+            id,
+            peers,
+            RaftConfig::default(),
+            Box::new(FileSnapshotStore::for_peer(id)),
+            move |dst: &[u8; 32], bytes: &[u8]| {
+                if let Some(raft) = routes.read().unwrap().get(dst) {
+                    let _ = raft.feed(bytes);
+                }
+            },
+            kv::apply,
+            Logger::root(Discard, o!()),
+        ).expect("unable to spawn");
+        registry.write().unwrap().insert(host(hosts[id as usize]), raft.clone());
+        rafts.push(raft);
+    }
+    rafts
+}
+
+fn store_n(leader: &Raft, n: usize) -> () {
+
+    //
+    // - issue n stores back to back and wait until the last one commits
+    // - the log commits in order so this covers all of them
+    //
+    let handles: Vec<_> = (0..n)
+        .filter_map(|k| {
+            let op = Op::SET(format!("key-{}", k).into_bytes(), vec![0; 64]);
+            leader.store_async(op.to_bytes()).ok()
+        })
+        .collect();
+    if let Some(rx) = handles.last() {
+        let _ = rx.recv();
+    }
+}
+
+fn benchmark(c: &mut Criterion) {
+
+    //
+    // - start the cluster and wait for a LEADER to settle before measuring anything
+    //
+    let event = Event::new();
+    let guard = event.guard();
+    let rafts = cluster(&guard);
+    let id = rafts[0].await_leader(Duration::from_secs(10)).expect("no LEADER elected");
+    let leader = rafts[id as usize].clone();
+    assert_eq!(leader.role(), Role::Leader);
+
+    let sizes = vec![1, 8, 32];
+    for n in sizes {
+        let leader = leader.clone();
+        c.bench_function(&format!("commit (3 peers, {} X 64B)", n), move |b| {
+            b.iter(|| store_n(&leader, n))
+        });
+    }
+
+    //
+    // - shut everything down
+    //
+    rsm::raft::ANCILLARY.reset();
+    for raft in &rafts {
+        raft.drain();
+    }
+    drop(guard);
+    event.wait();
+}
+
+criterion_group!(benches, benchmark);
+criterion_main!(benches);
@@ -5,7 +5,7 @@ extern crate rand;
 
 use criterion::Criterion;
 use rsm::primitives::*;
-use rsm::primitives::lock::Lock;
+use rsm::primitives::lock::{Lock, RwLock};
 use rsm::primitives::event::*;
 use std::thread;
 use std::sync::Arc;
@@ -37,9 +37,39 @@ fn lock_1k_n<T: 'static + Strategy + Default>(lock: Lock<T>, n: usize, slow: boo
     event.wait();
 }
 
+fn rw_1k_n<T: 'static + Strategy + Default>(lock: RwLock<T>, n: usize, ratio: usize) {
+
+    //
+    // - each thread reads and writes 1K times, one access out of ratio being a write
+    //
+    let lock = Arc::new(lock);
+    let event = Arc::new(Event::new());
+    {
+        let guard = event.guard();
+        for _ in 0..n {
+
+            let lock = lock.clone();
+            let guard = guard.clone();
+            let _ = thread::spawn(move || {
+                for k in 0..1000 {
+
+                    if k % ratio == 0 {
+                        lock.write(|n| n + 1);
+                    } else {
+                        lock.read(|n| n);
+                    }
+                }
+                drop(guard);
+            });
+        }
+    }
+
+    event.wait();
+}
+
 fn benchmark(c: &mut Criterion) {
 
-    let sizes = vec![1, 2, 4];
+    let sizes = vec![1, 2, 4, 8];
     for n in sizes {
         c.bench_function(&format!("lock (lifo, 1K X {})", n), move |b| {
             b.iter(|| lock_1k_n(Lock::<LIFO>::new(), n, false))
@@ -47,6 +77,37 @@ fn benchmark(c: &mut Criterion) {
         c.bench_function(&format!("lock (lifo [yield], 1K X {})", n), move |b| {
             b.iter(|| lock_1k_n(Lock::<LIFO>::new(), n, true))
         });
+        c.bench_function(&format!("lock (fifo, 1K X {})", n), move |b| {
+            b.iter(|| lock_1k_n(Lock::<FIFO>::new(), n, false))
+        });
+        c.bench_function(&format!("lock (fifo [yield], 1K X {})", n), move |b| {
+            b.iter(|| lock_1k_n(Lock::<FIFO>::new(), n, true))
+        });
+        c.bench_function(&format!("lock (adaptive, 1K X {})", n), move |b| {
+            b.iter(|| lock_1k_n(Lock::<Adaptive>::new(), n, false))
+        });
+        c.bench_function(&format!("lock (adaptive [yield], 1K X {})", n), move |b| {
+            b.iter(|| lock_1k_n(Lock::<Adaptive>::new(), n, true))
+        });
+    }
+
+    //
+    // - read-mostly mix (1 write every 10 accesses) compared with the exclusive locks
+    //
+    let sizes = vec![32, 128];
+    for n in sizes {
+        c.bench_function(&format!("rwlock (fifo, 9:1, 1K X {})", n), move |b| {
+            b.iter(|| rw_1k_n(RwLock::<FIFO>::new(), n, 10))
+        });
+        c.bench_function(&format!("lock (lifo, 1K X {})", n), move |b| {
+            b.iter(|| lock_1k_n(Lock::<LIFO>::new(), n, false))
+        });
+        c.bench_function(&format!("lock (fifo, 1K X {})", n), move |b| {
+            b.iter(|| lock_1k_n(Lock::<FIFO>::new(), n, false))
+        });
+        c.bench_function(&format!("lock (adaptive, 1K X {})", n), move |b| {
+            b.iter(|| lock_1k_n(Lock::<Adaptive>::new(), n, false))
+        });
     }
 }
 
@@ -0,0 +1,52 @@
+#[macro_use]
+extern crate criterion;
+extern crate rsm;
+
+use criterion::Criterion;
+use rsm::raft::snapshot::FileSnapshotStore;
+use rsm::raft::storage::{FileStorage, Storage, CAPACITY, ENTRY_BYTES};
+use std::env;
+use std::fs::remove_file;
+
+fn open(reserve: bool) -> FileStorage {
+
+    //
+    // - start from a fresh (sparse) log file, allocated upfront or not
+    //
+    let log = env::temp_dir().join("rsm.bench.log");
+    let _ = remove_file(&log);
+    let snapshots = Box::new(FileSnapshotStore::new(env::temp_dir().join("rsm.bench.snap")));
+    let mut storage = FileStorage::open(log, env::temp_dir().join("rsm.bench.vote"), snapshots)
+        .expect("unable to open the log");
+    if reserve {
+        storage.reserve((CAPACITY * ENTRY_BYTES) as u64).expect("unable to reserve the log");
+    }
+    storage
+}
+
+fn benchmark(c: &mut Criterion) {
+
+    //
+    // - append a whole lap of entries one by one, e.g each one lands on a slot never written
+    //   before: without a reservation this faults the file blocks in on the way
+    //
+    let entry = vec![1; ENTRY_BYTES];
+    for &reserve in &[false, true] {
+        let entry = entry.clone();
+        let label = if reserve { "reserved" } else { "sparse" };
+        let name = format!("append lap ({} X {}B, {})", CAPACITY, ENTRY_BYTES, label);
+        c.bench_function(&name, move |b| {
+            b.iter_with_setup(
+                || open(reserve),
+                |mut storage| {
+                    for off in 1..CAPACITY as u64 + 1 {
+                        storage.append_entries(off, &entry).expect("unable to write to the log");
+                    }
+                },
+            )
+        });
+    }
+}
+
+criterion_group!(benches, benchmark);
+criterion_main!(benches);
@@ -12,7 +12,10 @@ extern crate slog_async;
 extern crate slog_term;
 
 use rsm::primitives::event::*;
+use rsm::raft::config::RaftConfig;
+use rsm::raft::snapshot::FileSnapshotStore;
 use rsm::raft::protocol::Payload;
+use rsm::raft::sink::Notification;
 use slog::{Drain, Level, LevelFilter, Logger};
 use slog_term::{FullFormat, PlainSyncDecorator};
 use slog_async::Async;
@@ -20,7 +23,11 @@ use std::collections::HashMap;
 use std::io::stderr;
 use std::env;
 use std::path::Path;
+use std::process;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Default)]
 struct Empty {}
@@ -59,14 +66,11 @@ fn main() {
     //
     // - convert the host sequence into a id<->host map with id starting at 0
     //
-    let mut n: i8 = -1;
     let tokens: Vec<_> = args.values_of("PEERS").unwrap().collect();
     let peers: HashMap<_, _> = tokens
         .iter()
-        .map(|token| {
-            n += 1;
-            (n as u8, *token)
-        })
+        .enumerate()
+        .map(|(n, token)| (n as u16, *token))
         .collect();
 
     //
@@ -75,23 +79,51 @@ fn main() {
     // - grab our node id
     // - start a raft automaton using STDIN/STDOUT as I/O
     // - the automaton is passed an empty payload
+    // - hand the leadership off when draining, if leading
     //
     let event = Arc::new(Event::new());
     let guard = event.guard();
-    let id = value_t!(args, "ID", u8).unwrap();
-    let (raft, _, _) = rsm::raft::spawn_piped::<_, Empty, _>(
+    let id = value_t!(args, "ID", u16).unwrap();
+    let (raft, _, sink) = match rsm::raft::spawn_piped::<_, Empty, _>(
         &guard,
         id,
         peers,
-        |_, _| {},
+        RaftConfig {
+            transfer_on_drain: true,
+            ..RaftConfig::default()
+        },
+        Box::new(FileSnapshotStore::for_peer(id)),
+        |_, _| Ok(Vec::new()),
         root.new(o!("sys" => "raft", "id"=>id)),
-    );
+    ) {
+        Ok(spawned) => spawned,
+        Err(e) => {
+            error!(&log, "unable to start the automaton ({})", e);
+            process::exit(1);
+        }
+    };
+
+    //
+    // - consume the notification sink and log whenever the LEADER changes (this is where
+    //   writes would be forwarded to)
+    // - the sink fails once the automaton exits, which ends the loop
+    //
+    {
+        let log = root.new(o!("sys" => "sink"));
+        let _ = thread::spawn(move || while let Some(notification) = sink.next() {
+            if let Notification::LEADER(id, host) = notification {
+                info!(&log, "LEADER is now peer #{} ({})", id, host);
+            }
+        });
+    }
 
     //
     // - trap SIGINT/SIGTERM to properly terminate all threads
     // - each thread will signal the termination event
     //
+    let stopping = Arc::new(AtomicBool::new(false));
     {
+        let stopping = stopping.clone();
         ctrlc::set_handler(move || {
 
             //
@@ -100,6 +132,7 @@ fn main() {
             //
             rsm::raft::ANCILLARY.reset();
             raft.drain();
+            stopping.store(true, Ordering::Release);
 
         }).unwrap();
     }
@@ -107,8 +140,21 @@ fn main() {
     //
     // - block on the termination event
     // - we are waiting for all our threads to gracefully drain/exit
+    // - once asked to stop give them a bounded amount of time, then exit anyway while
+    //   reporting how many are still holding the guard
     //
+    let pending = Arc::downgrade(&guard);
     drop(guard);
-    event.wait();
+    let mut deadline = None;
+    while !event.wait_timeout(Duration::from_millis(250)) {
+        if stopping.load(Ordering::Acquire) {
+            let now = Instant::now();
+            if now >= *deadline.get_or_insert(now + Duration::from_secs(5)) {
+                let n = pending.upgrade().map_or(0, |guard| Arc::strong_count(&guard) - 1);
+                warn!(&log, "{} thread(s) did not drain, exiting anyway", n);
+                process::exit(1);
+            }
+        }
+    }
     info!(&log, "exiting");
 }
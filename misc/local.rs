@@ -1,5 +1,26 @@
 //! Test application running multiple raft automata and allowing them to exchange commands. The
 //! leader append a random number of empty records on a periodic basis.
+//!
+//! The cluster is either synthetic (see --size) or described by a TOML file (see --config)
+//! listing each peer, e.g
+//!
+//! ```ignore
+//!   [[peer]]
+//!   id = 0
+//!   host = "10.0.0.1:9000"
+//!
+//!   [[peer]]
+//!   id = 1
+//!   host = "10.0.0.2:9000"
+//!   learner = true
+//!
+//!   [[peer]]
+//!   id = 2
+//!   host = "10.0.1.1:9000"
+//!   priority = 50
+//! ```
+//!
+//! in which case the peers talk over TCP and --id picks which one to run on this host.
 extern crate bincode;
 #[macro_use]
 extern crate clap;
@@ -12,26 +33,85 @@ extern crate serde_derive;
 extern crate slog;
 extern crate slog_async;
 extern crate slog_term;
+extern crate toml;
 
 use bincode::{deserialize, serialize};
 use rand::{Rng, thread_rng};
 use rsm::primitives::event::*;
+use rsm::raft::config::{RaftConfig, MAX_PRIORITY};
+use rsm::raft::snapshot::FileSnapshotStore;
 use rsm::raft::protocol::{Payload, Raft};
 use rsm::raft::sink::*;
+use rsm::raft::transport::TcpTransport;
 use slog::{Drain, Level, LevelFilter, Logger};
 use slog_term::{FullFormat, PlainSyncDecorator};
 use slog_async::Async;
 use std::cmp;
 use std::collections::HashMap;
 use std::env;
+use std::fs;
 use std::path::Path;
 use std::io::stderr;
+use std::process;
 use std::str;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::time::Duration;
 
+/// Lapse of time in milliseconds given to each automaton to drain upon SIGINT/SIGTERM.
+const DRAIN_TIMEOUT: u64 = 5000;
+
+/// Cluster configuration file, see the module documentation.
+#[derive(Deserialize)]
+struct Cluster {
+    peer: Vec<Member>,
+}
+
+#[derive(Deserialize)]
+struct Member {
+    id: u16,
+    host: String,
+    #[serde(default)]
+    learner: bool,
+    #[serde(default)]
+    priority: Option<u32>,
+}
+
+fn load(path: &str) -> Result<Vec<Member>, String> {
+
+    //
+    // - parse the file
+    // - the ids must be unique and within [0, n) where n is the number of peers, e.g each
+    //   peer gets its own slot once sorted
+    // - hosts must fit in 32 bytes (this is how they travel on the wire)
+    // - priorities are bounded
+    //
+    let text = fs::read_to_string(path).map_err(|e| format!("unable to read {} ({})", path, e))?;
+    let cluster: Cluster =
+        toml::from_str(&text).map_err(|e| format!("malformed {} ({})", path, e))?;
+    let mut members = cluster.peer;
+    if members.is_empty() {
+        return Err(format!("no peer defined in {}", path));
+    }
+    members.sort_by_key(|member| member.id);
+    for (n, member) in members.iter().enumerate() {
+        if member.id as usize >= members.len() {
+            return Err(format!("peer #{} out of range [0, {})", member.id, members.len()));
+        }
+        if member.id as usize != n {
+            return Err(format!("peer #{} defined more than once", member.id));
+        }
+        if member.host.is_empty() || member.host.len() > 32 {
+            return Err(format!("peer #{} has an invalid host ({:?})", member.id, member.host));
+        }
+        if member.priority.map_or(false, |priority| priority > MAX_PRIORITY) {
+            return Err(format!("peer #{} priority above {}", member.id, MAX_PRIORITY));
+        }
+    }
+    Ok(members)
+}
+
 fn main() {
 
     //
@@ -52,10 +132,57 @@ fn main() {
         (version: env!("CARGO_PKG_VERSION"))
         (@arg SIZE: -s --size +takes_value "number of automata to run")
         (@arg CHDIR: -c --chdir +takes_value "chdir directory")
+        (@arg TCP: -t --tcp +takes_value "base port to talk over loopback TCP")
+        (@arg CONFIG: --config +takes_value "cluster configuration file (TOML)")
+        (@arg ID: -i --id +takes_value "only run this peer (with --config)")
     ).get_matches();
 
     //
-    // - optionally chdir if the --chdir argument is set
+    // - prep the cluster configuration, either from the --config file or a mock one with
+    //   --size peers (capped to 15)
+    // - this is similar to the zookeeper configuration
+    // - a mock peer network destination is a simple label instead of a host:port, unless
+    //   talking over TCP in which case each automaton listens on its own port
+    // - a cluster described in a file always talks over TCP
+    //
+    let members = match value_t!(args, "CONFIG", String) {
+        Ok(path) => match load(&path) {
+            Ok(members) => members,
+            Err(e) => {
+                error!(&log, "{}", e);
+                process::exit(1);
+            }
+        },
+        Err(_) => {
+            let port = value_t!(args, "TCP", u16).ok();
+            let size = cmp::min(value_t!(args, "SIZE", u16).unwrap_or(3), 15);
+            (0..size)
+                .map(|n| Member {
+                    id: n,
+                    host: match port {
+                        Some(port) => format!("127.0.0.1:{}", port + n),
+                        None => format!("automaton #{}", n),
+                    },
+                    learner: false,
+                    priority: None,
+                })
+                .collect()
+        }
+    };
+    let tcp = args.is_present("CONFIG") || args.is_present("TCP");
+    let tags: Vec<_> = members.iter().map(|member| member.host.clone()).collect();
+    let ids: Vec<_> = match value_t!(args, "ID", u16) {
+        Ok(id) if id as usize >= members.len() => {
+            error!(&log, "peer #{} is not part of the cluster", id);
+            process::exit(1);
+        }
+        Ok(id) => vec![id],
+        Err(_) => members.iter().map(|member| member.id).collect(),
+    };
+
+    //
+    // - optionally chdir if the --chdir argument is set (once the configuration file is
+    //   loaded)
     //
     if let Ok(root) = value_t!(args, "CHDIR", String) {
         let path = Path::new(&root);
@@ -64,28 +191,30 @@ fn main() {
 
     //
     // - use a termination event to synchronize our shutdown sequence
-    // - start a few raft automata using the --size CLI argument
-    // - cap to 15 automata
+    // - start the raft automata we run locally
     //
     let event = Arc::new(Event::new());
     let guard = event.guard();
-    let size = cmp::min(value_t!(args, "SIZE", u8).unwrap_or(3), 15);
     let peers = Arc::new(Mutex::new(HashMap::<[u8; 32], Arc<Raft>>::new()));
-    for id in 0..size {
+    let transport = TcpTransport::new(root.new(o!("sys" => "tcp")));
+    for id in ids {
         let guard = guard.clone();
         let shared = peers.clone();
+        let transport = transport.clone();
+        let tags = tags.clone();
+        let learner = members[id as usize].learner;
+        let priority = members[id as usize].priority.unwrap_or(MAX_PRIORITY);
         let log = root.new(o!("sys" => "raft", "id" => id));
+        let net = root.new(o!("sys" => "net", "id" => id));
+        let stats = root.new(o!("sys" => "stats", "id" => id));
         let _ = thread::spawn(move || {
 
             //
-            // - prep a mock configuration with n peers
-            // - this is similar to the zookeeper configuration
-            // - instead of a host:port our network destination is a simple label
+            // - turn the configuration into the id/host seed map
             //
-            let tags: Vec<_> = (0..size).map(|n| format!("automaton #{}", n)).collect();
             let seeds: HashMap<_, _> = tags.iter()
                 .enumerate()
-                .map(|(n, tag)| (n as u8, tag.as_str()))
+                .map(|(n, tag)| (n as u16, tag.as_str()))
                 .collect();
 
             //
@@ -111,23 +240,52 @@ fn main() {
             //
             // - start a new automata
             // - retrieve a read lock on the payload plus a notification sink
+            // - all the automata boot at once: stagger their first election
+            // - hold the emitter back if the followers can't keep up
             //
             let (raft, _, sink) = {
                 let guard = guard.clone();
                 let shared = shared.clone();
+                let transport = transport.clone();
+                let rejects = Mutex::new(HashMap::<[u8; 32], usize>::new());
                 rsm::raft::spawn::<_, _, COUNTER, _>(
                     &guard,
                     id,
                     seeds,
+                    RaftConfig {
+                        startup_jitter: Duration::from_millis(500),
+                        store_high_water: Some(256),
+                        learner,
+                        priority,
+                        ..RaftConfig::default()
+                    },
+                    Box::new(FileSnapshotStore::for_peer(id)),
                     move |host, bytes| {
 
                         //
-                        // - find the destination automaton in our map
+                        // - over TCP just hand the buffer to the transport
+                        // - otherwise find the destination automaton in our map
                         // - post the opaque byte buffer
+                        // - count and log whatever the destination rejects
                         //
+                        if tcp {
+                            transport.send(host, bytes);
+                            return;
+                        }
                         let peers = shared.lock().unwrap();
                         let raft = &peers[host];
-                        raft.feed(bytes);
+                        if let Err(e) = raft.feed(bytes) {
+                            let mut rejects = rejects.lock().unwrap();
+                            let n = rejects.entry(*host).or_insert(0);
+                            *n += 1;
+                            warn!(
+                                &net,
+                                "{} rejected a buffer ({:?}, {} so far)",
+                                str::from_utf8(host).unwrap().trim_right_matches('\0'),
+                                e,
+                                n
+                            );
+                        }
                     },
                     |payload, _| {
 
@@ -136,15 +294,20 @@ fn main() {
                         // - the closure is executed with a write lock being held
                         //
                         payload.count += 1;
+                        Ok(Vec::new())
                     },
                     log,
-                )
+                ).expect("unable to spawn")
             };
 
             //
+            // - listen on our port if talking over TCP
             // - lock the mutex
             // - add this automaton to the shared peer map
             //
+            if tcp {
+                transport.listen(&tags[id as usize], raft.clone()).expect("unable to listen");
+            }
             {
                 let mut peers = shared.lock().unwrap();
                 peers.insert(Raft::get_host(tags[id as usize].as_str()), raft.clone());
@@ -167,6 +330,7 @@ fn main() {
                         //
                         let raft = raft.clone();
                         let emit = emit.clone();
+                        let stats = stats.clone();
                         emit.store(true, Ordering::Release);
                         let _ = thread::spawn(move || loop {
                             if emit.load(Ordering::Relaxed) {
@@ -178,6 +342,44 @@ fn main() {
                                 for _ in 0..thread_rng().gen_range(0, 10) {
                                     raft.store(Vec::new());
                                 }
+
+                                //
+                                // - report how far each follower is, flagging the ones that
+                                //   never acknowledged anything (e.g stuck or down)
+                                //
+                                for peer in raft.peer_metrics() {
+                                    match peer.lag {
+                                        Some(lag) => debug!(
+                                            &stats,
+                                            "peer #{} lagging by {} entries ({} in flight)",
+                                            peer.id,
+                                            lag,
+                                            peer.inflight
+                                        ),
+                                        None => warn!(
+                                            &stats,
+                                            "peer #{} never acknowledged anything",
+                                            peer.id
+                                        ),
+                                    }
+                                }
+
+                                //
+                                // - read our counter under the payload read lock
+                                //
+                                let applied = raft.query(|p: &COUNTER| p.count).unwrap_or(0);
+                                debug!(&stats, "{} entries applied so far", applied);
+
+                                //
+                                // - report the PING/REPLICATE ratio of what we sent so far
+                                //
+                                let messages = raft.message_stats();
+                                debug!(
+                                    &stats,
+                                    "{} PING / {} REPLICATE sent so far",
+                                    messages.sent("PING"),
+                                    messages.sent("REPLICATE")
+                                );
                                 thread::sleep(Duration::from_millis(1000));
                             } else {
                                 break;
@@ -212,6 +414,7 @@ fn main() {
     //
     {
         let shared = peers.clone();
+        let log = log.clone();
         ctrlc::set_handler(move || {
 
             //
@@ -226,10 +429,14 @@ fn main() {
             for peer in &*peers {
 
                 //
-                // - drain() is going to gracefully shutdown the automaton thread
+                // - drain_timeout() is going to gracefully shutdown the automaton thread
                 // - upon termination it will signal the notification sink and drop its guard
+                // - an automaton that does not drain in time is aborted (its guard is dropped
+                //   anyway)
                 //
-                peer.1.drain();
+                if !peer.1.drain_timeout(Duration::from_millis(DRAIN_TIMEOUT)) {
+                    warn!(&log, "automaton aborted (unable to drain in time)");
+                }
             }
         }).unwrap();
     }
@@ -0,0 +1,94 @@
+//! Crate level error, e.g whatever the public API may fail with. The specific errors returned
+//! by the raft handle (`StoreError`, `FeedError`, etc) all convert into it.
+use bincode;
+use raft::config::ConfigError;
+use raft::protocol::{FeedError, MembershipError, ReadError, StoreError};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::PoisonError;
+
+#[derive(Debug)]
+pub enum RsmError {
+    /// Some I/O failed, e.g the log file could not be set up.
+    Io(io::Error),
+    /// Something could not be serialized or deserialized.
+    Serialization(bincode::Error),
+    /// A lock was poisoned by a thread panicking while holding it.
+    Poisoned,
+    /// The configuration is invalid, see `RaftConfig::validate()`.
+    Config(ConfigError),
+    /// A store was rejected, see `Raft::store()`.
+    Store(StoreError),
+    /// A read was rejected, see `Raft::read()`.
+    Read(ReadError),
+    /// A buffer was rejected, see `Raft::feed()`.
+    Feed(FeedError),
+    /// A membership change was rejected, see `Raft::add_peer()`.
+    Membership(MembershipError),
+}
+
+impl fmt::Display for RsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            RsmError::Io(ref e) => write!(f, "I/O error ({})", e),
+            RsmError::Serialization(ref e) => write!(f, "serialization error ({})", e),
+            RsmError::Poisoned => write!(f, "poisoned lock"),
+            RsmError::Config(ref e) => write!(f, "invalid configuration ({:?})", e),
+            RsmError::Store(ref e) => write!(f, "store rejected ({:?})", e),
+            RsmError::Read(ref e) => write!(f, "read rejected ({:?})", e),
+            RsmError::Feed(ref e) => write!(f, "buffer rejected ({:?})", e),
+            RsmError::Membership(ref e) => write!(f, "membership change rejected ({:?})", e),
+        }
+    }
+}
+
+impl Error for RsmError {}
+
+impl From<io::Error> for RsmError {
+    fn from(e: io::Error) -> Self {
+        RsmError::Io(e)
+    }
+}
+
+impl From<bincode::Error> for RsmError {
+    fn from(e: bincode::Error) -> Self {
+        RsmError::Serialization(e)
+    }
+}
+
+impl<T> From<PoisonError<T>> for RsmError {
+    fn from(_: PoisonError<T>) -> Self {
+        RsmError::Poisoned
+    }
+}
+
+impl From<ConfigError> for RsmError {
+    fn from(e: ConfigError) -> Self {
+        RsmError::Config(e)
+    }
+}
+
+impl From<StoreError> for RsmError {
+    fn from(e: StoreError) -> Self {
+        RsmError::Store(e)
+    }
+}
+
+impl From<ReadError> for RsmError {
+    fn from(e: ReadError) -> Self {
+        RsmError::Read(e)
+    }
+}
+
+impl From<FeedError> for RsmError {
+    fn from(e: FeedError) -> Self {
+        RsmError::Feed(e)
+    }
+}
+
+impl From<MembershipError> for RsmError {
+    fn from(e: MembershipError) -> Self {
+        RsmError::Membership(e)
+    }
+}
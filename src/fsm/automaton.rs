@@ -2,10 +2,11 @@
 //! dedicated thread.
 use primitives::event::*;
 use self::mpsc::*;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
 use std::mem;
+use std::time::Duration;
 use super::*;
 
 #[allow(dead_code)]
@@ -61,6 +62,9 @@ where
     event: Event,
     inbox: MPSC<T>,
     mode: AtomicUsize,
+    guard: Mutex<Option<Arc<Guard>>>,
+    aborted: AtomicBool,
+    exited: Event,
 }
 
 impl<T> Automaton<T>
@@ -86,6 +90,9 @@ where
             inbox: MPSC::new(),
             event: Event::new(),
             mode: AtomicUsize::new(0),
+            guard: Mutex::new(Some(guard)),
+            aborted: AtomicBool::new(false),
+            exited: Event::new(),
         });
 
         {
@@ -107,6 +114,15 @@ where
                 let _ = body.recv(&fsm, state, START);
                 loop {
 
+                    //
+                    // - abort() was invoked: give up on whatever is left in the queue
+                    // - recv() a last time on EXIT and exit the processing loop
+                    //
+                    if fsm.aborted.load(Ordering::Acquire) {
+                        let _ = body.recv(&fsm, state, EXIT);
+                        break;
+                    }
+
                     //
                     // - we are either RUNNING or SIGNALED
                     // - if SIGNALED (e.g drain() was invoked) transition to DRAINING and
@@ -140,7 +156,7 @@ where
                         // - transition a last time and recv() on EXIT
                         // - we're done, exit the processing loop
                         //
-                        fsm.mode.fetch_add(1, Ordering::Release);
+                        fsm.mode.store(DEAD as usize, Ordering::Release);
                         println!("posting EXIT");
                         let _ = body.recv(&fsm, state, EXIT);
                         break;
@@ -157,10 +173,11 @@ where
 
                 //
                 // - the automaton is now dead
-                // - drop the guard to signal the underlying event
+                // - drop the guard to signal the underlying event (unless abort() did)
                 // - exit the thread
                 //
-                drop(guard);
+                fsm.release();
+                fsm.exited.signal();
                 println!("exiting thread");
 
             });
@@ -191,12 +208,56 @@ where
         let _ = self.transition_if(RUNNING, || self.event.signal());
     }
 
+    /// Gives up on the commands still queued: the event loop recv() on EXIT as soon as it is
+    /// done with the current command and exits. The guard is dropped right away, e.g whoever
+    /// waits on it is not held back by an event loop stuck in its handler (its thread is then
+    /// left behind).
+    pub fn abort(&self) -> () {
+        self.aborted.store(true, Ordering::Release);
+        self.mode.store(DEAD as usize, Ordering::Release);
+        self.event.signal();
+        self.release();
+    }
+
+    /// Whether abort() was invoked.
+    #[inline]
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Acquire)
+    }
+
+    /// Waits for the event loop to exit.
+    pub fn wait(&self) -> () {
+
+        //
+        // - same as wait_timeout(), signal the exit event again so that it stays set
+        //
+        self.exited.wait();
+        self.exited.signal();
+    }
+
+    /// Waits for the event loop to exit for at most the specified lapse of time. Returns true
+    /// if it did, including if it already had upon calling.
+    pub fn wait_timeout(&self, lapse: Duration) -> bool {
+
+        //
+        // - the exit event is signaled once: signal it again so that it stays set
+        //
+        let exited = self.exited.wait_timeout(lapse);
+        if exited {
+            self.exited.signal();
+        }
+        exited
+    }
+
     #[inline]
     pub fn mode(&self) -> Mode {
         let mode = self.mode.load(Ordering::Relaxed);
         unsafe { mem::transmute(mode as u8) }
     }
 
+    /// Enqueues a command. Commands posted by one thread are processed in the order they were
+    /// posted. Commands posted concurrently from several threads are interleaved in some total
+    /// order which is not specified.
     #[inline]
     pub fn post(&self, msg: T) -> Result<(), Errors> {
 
@@ -214,6 +275,11 @@ where
         }
     }
 
+    fn release(&self) -> () {
+        let guard = self.guard.lock().unwrap().take();
+        drop(guard);
+    }
+
     #[inline]
     fn transition_if<F>(&self, expected: Mode, mut f: F) -> bool
     where
@@ -46,9 +46,13 @@ where
 
                 //
                 // - read from stdin and append to our buffer
+                // - STDIN failing (e.g closed by the parent) ends the stream
                 //
                 let n = {
-                    let read = pipe.fill_buf().unwrap();
+                    let read = match pipe.fill_buf() {
+                        Ok(read) => read,
+                        Err(_) => break,
+                    };
                     let n = read.len();
                     if n > 0 {
                         buf.extend_from_slice(read);
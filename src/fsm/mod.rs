@@ -9,12 +9,14 @@ mod tests {
     use primitives::event::*;
     use fsm::automaton::*;
     use fsm::timer::*;
-    use std::sync::Arc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
     use std::time::Duration;
 
     #[derive(Debug)]
     enum Command {
         TERMINATE,
+        PUSH(usize, usize),
     }
 
     #[derive(Debug, Copy, Clone, PartialEq)]
@@ -100,4 +102,120 @@ mod tests {
         drop(guard);
         event.wait();
     }
+
+    #[test]
+    fn per_thread_order() {
+
+        struct FSM {
+            seen: Arc<Mutex<Vec<(usize, usize)>>>,
+        }
+
+        impl Recv<Command, State> for FSM {
+            fn recv(
+                &mut self,
+                _this: &Arc<Automaton<Command>>,
+                state: State,
+                opcode: Opcode<Command, State>,
+            ) -> State {
+                if let Opcode::CMD(PUSH(id, n)) = opcode {
+                    self.seen.lock().unwrap().push((id, n));
+                }
+                state
+            }
+        }
+
+        //
+        // - 4 threads each post a sequence of 1000 commands concurrently
+        // - wait for them to be done and drain the automaton
+        //
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let event = Event::new();
+        let guard = event.guard();
+        let fsm = Automaton::spawn(guard.clone(), Box::new(FSM { seen: seen.clone() }));
+        let threads: Vec<_> = (0..4)
+            .map(|id| {
+                let fsm = fsm.clone();
+                thread::spawn(move || for n in 0..1000 {
+                    fsm.post(PUSH(id, n)).unwrap();
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        drop(guard);
+        fsm.drain();
+        event.wait();
+
+        //
+        // - everything must have been processed
+        // - each thread's sequence must show up in order
+        //
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 4000);
+        let mut next = [0; 4];
+        for &(id, n) in seen.iter() {
+            assert_eq!(n, next[id]);
+            next[id] += 1;
+        }
+    }
+
+    #[test]
+    fn abort_stuck_handler() {
+
+        struct FSM {
+            exited: Arc<Mutex<bool>>,
+        }
+
+        impl Recv<Command, State> for FSM {
+            fn recv(
+                &mut self,
+                _this: &Arc<Automaton<Command>>,
+                state: State,
+                opcode: Opcode<Command, State>,
+            ) -> State {
+                match opcode {
+                    Opcode::CMD(TERMINATE) => thread::sleep(Duration::from_millis(500)),
+                    Opcode::EXIT => *self.exited.lock().unwrap() = true,
+                    _ => {}
+                }
+                state
+            }
+        }
+
+        //
+        // - a clean drain is reported by wait_timeout()
+        //
+        let exited = Arc::new(Mutex::new(false));
+        let event = Event::new();
+        let guard = event.guard();
+        let fsm = Automaton::spawn(guard.clone(), Box::new(FSM { exited: exited.clone() }));
+        drop(guard);
+        fsm.drain();
+        assert!(fsm.wait_timeout(Duration::from_millis(250)));
+        assert!(fsm.wait_timeout(Duration::from_millis(0)));
+        assert!(!fsm.is_aborted());
+        event.wait();
+
+        //
+        // - the handler is stuck on a command, the drain does not complete in time
+        // - aborting releases the guard right away
+        // - the event loop still recv() on EXIT once the handler returns
+        //
+        let exited = Arc::new(Mutex::new(false));
+        let event = Event::new();
+        let guard = event.guard();
+        let fsm = Automaton::spawn(guard.clone(), Box::new(FSM { exited: exited.clone() }));
+        drop(guard);
+        fsm.post(TERMINATE).unwrap();
+        fsm.post(PUSH(0, 0)).unwrap();
+        fsm.drain();
+        assert!(!fsm.wait_timeout(Duration::from_millis(50)));
+        fsm.abort();
+        assert!(fsm.is_aborted());
+        assert!(event.wait_timeout(Duration::from_millis(50)));
+        assert!(fsm.post(PUSH(0, 1)).is_err());
+        assert!(fsm.wait_timeout(Duration::from_millis(1000)));
+        assert!(*exited.lock().unwrap());
+    }
 }
@@ -4,7 +4,7 @@ use fsm::automaton::{Automaton, Opcode, Recv};
 use primitives::event::*;
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
-use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::sync::{Arc, Condvar, Mutex, PoisonError, Weak};
 use std::thread;
 use std::time::{Duration, Instant};
 
@@ -222,8 +222,8 @@ where
                     // - lock the mutex and wait on the condition variable for up
                     //   to 25ms
                     //
-                    let lock = mtx.lock().unwrap();
-                    let _ = cv.wait_timeout(lock, Duration::from_millis(25)).unwrap();
+                    let lock = mtx.lock().unwrap_or_else(PoisonError::into_inner);
+                    let _ = cv.wait_timeout(lock, Duration::from_millis(25));
                 }
                 drop(guard);
             });
@@ -17,13 +17,22 @@
     too_many_arguments,
     use_self))]
 extern crate bincode;
+#[cfg(feature = "auth")]
+extern crate hmac;
 extern crate memmap;
 extern crate rand;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[cfg(feature = "json")]
+#[macro_use]
+extern crate serde_json;
 #[macro_use]
 extern crate slog;
+#[cfg(feature = "auth")]
+extern crate sha2;
 
+pub mod error;
 pub mod fsm;
 pub mod primitives;
 pub mod raft;
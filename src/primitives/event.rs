@@ -2,22 +2,32 @@
 //! [preshing blog](http://preshing.com/20150316/semaphores-are-surprisingly-versatile/) post.
 //! This construct can typically be used to wake threads up when work is available. An additional
 //! guard is also provided to allow for signaling the event when the guard drops (very handy to
-//! wait for a group of threads to complete work).
+//! wait for a group of threads to complete work). Once its guards are gone an event may be
+//! reset and reused for another group.
+//!
+//! An event may also be created in broadcast mode, in which case signaling it releases every
+//! waiting thread at once and it then remains signaled (until reset): any later wait returns
+//! right away.
 //!
 //! Please note each event may carry 32bits of user payload.
 use self::semaphore::*;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use super::*;
 
-/// Trivial auto-reset event wrapping a semaphore whose count is capped at 1.
+/// Trivial auto-reset event wrapping a semaphore whose count is capped at 1. In broadcast mode
+/// signaling disables the semaphore instead, which releases all its waiters.
 pub struct Event {
     sem: Arc<Semaphore>,
+    guards: Arc<AtomicUsize>,
+    broadcast: bool,
 }
 
 /// Shallow guard owning a clone of the event's semaphore and signaling it upon
 /// dropping. This is handy to synchronize a thread based on how long the guard
 /// is shared across 1+ other threads.
-pub struct Guard(Arc<Semaphore>);
+pub struct Guard(Arc<Semaphore>, Arc<AtomicUsize>, bool);
 
 impl Default for Event {
     fn default() -> Self {
@@ -33,7 +43,21 @@ impl Event {
 
     #[inline]
     pub fn with(tag: u32) -> Self {
-        Event { sem: Arc::new(Semaphore::with(tag)) }
+        Event {
+            sem: Arc::new(Semaphore::with(tag)),
+            guards: Arc::new(AtomicUsize::new(0)),
+            broadcast: false,
+        }
+    }
+
+    /// Creates an event in broadcast mode: one signal releases every thread waiting on it and
+    /// any thread waiting afterwards (until the event is reset).
+    #[inline]
+    pub fn broadcast() -> Self {
+        Event {
+            broadcast: true,
+            ..Event::new()
+        }
     }
 
     #[inline]
@@ -43,7 +67,11 @@ impl Event {
 
     #[inline]
     pub fn signal(&self) -> () {
-        self.sem.signal_under(1);
+        if self.broadcast {
+            self.sem.disable();
+        } else {
+            self.sem.signal_under(1);
+        }
     }
 
     #[inline]
@@ -51,14 +79,46 @@ impl Event {
         self.sem.wait();
     }
 
+    /// Waits for the event to be signaled for at most the specified lapse of time. Returns
+    /// true if it was, including if it already was upon calling.
+    #[inline]
+    pub fn wait_timeout(&self, lapse: Duration) -> bool {
+        self.sem.wait_timeout(lapse) || (self.broadcast && self.sem.is_disabled())
+    }
+
     #[inline]
     pub fn guard(&self) -> Arc<Guard> {
-        Arc::new(Guard(self.sem.clone()))
+        self.guards.fetch_add(1, Ordering::Relaxed);
+        Arc::new(Guard(self.sem.clone(), self.guards.clone(), self.broadcast))
+    }
+
+    /// Returns the event to its initial unsignaled state, e.g to reuse it for another
+    /// generation of guards. This is only valid once every guard is dropped and nobody is
+    /// waiting (their wakeup would otherwise be lost), which the caller must ensure does not
+    /// change while resetting.
+    ///
+    /// # Panics
+    ///
+    /// If a guard is still alive or if a thread is waiting on the event.
+    #[inline]
+    pub fn reset(&self) -> () {
+        assert!(self.guards.load(Ordering::Acquire) == 0, "resetting an event with live guards");
+        assert!(self.sem.reset(), "resetting an event with waiting threads");
     }
 }
 
 impl Drop for Guard {
     fn drop(&mut self) -> () {
-        self.0.signal();
+
+        //
+        // - account for the guard before signaling: whoever wakes up upon the signal sees it
+        //   gone and may reset the event
+        //
+        self.1.fetch_sub(1, Ordering::Release);
+        if self.2 {
+            self.0.disable();
+        } else {
+            self.0.signal();
+        }
     }
 }
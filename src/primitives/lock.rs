@@ -20,13 +20,26 @@
 //! building higher level synchronization primitives (or just debugging). In addition the
 //! lock tracks the count of pending threads, which is also precious informaton in
 //! some situations.
-use std::sync::atomic::{AtomicUsize, Ordering};
+//!
+//! A lock may also be acquired through a scoped guard releasing it upon dropping, in which case
+//! a thread panicking while holding it flags the lock as poisoned (instead of leaving it held
+//! forever). Whoever acquires it next is told about it and may then clear the flag if the state
+//! it protects is still consistent.
+//!
+//! The module also provides a `RwLock` letting many readers or one writer in. Writers queue
+//! up on a regular lock (and therefore follow its strategy) while readers arriving with a
+//! writer in or pending park in that same queue, e.g a steady flow of readers can't starve
+//! writers.
+use std::fmt;
+use std::sync::PoisonError;
+use std::sync::atomic::{spin_loop_hint, AtomicUsize, Ordering};
 use std::thread;
 use super::*;
 
 const LOCK: usize = 1;
 const BUSY: usize = 2;
 const PENDING: usize = 4;
+const POISONED: usize = 8;
 
 /// Raw lock storing its state in a atomic usize and maintaining a parking queue according to the
 /// specified strategy. The lock is able to carry user payload (as a u32) as well as a counter
@@ -94,6 +107,36 @@ where
         (cur & CNT_MSK) >> 8
     }
 
+    /// Whether a thread panicked while holding the lock through a `LockGuard`.
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        let cur = self.tag.load(Ordering::Relaxed);
+        cur & POISONED > 0
+    }
+
+    /// Unflags the lock, e.g once the caller made sure the state it protects is consistent.
+    #[inline]
+    pub fn clear_poison(&self) -> () {
+        let _ = self.tag.fetch_and(!POISONED, Ordering::Release);
+    }
+
+    /// Same as `lock()` except the lock is released when the returned guard drops. Fails if the
+    /// lock is poisoned, in which case the lock is still acquired and the error carries the
+    /// guard.
+    #[inline]
+    pub fn acquire<F>(&self, update: F) -> Result<LockGuard<'_, T>, Poisoned<LockGuard<'_, T>>>
+    where
+        F: Fn(u32) -> u32,
+    {
+        self.lock(update);
+        let guard = LockGuard { lock: self };
+        if self.is_poisoned() {
+            Err(Poisoned(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
     #[inline]
     pub fn lock<F>(&self, update: F) -> ()
     where
@@ -121,6 +164,34 @@ where
         };
     }
 
+    /// Same as `lock()` except it returns false right away if the lock is held, e.g the
+    /// thread is never parked.
+    #[inline]
+    pub fn try_lock<F>(&self, update: F) -> bool
+    where
+        F: Fn(u32) -> u32,
+    {
+        //
+        // - attempt to flip the LOCK bit as long as it is not set
+        // - retry upon spurious CAS failures or concurrent updates of the other bits
+        // - the queue is never touched, so giving up leaves nothing behind
+        //
+        let mut cur = self.tag.load(Ordering::Relaxed);
+        while cur & LOCK == 0 {
+            let user = update((cur >> 32) as u32);
+            match self.tag.compare_exchange_weak(
+                cur,
+                (cur & !USR_MSK) | ((user as usize) << 32) | LOCK,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(prv) => cur = prv,
+            }
+        }
+        false
+    }
+
     #[cold]
     #[inline(never)]
     unsafe fn lock_cold<F>(&self, update: F) -> ()
@@ -152,6 +223,36 @@ where
             ).is_err()
             {
 
+                //
+                // - keep on attempting to flip the LOCK bit for as long as the strategy wants
+                // - report how that went so that it may adjust
+                //
+                let spins = self.queue.spins();
+                if spins > 0 {
+                    let mut rounds = 0;
+                    let mut acquired = false;
+                    while rounds < spins && !acquired {
+                        rounds += 1;
+                        spin_loop_hint();
+                        let cur = self.tag.load(Ordering::Relaxed);
+                        if cur & LOCK == 0 {
+                            let user = update((cur >> 32) as u32);
+                            acquired = self.tag
+                                .compare_exchange_weak(
+                                    cur,
+                                    (cur & !USR_MSK) | ((user as usize) << 32) | LOCK,
+                                    Ordering::Acquire,
+                                    Ordering::Relaxed,
+                                )
+                                .is_ok();
+                        }
+                    }
+                    self.queue.spun(rounds, acquired);
+                    if acquired {
+                        break;
+                    }
+                }
+
                 //
                 // - the LOCK bit is still set in theory
                 // - attempt to spin and flip the BUSY bit as long as LOCK is set
@@ -184,7 +285,7 @@ where
                     // - lock the mutex and release the queue by flipping the BUSY bit
                     // - at this point other lock()/unlock() invokations may proceed
                     //
-                    let mut parked = synchro.0.lock().unwrap();
+                    let mut parked = synchro.0.lock().unwrap_or_else(PoisonError::into_inner);
                     let _ = self.tag.fetch_sub(BUSY, Ordering::Release);
 
                     //
@@ -193,7 +294,7 @@ where
                     //
                     *parked = true;
                     while *parked {
-                        parked = synchro.1.wait(parked).unwrap();
+                        parked = synchro.1.wait(parked).unwrap_or_else(PoisonError::into_inner);
                     }
 
                     //
@@ -289,7 +390,7 @@ where
             // - notify the condvar at which point the corresponding thread will be
             //   scheduling again (and attempt to acquire the lock in lock_cold())
             //
-            let mut parked = synchro.0.lock().unwrap();
+            let mut parked = synchro.0.lock().unwrap_or_else(PoisonError::into_inner);
             *parked = false;
             synchro.1.notify_one();
 
@@ -306,3 +407,163 @@ where
         }
     }
 }
+
+/// Scoped ownership of a `Lock`, see `Lock::acquire()`. The lock is released when the guard
+/// drops and flagged as poisoned if this happens while the thread is panicking.
+pub struct LockGuard<'a, T>
+where
+    T: 'a + Default + Strategy,
+{
+    lock: &'a Lock<T>,
+}
+
+impl<'a, T> Drop for LockGuard<'a, T>
+where
+    T: 'a + Default + Strategy,
+{
+    fn drop(&mut self) -> () {
+        if thread::panicking() {
+            let _ = self.lock.tag.fetch_or(POISONED, Ordering::Release);
+        }
+        self.lock.unlock(|n| n);
+    }
+}
+
+/// Error returned when acquiring a poisoned lock, carrying the guard (the lock is held anyway).
+pub struct Poisoned<G>(pub G);
+
+impl<G> Poisoned<G> {
+    /// Recovers the guard, e.g to proceed regardless.
+    #[inline]
+    pub fn into_inner(self) -> G {
+        self.0
+    }
+}
+
+impl<G> fmt::Debug for Poisoned<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "poisoned lock")
+    }
+}
+
+const WRITER: usize = 1;
+const READER: usize = 2;
+const RDR_MSK: usize = 0xFFFF_FFFE;
+
+/// Raw read-write lock built on top of a `Lock`. Any number of readers may hold it at the same
+/// time, or one writer. Like `Lock` it carries a u32 of user payload which readers are passed
+/// and the writer may update.
+///
+/// The state usize is laid out as follows:
+///
+///    |         32          |        31        |  1  |
+///             user                readers      writer
+pub struct RwLock<T>
+where
+    T: Strategy,
+{
+    state: AtomicUsize,
+    lock: Lock<T>,
+}
+
+impl<T> Default for RwLock<T>
+where
+    T: Default + Strategy,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> RwLock<T>
+where
+    T: Default + Strategy,
+{
+    #[inline]
+    pub fn new() -> Self {
+        RwLock::with(0)
+    }
+
+    #[inline]
+    pub fn with(tag: u32) -> Self {
+        RwLock {
+            state: AtomicUsize::new((tag as usize) << 32),
+            lock: Lock::new(),
+        }
+    }
+
+    #[inline]
+    pub fn tag(&self) -> u32 {
+        let cur = self.state.load(Ordering::Relaxed);
+        (cur >> 32) as u32
+    }
+
+    #[inline]
+    pub fn readers(&self) -> usize {
+        let cur = self.state.load(Ordering::Relaxed);
+        (cur & RDR_MSK) >> 1
+    }
+
+    /// Runs the closure holding the lock in shared mode, passing it the user payload. Whatever
+    /// the closure returns is passed back.
+    #[inline]
+    pub fn read<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(u32) -> R,
+    {
+        //
+        // - increment the readers count as long as no writer is in or pending
+        // - otherwise park in the writer queue: we'll be let through once the writer is done
+        //
+        let mut cur = self.state.load(Ordering::Relaxed);
+        loop {
+            if cur & WRITER == 0 {
+                match self.state.compare_exchange_weak(
+                    cur,
+                    cur + READER,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(prv) => cur = prv,
+                }
+            } else {
+                self.lock.lock(|n| n);
+                self.lock.unlock(|n| n);
+                cur = self.state.load(Ordering::Relaxed);
+            }
+        }
+
+        let res = f((cur >> 32) as u32);
+        let _ = self.state.fetch_sub(READER, Ordering::Release);
+        res
+    }
+
+    /// Runs the closure holding the lock in exclusive mode, passing it the user payload which
+    /// is then updated with whatever the closure returns.
+    #[inline]
+    pub fn write<F>(&self, update: F) -> ()
+    where
+        F: FnOnce(u32) -> u32,
+    {
+        //
+        // - queue up behind the other writers
+        // - flip the WRITER bit: from now on readers park behind us
+        // - wait for the readers which are already in to drain
+        //
+        self.lock.lock(|n| n);
+        let mut cur = self.state.fetch_or(WRITER, Ordering::Acquire) | WRITER;
+        while cur & RDR_MSK > 0 {
+            thread::yield_now();
+            cur = self.state.load(Ordering::Acquire);
+        }
+
+        //
+        // - we are alone: update the user payload and unset the WRITER bit in one go
+        // - release the writer queue, which also wakes up the next parked thread if any
+        //
+        let user = update((cur >> 32) as u32);
+        self.state.store((user as usize) << 32, Ordering::Release);
+        self.lock.unlock(|n| n);
+    }
+}
@@ -1,4 +1,5 @@
 use std::cell::UnsafeCell;
+use std::cmp;
 use std::ptr;
 use std::sync::{Arc, Mutex, Condvar};
 use std::sync::atomic::{AtomicUsize, Ordering, spin_loop_hint};
@@ -13,6 +14,8 @@ pub mod semaphore;
 
 const CNT_MSK: usize = 0xFFFF_FF00;
 const USR_MSK: usize = 0xFFFF_FFFF_0000_0000;
+const MIN_SPINS: usize = 16;
+const MAX_SPINS: usize = 1024;
 
 ///
 /// Internal double linked list node holding mutex/condvar pairs. Those
@@ -38,6 +41,16 @@ impl Node {
 pub trait Strategy {
     unsafe fn push(&self) -> Arc<(Mutex<bool>, Condvar)>;
     unsafe fn pop(&self) -> (bool, Arc<(Mutex<bool>, Condvar)>);
+
+    /// How many more times a contending thread should attempt to grab the lock before
+    /// parking, past the initial spinning period. None by default.
+    fn spins(&self) -> usize {
+        0
+    }
+
+    /// Reports how many of those attempts a thread made and whether it eventually got the
+    /// lock or parked.
+    fn spun(&self, _rounds: usize, _acquired: bool) -> () {}
 }
 
 /// Simple LIFO queue, e.g a stack. This is very light although not really
@@ -90,6 +103,35 @@ impl Strategy for LIFO {
     }
 }
 
+impl LIFO {
+    /// Unlinks the node holding the specified mutex/condvar pair, if still queued. This is
+    /// used to back out of a timed wait. The queue must be owned by the caller.
+    unsafe fn remove(&self, synchro: &Arc<(Mutex<bool>, Condvar)>) -> bool {
+
+        //
+        // - walk the stack from the head while keeping track of the link pointing to
+        //   the current node
+        // - once found re-link the previous node (or the head) past it
+        //
+        let mut link = self.head.get();
+        while !(*link).is_null() {
+            let cur = *link;
+            if Arc::ptr_eq(&(*cur).synchro, synchro) {
+                *link = *(*cur).p.get();
+                let _: Box<Node> = Box::from_raw(cur);
+                return true;
+            }
+            link = (*cur).p.get();
+        }
+        false
+    }
+
+    #[inline]
+    unsafe fn is_empty(&self) -> bool {
+        (*self.head.get()).is_null()
+    }
+}
+
 /// Simple FIFO queue. This is a bit heavier in terms of code but is
 /// guaranteed to be fair. Threads will be awaken based on their waiting
 /// order.
@@ -174,6 +216,61 @@ impl Strategy for FIFO {
     }
 }
 
+/// LIFO queue whose threads keep on attempting to grab the lock for a while before parking.
+/// How long they spin tracks a moving average of how many attempts recently sufficed, and
+/// shrinks whenever a thread has to park anyway (e.g under heavy contention spinning only
+/// burns CPU). This favors hot threads under light contention, like LIFO does, while
+/// avoiding most of the park/unpark round-trips.
+pub struct Adaptive {
+    queue: LIFO,
+    avg: AtomicUsize,
+}
+
+impl Default for Adaptive {
+    fn default() -> Self {
+        Adaptive {
+            queue: LIFO::default(),
+            avg: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Strategy for Adaptive {
+    #[inline]
+    unsafe fn push(&self) -> Arc<(Mutex<bool>, Condvar)> {
+        self.queue.push()
+    }
+
+    #[inline]
+    unsafe fn pop(&self) -> (bool, Arc<(Mutex<bool>, Condvar)>) {
+        self.queue.pop()
+    }
+
+    #[inline]
+    fn spins(&self) -> usize {
+        let avg = self.avg.load(Ordering::Relaxed);
+        cmp::min(MIN_SPINS + 2 * avg, MAX_SPINS)
+    }
+
+    fn spun(&self, rounds: usize, acquired: bool) -> () {
+
+        //
+        // - move the average 1/8th of the way towards the number of attempts it took
+        // - halve it if the thread parked
+        // - concurrent updates may be lost, which is fine for a heuristic
+        //
+        let avg = self.avg.load(Ordering::Relaxed);
+        let next = if !acquired {
+            avg / 2
+        } else if rounds > avg {
+            avg + (rounds - avg) / 8
+        } else {
+            avg - (avg - rounds) / 8
+        };
+        self.avg.store(next, Ordering::Relaxed);
+    }
+}
+
 pub fn set_or_spin<E, F, G>(
     state: &AtomicUsize,
     on: usize,
@@ -247,8 +344,9 @@ mod tests {
     use primitives::tests::rand::{Rng, thread_rng};
     use primitives::rwlock::*;
     use std::sync::Arc;
-    use std::sync::atomic::spin_loop_hint;
+    use std::sync::atomic::{spin_loop_hint, AtomicUsize, Ordering};
     use std::thread;
+    use std::time::Duration;
 
     fn random_work(spins: usize) -> () {
         let mut rng = thread_rng();
@@ -285,6 +383,38 @@ mod tests {
         assert!(lock.pending() == 0);
     }
 
+    #[test]
+    fn synchro_adaptive() {
+
+        //
+        // - same as synchro_event() with threads spinning before parking
+        // - the spin budget stays within its bounds
+        //
+        let lock = Arc::new(Lock::<Adaptive>::new());
+        let event = Arc::new(Event::new());
+        {
+            let guard = event.guard();
+            for _ in 0..64 {
+                let lock = lock.clone();
+                let guard = guard.clone();
+                let _ = thread::spawn(move || {
+                    for _ in 0..16 {
+                        lock.lock(|n| n);
+                        random_work(40);
+                        lock.unlock(|n| n + 1);
+                    }
+                    drop(guard);
+                });
+            }
+        }
+
+        event.wait();
+        assert!(lock.tag() == 64 * 16);
+        assert!(lock.pending() == 0);
+        let spins = lock.queue.spins();
+        assert!(spins >= MIN_SPINS && spins <= MAX_SPINS);
+    }
+
     #[test]
     fn rw_lock() {
 
@@ -327,6 +457,42 @@ mod tests {
         assert!(*lock.read() == 32);
     }
 
+    #[test]
+    fn synchro_rw() {
+
+        let lock = Arc::new(RwLock::<FIFO>::new());
+        let event = Arc::new(Event::new());
+
+        {
+            let guard = event.guard();
+            for n in 0..64 {
+
+                let lock = lock.clone();
+                let guard = guard.clone();
+                let _ = thread::spawn(move || {
+
+                    if n % 2 == 0 {
+                        lock.read(|_| {
+                            assert!(lock.readers() > 0);
+                            random_work(40);
+                        });
+                    } else {
+                        lock.write(|tag| {
+                            assert!(lock.readers() == 0);
+                            random_work(40);
+                            tag + 1
+                        });
+                    }
+                    drop(guard);
+                });
+            }
+        }
+
+        event.wait();
+        assert!(lock.readers() == 0);
+        assert!(lock.read(|n| n) == 32);
+    }
+
     #[test]
     fn synchro_once() {
 
@@ -408,4 +574,235 @@ mod tests {
             gate.open();
         }
     }
+
+    #[test]
+    fn queue_order() {
+
+        //
+        // - park 3 nodes and check the order they are handed back in
+        // - FIFO dequeues in arrival order, LIFO in reverse
+        // - only the last pop reports the queue as empty
+        //
+        unsafe {
+            let fifo = FIFO::default();
+            let pushed: Vec<_> = (0..3).map(|_| fifo.push()).collect();
+            for (n, synchro) in pushed.iter().enumerate() {
+                let (last, popped) = fifo.pop();
+                assert!(Arc::ptr_eq(synchro, &popped));
+                assert!(last == (n == 2));
+            }
+
+            let lifo = LIFO::default();
+            let pushed: Vec<_> = (0..3).map(|_| lifo.push()).collect();
+            for (n, synchro) in pushed.iter().rev().enumerate() {
+                let (last, popped) = lifo.pop();
+                assert!(Arc::ptr_eq(synchro, &popped));
+                assert!(last == (n == 2));
+            }
+        }
+    }
+
+    #[test]
+    fn event_timeout() {
+
+        //
+        // - nobody signals: we give up after the lapse of time
+        // - a pending signal is consumed right away
+        //
+        let event = Arc::new(Event::new());
+        assert!(!event.wait_timeout(Duration::from_millis(10)));
+        event.signal();
+        assert!(event.wait_timeout(Duration::from_millis(0)));
+        assert!(!event.wait_timeout(Duration::from_millis(0)));
+
+        //
+        // - a guard dropping from another thread while we are parked wakes us up
+        // - racing the deadline must never lose the signal: either we get it or it is
+        //   still pending afterwards
+        //
+        {
+            let guard = event.guard();
+            let _ = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                drop(guard);
+            });
+        }
+        assert!(event.wait_timeout(Duration::from_secs(5)));
+        for _ in 0..64 {
+            let guard = event.guard();
+            let _ = thread::spawn(move || {
+                random_work(1000);
+                drop(guard);
+            });
+            if !event.wait_timeout(Duration::from_millis(0)) {
+                event.wait();
+            }
+        }
+    }
+
+    #[test]
+    fn event_reset() {
+
+        //
+        // - run a few generations on the same event: each one waits for its own guards
+        // - a stale signal left over by the previous generation is discarded
+        //
+        let event = Arc::new(Event::new());
+        let lock = Arc::new(Lock::<FIFO>::new());
+        for n in 1..5 {
+            {
+                let guard = event.guard();
+                for _ in 0..16 {
+                    let lock = lock.clone();
+                    let guard = guard.clone();
+                    let _ = thread::spawn(move || {
+                        lock.lock(|n| n);
+                        random_work(40);
+                        lock.unlock(|n| n + 1);
+                        drop(guard);
+                    });
+                }
+            }
+            event.wait();
+            assert!(lock.tag() == n * 16);
+            event.signal();
+            event.reset();
+            assert!(!event.wait_timeout(Duration::from_millis(0)));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn event_reset_guarded() {
+
+        //
+        // - resetting while a guard is alive would lose its signal
+        //
+        let event = Event::new();
+        let _guard = event.guard();
+        event.reset();
+    }
+
+    #[test]
+    fn event_broadcast() {
+
+        //
+        // - park a bunch of threads on a broadcast event and signal it once
+        // - every single one of them is released
+        //
+        let event = Arc::new(Event::broadcast());
+        let parked = Arc::new(AtomicUsize::new(0));
+        let mut threads = Vec::new();
+        for _ in 0..16 {
+            let event = event.clone();
+            let parked = parked.clone();
+            threads.push(thread::spawn(move || {
+                parked.fetch_add(1, Ordering::Relaxed);
+                event.wait();
+            }));
+        }
+        while parked.load(Ordering::Relaxed) < 16 {
+            thread::yield_now();
+        }
+        thread::sleep(Duration::from_millis(10));
+        event.signal();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        //
+        // - the event remains signaled: late waiters return right away
+        // - once reset it blocks again, until a guard drops
+        //
+        event.wait();
+        assert!(event.wait_timeout(Duration::from_millis(0)));
+        event.reset();
+        assert!(!event.wait_timeout(Duration::from_millis(0)));
+        {
+            let guard = event.guard();
+            let _ = thread::spawn(move || {
+                random_work(1000);
+                drop(guard);
+            });
+        }
+        event.wait();
+        event.wait();
+    }
+
+    #[test]
+    fn lock_poisoning() {
+
+        //
+        // - a thread panics while holding the lock
+        // - the next acquirer gets the lock (instead of deadlocking) along with the poison flag
+        //
+        let lock = Arc::new(Lock::<FIFO>::new());
+        {
+            let lock = lock.clone();
+            let res = thread::spawn(move || {
+                let _guard = lock.acquire(|n| n + 1).unwrap();
+                panic!("boom");
+            }).join();
+            assert!(res.is_err());
+        }
+        assert!(lock.is_poisoned());
+        match lock.acquire(|n| n + 1) {
+            Ok(_) => panic!("lock should be poisoned"),
+            Err(e) => drop(e.into_inner()),
+        }
+        assert!(lock.tag() == 2);
+
+        //
+        // - once cleared the lock is usable as before
+        //
+        lock.clear_poison();
+        assert!(!lock.is_poisoned());
+        assert!(lock.acquire(|n| n + 1).is_ok());
+        assert!(lock.try_lock(|n| n));
+        lock.unlock(|n| n);
+        assert!(lock.tag() == 3);
+    }
+
+    #[test]
+    fn synchro_try_lock() {
+
+        //
+        // - a held lock can't be grabbed, a free one can
+        //
+        let lock = Arc::new(Lock::<FIFO>::new());
+        assert!(lock.try_lock(|n| n + 1));
+        assert!(!lock.try_lock(|n| n + 1));
+        lock.unlock(|n| n);
+        assert!(lock.tag() == 1);
+
+        //
+        // - mix blocking and opportunistic threads: whatever try_lock() gives up on must
+        //   not leave a pending thread behind
+        //
+        let event = Arc::new(Event::new());
+        {
+            let guard = event.guard();
+            for n in 0..64 {
+
+                let lock = lock.clone();
+                let guard = guard.clone();
+                let _ = thread::spawn(move || {
+                    for _ in 0..100 {
+                        if n % 2 == 0 {
+                            lock.lock(|n| n);
+                        } else if !lock.try_lock(|n| n) {
+                            continue;
+                        }
+                        random_work(40);
+                        lock.unlock(|n| n + 1);
+                    }
+                    drop(guard);
+                });
+            }
+        }
+
+        event.wait();
+        assert!(lock.tag() >= 1 + 32 * 100);
+        assert!(lock.pending() == 0);
+    }
 }
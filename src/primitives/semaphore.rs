@@ -8,8 +8,10 @@
 //! where consumers pop one item at a time for instance.
 //!
 //! Please note each lock may carry 32bits of user payload.
+use std::sync::PoisonError;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
+use std::time::{Duration, Instant};
 use super::*;
 
 const BUSY: usize = 1;
@@ -83,33 +85,73 @@ impl Semaphore {
         cur & CLOSED > 0
     }
 
+    #[inline]
+    pub fn is_disabled(&self) -> bool {
+        let cur = self.tag.load(Ordering::Relaxed);
+        cur & DEAD > 0
+    }
+
     #[inline]
     pub fn count(&self) -> usize {
         let cur = self.tag.load(Ordering::Relaxed);
         (cur & CNT_MSK) >> 8
     }
 
+    /// Drops the counter back to 0 and unsets the OPEN and DEAD bits, e.g returns to reset mode
+    /// and re-enables the semaphore if it was disabled. This fails (and returns false) if the
+    /// semaphore is closed, e.g if threads are parked.
+    #[inline]
+    pub fn reset(&self) -> bool {
+
+        //
+        // - wait for the BUSY bit to be unset and give up as soon as the CLOSED bit is set
+        // - zero the counter while preserving the user payload
+        //
+        set_or_spin(
+            &self.tag,
+            0,
+            BUSY | CLOSED,
+            0,
+            OPEN | DEAD,
+            &|user| user,
+            &|_| 0,
+            &|state| if state.load(Ordering::Relaxed) & CLOSED > 0 {
+                false
+            } else {
+                thread::yield_now();
+                true
+            },
+        ).is_ok()
+    }
+
     #[inline]
     pub fn disable(&self) -> () {
 
         //
         // - if the DEAD bit is already set fast-fail
-        // - otehrwise run a CAS loop to set it
+        // - otherwise run a CAS loop to set it while nobody holds the queue (a thread holding
+        //   the BUSY bit may be about to park, in which case we must see the CLOSED bit it
+        //   sets, while any thread grabbing the queue after us sees the DEAD bit)
         //
         let mut cur = self.tag.load(Ordering::Relaxed);
-        if cur & DEAD > 0 {
-            return;
-        }
-
         loop {
+            if cur & DEAD > 0 {
+                return;
+            }
             match self.tag.compare_exchange_weak(
-                cur,
-                cur | DEAD,
+                cur & !BUSY,
+                (cur & !BUSY) | DEAD,
                 Ordering::Acquire,
                 Ordering::Relaxed,
             ) {
-                Ok(_) => break,
+                Ok(prv) => {
+                    cur = prv;
+                    break;
+                }
                 Err(prv) => {
+                    if prv & BUSY > 0 {
+                        thread::yield_now();
+                    }
                     cur = prv;
                 }
             }
@@ -218,7 +260,7 @@ impl Semaphore {
             // - notify the condvar at which point the owning thread will be
             //   scheduling again
             //
-            let mut parked = synchro.0.lock().unwrap();
+            let mut parked = synchro.0.lock().unwrap_or_else(PoisonError::into_inner);
             *parked = false;
             synchro.1.notify_one();
 
@@ -297,7 +339,12 @@ impl Semaphore {
 
         //
         // - we are holding the BUSY bit, e.g we own the queue
+        // - we may have been disabled in the meantime: release the queue and bail out
         //
+        if cur & DEAD > 0 {
+            let _ = set_or_spin(&self.tag, BUSY, 0, 0, BUSY, &|user| user, &|c| c, &|_| true);
+            return;
+        }
         let cnt = (cur & CNT_MSK) >> 8;
         if cur & CLOSED > 0 || cnt == 0 {
 
@@ -310,7 +357,7 @@ impl Semaphore {
             // - lock the mutex and release the queue by flipping the BUSY bit
             // - at this point other lock()/unlock() invokations may proceed
             //
-            let mut parked = synchro.0.lock().unwrap();
+            let mut parked = synchro.0.lock().unwrap_or_else(PoisonError::into_inner);
 
             //
             // - release the queue by unsetting the BUSY bit
@@ -335,7 +382,7 @@ impl Semaphore {
             //
             *parked = true;
             while *parked {
-                parked = synchro.1.wait(parked).unwrap();
+                parked = synchro.1.wait(parked).unwrap_or_else(PoisonError::into_inner);
             }
 
         } else {
@@ -359,4 +406,140 @@ impl Semaphore {
             );
         }
     }
+
+    /// Same as `wait()` except the thread gives up once the lapse of time is elapsed. Returns
+    /// true if the semaphore was acquired (which is the case right away if it is open).
+    #[inline]
+    pub fn wait_timeout(&self, lapse: Duration) -> bool {
+
+        //
+        // - same fast path as wait()
+        // - fast fail if the DEAD bit is set, in which case the semaphore is not acquired
+        //
+        let cur = self.tag.load(Ordering::Relaxed);
+        if cur & DEAD > 0 {
+            return false;
+        }
+        let cnt = (cur & CNT_MSK) >> 8;
+        if cnt > 1 &&
+            self.tag
+                .compare_exchange_weak(
+                    (cur & !BUSY) | OPEN,
+                    (cur & !CNT_MSK) | ((cnt - 1) << 8),
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+        {
+            return true;
+        }
+        unsafe { self.wait_timeout_cold(Instant::now() + lapse) }
+    }
+
+    #[cold]
+    #[inline(never)]
+    unsafe fn wait_timeout_cold(&self, deadline: Instant) -> bool {
+
+        //
+        // - spin until we flip the BUSY bit on
+        // - if the count is available take it and return right away, exactly like
+        //   wait_cold()
+        // - bail out if we have been disabled in the meantime, exactly like wait_cold()
+        //
+        let cur = set_or_spin(&self.tag, 0, BUSY, BUSY, 0, &|user| user, &|c| c, &|_| {
+            thread::yield_now();
+            true
+        }).unwrap();
+
+        if cur & DEAD > 0 {
+            let _ = set_or_spin(&self.tag, BUSY, 0, 0, BUSY, &|user| user, &|c| c, &|_| true);
+            return false;
+        }
+        let cnt = (cur & CNT_MSK) >> 8;
+        if cur & CLOSED == 0 && cnt > 0 {
+            let mask = if cnt == 1 { BUSY | OPEN } else { BUSY };
+            let _ = set_or_spin(
+                &self.tag,
+                BUSY,
+                0,
+                0,
+                mask,
+                &|user| user,
+                &|c| c - 1,
+                &|_| true,
+            );
+            return true;
+        }
+
+        //
+        // - enqueue a new mutex+condvar and release the queue while forcing the
+        //   CLOSED bit (same as wait_cold())
+        // - freeze on the condvar until notified or until the deadline
+        //
+        let synchro = self.queue.push();
+        {
+            let mut parked = synchro.0.lock().unwrap_or_else(PoisonError::into_inner);
+            let _ = set_or_spin(
+                &self.tag,
+                BUSY,
+                0,
+                CLOSED,
+                BUSY,
+                &|user| user,
+                &|c| c + 1,
+                &|_| true,
+            );
+
+            *parked = true;
+            while *parked {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                parked = synchro
+                    .1
+                    .wait_timeout(parked, deadline - now)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0;
+            }
+            if !*parked {
+                return true;
+            }
+        }
+
+        //
+        // - we timed out: grab the queue again to back out
+        // - if our node is still queued nobody signaled us: unlink it, decrement the
+        //   counter and unset the CLOSED bit if the queue is now empty
+        //
+        let _ = set_or_spin(&self.tag, 0, BUSY, BUSY, 0, &|user| user, &|c| c, &|_| {
+            thread::yield_now();
+            true
+        });
+        if self.queue.remove(&synchro) {
+            let mask = if self.queue.is_empty() { BUSY | CLOSED } else { BUSY };
+            let _ = set_or_spin(
+                &self.tag,
+                BUSY,
+                0,
+                0,
+                mask,
+                &|user| user,
+                &|c| c - 1,
+                &|_| true,
+            );
+            return false;
+        }
+
+        //
+        // - otherwise a signal_cold() popped our node right at the deadline and is about
+        //   to notify us: release the queue and wait for the notification (it is ours)
+        //
+        let _ = set_or_spin(&self.tag, BUSY, 0, 0, BUSY, &|user| user, &|c| c, &|_| true);
+        let mut parked = synchro.0.lock().unwrap_or_else(PoisonError::into_inner);
+        while *parked {
+            parked = synchro.1.wait(parked).unwrap_or_else(PoisonError::into_inner);
+        }
+        true
+    }
 }
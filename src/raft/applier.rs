@@ -0,0 +1,429 @@
+//! Dedicated thread applying the committed entries to the payload, see
+//! `RaftConfig::apply_queue`. The automaton hands each committed entry over through a bounded
+//! queue and moves on with the protocol. Anything that must observe the entries applied (store
+//! completions, parked reads) goes through the same queue, which preserves the commit order.
+//! The automaton flushes the queue (e.g waits for it to empty) before reading or resetting the
+//! payload itself, for instance to checkpoint. Checkpoints triggered by a snapshot threshold are
+//! taken by the thread instead, once it applied everything they cover.
+//!
+//! With a conflict key (see `RaftBuilder::on_commit_parallel()`) the thread hands the entries
+//! over to a set of lanes instead, each applying what it gets in order: entries with the same
+//! key land on the same lane. Anything else (entries without a key, completions of skipped
+//! stores, reads, flushes, checkpoints) waits for all the lanes to be done first.
+use primitives::event::Guard;
+use primitives::rwlock::RWLock;
+use raft::protocol::{run_hooks, ApplyError, Hook, ReadError, StoreResult};
+use slog::Logger;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+
+/// Conflict key of an entry, see `RaftBuilder::on_commit_parallel()`.
+pub(super) type ConflictKey = Box<dyn Fn(&[u8]) -> Option<u64> + Send>;
+
+/// Apply closure bound to the payload, shared by the lanes.
+pub(super) type Runner = Arc<dyn Fn(&[u8]) -> Result<Vec<u8>, ApplyError> + Send + Sync>;
+
+/// Entries applied concurrently, see `RaftBuilder::on_commit_parallel()`.
+pub(super) struct Lanes {
+    /// Number of lanes, e.g applying threads.
+    pub(super) n: usize,
+    pub(super) key: ConflictKey,
+    pub(super) run: Runner,
+}
+
+/// Apply closure plus the hooks wrapping it, shared between the automaton (which registers the
+/// hooks and applies inline when there is no dedicated thread) and the applying thread.
+pub(super) struct Stage<T> {
+    pub(super) apply: T,
+    pub(super) pre_apply: Vec<Hook>,
+    pub(super) post_apply: Vec<Hook>,
+}
+
+impl<T> Stage<T> {
+    pub(super) fn new(apply: T) -> Self {
+        Stage {
+            apply,
+            pre_apply: Vec::new(),
+            post_apply: Vec::new(),
+        }
+    }
+
+    /// Passes an entry to the pre-apply hooks, the apply closure and then the post-apply hooks.
+    pub(super) fn run<U>(
+        &self,
+        logger: &Logger,
+        payload: &mut U,
+        off: u64,
+        bytes: &[u8],
+    ) -> Result<Vec<u8>, ApplyError>
+    where
+        T: Fn(&mut U, &[u8]) -> Result<Vec<u8>, ApplyError>,
+    {
+        run_hooks(logger, &self.pre_apply, off, bytes);
+        let res = (self.apply)(payload, bytes);
+        run_hooks(logger, &self.post_apply, off, bytes);
+        res
+    }
+}
+
+/// Work handed over to the applying thread, processed in order.
+pub(super) enum Job {
+    /// Apply the entry at that offset, completing the store waiting on it if any.
+    Apply(u64, Vec<u8>, Option<Sender<StoreResult>>),
+    /// Complete a store whose entry was skipped (e.g a deduplicated retry).
+    Skip(u64, Sender<StoreResult>),
+    /// Serve a parked read once everything queued before is applied.
+    Serve(u64, Sender<Result<u64, ReadError>>),
+    /// Acknowledge once everything queued before is applied.
+    Flush(Sender<()>),
+    /// Run a closure once everything queued before is applied, e.g to snapshot the payload
+    /// without holding the automaton up.
+    Run(Box<dyn Fn() + Send>),
+}
+
+/// Handle on the applying thread, owned by the automaton.
+pub(super) struct Applier {
+    tx: Option<SyncSender<Job>>,
+    thread: Option<JoinHandle<()>>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl Applier {
+    /// Starts the applying thread with a queue of the specified capacity, plus its lanes if
+    /// any. The thread holds the guard until it exits, e.g once the automaton closes it.
+    pub(super) fn spawn<T, U>(
+        guard: &Arc<Guard>,
+        capacity: usize,
+        payload: Arc<RWLock<U>>,
+        stage: Arc<Mutex<Stage<T>>>,
+        pending: Arc<AtomicUsize>,
+        lanes: Option<Lanes>,
+        logger: Logger,
+    ) -> Self
+    where
+        T: 'static + Send + Fn(&mut U, &[u8]) -> Result<Vec<u8>, ApplyError>,
+        U: 'static + Send,
+    {
+        let (tx, rx) = sync_channel(capacity);
+        let queued = Arc::new(AtomicUsize::new(0));
+        let thread = {
+            let guard = guard.clone();
+            let queued = queued.clone();
+            thread::spawn(move || {
+
+                //
+                // - start the lanes if any, each one with a queue of its own
+                //
+                let (key, lanes) = match lanes {
+                    Some(lanes) => {
+                        let txs: Vec<_> = (0..lanes.n)
+                            .map(|_| {
+                                let (tx, rx) = channel();
+                                let run = lanes.run.clone();
+                                let stage = stage.clone();
+                                let pending = pending.clone();
+                                let queued = queued.clone();
+                                let logger = logger.clone();
+                                let lane = thread::spawn(move || {
+                                    drive(&rx, &run, &stage, &pending, &queued, &logger)
+                                });
+                                (tx, lane)
+                            })
+                            .collect();
+                        (Some(lanes.key), txs)
+                    }
+                    None => (None, Vec::new()),
+                };
+
+                //
+                // - process the jobs in order until the automaton closes the queue
+                // - hand entries with a conflict key over to their lane
+                // - anything else first waits for the lanes to be done with what they got
+                // - lock the payload for writing while applying each entry
+                // - complete stores and reads (or run closures) only once whatever precedes them
+                //   is applied
+                // - count each job out before completing it, e.g so that a flush returns with
+                //   nothing left queued
+                //
+                for job in rx.iter() {
+                    let job = match (job, key.as_ref()) {
+                        (Job::Apply(off, bytes, tx), Some(key)) => match key(&bytes) {
+                            Some(k) => {
+                                let lane = &lanes[(k % lanes.len() as u64) as usize].0;
+                                let _ = lane.send(Job::Apply(off, bytes, tx));
+                                continue;
+                            }
+                            None => Job::Apply(off, bytes, tx),
+                        },
+                        (job, _) => job,
+                    };
+                    for &(ref lane, _) in &lanes {
+                        let (tx, rx) = channel();
+                        queued.fetch_add(1, Ordering::Release);
+                        let _ = lane.send(Job::Flush(tx));
+                        let _ = rx.recv();
+                    }
+                    match job {
+                        Job::Apply(off, bytes, tx) => {
+                            let mut lock = payload.write();
+                            let res = stage.lock().unwrap().run(&logger, &mut *lock, off, &bytes);
+                            drop(lock);
+                            complete(&logger, &pending, &queued, off, res, tx);
+                        }
+                        Job::Skip(off, tx) => {
+                            queued.fetch_sub(1, Ordering::Release);
+                            pending.fetch_sub(1, Ordering::Release);
+                            let _ = tx.send(StoreResult::Committed(off, Vec::new()));
+                        }
+                        Job::Serve(commit, tx) => {
+                            queued.fetch_sub(1, Ordering::Release);
+                            let _ = tx.send(Ok(commit));
+                        }
+                        Job::Flush(tx) => {
+                            queued.fetch_sub(1, Ordering::Release);
+                            let _ = tx.send(());
+                        }
+                        Job::Run(f) => {
+                            f();
+                            queued.fetch_sub(1, Ordering::Release);
+                        }
+                    }
+                }
+
+                //
+                // - close the lanes and wait for them to exit
+                //
+                for (lane, thread) in lanes {
+                    drop(lane);
+                    let _ = thread.join();
+                }
+                drop(guard);
+            })
+        };
+        Applier {
+            tx: Some(tx),
+            thread: Some(thread),
+            queued,
+        }
+    }
+
+    /// Queues a job, blocking if the queue is full. Returns false if it had to block.
+    pub(super) fn push(&self, job: Job) -> bool {
+        let tx = self.tx.as_ref().expect("applier is running");
+        self.queued.fetch_add(1, Ordering::Release);
+        match tx.try_send(job) {
+            Ok(_) => true,
+            Err(TrySendError::Full(job)) => {
+                let _ = tx.send(job);
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                self.queued.fetch_sub(1, Ordering::Release);
+                true
+            }
+        }
+    }
+
+    /// Number of jobs queued and not processed yet.
+    pub(super) fn queued(&self) -> usize {
+        self.queued.load(Ordering::Acquire)
+    }
+
+    /// Blocks until everything queued so far is processed.
+    pub(super) fn flush(&self) -> () {
+        if self.queued() > 0 {
+            let (tx, rx) = channel();
+            let _ = self.push(Job::Flush(tx));
+            let _ = rx.recv();
+        }
+    }
+
+    /// Closes the queue and waits for the thread to process whatever is left and exit. Returns
+    /// false if the thread died instead (e.g the apply closure panicked).
+    pub(super) fn close(&mut self) -> bool {
+        drop(self.tx.take());
+        match self.thread.take() {
+            Some(thread) => thread.join().is_ok(),
+            None => true,
+        }
+    }
+}
+
+/// Applies whatever is handed over to a lane, in order, until the applying thread closes it.
+/// The payload is shared with the other lanes: the hooks run one lane at a time while the apply
+/// closure itself runs concurrently.
+fn drive<T>(
+    rx: &Receiver<Job>,
+    run: &Runner,
+    stage: &Mutex<Stage<T>>,
+    pending: &AtomicUsize,
+    queued: &AtomicUsize,
+    logger: &Logger,
+) -> () {
+    for job in rx.iter() {
+        match job {
+            Job::Apply(off, bytes, tx) => {
+                run_hooks(logger, &stage.lock().unwrap().pre_apply, off, &bytes);
+                let res = run(&bytes);
+                run_hooks(logger, &stage.lock().unwrap().post_apply, off, &bytes);
+                complete(logger, pending, queued, off, res, tx);
+            }
+            Job::Flush(tx) => {
+                queued.fetch_sub(1, Ordering::Release);
+                let _ = tx.send(());
+            }
+            _ => debug_assert!(false, "only entries and flushes go to the lanes"),
+        }
+    }
+}
+
+/// Counts an applied entry out and completes the store waiting on it, if any.
+fn complete(
+    logger: &Logger,
+    pending: &AtomicUsize,
+    queued: &AtomicUsize,
+    off: u64,
+    res: Result<Vec<u8>, ApplyError>,
+    tx: Option<Sender<StoreResult>>,
+) -> () {
+    if let Err(ref e) = res {
+        debug!(logger, "entry #{} rejected ({})", off, e.0);
+    }
+    queued.fetch_sub(1, Ordering::Release);
+    if let Some(tx) = tx {
+        pending.fetch_sub(1, Ordering::Release);
+        let _ = tx.send(match res {
+            Ok(bytes) => StoreResult::Committed(off, bytes),
+            Err(e) => StoreResult::Failed(off, e),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use primitives::event::*;
+    use rand::{thread_rng, Rng};
+    use slog::Discard;
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn ordering() {
+
+        //
+        // - a slow apply closure appending each entry to the payload
+        // - entries are applied in order and the stores complete in order, after the entries
+        //   preceding them
+        //
+        let event = Event::new();
+        let guard = event.guard();
+        let payload = Arc::new(RWLock::from(Vec::<u8>::new()));
+        let stage = Arc::new(Mutex::new(Stage::new(
+            |p: &mut Vec<u8>, bytes: &[u8]| -> Result<Vec<u8>, ApplyError> {
+                thread::sleep(Duration::from_millis(1));
+                p.extend_from_slice(bytes);
+                Ok(vec![p.len() as u8])
+            },
+        )));
+        let pending = Arc::new(AtomicUsize::new(16));
+        let logger = Logger::root(Discard, o!());
+        let mut applier =
+            Applier::spawn(&guard, 4, payload.clone(), stage, pending.clone(), None, logger);
+        let mut waiters = Vec::new();
+        for n in 0..16u8 {
+            let (tx, rx) = channel();
+            let _ = applier.push(Job::Apply(u64::from(n), vec![n], Some(tx)));
+            waiters.push(rx);
+        }
+        for (n, rx) in waiters.into_iter().enumerate() {
+            assert_eq!(rx.recv().unwrap(), StoreResult::Committed(n as u64, vec![n as u8 + 1]));
+        }
+        applier.flush();
+        assert_eq!(applier.queued(), 0);
+        assert_eq!(pending.load(Ordering::Acquire), 0);
+        assert_eq!(*payload.read(), (0..16u8).collect::<Vec<_>>());
+
+        //
+        // - closing drains the queue and releases the guard
+        //
+        let _ = applier.push(Job::Apply(16, vec![16], None));
+        assert!(applier.close());
+        assert_eq!(payload.read().len(), 17);
+        drop(guard);
+        event.wait();
+    }
+
+    #[test]
+    fn lanes() {
+
+        //
+        // - 3 lanes over a payload tracking what was applied to each key, the first byte of
+        //   each entry being its key (255 means no key, e.g it goes to all of them)
+        // - a randomly slow apply closure mixes the lanes up
+        //
+        let event = Event::new();
+        let guard = event.guard();
+        let payload = Arc::new(RWLock::from(Mutex::new(vec![Vec::<u8>::new(); 4])));
+        let apply = |p: &Mutex<Vec<Vec<u8>>>, bytes: &[u8]| -> Result<Vec<u8>, ApplyError> {
+            if thread_rng().gen_range(0, 4) == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+            let mut keys = p.lock().unwrap();
+            match bytes[0] {
+                255 => keys.iter_mut().for_each(|key| key.push(bytes[1])),
+                k => keys[k as usize].push(bytes[1]),
+            }
+            Ok(vec![bytes[1]])
+        };
+        let run: Runner = {
+            let payload = payload.clone();
+            Arc::new(move |bytes: &[u8]| apply(&*payload.read(), bytes))
+        };
+        let lanes = Lanes {
+            n: 3,
+            key: Box::new(|bytes: &[u8]| match bytes[0] {
+                255 => None,
+                k => Some(u64::from(k)),
+            }),
+            run,
+        };
+        let stage = Arc::new(Mutex::new(Stage::new(
+            move |p: &mut Mutex<Vec<Vec<u8>>>, bytes: &[u8]| apply(p, bytes),
+        )));
+        let pending = Arc::new(AtomicUsize::new(64));
+        let logger = Logger::root(Discard, o!());
+        let mut applier =
+            Applier::spawn(&guard, 8, payload.clone(), stage, pending.clone(), Some(lanes), logger);
+
+        //
+        // - interleave entries on 4 keys with a few entries without a key
+        // - each key must see its entries in commit order, and the entries without a key in
+        //   between the same ones as when applying sequentially
+        //
+        let mut expected = vec![Vec::new(); 4];
+        let mut waiters = Vec::new();
+        for n in 0..64u8 {
+            let k = if n % 16 == 15 { 255 } else { n % 4 };
+            match k {
+                255 => expected.iter_mut().for_each(|key: &mut Vec<u8>| key.push(n)),
+                k => expected[k as usize].push(n),
+            }
+            let (tx, rx) = channel();
+            let _ = applier.push(Job::Apply(u64::from(n), vec![k, n], Some(tx)));
+            waiters.push((n, rx));
+        }
+        for (n, rx) in waiters {
+            assert_eq!(rx.recv().unwrap(), StoreResult::Committed(u64::from(n), vec![n]));
+        }
+        applier.flush();
+        assert_eq!(applier.queued(), 0);
+        assert_eq!(pending.load(Ordering::Acquire), 0);
+        assert_eq!(*payload.read().lock().unwrap(), expected);
+        assert!(applier.close());
+        drop(guard);
+        event.wait();
+    }
+}
@@ -0,0 +1,37 @@
+//! Optional authentication of the byte buffers exchanged between peers using a shared cluster
+//! secret (see `RaftConfig::secret`). The MAC is a HMAC-SHA256 computed over the serialized RAW
+//! and appended to it, e.g the wire layout becomes
+//!
+//! ```ignore
+//!   | serialized RAW (n bytes) | HMAC-SHA256 (32 bytes) |
+//! ```
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Byte size of the MAC trailer.
+pub const MAC_BYTES: usize = 32;
+
+/// Appends the MAC to a serialized RAW.
+pub fn sign(secret: &[u8], bytes: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret).unwrap();
+    mac.input(bytes);
+    let mut buf = Vec::with_capacity(bytes.len() + MAC_BYTES);
+    buf.extend_from_slice(bytes);
+    buf.extend_from_slice(&mac.result().code());
+    buf
+}
+
+/// Checks the MAC trailer and returns the serialized RAW it covers, or None if the buffer is
+/// truncated, tampered with or signed with another secret.
+pub fn verify<'a>(secret: &[u8], bytes: &'a [u8]) -> Option<&'a [u8]> {
+    if bytes.len() < MAC_BYTES {
+        return None;
+    }
+    let (body, tag) = bytes.split_at(bytes.len() - MAC_BYTES);
+    let mut mac = Hmac::<Sha256>::new_varkey(secret).unwrap();
+    mac.input(body);
+    match mac.verify(tag) {
+        Ok(_) => Some(body),
+        Err(_) => None,
+    }
+}
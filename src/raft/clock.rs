@@ -0,0 +1,140 @@
+//! Time source of the automaton. Every timestamp it takes and every timeout it arms goes
+//! through a `Clock`: `SystemClock` (the default) follows the wall clock while `ManualClock`
+//! only moves when told to, which makes timing dependent behaviors (elections, heartbeats,
+//! etc) testable without sleeping.
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Trait defining a time source, see `RaftBuilder::clock()`.
+pub trait Clock: Send + Sync {
+    /// Current instant.
+    fn now(&self) -> Instant;
+
+    /// Returns the clock itself if it is driven by hand, in which case the automaton arms its
+    /// timeouts on it instead of the real timer. None by default.
+    fn manual(&self) -> Option<&ManualClock> {
+        None
+    }
+}
+
+/// Default clock, following the wall clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Clock driven by hand: time stands still until `advance()` is invoked, which then fires
+/// whatever timeouts are due. It can be shared by several automata.
+pub struct ManualClock {
+    timeline: Mutex<Timeline>,
+}
+
+struct Timeline {
+    now: Instant,
+    seq: u64,
+    armed: Vec<(Instant, u64, Box<dyn FnMut() + Send>)>,
+}
+
+impl ManualClock {
+    /// Starts at the current instant.
+    pub fn new() -> Self {
+        ManualClock {
+            timeline: Mutex::new(Timeline {
+                now: Instant::now(),
+                seq: 0,
+                armed: Vec::new(),
+            }),
+        }
+    }
+
+    /// Moves the clock forward and fires the timeouts that are now due, earliest first (in
+    /// the order they were armed for the same deadline).
+    pub fn advance(&self, lapse: Duration) -> () {
+
+        //
+        // - move forward and pick whatever is due
+        // - fire outside of the lock, the handlers may arm new timeouts
+        //
+        let mut due: Vec<_> = {
+            let mut timeline = self.timeline.lock().unwrap();
+            timeline.now += lapse;
+            let now = timeline.now;
+            let (due, armed) = timeline.armed.drain(..).partition(|t| t.0 <= now);
+            timeline.armed = armed;
+            due
+        };
+        due.sort_by_key(|t| (t.0, t.1));
+        for mut t in due {
+            (t.2)();
+        }
+    }
+
+    /// Number of timeouts armed and not fired yet.
+    pub fn pending(&self) -> usize {
+        self.timeline.lock().unwrap().armed.len()
+    }
+
+    pub(super) fn arm(&self, lapse: Duration, fire: Box<dyn FnMut() + Send>) -> () {
+        let mut timeline = self.timeline.lock().unwrap();
+        let deadline = timeline.now + lapse;
+        timeline.seq += 1;
+        let seq = timeline.seq;
+        timeline.armed.push((deadline, seq, fire));
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.timeline.lock().unwrap().now
+    }
+
+    fn manual(&self) -> Option<&ManualClock> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::*;
+
+    #[test]
+    fn manual() {
+
+        //
+        // - arm 2 timeouts, nothing fires until the clock is advanced past their deadline
+        // - time does not move on its own
+        //
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let fired = Arc::new(AtomicUsize::new(0));
+        for n in 1..3 {
+            let fired = fired.clone();
+            clock.arm(
+                Duration::from_millis(100 * n),
+                Box::new(move || {
+                    fired.fetch_add(1, Ordering::Relaxed);
+                }),
+            );
+        }
+        clock.advance(Duration::from_millis(50));
+        assert_eq!(fired.load(Ordering::Relaxed), 0);
+        clock.advance(Duration::from_millis(50));
+        assert_eq!(fired.load(Ordering::Relaxed), 1);
+        assert_eq!(clock.pending(), 1);
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(fired.load(Ordering::Relaxed), 2);
+        assert_eq!(clock.now() - start, Duration::from_millis(600));
+    }
+}
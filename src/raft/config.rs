@@ -0,0 +1,380 @@
+//! Tunables passed to the raft automaton upon spawning. Each field defaults to the historical
+//! behavior of the automaton.
+use std::ops::Range;
+use std::time::Duration;
+
+/// Highest (and default) election priority, see `RaftConfig::priority`.
+pub const MAX_PRIORITY: u32 = 100;
+
+/// Raft automaton configuration, see `spawn()`.
+#[derive(Clone, Debug)]
+pub struct RaftConfig {
+    /// Lapse of time after which a FOLLOWER which did not hear from its LEADER starts a new
+    /// election. This is what the raft paper calls the election timeout: its randomized range
+    /// maps to `liveness_timeout + election_jitter`.
+    pub liveness_timeout: Duration,
+    /// Lapse of time after which a pre-vote or an election that did not reach quorum is
+    /// attempted again. This is not the raft election timeout (see `liveness_timeout`).
+    pub election_timeout: Duration,
+    /// Bounds of the random delay a peer waits for once promoted to CANDIDATE before running
+    /// for election, drawn uniformly. This avoids split votes when several peers time out at
+    /// around the same time: the wider the range the less likely, but the longer the election
+    /// on average.
+    pub election_jitter: Range<Duration>,
+    /// Lapse of time between two heartbeats sent by the LEADER. This must be at most half the
+    /// liveness timeout, e.g comfortably below the shortest raft election timeout, otherwise
+    /// the configuration is rejected.
+    pub heartbeat_interval: Duration,
+    /// Lapse of time during which a FOLLOWER receiving a burst of REPLICATE coalesces its ACKs
+    /// into a single one covering its latest head offset. Zero means ACK immediately. This should
+    /// remain a small fraction of the heartbeat period to avoid stalling the LEADER.
+    pub ack_coalesce: Duration,
+    /// Maximum number of pending `Raft::store_async()` calls, e.g appended or not but not yet
+    /// committed.
+    pub store_queue: usize,
+    /// Backlog (see `StoreAdmission::backlog`) at which a LEADER starts holding writers back:
+    /// `Raft::store()` then blocks and `Raft::store_async()` fails with `WouldBlock` until the
+    /// followers catch up. This is a soft bound (concurrent writers may overshoot it a bit) and
+    /// must be above 1, the backlog never being empty. None means no limit.
+    pub store_high_water: Option<u64>,
+    /// Upper bound of a random delay added to the very first liveness timeout. This staggers
+    /// the initial elections when a lot of peers boot at the same time. Zero means no delay.
+    pub startup_jitter: Duration,
+    /// Lapse of time beyond which committed entries still waiting to be applied trigger an
+    /// `APPLY_LAG` notification (e.g the apply closure is too slow). None disables the alarm.
+    pub apply_lag_alarm: Option<Duration>,
+    /// Capacity of the queue of committed entries handed over to a dedicated apply thread, so
+    /// that a slow apply closure does not hold the protocol back. The automaton only blocks
+    /// once the queue is full (which also raises `APPLY_LAG` if the alarm is set), when
+    /// checkpointing and when rebased. None applies inline, on the automaton thread.
+    pub apply_queue: Option<usize>,
+    /// Number of threads applying committed entries concurrently when they come with a conflict
+    /// key (see `RaftBuilder::on_commit_parallel()`). This requires `apply_queue`.
+    pub apply_lanes: usize,
+    /// Number of bytes applied since the last checkpoint beyond which a new checkpoint is taken
+    /// right away, regardless of how many entries were committed. None only checkpoints every
+    /// fixed number of commits.
+    pub snapshot_threshold_bytes: Option<usize>,
+    /// Same as `snapshot_threshold_bytes` but in number of entries committed since the last
+    /// checkpoint. None only checkpoints every fixed number of commits.
+    pub snapshot_threshold_entries: Option<u64>,
+    /// Number of entries preceding the latest checkpoint which are kept in the log. Peers lagging
+    /// by less than that are caught up with regular REPLICATE messages instead of being rebased
+    /// with a snapshot. This is bounded by the log size.
+    pub snapshot_retain_entries: u64,
+    /// Maximum number of peers a LEADER rebases with a snapshot at the same time. Others are
+    /// queued (while still receiving heartbeats). None means no limit.
+    pub max_concurrent_snapshots: Option<usize>,
+    /// Size of the chunks a snapshot is split into when rebasing a peer, e.g one
+    /// INSTALL_SNAPSHOT per chunk.
+    pub snapshot_chunk_bytes: usize,
+    /// Maximum number of entries a LEADER sends in a single REPLICATE. More entries are split
+    /// across several consecutive REPLICATE. None means no limit.
+    pub max_batch: Option<usize>,
+    /// Same as `max_batch` but in bytes (each entry takes a fixed size slot, so this rounds down
+    /// to a number of entries, at least one).
+    pub max_batch_bytes: Option<usize>,
+    /// Maximum number of entries sent to a peer and not acknowledged yet. Beyond that the
+    /// LEADER holds back (the peer still gets heartbeats) and resumes as soon as ACKs come back.
+    /// None means no limit, e.g everything is sent upon each heartbeat.
+    pub max_inflight: Option<usize>,
+    /// Largest buffer accepted by `Raft::feed()`, checked on its frame header before anything
+    /// is decoded. Larger buffers are dropped (see `FeedError::TooLarge`). This must leave room
+    /// for the largest REPLICATE and for a whole snapshot chunk. None means no limit.
+    pub max_message_bytes: Option<usize>,
+    /// Maximum number of entries accepted in a single REPLICATE received from the LEADER, above
+    /// which it is dropped. The LEADER `max_batch` must remain below it. None means no limit.
+    pub max_replicate_entries: Option<usize>,
+    /// Lapse of time after which a LEADER that did not get any ACK for what it replicated to a
+    /// peer sends it again (e.g the REPLICATE or its ACK got lost). Checked upon each heartbeat.
+    /// The timeout doubles upon each resend, up to the liveness timeout, and resets as soon as
+    /// the peer makes progress. None means never resend (a peer left behind gets rebased once
+    /// the LEADER replicates something new).
+    pub resend_timeout: Option<Duration>,
+    /// Whether to check the CRC32 each entry carries whenever it is used, not only when the
+    /// log is recovered (a slot that is torn or does not match marks the end of the log then).
+    /// Entries received from the LEADER that don't match are dropped (e.g treated as a
+    /// transport error) while entries read back from the log that don't match trigger a
+    /// `SAFETY_VIOLATION`. This adds a bit of overhead per entry.
+    pub entry_checksums: bool,
+    /// Size of the chunks in which the log is allocated upfront (see `Storage::reserve()`), one
+    /// chunk ahead of the entries written, so that appending does not have to grow the log
+    /// (e.g fault in the blocks of a sparse file) on the way. None allocates as entries are
+    /// written.
+    pub log_reserve_bytes: Option<u64>,
+    /// Whether this peer starts as a non-voting learner: it follows the LEADER and applies the
+    /// entries but never votes, never campaigns and does not count towards the commit quorum
+    /// until promoted (see `Raft::promote()`). This is meant to let a fresh peer catch up.
+    pub learner: bool,
+    /// Election preference of this peer, from 0 to `MAX_PRIORITY`. A CANDIDATE runs for election
+    /// after its random jitter plus a fraction of the liveness timeout proportional to how far
+    /// below the maximum its priority is, so that healthy peers with a higher priority tend to
+    /// win. A priority of 0 waits a whole liveness timeout, e.g it only gets elected if no other
+    /// peer could. All the peers at the maximum means no preference.
+    pub priority: u32,
+    /// Lapse of time a LEADER transferring its leadership (see `Raft::transfer_leadership()`)
+    /// waits for the target to catch up and get elected before giving up and leading again. It
+    /// stops heartbeating once the target campaigns: this must remain below the liveness timeout.
+    pub transfer_timeout: Duration,
+    /// Whether `Raft::drain()` first transfers the leadership when leading, instead of letting
+    /// the other peers time out and hold an election.
+    pub transfer_on_drain: bool,
+    /// Number of client request ids remembered once applied to deduplicate retried stores (see
+    /// `Raft::store_once()`). The table travels with the snapshots. 0 disables deduplication.
+    pub dedup_window: usize,
+    /// Shared cluster secret used to sign outgoing buffers and authenticate incoming ones (see
+    /// `Raft::feed()`). All the peers must use the same. None disables authentication.
+    #[cfg(feature = "auth")]
+    pub secret: Option<Vec<u8>>,
+    /// Term the automaton starts at (testing only, ignored when recovering).
+    #[cfg(feature = "testing")]
+    pub initial_term: u64,
+    /// Offset the automaton log starts at, e.g its initial tail, head and commit offsets
+    /// (testing only, ignored when recovering). Must be at least #1.
+    #[cfg(feature = "testing")]
+    pub initial_index: u64,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        RaftConfig {
+            liveness_timeout: Duration::from_millis(3000),
+            election_timeout: Duration::from_millis(750),
+            election_jitter: Duration::from_millis(25)..Duration::from_millis(150),
+            heartbeat_interval: Duration::from_millis(750),
+            ack_coalesce: Duration::from_millis(0),
+            store_queue: 1024,
+            store_high_water: None,
+            startup_jitter: Duration::from_millis(0),
+            apply_lag_alarm: None,
+            apply_queue: None,
+            apply_lanes: 4,
+            snapshot_threshold_bytes: None,
+            snapshot_threshold_entries: None,
+            snapshot_retain_entries: 0,
+            max_concurrent_snapshots: None,
+            snapshot_chunk_bytes: 64 * 1024,
+            max_batch: None,
+            max_batch_bytes: None,
+            max_inflight: None,
+            max_message_bytes: None,
+            max_replicate_entries: None,
+            resend_timeout: None,
+            entry_checksums: false,
+            log_reserve_bytes: None,
+            learner: false,
+            priority: MAX_PRIORITY,
+            transfer_timeout: Duration::from_millis(1500),
+            transfer_on_drain: false,
+            dedup_window: 1024,
+            #[cfg(feature = "auth")]
+            secret: None,
+            #[cfg(feature = "testing")]
+            initial_term: 0,
+            #[cfg(feature = "testing")]
+            initial_index: 1,
+        }
+    }
+}
+
+/// Runtime changes to a `RaftConfig`, see `Raft::reconfigure()`. Only the fields that are set
+/// are updated.
+#[derive(Clone, Debug, Default)]
+pub struct RaftConfigDelta {
+    pub liveness_timeout: Option<Duration>,
+    pub election_timeout: Option<Duration>,
+    pub election_jitter: Option<Range<Duration>>,
+    pub heartbeat_interval: Option<Duration>,
+    pub ack_coalesce: Option<Duration>,
+    pub apply_lag_alarm: Option<Option<Duration>>,
+    pub snapshot_threshold_bytes: Option<Option<usize>>,
+    pub snapshot_threshold_entries: Option<Option<u64>>,
+    pub max_concurrent_snapshots: Option<Option<usize>>,
+}
+
+/// Reasons for `Raft::reconfigure()` to fail, also see `RaftConfig::validate()`.
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    /// The resulting configuration does not make sense (the reason is passed along).
+    Invalid(&'static str),
+    /// The automaton is not running anymore.
+    Unavailable,
+}
+
+impl RaftConfig {
+    /// Returns a copy of this configuration with the delta applied, provided it is valid.
+    pub fn apply(&self, delta: &RaftConfigDelta) -> Result<RaftConfig, ConfigError> {
+        let mut config = self.clone();
+        if let Some(lapse) = delta.liveness_timeout {
+            config.liveness_timeout = lapse;
+        }
+        if let Some(lapse) = delta.election_timeout {
+            config.election_timeout = lapse;
+        }
+        if let Some(ref range) = delta.election_jitter {
+            config.election_jitter = range.clone();
+        }
+        if let Some(lapse) = delta.heartbeat_interval {
+            config.heartbeat_interval = lapse;
+        }
+        if let Some(lapse) = delta.ack_coalesce {
+            config.ack_coalesce = lapse;
+        }
+        if let Some(alarm) = delta.apply_lag_alarm {
+            config.apply_lag_alarm = alarm;
+        }
+        if let Some(limit) = delta.snapshot_threshold_bytes {
+            config.snapshot_threshold_bytes = limit;
+        }
+        if let Some(limit) = delta.snapshot_threshold_entries {
+            config.snapshot_threshold_entries = limit;
+        }
+        if let Some(limit) = delta.max_concurrent_snapshots {
+            config.max_concurrent_snapshots = limit;
+        }
+        config.validate().map(|_| config)
+    }
+
+    /// Checks the configuration makes sense, which is asserted upon spawning.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+
+        //
+        // - the LEADER must heartbeat well within the liveness timeout, otherwise its
+        //   followers would keep on starting elections
+        // - ACKs must not be held back longer than a heartbeat
+        // - a LEADER transferring its leadership stops heartbeating: it must give up before
+        //   its followers time out
+        // - the election jitter must be a non empty range (at millisecond granularity)
+        // - the apply queue, its lanes, batches, the receive limits, the in-flight window,
+        //   the resend timeout, the snapshot threshold and chunks, the high-water mark and the
+        //   log reservation chunk can't be empty
+        // - we must accept whatever batches we send
+        // - the priority is bounded
+        //
+        if self.heartbeat_interval == Duration::from_millis(0) {
+            return Err(ConfigError::Invalid("heartbeat interval must be > 0"));
+        }
+        if self.heartbeat_interval * 2 > self.liveness_timeout {
+            return Err(ConfigError::Invalid("heartbeat interval must be <= liveness / 2"));
+        }
+        if self.ack_coalesce >= self.heartbeat_interval {
+            return Err(ConfigError::Invalid("ack coalescing must be < heartbeat interval"));
+        }
+        if self.transfer_timeout >= self.liveness_timeout {
+            return Err(ConfigError::Invalid("transfer timeout must be < liveness timeout"));
+        }
+        if millis(self.election_jitter.start) >= millis(self.election_jitter.end) {
+            return Err(ConfigError::Invalid("election jitter must be a non empty range"));
+        }
+        if self.apply_queue == Some(0) {
+            return Err(ConfigError::Invalid("apply queue must be > 0"));
+        }
+        if self.apply_lanes == 0 {
+            return Err(ConfigError::Invalid("apply lanes must be > 0"));
+        }
+        if self.max_batch == Some(0) || self.max_batch_bytes == Some(0) {
+            return Err(ConfigError::Invalid("batch limits must be > 0"));
+        }
+        if self.max_message_bytes == Some(0) || self.max_replicate_entries == Some(0) {
+            return Err(ConfigError::Invalid("receive limits must be > 0"));
+        }
+        if let Some(limit) = self.max_replicate_entries {
+            if self.max_batch.map_or(true, |batch| batch > limit) {
+                return Err(ConfigError::Invalid("batch limit must be <= replicate limit"));
+            }
+        }
+        if self.max_inflight == Some(0) {
+            return Err(ConfigError::Invalid("in-flight window must be > 0"));
+        }
+        if self.resend_timeout == Some(Duration::from_millis(0)) {
+            return Err(ConfigError::Invalid("resend timeout must be > 0"));
+        }
+        if self.snapshot_threshold_entries == Some(0) {
+            return Err(ConfigError::Invalid("snapshot threshold must be > 0"));
+        }
+        if self.snapshot_chunk_bytes == 0 {
+            return Err(ConfigError::Invalid("snapshot chunks must be > 0"));
+        }
+        if self.log_reserve_bytes == Some(0) {
+            return Err(ConfigError::Invalid("log reservation chunk must be > 0"));
+        }
+        if self.store_high_water.map_or(false, |mark| mark < 2) {
+            return Err(ConfigError::Invalid("store high-water mark must be > 1"));
+        }
+        if self.priority > MAX_PRIORITY {
+            return Err(ConfigError::Invalid("priority must be <= MAX_PRIORITY"));
+        }
+        Ok(())
+    }
+}
+
+/// Number of whole milliseconds in a lapse of time.
+pub(super) fn millis(lapse: Duration) -> u64 {
+    lapse.as_secs() * 1000 + u64::from(lapse.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn deltas() {
+
+        //
+        // - only what is set changes
+        //
+        let delta = RaftConfigDelta {
+            heartbeat_interval: Some(Duration::from_millis(100)),
+            max_concurrent_snapshots: Some(Some(2)),
+            ..RaftConfigDelta::default()
+        };
+        let config = RaftConfig::default().apply(&delta).unwrap();
+        assert_eq!(config.heartbeat_interval, Duration::from_millis(100));
+        assert_eq!(config.max_concurrent_snapshots, Some(2));
+        assert_eq!(config.liveness_timeout, RaftConfig::default().liveness_timeout);
+
+        //
+        // - heartbeats slower than half the liveness timeout are rejected
+        //
+        let delta = RaftConfigDelta {
+            liveness_timeout: Some(Duration::from_millis(1000)),
+            heartbeat_interval: Some(Duration::from_millis(600)),
+            ..RaftConfigDelta::default()
+        };
+        assert!(RaftConfig::default().apply(&delta).is_err());
+    }
+
+    #[test]
+    fn validation() {
+
+        //
+        // - the defaults are valid
+        // - an empty election jitter is rejected
+        // - a transfer timeout that is not below the liveness timeout is rejected, including
+        //   once the liveness timeout is changed at runtime
+        //
+        assert!(RaftConfig::default().validate().is_ok());
+        let config = RaftConfig {
+            election_jitter: Duration::from_millis(100)..Duration::from_millis(100),
+            ..RaftConfig::default()
+        };
+        assert!(config.validate().is_err());
+        let delta = RaftConfigDelta {
+            election_jitter: Some(Duration::from_millis(10)..Duration::from_millis(20)),
+            ..RaftConfigDelta::default()
+        };
+        let config = RaftConfig::default().apply(&delta).unwrap();
+        assert_eq!(config.election_jitter.end, Duration::from_millis(20));
+        let config = RaftConfig {
+            transfer_timeout: RaftConfig::default().liveness_timeout,
+            ..RaftConfig::default()
+        };
+        assert!(config.validate().is_err());
+        let delta = RaftConfigDelta {
+            liveness_timeout: Some(Duration::from_millis(1500)),
+            ..RaftConfigDelta::default()
+        };
+        assert!(RaftConfig::default().apply(&delta).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+//! Client request deduplication. Entries stored with a client request id (see
+//! `Raft::store_once()`) are remembered once applied so that a retry is answered with the
+//! original offset instead of being applied twice. Only the latest ids are kept: the table is
+//! bounded by `RaftConfig::dedup_window`.
+use std::collections::{HashMap, VecDeque};
+
+/// Bounded map of client request id <-> offset the request was applied at. The oldest id is
+/// evicted first.
+pub(super) struct Dedup {
+    offsets: HashMap<u64, u64>,
+    order: VecDeque<u64>,
+    capacity: usize,
+}
+
+impl Dedup {
+    pub(super) fn new(capacity: usize) -> Self {
+        Dedup {
+            offsets: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Rebuilds a table from its entries, oldest first (see `entries()`).
+    pub(super) fn restore(capacity: usize, entries: &[(u64, u64)]) -> Self {
+        let mut dedup = Self::new(capacity);
+        for &(id, off) in entries {
+            dedup.record(id, off);
+        }
+        dedup
+    }
+
+    /// Offset at which the specified request was applied, if still known.
+    pub(super) fn get(&self, id: u64) -> Option<u64> {
+        self.offsets.get(&id).cloned()
+    }
+
+    pub(super) fn record(&mut self, id: u64, off: u64) -> () {
+
+        //
+        // - remember the id (a zero capacity disables the table)
+        // - evict the oldest ones beyond our capacity
+        //
+        if self.capacity == 0 || self.offsets.insert(id, off).is_some() {
+            return;
+        }
+        self.order.push_back(id);
+        while self.order.len() > self.capacity {
+            let _ = self.order.pop_front().map(|id| self.offsets.remove(&id));
+        }
+    }
+
+    /// All the ids and their offset, oldest first, e.g to be attached to a snapshot.
+    pub(super) fn entries(&self) -> Vec<(u64, u64)> {
+        self.order.iter().map(|id| (*id, self.offsets[id])).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn bounded() {
+
+        //
+        // - remember 3 ids at most: the oldest one is evicted
+        // - the table survives a round trip through its entries
+        //
+        let mut dedup = Dedup::new(3);
+        for id in 1..5 {
+            dedup.record(id, id * 10);
+        }
+        assert_eq!(dedup.get(1), None);
+        assert_eq!(dedup.get(4), Some(40));
+        let restored = Dedup::restore(3, &dedup.entries());
+        assert_eq!(restored.entries(), vec![(2, 20), (3, 30), (4, 40)]);
+
+        //
+        // - a zero capacity remembers nothing
+        //
+        let mut dedup = Dedup::new(0);
+        dedup.record(1, 10);
+        assert_eq!(dedup.get(1), None);
+    }
+}
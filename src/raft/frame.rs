@@ -0,0 +1,77 @@
+//! Framing of the byte buffers exchanged between peers. Each buffer (e.g a serialized RAW, signed
+//! if a cluster secret is set) is prefixed with its length and a CRC32 of its bytes. This way a
+//! buffer corrupted or truncated by the transport is dropped instead of being decoded, e.g the
+//! wire layout becomes
+//!
+//! ```ignore
+//!   | length (4 bytes) | CRC32 (4 bytes) | buffer (n bytes) |
+//! ```
+use raft::slots::crc32;
+
+/// Byte size of the frame header.
+pub const HEADER_BYTES: usize = 8;
+
+/// Prepends the frame header to a buffer.
+pub fn frame(bytes: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_BYTES + bytes.len());
+    put(&mut buf, bytes.len() as u32);
+    put(&mut buf, crc32(bytes));
+    buf.extend_from_slice(bytes);
+    buf
+}
+
+/// Checks the frame header and returns the buffer it covers, or None if the frame is truncated
+/// or corrupted.
+pub fn unframe(bytes: &[u8]) -> Option<&[u8]> {
+    if bytes.len() < HEADER_BYTES {
+        return None;
+    }
+    let (header, body) = bytes.split_at(HEADER_BYTES);
+    if get(&header[..4]) as usize != body.len() || get(&header[4..]) != crc32(body) {
+        return None;
+    }
+    Some(body)
+}
+
+/// Length of the buffer covered by a frame header, e.g to read the rest of a frame off a stream.
+pub fn length(header: &[u8]) -> usize {
+    debug_assert!(header.len() >= HEADER_BYTES);
+    get(&header[..4]) as usize
+}
+
+fn put(buf: &mut Vec<u8>, n: u32) -> () {
+    for i in 0..4 {
+        buf.push((n >> (8 * i)) as u8);
+    }
+}
+
+fn get(bytes: &[u8]) -> u32 {
+    bytes.iter().rev().fold(0, |n, &b| (n << 8) | u32::from(b))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn corruption() {
+
+        //
+        // - a frame round-trips
+        // - flipping any single bit or truncating it is detected
+        //
+        let bytes = b"some serialized buffer".to_vec();
+        let framed = frame(&bytes);
+        assert_eq!(framed.len(), HEADER_BYTES + bytes.len());
+        assert_eq!(length(&framed), bytes.len());
+        assert_eq!(unframe(&framed), Some(&bytes[..]));
+        for n in 0..framed.len() * 8 {
+            let mut corrupted = framed.clone();
+            corrupted[n / 8] ^= 1 << (n % 8);
+            assert!(unframe(&corrupted).is_none());
+        }
+        assert!(unframe(&framed[..framed.len() - 1]).is_none());
+        assert!(unframe(&framed[..4]).is_none());
+    }
+}
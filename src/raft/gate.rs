@@ -0,0 +1,111 @@
+//! Write backpressure shared between the automaton and its handles, see
+//! `RaftConfig::store_high_water`. While leading the automaton publishes its backlog (e.g the
+//! number of entries appended and not committed yet) and writers are held back as long as it
+//! sits at or above the high-water mark. Closing the gate upon shutdown releases them.
+use raft::protocol::StoreError;
+use std::sync::{Condvar, Mutex};
+
+pub(super) struct Gate {
+    limit: Option<u64>,
+    state: Mutex<(u64, bool)>,
+    cv: Condvar,
+}
+
+impl Gate {
+    pub(super) fn new(limit: Option<u64>) -> Self {
+        Gate {
+            limit,
+            state: Mutex::new((0, false)),
+            cv: Condvar::new(),
+        }
+    }
+
+    /// Updates the backlog, waking the blocked writers up if it dropped below the mark.
+    pub(super) fn publish(&self, backlog: u64) -> () {
+        if let Some(limit) = self.limit {
+            let mut state = self.state.lock().unwrap();
+            if state.0 != backlog {
+                state.0 = backlog;
+                if backlog < limit {
+                    self.cv.notify_all();
+                }
+            }
+        }
+    }
+
+    /// Releases all the blocked writers for good, see `Raft::drain()`.
+    pub(super) fn close(&self) -> () {
+        let mut state = self.state.lock().unwrap();
+        state.1 = true;
+        self.cv.notify_all();
+    }
+
+    /// Fails with `WouldBlock` if the backlog is at or above the mark.
+    pub(super) fn check(&self) -> Result<(), StoreError> {
+        let state = self.state.lock().unwrap();
+        match self.limit {
+            _ if state.1 => Err(StoreError::Unavailable),
+            Some(limit) if state.0 >= limit => Err(StoreError::WouldBlock),
+            _ => Ok(()),
+        }
+    }
+
+    /// Blocks until the backlog drops below the mark, or fails once the gate is closed.
+    pub(super) fn wait(&self) -> Result<(), StoreError> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            match self.limit {
+                _ if state.1 => return Err(StoreError::Unavailable),
+                Some(limit) if state.0 >= limit => state = self.cv.wait(state).unwrap(),
+                _ => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn backpressure() {
+
+        //
+        // - no mark: never blocks
+        // - at the mark writers are held back until the backlog drops
+        //
+        let gate = Gate::new(None);
+        gate.publish(1000);
+        assert!(gate.check().is_ok());
+        let gate = Arc::new(Gate::new(Some(4)));
+        gate.publish(4);
+        match gate.check() {
+            Err(StoreError::WouldBlock) => {}
+            _ => panic!("store admitted above the mark"),
+        }
+        let writer = {
+            let gate = gate.clone();
+            thread::spawn(move || gate.wait().is_ok())
+        };
+        thread::sleep(Duration::from_millis(20));
+        gate.publish(3);
+        assert!(writer.join().unwrap());
+
+        //
+        // - closing the gate releases a blocked writer with an error
+        //
+        gate.publish(10);
+        let writer = {
+            let gate = gate.clone();
+            thread::spawn(move || gate.wait().is_err())
+        };
+        thread::sleep(Duration::from_millis(20));
+        gate.close();
+        assert!(writer.join().unwrap());
+        assert!(gate.check().is_err());
+    }
+}
@@ -0,0 +1,146 @@
+//! Minimal key/value payload. Mutations are encoded as bincode commands which are stored via
+//! the raft automaton and applied upon commit via `apply()`. The whole map is serialized upon
+//! checkpointing.
+use bincode::{deserialize, serialize};
+use raft::protocol::{ApplyError, Payload};
+use std::collections::HashMap;
+
+/// Mutations supported by the key/value store.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Op {
+    /// Set a key to a value.
+    SET(Vec<u8>, Vec<u8>),
+    /// Remove a key.
+    DELETE(Vec<u8>),
+    /// Set a key to a value if its current value matches the expected one (None meaning the
+    /// key must not exist).
+    CAS(Vec<u8>, Option<Vec<u8>>, Vec<u8>),
+}
+
+impl Op {
+    /// Encodes the mutation, e.g what should be passed to `Raft::store()`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serialize(self).unwrap()
+    }
+}
+
+/// Key/value payload.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct KV {
+    map: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl KV {
+    #[allow(dead_code)]
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.map.get(key).map(|val| &val[..])
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl Payload for KV {
+    fn flush(&self) -> Vec<u8> {
+        serialize(&self.map).unwrap()
+    }
+
+    fn reset(&mut self, bytes: &[u8]) -> () {
+        self.map = if bytes.is_empty() {
+            HashMap::new()
+        } else {
+            deserialize(bytes).unwrap()
+        };
+    }
+}
+
+/// Apply closure to pass upon spawning, returning the previous value of the key if any. Empty
+/// entries (for instance the no-op entries appended by the automaton) are ignored while anything
+/// else that can't be decoded is rejected. A CAS whose expectation is not met is rejected as well
+/// and leaves the map as is.
+pub fn apply(kv: &mut KV, bytes: &[u8]) -> Result<Vec<u8>, ApplyError> {
+    if bytes.is_empty() {
+        return Ok(Vec::new());
+    }
+    let op = deserialize::<Op>(bytes).map_err(|_| ApplyError("malformed op".to_string()))?;
+    execute(kv, &op)
+}
+
+/// Same as `apply()` for a decoded mutation, e.g what to pass to `RaftBuilder::on_command()`
+/// when storing them with `Raft::store_command()`.
+pub fn execute(kv: &mut KV, op: &Op) -> Result<Vec<u8>, ApplyError> {
+    let previous = match *op {
+        Op::SET(ref key, ref val) => kv.map.insert(key.clone(), val.clone()),
+        Op::DELETE(ref key) => kv.map.remove(key),
+        Op::CAS(ref key, ref expected, ref val) => {
+            if kv.map.get(key) != expected.as_ref() {
+                return Err(ApplyError("unexpected value".to_string()));
+            }
+            kv.map.insert(key.clone(), val.clone())
+        }
+    };
+    Ok(previous.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use raft::protocol::Payload;
+    use super::*;
+
+    #[test]
+    fn mutations() {
+
+        //
+        // - set, overwrite and delete
+        //
+        let mut kv = KV::default();
+        let set = |key: &[u8], val: &[u8]| Op::SET(key.to_vec(), val.to_vec()).to_bytes();
+        assert_eq!(apply(&mut kv, &set(b"a", b"1")), Ok(vec![]));
+        assert_eq!(apply(&mut kv, &set(b"b", b"2")), Ok(vec![]));
+        assert_eq!(apply(&mut kv, &set(b"a", b"3")), Ok(b"1".to_vec()));
+        assert_eq!(apply(&mut kv, &Op::DELETE(b"b".to_vec()).to_bytes()), Ok(b"2".to_vec()));
+        assert_eq!(kv.get(b"a"), Some(&b"3"[..]));
+        assert_eq!(kv.get(b"b"), None);
+
+        //
+        // - CAS only goes through if the expectation is met
+        // - garbage is rejected, empty entries are ignored
+        //
+        let cas = |key: &[u8], expected: Option<&[u8]>, val: &[u8]| {
+            Op::CAS(key.to_vec(), expected.map(|e| e.to_vec()), val.to_vec()).to_bytes()
+        };
+        assert!(apply(&mut kv, &cas(b"a", Some(&b"1"[..]), b"4")).is_err());
+        assert_eq!(kv.get(b"a"), Some(&b"3"[..]));
+        assert_eq!(apply(&mut kv, &cas(b"a", Some(&b"3"[..]), b"4")), Ok(b"3".to_vec()));
+        assert_eq!(kv.get(b"a"), Some(&b"4"[..]));
+        assert_eq!(apply(&mut kv, &cas(b"b", None, b"5")), Ok(vec![]));
+        assert_eq!(kv.get(b"b"), Some(&b"5"[..]));
+        assert!(apply(&mut kv, &[0xff; 3]).is_err());
+        assert_eq!(apply(&mut kv, &[]), Ok(vec![]));
+        assert_eq!(kv.len(), 2);
+    }
+
+    #[test]
+    fn snapshot() {
+
+        //
+        // - flush and reset into a blank payload
+        //
+        let mut kv = KV::default();
+        let _ = apply(&mut kv, &Op::SET(b"a".to_vec(), b"1".to_vec()).to_bytes());
+        let _ = apply(&mut kv, &Op::SET(b"b".to_vec(), b"2".to_vec()).to_bytes());
+        let mut copy = KV::default();
+        copy.reset(&kv.flush());
+        assert_eq!(copy, kv);
+        copy.reset(&[]);
+        assert!(copy.is_empty());
+    }
+}
@@ -1,17 +1,28 @@
-use bincode::serialize;
+use bincode::{deserialize, serialize, serialized_size};
+#[cfg(feature = "json")]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "json")]
+use serde::Serialize;
+#[cfg(feature = "json")]
+use serde_json::{to_value, Value};
+use std::fmt;
 
 macro_rules! declare {
     ($code:expr, $msg:ident) => {
         impl $msg {
             pub(super) const CODE: u8 = $code;
-            pub(super) fn to_raw(&self, src: &[u8;32], dst: &[u8;32]) -> Vec<u8> {
-                let raw = RAW {
+            pub(super) fn envelope(&self, src: &[u8;32], dst: &[u8;32]) -> RAW {
+                RAW {
+                    version: VERSION,
                     code: $msg::CODE,
                     src: src.clone(),
                     dst: dst.clone(),
-                    msg: serialize(&self).unwrap(),
-                };
-                serialize(&raw).unwrap()
+                    msg: serialize(&self).expect("message is serializable"),
+                }
+            }
+            #[allow(dead_code)]
+            pub(super) fn to_raw(&self, src: &[u8;32], dst: &[u8;32]) -> Vec<u8> {
+                serialize(&self.envelope(src, dst)).expect("RAW is serializable")
             }
         }
     };
@@ -26,55 +37,222 @@ declare!(5, PROBE);
 declare!(6, AVAILABLE);
 declare!(7, ADVERTISE);
 declare!(8, VOTE);
+declare!(9, INSTALL_SNAPSHOT);
+declare!(10, TIMEOUT_NOW);
+
+/// Message types indexed by code.
+pub(super) const KINDS: [&str; 11] = [
+    "PING",
+    "REPLICATE",
+    "ACK",
+    "REBASE",
+    "UPGRADE",
+    "PROBE",
+    "AVAILABLE",
+    "ADVERTISE",
+    "VOTE",
+    "INSTALL_SNAPSHOT",
+    "TIMEOUT_NOW",
+];
 
+/// Protocol version stamped on each `RAW`. The high byte is the major version, which must be
+/// bumped whenever the layout of any message changes: bincode is not self-describing, so even an
+/// added optional field breaks decoding on the other side. The low byte is the minor version,
+/// bumped for changes that keep every layout intact (e.g how a field is interpreted): peers with
+/// the same major version interoperate. Version 2.0 widened peer ids to 16 bits. Version 3.0
+/// added client request ids to the log slots and to the snapshots shipped with
+/// INSTALL_SNAPSHOT. Version 4.0 added the payload digest to ACK.
+pub(super) const VERSION: u16 = 0x0400;
+
+/// Whether a peer using that protocol version can talk to us.
+pub(super) fn compatible(version: u16) -> bool {
+    version >> 8 == VERSION >> 8
+}
+
+/// Envelope for all the messages exchanged between peers, e.g what is passed to the `write`
+/// closure once serialized. The protocol version comes first so that it can always be read.
 #[derive(Debug, Serialize, Deserialize)]
-pub(super) struct RAW {
+pub struct RAW {
+    pub(super) version: u16,
     pub(super) code: u8,
     pub(super) src: [u8; 32],
     pub(super) dst: [u8; 32],
     pub(super) msg: Vec<u8>,
 }
 
+impl RAW {
+    /// Number of bytes this envelope takes once serialized, computed without serializing it.
+    /// This does not cover the frame header nor the MAC added before it is handed over to the
+    /// `write` closure, see `Raft::wire_len()` for that.
+    pub fn encoded_len(&self) -> usize {
+        serialized_size(self).expect("RAW is serializable") as usize
+    }
+
+    /// Sender of a serialized envelope, read off its fixed size prefix without decoding the
+    /// rest (e.g the version, the code and then the source host). None if the buffer is too
+    /// short.
+    pub(super) fn peek_src(bytes: &[u8]) -> Option<[u8; 32]> {
+        if bytes.len() < 35 {
+            return None;
+        }
+        let mut src = [0; 32];
+        src.copy_from_slice(&bytes[3..35]);
+        Some(src)
+    }
+
+    /// Whether the enclosed message is one we know of.
+    pub(super) fn known(&self) -> bool {
+        self.kind() != "?"
+    }
+
+    /// Type of the enclosed message (PING, VOTE, etc), mostly meant for interceptors (see
+    /// `Raft::intercept()`).
+    pub fn kind(&self) -> &'static str {
+        KINDS.get(self.code as usize).cloned().unwrap_or("?")
+    }
+}
+
+/// Host label without the zero padding, see `RAW`.
+pub(super) fn label(host: &[u8; 32]) -> String {
+    let n = host.iter().position(|&b| b == 0).unwrap_or(32);
+    String::from_utf8_lossy(&host[..n]).into_owned()
+}
+
+impl fmt::Display for RAW {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+
+        //
+        // - decode the enclosed message based on its code, e.g for logging purposes
+        //
+        write!(f, "{} -> {} | ", label(&self.src), label(&self.dst))?;
+        let bytes = &self.msg[..];
+        let res = match self.code {
+            PING::CODE => deserialize::<PING>(bytes).map(|msg| format!("{:?}", msg)),
+            REPLICATE::CODE => deserialize::<REPLICATE>(bytes).map(|msg| {
+                format!(
+                    "REPLICATE {{ id: {}, term: {}, off: {}, age: {}, commit: {}, {}B appended }}",
+                    msg.id,
+                    msg.term,
+                    msg.off,
+                    msg.age,
+                    msg.commit,
+                    msg.append.len()
+                )
+            }),
+            ACK::CODE => deserialize::<ACK>(bytes).map(|msg| format!("{:?}", msg)),
+            REBASE::CODE => deserialize::<REBASE>(bytes).map(|msg| format!("{:?}", msg)),
+            UPGRADE::CODE => deserialize::<UPGRADE>(bytes).map(|msg| format!("{:?}", msg)),
+            PROBE::CODE => deserialize::<PROBE>(bytes).map(|msg| format!("{:?}", msg)),
+            AVAILABLE::CODE => deserialize::<AVAILABLE>(bytes).map(|msg| format!("{:?}", msg)),
+            ADVERTISE::CODE => deserialize::<ADVERTISE>(bytes).map(|msg| format!("{:?}", msg)),
+            VOTE::CODE => deserialize::<VOTE>(bytes).map(|msg| format!("{:?}", msg)),
+            INSTALL_SNAPSHOT::CODE => deserialize::<INSTALL_SNAPSHOT>(bytes).map(|msg| {
+                format!(
+                    "INSTALL_SNAPSHOT {{ id: {}, term: {}, last_index: {}, last_term: {}, \
+                     [{}B +{}B] of {}B }}",
+                    msg.id,
+                    msg.term,
+                    msg.last_index,
+                    msg.last_term,
+                    msg.offset,
+                    msg.chunk.len(),
+                    msg.size
+                )
+            }),
+            TIMEOUT_NOW::CODE => {
+                deserialize::<TIMEOUT_NOW>(bytes).map(|msg| format!("{:?}", msg))
+            }
+            code => return write!(f, "unknown code #{} ({}B)", code, bytes.len()),
+        };
+        match res {
+            Ok(text) => write!(f, "{}", text),
+            Err(_) => write!(f, "invalid {} ({}B)", self.kind(), bytes.len()),
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+impl RAW {
+    /// JSON rendition of the envelope with the enclosed message decoded based on its code, e.g
+    /// for debugging purposes. This is only a view: what is exchanged between peers remains
+    /// encoded with bincode. None if the message can't be decoded.
+    pub fn to_json(&self) -> Option<String> {
+        let bytes = &self.msg[..];
+        let msg = match self.code {
+            PING::CODE => decode::<PING>(bytes),
+            REPLICATE::CODE => decode::<REPLICATE>(bytes),
+            ACK::CODE => decode::<ACK>(bytes),
+            REBASE::CODE => decode::<REBASE>(bytes),
+            UPGRADE::CODE => decode::<UPGRADE>(bytes),
+            PROBE::CODE => decode::<PROBE>(bytes),
+            AVAILABLE::CODE => decode::<AVAILABLE>(bytes),
+            ADVERTISE::CODE => decode::<ADVERTISE>(bytes),
+            VOTE::CODE => decode::<VOTE>(bytes),
+            INSTALL_SNAPSHOT::CODE => decode::<INSTALL_SNAPSHOT>(bytes),
+            TIMEOUT_NOW::CODE => decode::<TIMEOUT_NOW>(bytes),
+            _ => None,
+        }?;
+        let json = json!({
+            "version": self.version,
+            "kind": self.kind(),
+            "src": label(&self.src),
+            "dst": label(&self.dst),
+            "msg": msg,
+        });
+        Some(json.to_string())
+    }
+}
+
+#[cfg(feature = "json")]
+fn decode<T>(bytes: &[u8]) -> Option<Value>
+where
+    T: DeserializeOwned + Serialize,
+{
+    deserialize::<T>(bytes).ok().and_then(|msg| to_value(msg).ok())
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub(super) struct PING {
-    pub(super) id: u8,
+    pub(super) id: u16,
     pub(super) term: u64,
     pub(super) commit: u64,
+    pub(super) digest: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(super) struct REPLICATE {
-    pub(super) id: u8,
+    pub(super) id: u16,
     pub(super) term: u64,
     pub(super) off: u64,
     pub(super) age: u64,
     pub(super) commit: u64,
     pub(super) append: Vec<u8>,
-    pub(super) snapshot: Vec<u8>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(super) struct ACK {
-    pub(super) id: u8,
+    pub(super) id: u16,
     pub(super) term: u64,
     pub(super) ack: u64,
+    pub(super) learner: bool,
+    pub(super) digest: Option<(u64, u64)>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(super) struct REBASE {
-    pub(super) id: u8,
+    pub(super) id: u16,
     pub(super) term: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(super) struct UPGRADE {
-    pub(super) id: u8,
+    pub(super) id: u16,
     pub(super) term: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(super) struct PROBE {
-    pub(super) id: u8,
+    pub(super) id: u16,
     pub(super) term: u64,
     pub(super) head: u64,
     pub(super) age: u64,
@@ -82,20 +260,44 @@ pub(super) struct PROBE {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(super) struct AVAILABLE {
-    pub(super) id: u8,
+    pub(super) id: u16,
     pub(super) term: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(super) struct ADVERTISE {
-    pub(super) id: u8,
+    pub(super) id: u16,
     pub(super) term: u64,
     pub(super) head: u64,
     pub(super) age: u64,
+    pub(super) transfer: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(super) struct VOTE {
-    pub(super) id: u8,
+    pub(super) id: u16,
+    pub(super) term: u64,
+}
+
+/// Chunk at `offset` of a snapshot reflecting every entry up to `last_index`. The client
+/// request ids and the cluster membership only come with the first chunk.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct INSTALL_SNAPSHOT {
+    pub(super) id: u16,
+    pub(super) term: u64,
+    pub(super) last_index: u64,
+    pub(super) last_term: u64,
+    pub(super) offset: u64,
+    pub(super) size: u64,
+    pub(super) chunk: Vec<u8>,
+    pub(super) requests: Vec<(u64, u64)>,
+    pub(super) peers: Vec<(u16, [u8; 32])>,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct TIMEOUT_NOW {
+    pub(super) id: u16,
     pub(super) term: u64,
 }
@@ -0,0 +1,204 @@
+//! Throughput counters maintained by the raft automaton, see `Raft::metrics()`. Each counter
+//! keeps a running total plus a small ring of per-second buckets from which a rate over a
+//! rolling window is derived. Updating a counter is a couple of integer operations and the
+//! memory footprint is fixed.
+use raft::messages::KINDS;
+use raft::protocol::Role;
+use std::cmp;
+use std::time::{Duration, Instant};
+
+/// Number of one second buckets, e.g the rolling window rates are computed over.
+pub const WINDOW: usize = 10;
+
+/// Snapshot of the automaton counters.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct RaftMetrics {
+    /// Total number of entries appended to the log since spawning.
+    pub appended: u64,
+    /// Total number of entries committed (e.g applied) since spawning.
+    pub committed: u64,
+    /// Entries appended per second over the rolling window.
+    pub append_rate: f64,
+    /// Entries committed per second over the rolling window.
+    pub commit_rate: f64,
+    /// Number of bytes used by the live portion of the log plus the latest snapshot.
+    pub disk_usage: u64,
+    /// Current role.
+    pub role: Role,
+    /// Number of incoming buffers dropped by `Raft::feed()`.
+    pub dropped: usize,
+    /// Messages sent and received so far, by type.
+    pub messages: MessageStats,
+}
+
+/// Number of messages sent and received since spawning, by type, see `Raft::message_stats()`.
+/// Both arrays are indexed by message code. Rates are left to the caller, e.g by diffing
+/// successive snapshots.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct MessageStats {
+    /// Messages handed over to the `write` closure (e.g not dropped by an interceptor).
+    pub sent: [u64; 11],
+    /// Valid messages received from peers, counted before any interceptor runs.
+    pub received: [u64; 11],
+}
+
+impl MessageStats {
+    /// Number of messages of that type (PING, REPLICATE, etc) sent so far.
+    pub fn sent(&self, kind: &str) -> u64 {
+        KINDS.iter().position(|k| *k == kind).map_or(0, |n| self.sent[n])
+    }
+
+    /// Number of messages of that type (PING, REPLICATE, etc) received so far.
+    pub fn received(&self, kind: &str) -> u64 {
+        KINDS.iter().position(|k| *k == kind).map_or(0, |n| self.received[n])
+    }
+
+    /// Message types paired with how many were sent and received, in code order.
+    pub fn kinds(&self) -> Vec<(&'static str, u64, u64)> {
+        KINDS
+            .iter()
+            .enumerate()
+            .map(|(n, kind)| (*kind, self.sent[n], self.received[n]))
+            .collect()
+    }
+
+    pub(super) fn on_sent(&mut self, code: u8) -> () {
+        if let Some(n) = self.sent.get_mut(code as usize) {
+            *n += 1;
+        }
+    }
+
+    pub(super) fn on_received(&mut self, code: u8) -> () {
+        if let Some(n) = self.received.get_mut(code as usize) {
+            *n += 1;
+        }
+    }
+}
+
+/// Replication progress of a given peer as tracked by the LEADER, see `Raft::peer_metrics()`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PeerMetric {
+    /// Peer id.
+    pub id: u16,
+    /// Offset carried by the latest ACK received from that peer (None if it never acknowledged
+    /// anything). ACKs may arrive out of order so this may be below `matched`.
+    pub acked: Option<u64>,
+    /// Highest acknowledged offset, e.g up to where its log is known to match ours.
+    pub matched: u64,
+    /// Number of entries it is behind our head (None if it never acknowledged anything, as
+    /// opposed to zero for a peer that is up to date).
+    pub lag: Option<u64>,
+    /// Number of REPLICATE sent to it and not acknowledged yet.
+    pub inflight: usize,
+    /// Time elapsed since its latest ACK (None if it never acknowledged anything).
+    pub since_ack: Option<Duration>,
+}
+
+/// Monotonic counter with a rolling rate.
+pub struct Throughput {
+    /// Reference time, bucket seconds are relative to it.
+    epoch: Instant,
+    /// Ring of (second, count) pairs, indexed by second modulo the window.
+    buckets: [(u64, u64); WINDOW],
+    /// Running total.
+    total: u64,
+}
+
+impl Throughput {
+    pub fn new(epoch: Instant) -> Self {
+        Throughput {
+            epoch,
+            buckets: [(0, 0); WINDOW],
+            total: 0,
+        }
+    }
+
+    fn second(&self, now: Instant) -> u64 {
+        if now > self.epoch {
+            (now - self.epoch).as_secs()
+        } else {
+            0
+        }
+    }
+
+    /// Accounts for n events happening at the specified time.
+    pub fn record(&mut self, n: u64, now: Instant) -> () {
+
+        //
+        // - locate the bucket for the current second
+        // - if it is left over from a previous lap around the ring reset it
+        //
+        let second = self.second(now);
+        let bucket = &mut self.buckets[second as usize % WINDOW];
+        if bucket.0 != second {
+            *bucket = (second, 0);
+        }
+        bucket.1 += n;
+        self.total += n;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Average number of events per second over the rolling window as of the specified time.
+    pub fn rate(&self, now: Instant) -> f64 {
+
+        //
+        // - only consider the seconds fully elapsed, e.g not the current one
+        // - stale buckets (older than the window) are skipped
+        //
+        let second = self.second(now);
+        let span = cmp::min(second, WINDOW as u64);
+        if span == 0 {
+            return 0.0;
+        }
+        let sum: u64 = self
+            .buckets
+            .iter()
+            .filter(|bucket| bucket.0 < second && bucket.0 >= second - span)
+            .map(|bucket| bucket.1)
+            .sum();
+        sum as f64 / span as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::{Duration, Instant};
+    use super::*;
+
+    #[test]
+    fn rolling_rate() {
+
+        //
+        // - record 50 events per second spread over 30 seconds
+        // - the rate must be close to 50 and the total exact
+        //
+        let epoch = Instant::now();
+        let mut counter = Throughput::new(epoch);
+        for n in 0..1500u64 {
+            counter.record(1, epoch + Duration::from_millis(n * 20));
+        }
+        let now = epoch + Duration::from_millis(30_000);
+        assert_eq!(counter.total(), 1500);
+        assert!((counter.rate(now) - 50.0).abs() < 1.0);
+
+        //
+        // - double the load for a full window, the rate must follow
+        //
+        for n in 0..(WINDOW as u64 * 100) {
+            counter.record(1, now + Duration::from_millis(n * 10));
+        }
+        let now = now + Duration::from_secs(WINDOW as u64);
+        assert!((counter.rate(now) - 100.0).abs() < 1.0);
+
+        //
+        // - once idle for a full window the rate drops to zero but not the total
+        //
+        let now = now + Duration::from_secs(WINDOW as u64 + 1);
+        assert!(counter.rate(now) < ::std::f64::EPSILON);
+        assert_eq!(counter.total(), 1500 + WINDOW as u64 * 100);
+    }
+}
@@ -1,25 +1,50 @@
+pub mod applier;
+#[cfg(feature = "auth")]
+pub mod auth;
+pub mod clock;
+pub mod config;
+pub mod dedup;
+pub mod frame;
+pub mod gate;
+pub mod kv;
 pub mod messages;
+pub mod metrics;
 pub mod protocol;
+pub mod quorum;
 pub mod sink;
 pub mod slots;
+pub mod snapshot;
+pub mod storage;
+pub mod transport;
 
 use bincode::deserialize;
+use error::RsmError;
 use fsm::automaton::Automaton;
 use fsm::timer::Timer;
-use memmap::MmapMut;
 use primitives::event::*;
 use primitives::once::*;
 use primitives::rwlock::*;
-use self::protocol::{Command, FSM, Payload, Peer, Raft};
+use self::applier::{Applier, ConflictKey, Lanes, Runner, Stage};
+use self::clock::{Clock, SystemClock};
+use self::config::{ConfigError, RaftConfig};
+use self::gate::Gate;
+use self::dedup::Dedup;
+use self::metrics::Throughput;
+use self::protocol::{ApplyError, Command, FSM, Payload, Peer, Raft};
+use self::quorum::Majority;
 use self::sink::Sink;
-use slog::Logger;
+use self::snapshot::{FileSnapshotStore, SnapshotStore};
+use self::storage::{FileStorage, Storage};
+use serde::de::DeserializeOwned;
+use slog::{Discard, Logger};
 use std::cmp;
-use std::collections::HashMap;
-use std::fs::OpenOptions;
-use std::hash::BuildHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{BuildHasher, Hash, Hasher};
 use std::io::{stdout, Write};
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicUsize;
+use std::time::Duration;
 
 macro_rules! clip_to_array {
     ($tag:expr) => {
@@ -44,27 +69,353 @@ pub static ANCILLARY: Once<Shared> = Once::new();
 /// Constructor method to spawn a new raft peer with a given id. It returns a triplet made of the
 /// automaton wrapper, a read-only lock on the raft payload and a notification sink.
 ///
-/// The automaton is defined by a unique u8 identifier and a network destination
+/// The automaton is defined by a unique u16 identifier and a network destination
 /// (e.g host+port). An optional set of seed peers may be specified. Binary buffers that need
 /// to be sent to a given peer are passed to the `write` closure. It is up to the user to then
 /// transmit those buffers depending on the implementation (socket, pipe, etc).
 ///
 /// The method is parameterized with the payload to use: the automaton will create and own this
-/// payload. It will also update it upon each commit via the `apply` closure.
+/// payload. It will also update it upon each commit via the `apply` closure, which returns
+/// either some bytes or an `ApplyError` to reject the entry. Either way the entry is committed
+/// and the outcome is passed on to whoever stored it (see `StoreResult`).
+///
+/// Tunables (timeouts, etc.) are passed via a `RaftConfig`, use its default for the standard
+/// behavior. Snapshots taken upon checkpointing are persisted via the specified store, see
+/// `FileSnapshotStore` for the default one. The log and votes go to files in the current
+/// directory (see `FileStorage`), use the builder to pass another `Storage`.
+///
+/// Spawning fails if the configuration is invalid or if the log file can't be set up.
 ///
 pub fn spawn<'a, S, T, U, V: BuildHasher>(
     guard: &Arc<Guard>,
-    id: u8,
-    mut peers: HashMap<u8, &'a str, V>,
+    id: u16,
+    peers: HashMap<u16, &'a str, V>,
+    config: RaftConfig,
+    snapshots: Box<dyn SnapshotStore>,
+    write: S,
+    apply: T,
+    logger: Logger,
+) -> Result<(Arc<Raft>, Arc<ROLock<U>>, Arc<Sink>), RsmError>
+where
+    S: 'static + Send + Fn(&[u8; 32], &[u8]) -> (),
+    T: 'static + Send + Fn(&mut U, &[u8]) -> Result<Vec<u8>, ApplyError>,
+    U: 'static + Send + Default + Payload,
+{
+    let clock = Arc::new(SystemClock);
+    launch(guard, id, peers, config, None, snapshots, clock, write, apply, None, logger, false)
+}
+
+/// Same as spawn() except the automaton picks up where a previous incarnation left off (e.g
+/// after a restart). Its payload is reset from the latest snapshot found in the store and
+/// whatever entries follow in its log file are kept. Those entries are then applied as the
+/// LEADER commits them, meaning only what the peer missed while down is replicated. The
+/// automaton starts afresh if nothing can be recovered.
+pub fn recover<'a, S, T, U, V: BuildHasher>(
+    guard: &Arc<Guard>,
+    id: u16,
+    peers: HashMap<u16, &'a str, V>,
+    config: RaftConfig,
+    snapshots: Box<dyn SnapshotStore>,
+    write: S,
+    apply: T,
+    logger: Logger,
+) -> Result<(Arc<Raft>, Arc<ROLock<U>>, Arc<Sink>), RsmError>
+where
+    S: 'static + Send + Fn(&[u8; 32], &[u8]) -> (),
+    T: 'static + Send + Fn(&mut U, &[u8]) -> Result<Vec<u8>, ApplyError>,
+    U: 'static + Send + Default + Payload,
+{
+    let clock = Arc::new(SystemClock);
+    launch(guard, id, peers, config, None, snapshots, clock, write, apply, None, logger, true)
+}
+
+/// Alternative to spawn() and recover() setting the automaton up step by step. Anything not
+/// set defaults to what those would typically be passed: default `RaftConfig`, a `FileStorage`
+/// with snapshots stored next to the log file, discarded buffers, no-op apply closure and a
+/// discarding logger.
+pub struct RaftBuilder<U> {
+    id: u16,
+    peers: HashMap<u16, String>,
+    config: RaftConfig,
+    storage: Option<Box<dyn Storage>>,
+    snapshots: Option<Box<dyn SnapshotStore>>,
+    clock: Option<Arc<dyn Clock>>,
+    write: Option<Box<dyn Fn(&[u8; 32], &[u8]) + Send>>,
+    apply: Option<Box<dyn Fn(&mut U, &[u8]) -> Result<Vec<u8>, ApplyError> + Send>>,
+    parallel: Option<Parallel<U>>,
+    logger: Option<Logger>,
+    recovering: bool,
+}
+
+/// Conflict key plus the apply closure to bind to the payload once spawned, see
+/// `RaftBuilder::on_commit_parallel()`.
+struct Parallel<U> {
+    key: ConflictKey,
+    bind: Box<dyn FnOnce(Arc<RWLock<U>>) -> Runner + Send>,
+}
+
+impl<U> RaftBuilder<U>
+where
+    U: 'static + Send + Default + Payload,
+{
+    pub fn new() -> Self {
+        RaftBuilder {
+            id: 0,
+            peers: HashMap::new(),
+            config: RaftConfig::default(),
+            storage: None,
+            snapshots: None,
+            clock: None,
+            write: None,
+            apply: None,
+            parallel: None,
+            logger: None,
+            recovering: false,
+        }
+    }
+
+    /// Local peer id, which must be part of the peers. Defaults to 0.
+    pub fn id(mut self, id: u16) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// Adds one peer (the local one included).
+    pub fn peer(mut self, id: u16, host: &str) -> Self {
+        self.peers.insert(id, host.to_string());
+        self
+    }
+
+    /// Adds a set of peers, e.g the whole cluster.
+    pub fn peers<V: BuildHasher>(mut self, peers: HashMap<u16, &str, V>) -> Self {
+        for (id, host) in peers {
+            self.peers.insert(id, host.to_string());
+        }
+        self
+    }
+
+    /// Tunables, replacing whatever was set so far.
+    pub fn config(mut self, config: RaftConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Shortcut to set `RaftConfig::election_timeout`.
+    pub fn election_timeout(mut self, lapse: Duration) -> Self {
+        self.config.election_timeout = lapse;
+        self
+    }
+
+    /// Shortcut to set `RaftConfig::snapshot_threshold_entries`, e.g to checkpoint every so
+    /// many committed entries.
+    pub fn snapshot_threshold(mut self, entries: u64) -> Self {
+        self.config.snapshot_threshold_entries = Some(entries);
+        self
+    }
+
+    /// Shortcut to set `RaftConfig::snapshot_threshold_bytes`.
+    pub fn snapshot_threshold_bytes(mut self, bytes: usize) -> Self {
+        self.config.snapshot_threshold_bytes = Some(bytes);
+        self
+    }
+
+    /// Shortcut to set `RaftConfig::store_high_water`, e.g to hold writers back once that many
+    /// entries are waiting to commit.
+    pub fn store_high_water(mut self, entries: u64) -> Self {
+        self.config.store_high_water = Some(entries);
+        self
+    }
+
+    /// Shortcut to set `RaftConfig::priority`, e.g how much this peer is preferred as a LEADER.
+    pub fn priority(mut self, priority: u32) -> Self {
+        self.config.priority = priority;
+        self
+    }
+
+    /// Shortcut to set `RaftConfig::learner`.
+    pub fn learner(mut self, learner: bool) -> Self {
+        self.config.learner = learner;
+        self
+    }
+
+    /// Where the log, the vote and snapshots are persisted, see `Storage`. The snapshot store
+    /// is ignored when this is set.
+    pub fn storage(mut self, storage: Box<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    pub fn snapshots(mut self, snapshots: Box<dyn SnapshotStore>) -> Self {
+        self.snapshots = Some(snapshots);
+        self
+    }
+
+    /// Time source, see `Clock`. Defaults to the wall clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Closure passed each buffer to send to a given peer, see spawn().
+    pub fn on_send<F>(mut self, f: F) -> Self
+    where
+        F: 'static + Send + Fn(&[u8; 32], &[u8]) -> (),
+    {
+        self.write = Some(Box::new(f));
+        self
+    }
+
+    /// Closure applying each committed entry to the payload, see spawn().
+    pub fn on_commit<F>(mut self, f: F) -> Self
+    where
+        F: 'static + Send + Fn(&mut U, &[u8]) -> Result<Vec<u8>, ApplyError>,
+    {
+        self.apply = Some(Box::new(f));
+        self.parallel = None;
+        self
+    }
+
+    /// Same as `on_commit()` but entries coming with a conflict key are applied concurrently by
+    /// `RaftConfig::apply_lanes` threads: entries with the same key are applied in commit order
+    /// while entries without one wait for whatever precedes them and hold back whatever follows.
+    /// The apply closure therefore only gets a shared reference to the payload, which must
+    /// synchronize its own updates. All the peers must use the same conflict key and entries
+    /// with different keys must commute, otherwise the payloads diverge. Stores with different
+    /// keys may complete out of order. This requires `RaftConfig::apply_queue`, entries being
+    /// applied one at a time otherwise.
+    ///
+    /// The lanes only hold the payload's read lock, which readers (`Raft::query()` or the lock
+    /// returned upon spawning) take as well. A reader may therefore see some entries half
+    /// applied, or entries with a given key applied while earlier ones with another key are
+    /// not yet, e.g a state matching no commit offset. Entries without a conflict key still
+    /// take the write lock once the lanes are done, so the payload is consistent whenever one
+    /// of those is applied. Payloads that can't tolerate this should use `on_commit()` instead.
+    pub fn on_commit_parallel<K, F, G>(mut self, key: F, apply: G) -> Self
+    where
+        U: Sync,
+        K: Hash,
+        F: 'static + Send + Fn(&[u8]) -> Option<K>,
+        G: 'static + Send + Sync + Fn(&U, &[u8]) -> Result<Vec<u8>, ApplyError>,
+    {
+        let apply = Arc::new(apply);
+        let inline = apply.clone();
+        self.apply = Some(Box::new(move |payload: &mut U, bytes: &[u8]| inline(payload, bytes)));
+        self.parallel = Some(Parallel {
+            key: Box::new(move |bytes: &[u8]| {
+                key(bytes).map(|k| {
+                    let mut hasher = DefaultHasher::new();
+                    k.hash(&mut hasher);
+                    hasher.finish()
+                })
+            }),
+            bind: Box::new(move |payload: Arc<RWLock<U>>| -> Runner {
+                Arc::new(move |bytes: &[u8]| apply(&*payload.read(), bytes))
+            }),
+        });
+        self
+    }
+
+    /// Same as `on_commit()` but each entry is first decoded into a typed command, e.g whatever
+    /// was passed to `Raft::store_command()`. Empty entries (see `Raft::noop()`) are ignored
+    /// while anything else that can't be decoded is rejected.
+    pub fn on_command<C, F>(self, f: F) -> Self
+    where
+        C: 'static + DeserializeOwned,
+        F: 'static + Send + Fn(&mut U, &C) -> Result<Vec<u8>, ApplyError>,
+    {
+        self.on_commit(move |payload: &mut U, bytes: &[u8]| {
+            if bytes.is_empty() {
+                return Ok(Vec::new());
+            }
+            let cmd = deserialize::<C>(bytes)
+                .map_err(|_| ApplyError("malformed command".to_string()))?;
+            f(payload, &cmd)
+        })
+    }
+
+    pub fn logger(mut self, logger: Logger) -> Self {
+        self.logger = Some(logger);
+        self
+    }
+
+    /// Picks up where a previous incarnation left off, see recover().
+    pub fn recover(mut self, recovering: bool) -> Self {
+        self.recovering = recovering;
+        self
+    }
+
+    /// Starts the automaton, see spawn() for what is returned.
+    pub fn spawn(
+        self,
+        guard: &Arc<Guard>,
+    ) -> Result<(Arc<Raft>, Arc<ROLock<U>>, Arc<Sink>), RsmError> {
+        let id = self.id;
+        let peers: HashMap<_, _> = self.peers
+            .iter()
+            .map(|(id, host)| (*id, host.as_str()))
+            .collect();
+        let snapshots = self.snapshots
+            .unwrap_or_else(|| Box::new(FileSnapshotStore::for_peer(id)));
+        let clock = self.clock.unwrap_or_else(|| Arc::new(SystemClock));
+        let write = self.write.unwrap_or_else(|| Box::new(|_: &[u8; 32], _: &[u8]| {}));
+        let apply = self.apply.unwrap_or_else(|| {
+            Box::new(|_: &mut U, _: &[u8]| Ok::<_, ApplyError>(Vec::new()))
+        });
+        let logger = self.logger.unwrap_or_else(|| Logger::root(Discard, o!()));
+        launch(
+            guard,
+            id,
+            peers,
+            self.config,
+            self.storage,
+            snapshots,
+            clock,
+            move |host: &[u8; 32], bytes: &[u8]| write(host, bytes),
+            move |payload: &mut U, bytes: &[u8]| apply(payload, bytes),
+            self.parallel,
+            logger,
+            self.recovering,
+        )
+    }
+}
+
+impl<U> Default for RaftBuilder<U>
+where
+    U: 'static + Send + Default + Payload,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn launch<'a, S, T, U, V: BuildHasher>(
+    guard: &Arc<Guard>,
+    id: u16,
+    mut peers: HashMap<u16, &'a str, V>,
+    config: RaftConfig,
+    storage: Option<Box<dyn Storage>>,
+    snapshots: Box<dyn SnapshotStore>,
+    clock: Arc<dyn Clock>,
     write: S,
     apply: T,
+    parallel: Option<Parallel<U>>,
     logger: Logger,
-) -> (Arc<Raft>, Arc<ROLock<U>>, Arc<Sink>)
+    recovering: bool,
+) -> Result<(Arc<Raft>, Arc<ROLock<U>>, Arc<Sink>), RsmError>
 where
     S: 'static + Send + Fn(&[u8; 32], &[u8]) -> (),
-    T: 'static + Send + Fn(&mut U, &[u8]) -> (),
+    T: 'static + Send + Fn(&mut U, &[u8]) -> Result<Vec<u8>, ApplyError>,
     U: 'static + Send + Default + Payload,
 {
+    //
+    // - check the configuration and our id first: nothing must be started (e.g the shared
+    //   timer, which holds onto the guard) if either is invalid
+    //
+    config.validate()?;
+    if usize::from(id) >= peers.len() || !peers.contains_key(&id) {
+        return Err(RsmError::Config(ConfigError::Invalid("local id not found in the peers")));
+    }
+
     //
     // - retrieve (and create upon the first invokation) a shared timer automaton used to fire
     //   timeout notifications
@@ -77,18 +428,6 @@ where
     // - turn the specified id/host mapping into our peer map
     // - make sure to remove any entry that would be using our peer id
     //
-    assert!(peers.len() < 64, "only 64 peers max are supported");
-    assert!(
-        id < peers.len() as u8,
-        "id={} but on {} peers are specified",
-        id,
-        peers.len()
-    );
-    assert!(
-        peers.contains_key(&id),
-        "{} not found in the specified map",
-        id
-    );
     let host = clip_to_array!(peers[&id]);
     peers.retain(|&n, _| n != id);
     let peers: HashMap<_, _> = peers
@@ -100,24 +439,24 @@ where
                     host: clip_to_array!(host),
                     off: 1,
                     ack: 1,
+                    rebasing: None,
+                    learner: false,
+                    diverged: false,
+                    acked: None,
+                    inflight: VecDeque::new(),
+                    resend: None,
                 },
             )
         })
         .collect();
 
     //
-    // - setup the log file
-    // - resize it
+    // - default to the log file, vote file and snapshot store in the current directory
     //
-    let path = PathBuf::from(format!("log.{}", id));
-    let file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(&path)
-        .unwrap();
-    let off = FSM::<S, T, U>::RESOLUTION * FSM::<S, T, U>::SLOT_BYTES;
-    file.set_len(off as u64).unwrap();
+    let storage = match storage {
+        Some(storage) => storage,
+        None => Box::new(FileStorage::for_peer(id, snapshots)?),
+    };
 
     //
     // - create a notification sink
@@ -128,6 +467,52 @@ where
     let sink = Arc::new(Sink::new());
     let payload = Arc::new(RWLock::from(Default::default()));
     let lock = Arc::new(payload.read_only());
+    let pending = Arc::new(AtomicUsize::new(0));
+    let role = Arc::new(AtomicUsize::new(0));
+    let gate = Arc::new(Gate::new(config.store_high_water));
+    let capacity = config.store_queue;
+    let handoff = config.transfer_on_drain;
+
+    //
+    // - if configured, start the dedicated apply thread sharing the payload and the apply
+    //   closure with the automaton, plus its lanes if entries come with a conflict key
+    //
+    let stage = Arc::new(Mutex::new(Stage::new(apply)));
+    let applier = config.apply_queue.map(|n| {
+        let lanes = parallel.map(|parallel| Lanes {
+            n: config.apply_lanes,
+            key: parallel.key,
+            run: (parallel.bind)(payload.clone()),
+        });
+        Applier::spawn(
+            guard,
+            n,
+            payload.clone(),
+            stage.clone(),
+            pending.clone(),
+            lanes,
+            logger.clone(),
+        )
+    });
+    let flush_on_drain = applier.is_some();
+    let max_bytes = config.max_message_bytes;
+
+    //
+    // - frame every outgoing buffer with its length and checksum
+    // - if a cluster secret is set sign it first
+    //
+    let write = move |host: &[u8; 32], bytes: &[u8]| write(host, &frame::frame(bytes));
+    #[cfg(feature = "auth")]
+    let secret = config.secret.clone();
+    #[cfg(feature = "auth")]
+    let write = {
+        let secret = secret.clone();
+        move |host: &[u8; 32], bytes: &[u8]| match secret {
+            Some(ref secret) => write(host, &auth::sign(secret, bytes)),
+            None => write(host, bytes),
+        }
+    };
+    let now = clock.now();
     let fsm = Automaton::spawn(
         guard.clone(),
         Box::new(FSM {
@@ -135,23 +520,81 @@ where
             host,
             seq: 0,
             term: 0,
+            vote: None,
             tail: 1,
             head: 1,
             age: 0,
             commit: 1,
+            contact: now,
+            confirming: VecDeque::new(),
+            idle_since: None,
+            reported: None,
+            transfer: None,
+            watchers: Vec::new(),
+            ack_to: None,
             peers,
+            configured: 1,
+            changing: None,
+            removed: false,
+            reconfigured: false,
+            successor: None,
+            ballots: HashSet::new(),
             timer: shared.timer.clone(),
-            log: unsafe { MmapMut::map_mut(&file).unwrap() },
+            clock,
+            storage,
             sink: sink.clone(),
+            observer: None,
+            observed: 0,
+            elected: now,
             payload,
             snapshot: Vec::new(),
+            snapshot_off: 1,
+            installing: None,
+            snapshot_requests: Vec::new(),
+            recovering,
+            uncompacted: 0,
+            reserved: 0,
+            unsynced: false,
+            compaction_due: false,
+            checkpointing: None,
+            requests: Dedup::new(config.dedup_window),
+            proposed: HashMap::new(),
+            config,
             write,
-            apply,
-            logger,
+            stage,
+            applier,
+            quorum: Box::new(Majority),
+            interceptor: None,
+            replaying: false,
+            vote_hook: None,
+            quarantined: false,
+            pending: pending.clone(),
+            gate: gate.clone(),
+            role: role.clone(),
+            waiters: VecDeque::new(),
+            readers: Vec::new(),
+            appended: Throughput::new(now),
+            committed: Throughput::new(now),
+            messages: Default::default(),
+            logger: logger.clone(),
         }),
     );
 
-    (Arc::new(Raft { fsm }), lock, sink)
+    Ok((Arc::new(Raft {
+        fsm,
+        pending,
+        gate,
+        capacity,
+        dropped: Arc::new(AtomicUsize::new(0)),
+        role,
+        handoff,
+        flush_on_drain,
+        max_bytes,
+        payload: lock.clone(),
+        logger,
+        #[cfg(feature = "auth")]
+        secret,
+    }), lock, sink))
 }
 
 /// Same as spawn() except the I/O is set to stream from/to STDIN/STDOUT. Please note Only one
@@ -164,13 +607,15 @@ where
 /// The internal streaming thread is not be guarded and left to die with the process.
 pub fn spawn_piped<'a, S, T, U: BuildHasher>(
     guard: &Arc<Guard>,
-    id: u8,
-    peers: HashMap<u8, &'a str, U>,
+    id: u16,
+    peers: HashMap<u16, &'a str, U>,
+    config: RaftConfig,
+    snapshots: Box<dyn SnapshotStore>,
     apply: S,
     logger: Logger,
-) -> (Arc<Raft>, Arc<ROLock<T>>, Arc<Sink>)
+) -> Result<(Arc<Raft>, Arc<ROLock<T>>, Arc<Sink>), RsmError>
 where
-    S: 'static + Send + Fn(&mut T, &[u8]) -> (),
+    S: 'static + Send + Fn(&mut T, &[u8]) -> Result<Vec<u8>, ApplyError>,
     T: 'static + Send + Default + Payload,
 {
     let (raft, lock, sink) = {
@@ -178,6 +623,8 @@ where
             guard,
             id,
             peers,
+            config,
+            snapshots,
             move |host, bytes| {
 
                 //
@@ -209,34 +656,5547 @@ where
 
                 //
                 // - the wrapped line writer in stdout() will flush upon 0x0a (which is fine)
+                // - a failed write drops the buffer (e.g same as a lossy network)
                 //
                 assert!(n > 0);
-                let _ = pipe.write(&preamble[..n]).unwrap();
-                let _ = pipe.write(&host[..]).unwrap();
-                let _ = pipe.write(&bytes[..]).unwrap();
-                let _ = pipe.flush();
+                let _ = pipe
+                    .write_all(&preamble[..n])
+                    .and_then(|_| pipe.write_all(&host[..]))
+                    .and_then(|_| pipe.write_all(&bytes[..]))
+                    .and_then(|_| pipe.flush());
             },
             apply,
             logger,
-        )
+        )?
     };
 
     //
     // - paste a streaming thread on top of this automaton
     //
-    super::fsm::io::pipe::stream_from_sdtin(&raft.fsm, |bytes| {
+    let handle = (*raft).clone();
+    super::fsm::io::pipe::stream_from_sdtin(&raft.fsm, move |bytes| {
 
         //
         // - unpack the incoming byte stream
-        // - cast to a RAW message
+        // - cast to a RAW message (checking its MAC if needed)
         // - silently discard if invalid
         //
-        if let Ok(raw) = deserialize(&bytes[..]) {
-            Some(Command::BYTES(raw))
-        } else {
-            None
-        }
+        handle.unpack(&bytes[..]).ok().map(Command::BYTES)
     });
 
-    (raft, lock, sink)
+    Ok((raft, lock, sink))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use bincode::{deserialize, serialize};
+    use error::RsmError;
+    use fsm::automaton::*;
+    use fsm::timer::Timer;
+    use primitives::event::*;
+    use primitives::rwlock::*;
+    use rand::{Rng, thread_rng};
+    use serde::ser::{Error, Serialize, Serializer};
+    use raft::applier::{Applier, Job, Stage};
+    #[cfg(feature = "auth")]
+    use raft::auth;
+    use raft::clock::*;
+    use raft::frame;
+    use raft::gate::Gate;
+    use raft::config::{ConfigError, RaftConfig, RaftConfigDelta, MAX_PRIORITY};
+    use raft::dedup::Dedup;
+    use raft::kv::{self, KV, Op};
+    use raft::messages::*;
+    use raft::metrics::*;
+    use raft::protocol::*;
+    use raft::protocol::Command::*;
+    use raft::quorum::*;
+    use raft::sink::*;
+    use raft::slots::*;
+    use raft::snapshot::*;
+    use raft::storage::*;
+    use slog::{Discard, Logger};
+    use std::cmp;
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::env;
+    use std::fs::remove_file;
+    use std::io::{self, Read};
+    use std::ops::Range;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::mpsc::{channel, TryRecvError};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use super::RaftBuilder;
+
+    type Write = Box<dyn Fn(&[u8; 32], &[u8]) + Send>;
+    type Node<U> = FSM<Write, Apply<U>, U>;
+    type Apply<U> = fn(&mut U, &[u8]) -> Result<Vec<u8>, ApplyError>;
+
+    static FILES: AtomicUsize = AtomicUsize::new(0);
+    static HOLD: AtomicBool = AtomicBool::new(false);
+    static FLUSHING: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Default)]
+    struct Empty {}
+
+    impl Payload for Empty {}
+
+    /// Payload whose flush blocks while `HOLD` is set.
+    #[derive(Default)]
+    struct Held {}
+
+    impl Payload for Held {
+        fn flush(&self) -> Vec<u8> {
+            FLUSHING.fetch_add(1, Ordering::AcqRel);
+            while HOLD.load(Ordering::Acquire) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            Vec::new()
+        }
+    }
+
+    #[derive(Default)]
+    struct Counter {
+        n: u64,
+    }
+
+    impl Payload for Counter {
+        fn checksum(&self) -> Option<u64> {
+            Some(self.n)
+        }
+    }
+
+    struct Nowhere;
+
+    impl SnapshotStore for Nowhere {
+        fn save(&mut self, _: &SnapshotMeta, _: &mut dyn Read) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn load(&mut self) -> io::Result<Option<(SnapshotMeta, Box<dyn Read>)>> {
+            Ok(None)
+        }
+    }
+
+    struct Nop;
+
+    impl Recv<Command, State> for Nop {
+        fn recv(
+            &mut self,
+            _: &Arc<Automaton<Command>>,
+            state: State,
+            _: Opcode<Command, State>,
+        ) -> State {
+            state
+        }
+    }
+
+    fn host(id: u16) -> [u8; 32] {
+        let mut buf = [0; 32];
+        let tag = format!("#{}", id);
+        let n = cmp::min(tag.len(), 32);
+        buf[..n].copy_from_slice(&tag.as_bytes()[..n]);
+        buf
+    }
+
+    fn entries(from: u64, terms: &[u64]) -> Vec<u8> {
+        let slots: Vec<_> = terms.iter().map(|term| (*term, Vec::new())).collect();
+        records(from, &slots)
+    }
+
+    fn records(from: u64, slots: &[(u64, Vec<u8>)]) -> Vec<u8> {
+
+        //
+        // - lay out one slot per (term, bytes) pair starting at the specified offset, each
+        //   padded to the slot width
+        //
+        let width = <Node<Empty>>::SLOT_BYTES;
+        let mut buf = vec![0; slots.len() * width];
+        for (n, &(term, ref bytes)) in slots.iter().enumerate() {
+            let slot = SLOT {
+                code: 255,
+                term,
+                off: from + n as u64,
+                bytes: bytes.clone(),
+                crc: Some(crc32(bytes)),
+                request: None,
+            };
+            let bytes = serialize(&slot).unwrap();
+            buf[n * width..n * width + bytes.len()].copy_from_slice(&bytes);
+        }
+        buf
+    }
+
+    /// Raft automaton driven by hand: commands are passed directly to `recv()` and whatever
+    /// is written to the network is captured. The timer is live but its timeouts are routed to
+    /// a no-op automaton, e.g they must be injected explicitly.
+    #[allow(dead_code)]
+    struct Harness<U>
+    where
+        U: 'static + Send + Default + Payload,
+    {
+        fsm: Node<U>,
+        this: Arc<Automaton<Command>>,
+        state: State,
+        sink: Arc<Sink>,
+        out: Arc<Mutex<Vec<([u8; 32], Vec<u8>)>>>,
+    }
+
+    impl<U> Harness<U>
+    where
+        U: 'static + Send + Default + Payload,
+    {
+        fn new(id: u16, size: u16, config: RaftConfig, apply: Apply<U>) -> Self {
+
+            let event = Event::new();
+            let guard = event.guard();
+            let out = Arc::new(Mutex::new(Vec::new()));
+            let write: Write = {
+                let out = out.clone();
+                Box::new(move |host, bytes| {
+                    out.lock().unwrap().push((*host, bytes.to_vec()));
+                })
+            };
+
+            let sink = Arc::new(Sink::new());
+            let peers = (0..size)
+                .filter(|n| *n != id)
+                .map(|n| {
+                    (
+                        n,
+                        Peer {
+                            host: host(n),
+                            off: 1,
+                            ack: 1,
+                            rebasing: None,
+                            learner: false,
+                            diverged: false,
+                            acked: None,
+                            inflight: VecDeque::new(),
+                            resend: None,
+                        },
+                    )
+                })
+                .collect();
+
+            Harness {
+                fsm: FSM {
+                    id,
+                    host: host(id),
+                    seq: 0,
+                    term: 0,
+                    vote: None,
+                    tail: 1,
+                    head: 1,
+                    age: 0,
+                    commit: 1,
+                    contact: Instant::now(),
+                    confirming: VecDeque::new(),
+                    idle_since: None,
+                    reported: None,
+                    transfer: None,
+                    watchers: Vec::new(),
+                    ack_to: None,
+                    peers,
+                    configured: 1,
+                    changing: None,
+                    removed: false,
+                    reconfigured: false,
+                    successor: None,
+                    ballots: HashSet::new(),
+                    timer: Arc::new(Timer::spawn(guard.clone())),
+                    clock: Arc::new(SystemClock),
+                    storage: Box::new(MemoryStorage::new()),
+                    sink: sink.clone(),
+                    observer: None,
+                    observed: 0,
+                    elected: Instant::now(),
+                    payload: Arc::new(RWLock::from(Default::default())),
+                    snapshot: Vec::new(),
+                    snapshot_off: 1,
+                    installing: None,
+                    snapshot_requests: Vec::new(),
+                    recovering: false,
+                    uncompacted: 0,
+                    reserved: 0,
+                    unsynced: false,
+                    compaction_due: false,
+                    checkpointing: None,
+                    requests: Dedup::new(config.dedup_window),
+                    proposed: HashMap::new(),
+                    gate: Arc::new(Gate::new(config.store_high_water)),
+                    config,
+                    write,
+                    stage: Arc::new(Mutex::new(Stage::new(apply))),
+                    applier: None,
+                    quorum: Box::new(Majority),
+                    interceptor: None,
+                    replaying: false,
+                    vote_hook: None,
+                    quarantined: false,
+                    pending: Arc::new(AtomicUsize::new(0)),
+                    role: Arc::new(AtomicUsize::new(0)),
+                    waiters: VecDeque::new(),
+                    readers: Vec::new(),
+                    appended: Throughput::new(Instant::now()),
+                    committed: Throughput::new(Instant::now()),
+                    messages: Default::default(),
+                    logger: Logger::root(Discard, o!()),
+                },
+                this: Automaton::spawn(guard, Box::new(Nop)),
+                state: State::default(),
+                sink,
+                out,
+            }
+        }
+
+        fn start(&mut self) -> () {
+            let _ = self.fsm.recv(&self.this, self.state, Opcode::START);
+        }
+
+        fn post(&mut self, cmd: Command) -> () {
+
+            //
+            // - same sequence as the automaton event loop
+            //
+            let next = self.fsm.recv(&self.this, self.state, Opcode::CMD(cmd));
+            if next != self.state {
+                self.fsm.recv(&self.this, next, Opcode::TRANSITION(self.state));
+            }
+            self.state = next;
+        }
+
+        fn deliver(&mut self, bytes: &[u8]) -> () {
+            let raw: RAW = deserialize(bytes).unwrap();
+            self.post(BYTES(raw));
+        }
+
+        fn restart(self) -> Self {
+
+            //
+            // - simulate a crash: build a new automaton using the same storage, then have it
+            //   recover
+            //
+            let size = (self.fsm.peers.len() + 1) as u16;
+            let apply = self.fsm.stage.lock().unwrap().apply;
+            let mut node = Harness::new(self.fsm.id, size, self.fsm.config.clone(), apply);
+            node.fsm.storage = self.fsm.storage;
+            node.fsm.recovering = true;
+            node.start();
+            node
+        }
+
+        fn tamper<F>(&mut self, off: u64, f: F) -> ()
+        where
+            F: Fn(&mut [u8]) -> (),
+        {
+
+            //
+            // - alter the slot stored at that offset in place, e.g to simulate disk corruption
+            //
+            let mut buf = Vec::new();
+            self.fsm.storage.read_entries(off..off + 1, &mut buf).unwrap();
+            f(&mut buf);
+            self.fsm.storage.append_entries(off, &buf).unwrap();
+        }
+
+        fn notifications(&self) -> Vec<Notification> {
+            let mut all = Vec::new();
+            while let Ok(n) = self.sink.fifo.pop() {
+                all.push(n);
+            }
+            all
+        }
+    }
+
+    type Registry = Arc<Mutex<HashMap<[u8; 32], Arc<Raft>>>>;
+
+    /// Live peers wired in-process, see `cluster()`. They are all drained upon dropping.
+    struct Cluster<U>
+    where
+        U: 'static + Send + Default + Payload,
+    {
+        rafts: Vec<Arc<Raft>>,
+        payloads: Vec<Arc<ROLock<U>>>,
+        registry: Registry,
+    }
+
+    impl<U> Cluster<U>
+    where
+        U: 'static + Send + Default + Payload,
+    {
+        fn leader(&self) -> Option<Arc<Raft>> {
+            self.rafts.iter().find(|raft| raft.role() == Role::Leader).cloned()
+        }
+
+        fn count(&self, role: Role) -> usize {
+            self.rafts.iter().filter(|raft| raft.role() == role).count()
+        }
+
+        /// Makes sure each automaton processed whatever was posted to it so far: a status round
+        /// trip to each of them, a few rounds letting the messages they exchange play out.
+        fn settle(&self) -> () {
+            for _ in 0..4 {
+                for raft in &self.rafts {
+                    let _ = raft.status();
+                }
+            }
+        }
+    }
+
+    impl<U> Drop for Cluster<U>
+    where
+        U: 'static + Send + Default + Payload,
+    {
+        fn drop(&mut self) -> () {
+            for raft in &self.rafts {
+                raft.drain();
+            }
+            self.registry.lock().unwrap().clear();
+        }
+    }
+
+    /// Spawns `size` peers (#0, #1, ...) over in-memory storage and routes whatever they send
+    /// to each other. Each builder is completed by `setup` (e.g to set a clock or the apply
+    /// closure) and each buffer goes through `route`, which drops it by returning false.
+    fn cluster<U, F, G>(
+        guard: &Arc<Guard>,
+        size: u16,
+        config: RaftConfig,
+        setup: F,
+        route: G,
+    ) -> Cluster<U>
+    where
+        U: 'static + Send + Default + Payload,
+        F: Fn(u16, RaftBuilder<U>) -> RaftBuilder<U>,
+        G: 'static + Send + Sync + Fn(&[u8; 32], &[u8]) -> bool,
+    {
+        let registry: Registry = Arc::new(Mutex::new(HashMap::new()));
+        let route = Arc::new(route);
+        let tags: Vec<_> = (0..size).map(|id| format!("#{}", id)).collect();
+        let peers: HashMap<_, _> = tags.iter()
+            .enumerate()
+            .map(|(n, tag)| (n as u16, tag.as_str()))
+            .collect();
+        let mut rafts = Vec::new();
+        let mut payloads = Vec::new();
+        for id in 0..size {
+            let routes = registry.clone();
+            let route = route.clone();
+            let builder = Raft::builder::<U>()
+                .id(id)
+                .peers(peers.clone())
+                .config(config.clone())
+                .storage(Box::new(MemoryStorage::new()))
+                .on_send(move |dst: &[u8; 32], bytes: &[u8]| {
+                    if !route(dst, bytes) {
+                        return;
+                    }
+                    if let Some(raft) = routes.lock().unwrap().get(dst) {
+                        let _ = raft.feed(bytes);
+                    }
+                });
+            let (raft, payload, _) = setup(id, builder).spawn(guard).unwrap();
+            registry.lock().unwrap().insert(host(id), raft.clone());
+            rafts.push(raft);
+            payloads.push(payload);
+        }
+        Cluster {
+            rafts,
+            payloads,
+            registry,
+        }
+    }
+
+    #[test]
+    fn term_lookup() {
+
+        //
+        // - replicate #2 to #16 at term 1 and #17 to #21 at term 2 from peer #0
+        // - commit up to #21, which checkpoints at #15
+        //
+        let mut node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        let mut terms = vec![1; 15];
+        terms.extend(vec![2; 5]);
+        let msg = REPLICATE {
+            id: 0,
+            term: 2,
+            off: 1,
+            age: 0,
+            commit: 1,
+            append: entries(2, &terms),
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        let msg = PING {
+            id: 0,
+            term: 2,
+            commit: 21,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(node.fsm.head, 21);
+        assert_eq!(node.fsm.tail, 15);
+
+        //
+        // - below the tail: term of the snapshot
+        // - within the log: term of the entry
+        // - beyond the head (or #0): none
+        //
+        assert_eq!(node.fsm.term_at(3), Some(1));
+        assert_eq!(node.fsm.term_at(16), Some(1));
+        assert_eq!(node.fsm.term_at(17), Some(2));
+        assert_eq!(node.fsm.term_at(21), Some(2));
+        assert_eq!(node.fsm.term_at(22), None);
+        assert_eq!(node.fsm.term_at(0), None);
+    }
+
+    #[test]
+    fn message_stats() {
+
+        //
+        // - peer #0 replicates #2 to #4 then asserts its commit offset
+        // - each message we get is counted once, each one we send as well
+        //
+        let mut node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        node.out.lock().unwrap().clear();
+        let msg = REPLICATE {
+            id: 0,
+            term: 1,
+            off: 1,
+            age: 0,
+            commit: 1,
+            append: entries(2, &[1, 1, 1]),
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 4,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        let stats = node.fsm.messages;
+        assert_eq!(stats.received("REPLICATE"), 1);
+        assert_eq!(stats.received("PING"), 1);
+        assert_eq!(stats.received.iter().sum::<u64>(), 2);
+        let out = node.out.lock().unwrap().clone();
+        let acks = out
+            .iter()
+            .filter(|&&(_, ref bytes)| {
+                let raw: RAW = deserialize(bytes).unwrap();
+                raw.code == ACK::CODE
+            })
+            .count();
+        assert!(acks > 0);
+        assert_eq!(stats.sent("ACK"), acks as u64);
+        assert_eq!(stats.sent.iter().sum::<u64>(), out.len() as u64);
+        assert_eq!(stats.sent("BOGUS"), 0);
+    }
+
+    #[test]
+    fn divergence_quarantine() {
+
+        //
+        // - replicate and commit #2 to #4 from peer #0
+        // - the leader reports the same digest: all is good
+        //
+        let mut node = Harness::<Counter>::new(1, 3, RaftConfig::default(), |p, _| {
+            p.n += 1;
+            Ok(vec![])
+        });
+        node.start();
+        let msg = REPLICATE {
+            id: 0,
+            term: 1,
+            off: 1,
+            age: 0,
+            commit: 1,
+            append: entries(2, &[1, 1, 1]),
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        let digest = {
+            let msg = PING {
+                id: 0,
+                term: 1,
+                commit: 4,
+                digest: None,
+            };
+            node.deliver(&msg.to_raw(&host(0), &host(1)));
+            node.fsm.payload.read().checksum().unwrap()
+        };
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 4,
+            digest: Some(digest),
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert!(!node.fsm.quarantined);
+
+        //
+        // - inject a divergence
+        // - the follower must quarantine itself and notify SAFETY_VIOLATION
+        //
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 4,
+            digest: Some(digest + 1),
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert!(node.fsm.quarantined);
+        assert!(node.notifications().iter().any(|n| match *n {
+            Notification::SAFETY_VIOLATION(4) => true,
+            _ => false,
+        }));
+
+        //
+        // - liveness timeouts must not trigger an election while quarantined
+        //
+        for _ in 0..3 {
+            let seq = node.fsm.seq;
+            node.post(TIMEOUT(seq));
+        }
+        assert!(node.state == State::default());
+
+        //
+        // - clear the quarantine: the next liveness timeout starts a new election
+        //
+        node.post(CLEAR);
+        assert!(!node.fsm.quarantined);
+        let seq = node.fsm.seq;
+        node.post(TIMEOUT(seq));
+        match node.state {
+            State::PREV(_) => {}
+            _ => panic!("expected PREVOTE"),
+        }
+    }
+
+    #[test]
+    fn leader_divergence() {
+
+        //
+        // - force peer #0 to lead at term 1, store #2 and #3 and have peer #1 acknowledge them
+        //
+        let mut node = Harness::<Counter>::new(0, 3, RaftConfig::default(), |p, _| {
+            p.n += 1;
+            Ok(vec![])
+        });
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        node.post(STORE_ASYNC(vec![1], channel().0, None));
+        node.post(STORE_ASYNC(vec![2], channel().0, None));
+        let ack = |id: u16, digest: Option<(u64, u64)>| ACK {
+            id,
+            term: 1,
+            ack: 3,
+            learner: false,
+            digest,
+        };
+        node.deliver(&ack(1, None).to_raw(&host(1), &host(0)));
+        let commit = node.fsm.commit;
+        let digest = node.fsm.payload.read().checksum().unwrap();
+
+        //
+        // - peer #1 reports the same digest and then a different one: a single peer disagreeing
+        //   is not enough for us to step down
+        // - a digest taken at another commit offset is not compared
+        //
+        node.deliver(&ack(1, Some((commit, digest))).to_raw(&host(1), &host(0)));
+        node.deliver(&ack(1, Some((commit, digest + 1))).to_raw(&host(1), &host(0)));
+        node.deliver(&ack(2, Some((commit - 1, digest + 1))).to_raw(&host(2), &host(0)));
+        assert!(!node.fsm.quarantined);
+        match node.state {
+            State::LEAD(_) => {}
+            _ => panic!("expected LEADER"),
+        }
+
+        //
+        // - peer #2 disagrees as well: we are the ones who diverged, quarantine ourselves,
+        //   notify SAFETY_VIOLATION and step down
+        // - stores are rejected
+        //
+        node.deliver(&ack(2, Some((commit, digest + 1))).to_raw(&host(2), &host(0)));
+        assert!(node.fsm.quarantined);
+        match node.state {
+            State::FLWR(ctx) => assert_eq!(ctx.leader, None),
+            _ => panic!("expected FOLLOWER"),
+        }
+        assert!(node.notifications().iter().any(|n| match *n {
+            Notification::SAFETY_VIOLATION(off) => off == commit,
+            _ => false,
+        }));
+        let (tx, rx) = channel();
+        node.post(STORE_ASYNC(vec![3], tx, None));
+        assert_eq!(rx.recv().unwrap(), StoreResult::Rejected);
+    }
+
+    #[test]
+    fn async_store() {
+
+        //
+        // - force peer #0 to lead at term 1
+        // - store #2 and #3 asynchronously
+        //
+        let mut node = Harness::<Empty>::new(0, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        let (tx, a) = channel();
+        node.post(STORE_ASYNC(vec![1], tx, None));
+        let (tx, b) = channel();
+        node.post(STORE_ASYNC(vec![2], tx, None));
+        assert_eq!(node.fsm.head, 3);
+        assert!(a.try_recv().is_err());
+
+        //
+        // - peer #1 acknowledges #3, which commits #2
+        //
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: 3,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        assert_eq!(a.try_recv().unwrap(), StoreResult::Committed(2, vec![]));
+        assert!(b.try_recv().is_err());
+
+        //
+        // - peer #2 takes over: #3 is lost
+        // - we can't append anymore
+        //
+        let msg = PING {
+            id: 2,
+            term: 2,
+            commit: 2,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(2), &host(0)));
+        assert_eq!(b.try_recv().unwrap(), StoreResult::Lost(3));
+        let (tx, c) = channel();
+        node.post(STORE_ASYNC(vec![3], tx, None));
+        assert_eq!(c.try_recv().unwrap(), StoreResult::Rejected);
+    }
+
+    #[test]
+    fn raw_length() {
+
+        //
+        // - the computed length must match the actual encoding, whatever the message
+        //
+        let messages = vec![
+            PING {
+                id: 0,
+                term: 3,
+                commit: 7,
+                digest: Some(11),
+            }.to_raw(&host(0), &host(1)),
+            ACK {
+                id: 1,
+                term: 3,
+                ack: 7,
+                learner: false,
+                digest: None,
+            }.to_raw(&host(1), &host(0)),
+            REPLICATE {
+                id: 0,
+                term: 3,
+                off: 1,
+                age: 0,
+                commit: 1,
+                append: entries(2, &[1, 2, 3]),
+            }.to_raw(&host(0), &host(2)),
+            INSTALL_SNAPSHOT {
+                id: 0,
+                term: 3,
+                last_index: 17,
+                last_term: 2,
+                offset: 0,
+                size: 17,
+                chunk: vec![0; 17],
+                requests: vec![(5, 9)],
+                peers: vec![(0, host(0)), (2, host(2))],
+            }.to_raw(&host(0), &host(2)),
+        ];
+        for bytes in messages {
+            let raw: RAW = deserialize(&bytes).unwrap();
+            assert_eq!(raw.encoded_len(), bytes.len());
+        }
+    }
+
+    #[test]
+    fn wire_length() {
+
+        //
+        // - spawn 3 peers wired in-process, with and without a cluster secret, and store a few
+        //   entries so that all the common messages get sent (each is chased with a no-op so
+        //   that it commits, though only what gets sent matters here)
+        // - the wire length of each envelope must match what the write closure got
+        //
+        let event = Event::new();
+        let guard = event.guard();
+        let config = RaftConfig {
+            liveness_timeout: Duration::from_millis(500),
+            election_timeout: Duration::from_millis(200),
+            heartbeat_interval: Duration::from_millis(50),
+            transfer_timeout: Duration::from_millis(300),
+            ..RaftConfig::default()
+        };
+        #[allow(unused_mut)]
+        let mut configs = vec![config.clone()];
+        #[cfg(feature = "auth")]
+        configs.push(RaftConfig {
+            secret: Some(b"secret".to_vec()),
+            ..config
+        });
+        for config in configs {
+            let sent = Arc::new(Mutex::new(Vec::new()));
+            let cluster = {
+                let sent = sent.clone();
+                cluster::<Empty, _, _>(
+                    &guard,
+                    3,
+                    config,
+                    |_, builder| builder,
+                    move |_, bytes| {
+                        sent.lock().unwrap().push(bytes.to_vec());
+                        true
+                    },
+                )
+            };
+            let leader = cluster.rafts[0].await_leader(Duration::from_secs(10)).unwrap();
+            let leader = cluster.rafts[leader as usize].clone();
+            for n in 0..3 {
+                let handle = leader.store(vec![n; 17]).unwrap();
+                let _ = leader.noop();
+                let _ = handle.wait();
+            }
+            let raft = &cluster.rafts[0];
+            let sent = sent.lock().unwrap();
+            assert!(sent.len() > 0);
+            for bytes in sent.iter() {
+                let raw = raft.unpack(bytes).unwrap();
+                assert_eq!(raft.wire_len(&raw), bytes.len());
+            }
+        }
+    }
+
+    #[test]
+    fn startup_jitter() {
+
+        //
+        // - no jitter: the first timeout is the liveness timeout
+        // - otherwise it is spread over [liveness, liveness + jitter]
+        //
+        let node = Harness::<Empty>::new(0, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        let base = node.fsm.startup_delay();
+        assert!(base == node.fsm.startup_delay());
+        let config = RaftConfig {
+            startup_jitter: Duration::from_millis(500),
+            ..RaftConfig::default()
+        };
+        let node = Harness::<Empty>::new(0, 3, config, |_, _| Ok(vec![]));
+        let delays: Vec<_> = (0..64).map(|_| node.fsm.startup_delay()).collect();
+        assert!(delays.iter().all(|d| *d >= base && *d <= base + Duration::from_millis(500)));
+        assert!(delays.iter().any(|d| *d != delays[0]));
+    }
+
+    #[test]
+    fn staggered_boot() {
+
+        //
+        // - cold start 3 peers at once over a manual clock and step it 1ms at a time until one
+        //   of them leads, recording when each one first asked for pre-votes
+        // - a FOLLOWER only votes once it timed out itself: at least 2 of them campaign
+        // - without jitter their first liveness timeouts fire together
+        // - with some jitter they are spread past the liveness timeout (2 of them timing out
+        //   within the same millisecond is unlikely enough)
+        //
+        let campaigns = |jitter: u64| -> Vec<u64> {
+            let event = Event::new();
+            let guard = event.guard();
+            let clock = Arc::new(ManualClock::new());
+            let config = RaftConfig {
+                liveness_timeout: Duration::from_millis(300),
+                election_timeout: Duration::from_millis(200),
+                heartbeat_interval: Duration::from_millis(100),
+                startup_jitter: Duration::from_millis(jitter),
+                transfer_timeout: Duration::from_millis(200),
+                ..RaftConfig::default()
+            };
+            let cluster = cluster::<Empty, _, _>(
+                &guard,
+                3,
+                config,
+                |_, builder| builder.clock(clock.clone()),
+                |_, _| true,
+            );
+            let step = Arc::new(AtomicUsize::new(0));
+            let probed = Arc::new(Mutex::new(HashMap::new()));
+            for (id, raft) in cluster.rafts.iter().enumerate() {
+                let step = step.clone();
+                let probed = probed.clone();
+                raft.intercept(move |direction, raw| {
+                    if let (Direction::Outbound, "PROBE") = (direction, raw.kind()) {
+                        let at = step.load(Ordering::Acquire) as u64;
+                        probed.lock().unwrap().entry(id).or_insert(at);
+                    }
+                    Action::Pass
+                });
+            }
+            cluster.settle();
+            while cluster.leader().is_none() {
+                let n = step.fetch_add(1, Ordering::AcqRel) + 1;
+                assert!(n < 20000, "no LEADER elected");
+                clock.advance(Duration::from_millis(1));
+                cluster.settle();
+            }
+            let mut first: Vec<_> = probed.lock().unwrap().values().cloned().collect();
+            first.sort();
+            first
+        };
+        let first = campaigns(0);
+        assert_eq!(first.len(), 3);
+        assert!(first[0] >= 300 && first.iter().all(|at| *at == first[0]));
+        let first = campaigns(3000);
+        assert!(first.len() >= 2);
+        assert!(first.iter().all(|at| *at >= 300 && *at <= 3301));
+        assert!(first.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn election_priority() {
+
+        //
+        // - at the maximum priority the election delay is within the jitter
+        // - a lower priority pushes it back by a fraction of the liveness timeout, a whole one
+        //   at 0
+        //
+        let jitter = Duration::from_millis(25)..Duration::from_millis(150);
+        let delays = |priority: u32| -> Vec<Duration> {
+            let config = RaftConfig {
+                liveness_timeout: Duration::from_millis(1000),
+                election_jitter: jitter.clone(),
+                priority,
+                ..RaftConfig::default()
+            };
+            let node = Harness::<Empty>::new(0, 3, config, |_, _| Ok(vec![]));
+            (0..64).map(|_| node.fsm.election_delay()).collect()
+        };
+        let within = |delays: &[Duration], extra: u64| {
+            let extra = Duration::from_millis(extra);
+            delays.iter().all(|d| *d >= jitter.start + extra && *d < jitter.end + extra)
+        };
+        assert!(within(&delays(MAX_PRIORITY), 0));
+        assert!(within(&delays(MAX_PRIORITY / 2), 500));
+        assert!(within(&delays(0), 1000));
+
+        //
+        // - priorities above the maximum are rejected
+        //
+        let config = RaftConfig {
+            priority: MAX_PRIORITY + 1,
+            ..RaftConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn forced_vote() {
+
+        //
+        // - replicate and commit #2 to #4 from peer #0
+        //
+        let mut node = Harness::<Counter>::new(1, 3, RaftConfig::default(), |p, _| {
+            p.n += 1;
+            Ok(vec![])
+        });
+        node.start();
+        let msg = REPLICATE {
+            id: 0,
+            term: 1,
+            off: 1,
+            age: 0,
+            commit: 1,
+            append: entries(2, &[1, 1, 1]),
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 4,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        let applied = node.fsm.payload.read().n;
+
+        //
+        // - peer #2 runs with an empty log: the standard rule denies it
+        // - force the vote anyway
+        //
+        node.post(VOTE_HOOK(Box::new(|vote: &VoteContext| {
+            assert!(!vote.granted);
+            VoteDecision::Grant
+        })));
+        node.state = State::CNDT(Default::default());
+        node.out.lock().unwrap().clear();
+        let msg = ADVERTISE {
+            id: 2,
+            term: 2,
+            head: 1,
+            age: 0,
+            transfer: false,
+        };
+        node.deliver(&msg.to_raw(&host(2), &host(1)));
+        let out = node.out.lock().unwrap().clone();
+        assert!(out.iter().any(|&(ref dst, ref bytes)| {
+            let raw: RAW = deserialize(bytes).unwrap();
+            *dst == host(2) && raw.code == VOTE::CODE
+        }));
+
+        //
+        // - the rogue leader asserts itself with a lower commit offset
+        // - whatever was committed and applied must not regress
+        //
+        let msg = PING {
+            id: 2,
+            term: 2,
+            commit: 1,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(2), &host(1)));
+        assert_eq!(node.fsm.commit, 4);
+        assert_eq!(node.fsm.payload.read().n, applied);
+    }
+
+    #[test]
+    fn kv_replicas() {
+
+        //
+        // - replicate the same sequence of mutations to peers #1 and #2, followed by a blank
+        //   entry (the commit offset being exclusive)
+        // - commit everything
+        //
+        let ops = vec![
+            Op::SET(b"a".to_vec(), b"1".to_vec()),
+            Op::SET(b"b".to_vec(), b"2".to_vec()),
+            Op::CAS(b"a".to_vec(), Some(b"1".to_vec()), b"3".to_vec()),
+            Op::CAS(b"b".to_vec(), Some(b"1".to_vec()), b"4".to_vec()),
+            Op::DELETE(b"c".to_vec()),
+            Op::SET(b"c".to_vec(), b"5".to_vec()),
+            Op::DELETE(b"b".to_vec()),
+        ];
+        let mut slots: Vec<_> = ops.iter().map(|op| (1, op.to_bytes())).collect();
+        slots.push((1, Vec::new()));
+        let mut replicas = Vec::new();
+        for id in 1..3 {
+            let mut node = Harness::<KV>::new(id, 3, RaftConfig::default(), kv::apply);
+            node.start();
+            let msg = REPLICATE {
+                id: 0,
+                term: 1,
+                off: 1,
+                age: 0,
+                commit: 1,
+                append: records(2, &slots),
+            };
+            node.deliver(&msg.to_raw(&host(0), &host(id)));
+            let msg = PING {
+                id: 0,
+                term: 1,
+                commit: ops.len() as u64 + 2,
+                digest: None,
+            };
+            node.deliver(&msg.to_raw(&host(0), &host(id)));
+            assert_eq!(node.fsm.commit, ops.len() as u64 + 2);
+            let kv = (*node.fsm.payload.read()).clone();
+            replicas.push(kv);
+        }
+
+        //
+        // - both replicas must end up with the same state
+        //
+        assert_eq!(replicas[0], replicas[1]);
+        assert_eq!(replicas[0].get(b"a"), Some(&b"3"[..]));
+        assert_eq!(replicas[0].get(b"b"), None);
+        assert_eq!(replicas[0].get(b"c"), Some(&b"5"[..]));
+    }
+
+    #[test]
+    fn noop_entries() {
+
+        //
+        // - force peer #0 to lead at term 1
+        // - append 16 empty entries (#2 to #17)
+        //
+        let mut node = Harness::<Counter>::new(0, 3, RaftConfig::default(), |p, bytes| {
+            assert!(bytes.is_empty());
+            p.n += 1;
+            Ok(vec![])
+        });
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        for n in 2..18 {
+            let (tx, rx) = channel();
+            node.post(NOOP(tx));
+            assert_eq!(rx.recv().unwrap(), Some(n));
+        }
+
+        //
+        // - peer #1 acknowledges everything
+        // - the empty entries must be applied and checkpointed like any other
+        //
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: 17,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        assert_eq!(node.fsm.commit, 17);
+        assert_eq!(node.fsm.tail, 15);
+        assert_eq!(node.fsm.payload.read().n, 16);
+
+        //
+        // - a FOLLOWER can't append
+        //
+        node.state = State::FLWR(Default::default());
+        let (tx, rx) = channel();
+        node.post(NOOP(tx));
+        assert_eq!(rx.recv().unwrap(), None);
+    }
+
+    #[test]
+    fn apply_lag() {
+
+        //
+        // - each entry takes 20 ms to apply on a manual clock (a pre-apply hook advances it)
+        //   with a 50 ms alarm
+        // - replicate and commit #2 to #6 from peer #0
+        //
+        let config = RaftConfig {
+            apply_lag_alarm: Some(Duration::from_millis(50)),
+            ..RaftConfig::default()
+        };
+        let clock = Arc::new(ManualClock::new());
+        let mut node = Harness::<Empty>::new(1, 3, config, |_, _| Ok(vec![]));
+        node.fsm.clock = clock.clone();
+        let lapse = clock.clone();
+        node.post(PRE_APPLY(Box::new(move |_, _| lapse.advance(Duration::from_millis(20)))));
+        node.start();
+        let msg = REPLICATE {
+            id: 0,
+            term: 1,
+            off: 1,
+            age: 0,
+            commit: 1,
+            append: entries(2, &[1; 5]),
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 6,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(node.fsm.commit, 6);
+
+        //
+        // - the alarm must have tripped exactly once, past 60 ms with 2 entries left to apply
+        //
+        let lags: Vec<_> = node
+            .notifications()
+            .into_iter()
+            .filter_map(|n| match n {
+                Notification::APPLY_LAG(gap) => Some(gap),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(lags, vec![2]);
+    }
+
+    #[test]
+    fn commit_clamp() {
+
+        //
+        // - replicate #2 to #4 from peer #0 which claims #9 is committed
+        // - we must only commit what we have
+        //
+        let mut node = Harness::<Counter>::new(1, 3, RaftConfig::default(), |p, _| {
+            p.n += 1;
+            Ok(vec![])
+        });
+        node.start();
+        let msg = REPLICATE {
+            id: 0,
+            term: 1,
+            off: 1,
+            age: 0,
+            commit: 9,
+            append: entries(2, &[1, 1, 1]),
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(node.fsm.head, 4);
+        assert_eq!(node.fsm.commit, 4);
+        assert_eq!(node.fsm.payload.read().n, 3);
+
+        //
+        // - same thing with a heartbeat
+        //
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 12,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(node.fsm.commit, 4);
+        assert_eq!(node.fsm.payload.read().n, 3);
+
+        //
+        // - #5 and #6 come in, the leader still being ahead
+        //
+        let msg = REPLICATE {
+            id: 0,
+            term: 1,
+            off: 4,
+            age: 1,
+            commit: 12,
+            append: entries(5, &[1, 1]),
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(node.fsm.head, 6);
+        assert_eq!(node.fsm.commit, 6);
+        assert_eq!(node.fsm.payload.read().n, 5);
+    }
+
+    #[cfg(feature = "auth")]
+    #[test]
+    fn forged_buffers() {
+
+        //
+        // - wrap our harness in a handle using a cluster secret
+        //
+        let node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        let raft = Raft {
+            fsm: node.this.clone(),
+            pending: Arc::new(AtomicUsize::new(0)),
+            gate: Arc::new(Gate::new(None)),
+            capacity: 1,
+            dropped: Arc::new(AtomicUsize::new(0)),
+            role: Arc::new(AtomicUsize::new(0)),
+            handoff: false,
+            flush_on_drain: false,
+            max_bytes: None,
+            payload: Arc::new(node.fsm.payload.read_only()),
+            logger: Logger::root(Discard, o!()),
+            secret: Some(b"secret".to_vec()),
+        };
+        let msg = VOTE { id: 0, term: 5 };
+        let bytes = auth::sign(b"secret", &msg.to_raw(&host(0), &host(1)));
+        assert!(raft.unpack(&frame::frame(&bytes)).is_ok());
+
+        //
+        // - flip one bit in the payload, in the MAC, sign with another secret or skip signing
+        //   (each buffer being framed afterwards, e.g it is not corrupted in transit)
+        // - all must be dropped
+        //
+        let denied = Some(FeedError::Unauthenticated);
+        let mut tampered = bytes.clone();
+        tampered[70] ^= 1;
+        assert_eq!(raft.unpack(&frame::frame(&tampered)).err(), denied);
+        let mut tampered = bytes.clone();
+        let n = tampered.len();
+        tampered[n - 1] ^= 1;
+        assert_eq!(raft.unpack(&frame::frame(&tampered)).err(), denied);
+        let forged = auth::sign(b"guess", &msg.to_raw(&host(0), &host(1)));
+        assert_eq!(raft.unpack(&frame::frame(&forged)).err(), denied);
+        let unsigned = frame::frame(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(raft.unpack(&unsigned).err(), denied);
+        assert_eq!(raft.dropped(), 4);
+    }
+
+    #[test]
+    fn byte_checkpoint() {
+
+        //
+        // - force peer #0 to lead at term 1 with a 2KB checkpoint threshold
+        // - append 3 entries of 900B (#2 to #4)
+        //
+        let config = RaftConfig {
+            snapshot_threshold_bytes: Some(2048),
+            ..RaftConfig::default()
+        };
+        let mut node = Harness::<Empty>::new(0, 3, config, |_, _| Ok(vec![]));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        for _ in 0..3 {
+            node.post(STORE_ASYNC(vec![7; 900], channel().0, None));
+        }
+
+        //
+        // - peer #1 acknowledges #4, which commits #2 and #3
+        // - no checkpoint since we applied 1800B
+        //
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: 4,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        assert_eq!(node.fsm.commit, 4);
+        assert_eq!(node.fsm.tail, 1);
+
+        //
+        // - append #5 and have #4 committed: we are past 2KB, way before the 15 commits
+        //   checkpoint
+        //
+        node.post(STORE_ASYNC(vec![7; 900], channel().0, None));
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: 5,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        assert_eq!(node.fsm.commit, 5);
+        assert_eq!(node.fsm.tail, 5);
+        assert_eq!(node.fsm.uncompacted, 0);
+        assert!(node.notifications().iter().any(|n| match *n {
+            Notification::CHECKPOINT(5) => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn entry_checkpoint() {
+
+        //
+        // - force peer #0 to lead at term 1 with a 3 entries checkpoint threshold
+        // - append #2 to #5, peer #1 acknowledges #3 which commits #2
+        //
+        let config = RaftConfig {
+            snapshot_threshold_entries: Some(3),
+            ..RaftConfig::default()
+        };
+        let mut node = Harness::<Empty>::new(0, 3, config, |_, _| Ok(vec![]));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        for n in 0..4 {
+            node.post(STORE_ASYNC(vec![n], channel().0, None));
+        }
+        let ack = |ack| ACK {
+            id: 1,
+            term: 1,
+            ack,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&ack(3).to_raw(&host(1), &host(0)));
+        assert_eq!(node.fsm.commit, 3);
+        assert_eq!(node.fsm.snapshot_off, 1);
+
+        //
+        // - #3 and #4 commit at once: we are past the threshold and checkpoint once, at the
+        //   commit offset and way before the 15 commits checkpoint
+        //
+        let _ = node.notifications();
+        node.deliver(&ack(5).to_raw(&host(1), &host(0)));
+        assert_eq!(node.fsm.commit, 5);
+        assert_eq!(node.fsm.snapshot_off, 5);
+        assert!(!node.fsm.compaction_due);
+        let checkpoints = node.notifications().iter().filter(|n| match **n {
+            Notification::CHECKPOINT(_) => true,
+            _ => false,
+        }).count();
+        assert_eq!(checkpoints, 1);
+    }
+
+    #[test]
+    fn background_checkpoint() {
+
+        //
+        // - spawn 3 peers wired in-process with a dedicated apply thread and a 2 entries
+        //   checkpoint threshold, over a payload whose flush blocks
+        // - store entries until some peer starts flushing its payload
+        //
+        let event = Event::new();
+        let guard = event.guard();
+        let config = RaftConfig {
+            liveness_timeout: Duration::from_millis(500),
+            election_timeout: Duration::from_millis(200),
+            heartbeat_interval: Duration::from_millis(50),
+            transfer_timeout: Duration::from_millis(300),
+            apply_queue: Some(16),
+            snapshot_threshold_entries: Some(2),
+            ..RaftConfig::default()
+        };
+        HOLD.store(true, Ordering::Release);
+        let cluster = cluster::<Held, _, _>(&guard, 3, config, |_, builder| builder, |_, _| true);
+        let leader = cluster.rafts[0].await_leader(Duration::from_secs(10)).unwrap();
+        let leader = cluster.rafts[leader as usize].clone();
+        let observer = leader.observe().unwrap();
+        let start = Instant::now();
+        while FLUSHING.load(Ordering::Acquire) == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10), "no checkpoint started");
+            let _ = leader.store(vec![1]);
+            let _ = leader.noop();
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        //
+        // - the automata keep on replicating and committing meanwhile (the store itself only
+        //   completes once applied, e.g after the flush)
+        // - release the flush: the store completes and the snapshot is adopted
+        //
+        let commit = leader.status().commit;
+        let handle = leader.store(vec![2]).unwrap();
+        let _ = leader.noop();
+        let start = Instant::now();
+        while leader.status().commit <= commit + 1 {
+            assert!(start.elapsed() < Duration::from_secs(10), "commit stalled");
+            thread::sleep(Duration::from_millis(10));
+        }
+        HOLD.store(false, Ordering::Release);
+        assert!(handle.wait().is_ok());
+        let start = Instant::now();
+        loop {
+            assert!(start.elapsed() < Duration::from_secs(10), "no snapshot taken");
+            match observer.fifo.pop() {
+                Ok(Observation::SnapshotTaken(_)) => break,
+                Ok(_) => {}
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+    }
+
+    #[test]
+    fn store_admission() {
+
+        //
+        // - a FOLLOWER rejects stores
+        //
+        let mut node = Harness::<Empty>::new(0, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        let admission = |node: &mut Harness<Empty>| {
+            let (tx, rx) = channel();
+            node.post(ADMISSION(tx));
+            rx.recv().unwrap()
+        };
+        assert!(!admission(&mut node).accepted());
+
+        //
+        // - force peer #0 to lead at term 1 and append #2 to #4
+        //
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        for _ in 0..3 {
+            node.post(STORE_ASYNC(vec![], channel().0, None));
+        }
+        let res = admission(&mut node);
+        assert!(res.accepted());
+        assert_eq!(res.backlog, 4);
+        assert_eq!(res.room, 124);
+
+        //
+        // - fill the log up
+        //
+        for _ in 0..124 {
+            node.post(STORE_ASYNC(vec![], channel().0, None));
+        }
+        let res = admission(&mut node);
+        assert!(!res.accepted());
+        assert_eq!(res.room, 0);
+    }
+
+    #[test]
+    fn chunked_snapshot() {
+
+        //
+        // - force peer #0 to lead at term 1, with small snapshot chunks
+        // - append and commit #2 to #17, which checkpoints at #15
+        //
+        let config = RaftConfig {
+            snapshot_chunk_bytes: 32,
+            ..RaftConfig::default()
+        };
+        let mut leader = Harness::<KV>::new(0, 3, config, kv::apply);
+        leader.start();
+        leader.fsm.term = 1;
+        leader.state = State::LEAD(Default::default());
+        for n in 0..16u8 {
+            leader.post(STORE_ASYNC(Op::SET(vec![n], vec![n]).to_bytes(), channel().0, None));
+        }
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: 17,
+            learner: false,
+            digest: None,
+        };
+        leader.deliver(&msg.to_raw(&host(1), &host(0)));
+        leader.fsm.peers.get_mut(&1).unwrap().off = 17;
+        assert_eq!(leader.fsm.tail, 15);
+
+        //
+        // - peer #2 is still at #1: heartbeat and grab what we send it
+        // - the snapshot is split into several chunks sent in order
+        //
+        let sent_to_2 = |leader: &mut Harness<KV>| {
+            leader.out.lock().unwrap().clear();
+            let seq = leader.fsm.seq;
+            leader.post(TIMEOUT(seq));
+            let out = leader.out.lock().unwrap().clone();
+            out.into_iter()
+                .filter(|&(dst, ref bytes)| {
+                    dst == host(2) && deserialize::<RAW>(bytes).unwrap().code != PING::CODE
+                })
+                .map(|(_, bytes)| bytes)
+                .collect::<Vec<_>>()
+        };
+        let chunks = sent_to_2(&mut leader);
+        assert!(chunks.len() > 2);
+        let mut offset = 0;
+        for bytes in &chunks {
+            let raw: RAW = deserialize(bytes).unwrap();
+            let msg: INSTALL_SNAPSHOT = deserialize(&raw.msg).unwrap();
+            assert_eq!((msg.last_index, msg.last_term), (16, 1));
+            assert_eq!(msg.offset, offset);
+            assert!(msg.chunk.len() <= 32);
+            offset += msg.chunk.len() as u64;
+        }
+        assert_eq!(offset, leader.fsm.snapshot.len() as u64);
+
+        //
+        // - peer #2 misses a chunk: what follows is dropped and nothing is installed
+        //
+        let mut node = Harness::<KV>::new(2, 3, RaftConfig::default(), kv::apply);
+        node.start();
+        node.deliver(&chunks[0]);
+        for bytes in &chunks[2..] {
+            node.deliver(bytes);
+        }
+        assert_eq!((node.fsm.tail, node.fsm.head, node.fsm.commit), (1, 1, 1));
+        assert!(node.fsm.installing.is_none());
+
+        //
+        // - all the chunks in order: the payload is reset from the snapshot and the log now
+        //   starts at #16, which gets acknowledged
+        //
+        for bytes in &chunks {
+            node.deliver(bytes);
+        }
+        assert_eq!((node.fsm.tail, node.fsm.head, node.fsm.commit), (16, 16, 17));
+        assert_eq!(node.fsm.term_at(16), Some(1));
+        assert_eq!(node.fsm.snapshot, leader.fsm.snapshot);
+        {
+            let kv = node.fsm.payload.read();
+            let expected = leader.fsm.payload.read();
+            assert!(kv.len() > 0);
+            assert_eq!(kv.len(), expected.len());
+            assert_eq!(kv.get(&[3]), expected.get(&[3]));
+        }
+        let (_, bytes) = node.out.lock().unwrap().last().cloned().unwrap();
+        let raw: RAW = deserialize(&bytes).unwrap();
+        assert_eq!(raw.code, ACK::CODE);
+        leader.deliver(&bytes);
+
+        //
+        // - regular replication picks up right after the snapshot
+        // - commit #18: #17 is applied on top of the snapshot
+        //
+        leader.post(STORE_ASYNC(Op::SET(vec![16], vec![16]).to_bytes(), channel().0, None));
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: 18,
+            learner: false,
+            digest: None,
+        };
+        leader.deliver(&msg.to_raw(&host(1), &host(0)));
+        leader.fsm.peers.get_mut(&1).unwrap().off = 18;
+        let out = sent_to_2(&mut leader);
+        assert_eq!(out.len(), 1);
+        node.deliver(&out[0]);
+        assert_eq!((node.fsm.head, node.fsm.commit), (18, 18));
+        assert_eq!(node.fsm.payload.read().len(), 16);
+    }
+
+    #[test]
+    fn retained_entries() {
+
+        let replicate_to = |retain: u64, off: u64| {
+
+            //
+            // - force peer #0 to lead at term 1
+            // - append and commit #2 to #17, which checkpoints at #15
+            //
+            let config = RaftConfig {
+                snapshot_retain_entries: retain,
+                ..RaftConfig::default()
+            };
+            let mut node = Harness::<KV>::new(0, 3, config, kv::apply);
+            node.start();
+            node.fsm.term = 1;
+            node.state = State::LEAD(Default::default());
+            for n in 0..16u8 {
+                node.post(STORE_ASYNC(Op::SET(vec![n], vec![n]).to_bytes(), channel().0, None));
+            }
+            let msg = ACK {
+                id: 1,
+                term: 1,
+                ack: 17,
+                learner: false,
+                digest: None,
+            };
+            node.deliver(&msg.to_raw(&host(1), &host(0)));
+            assert_eq!(node.fsm.commit, 17);
+            assert_eq!(node.fsm.tail, 15 - retain);
+
+            //
+            // - peer #1 is up to date
+            // - peer #2 is at the specified offset, behind the checkpoint
+            // - heartbeat and grab what we replicate to it
+            //
+            node.fsm.peers.get_mut(&1).unwrap().off = 17;
+            node.fsm.peers.get_mut(&2).unwrap().off = off;
+            node.out.lock().unwrap().clear();
+            let seq = node.fsm.seq;
+            node.post(TIMEOUT(seq));
+            let out = node.out.lock().unwrap().clone();
+            out.iter()
+                .filter(|&&(ref dst, _)| *dst == host(2))
+                .map(|&(_, ref bytes)| deserialize::<RAW>(bytes).unwrap())
+                .filter(|raw| raw.code != PING::CODE)
+                .collect::<Vec<_>>()
+        };
+
+        //
+        // - by default the peer must be rebased with our snapshot, which reflects #16
+        //
+        let out = replicate_to(0, 13);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].code, INSTALL_SNAPSHOT::CODE);
+        let msg: INSTALL_SNAPSHOT = deserialize(&out[0].msg).unwrap();
+        assert!(!msg.chunk.is_empty());
+        assert_eq!((msg.last_index, msg.last_term), (16, 1));
+
+        //
+        // - retaining 4 entries lets us replicate #15 to #17 instead
+        //
+        let out = replicate_to(4, 14);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].code, REPLICATE::CODE);
+        let msg: REPLICATE = deserialize(&out[0].msg).unwrap();
+        assert_eq!(msg.off, 14);
+        assert_eq!(msg.append.len(), 3 * <Node<KV>>::SLOT_BYTES);
+    }
+
+    #[test]
+    fn unknown_voters() {
+
+        //
+        // - peer #1 is running for term 1
+        // - peer #2 is removed from its configuration
+        //
+        let mut node = Harness::<Empty>::new(1, 4, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        node.fsm.term = 1;
+        node.fsm.peers.remove(&2);
+        node.state = State::CNDT(Default::default());
+
+        //
+        // - vote requests from the removed peer #2 and the unknown peer #7
+        // - neither must be granted nor change our term
+        //
+        for id in &[2, 7] {
+            let msg = ADVERTISE {
+                id: *id,
+                term: 2,
+                head: 1,
+                age: 0,
+                transfer: false,
+            };
+            node.deliver(&msg.to_raw(&host(*id), &host(1)));
+        }
+        assert!(node.out.lock().unwrap().is_empty());
+        assert_eq!(node.fsm.term, 1);
+
+        //
+        // - votes from those peers must not count either
+        //
+        for id in &[2, 7] {
+            let msg = VOTE { id: *id, term: 1 };
+            node.deliver(&msg.to_raw(&host(*id), &host(1)));
+        }
+        match node.state {
+            State::CNDT(_) => assert!(node.fsm.ballots.is_empty()),
+            _ => panic!("expected CANDIDATE"),
+        }
+
+        //
+        // - a vote request from a member is granted
+        //
+        let msg = ADVERTISE {
+            id: 3,
+            term: 2,
+            head: 1,
+            age: 0,
+            transfer: false,
+        };
+        node.deliver(&msg.to_raw(&host(3), &host(1)));
+        assert_eq!(node.out.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn forced_campaign() {
+
+        //
+        // - peer #1 follows peer #0
+        //
+        let mut node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 1,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert!(node.state == State::default());
+
+        //
+        // - force an election: we must go to PREVOTE, then CANDIDATE upon quorum
+        //
+        node.post(CAMPAIGN);
+        match node.state {
+            State::PREV(_) => {}
+            _ => panic!("expected PREVOTE"),
+        }
+        for id in 0..2 {
+            let msg = AVAILABLE { id: id * 2, term: 1 };
+            node.deliver(&msg.to_raw(&host(id * 2), &host(1)));
+        }
+        match node.state {
+            State::CNDT(_) => {}
+            _ => panic!("expected CANDIDATE"),
+        }
+    }
+
+    #[test]
+    fn restart_recovery() {
+
+        //
+        // - replicate #2 to #21 from peer #0, each entry setting a distinct key
+        // - commit up to #17 (which checkpoints) and then up to #21
+        //
+        let mut node = Harness::<KV>::new(1, 3, RaftConfig::default(), kv::apply);
+        node.start();
+        let slots: Vec<_> = (0..20u8)
+            .map(|n| (1, Op::SET(vec![n], vec![n]).to_bytes()))
+            .collect();
+        let msg = REPLICATE {
+            id: 0,
+            term: 1,
+            off: 1,
+            age: 0,
+            commit: 1,
+            append: records(2, &slots),
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        for commit in &[17, 21] {
+            let msg = PING {
+                id: 0,
+                term: 1,
+                commit: *commit,
+                digest: None,
+            };
+            node.deliver(&msg.to_raw(&host(0), &host(1)));
+        }
+        assert_eq!(node.fsm.snapshot_off, 17);
+        let before = (*node.fsm.payload.read()).clone();
+
+        //
+        // - crash and recover
+        // - the payload is restored from the snapshot and the log is intact
+        //
+        let mut node = node.restart();
+        assert_eq!(node.fsm.tail, 17);
+        assert_eq!(node.fsm.head, 21);
+        assert_eq!(node.fsm.commit, 17);
+        assert_eq!(node.fsm.term, 1);
+        assert_eq!(node.fsm.payload.read().len(), 15);
+
+        //
+        // - the LEADER asserts its commit offset: the entries are applied from our log
+        //
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 21,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(*node.fsm.payload.read(), before);
+
+        //
+        // - the LEADER replicates #22 and #23: they must be accepted right away (no rebase)
+        //
+        node.out.lock().unwrap().clear();
+        let msg = REPLICATE {
+            id: 0,
+            term: 1,
+            off: 21,
+            age: 1,
+            commit: 21,
+            append: records(22, &slots[..2]),
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(node.fsm.head, 23);
+        let out = node.out.lock().unwrap().clone();
+        assert_eq!(out.len(), 1);
+        let raw: RAW = deserialize(&out[0].1).unwrap();
+        assert_eq!(raw.code, ACK::CODE);
+    }
+
+    #[test]
+    fn sequential_rebases() {
+
+        //
+        // - force peer #0 to lead at term 1 in a 6 peers cluster, rebasing one peer at most
+        // - append #2 to #17
+        // - peers #3 to #5 acknowledge everything, which checkpoints at #15
+        //
+        let config = RaftConfig {
+            max_concurrent_snapshots: Some(1),
+            ..RaftConfig::default()
+        };
+        let mut node = Harness::<KV>::new(0, 6, config, kv::apply);
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        for n in 0..16u8 {
+            node.post(STORE_ASYNC(Op::SET(vec![n], vec![n]).to_bytes(), channel().0, None));
+        }
+        for id in 3..6 {
+            let msg = ACK {
+                id,
+                term: 1,
+                ack: 17,
+                learner: false,
+                digest: None,
+            };
+            node.deliver(&msg.to_raw(&host(id), &host(0)));
+            node.fsm.peers.get_mut(&id).unwrap().off = 17;
+        }
+        assert_eq!(node.fsm.tail, 15);
+
+        //
+        // - peers #1 and #2 lag behind the tail and must be rebased
+        // - each heartbeat returns who got a PING and who got rebased
+        //
+        let heartbeat = |node: &mut Harness<KV>| {
+            node.out.lock().unwrap().clear();
+            let seq = node.fsm.seq;
+            node.post(TIMEOUT(seq));
+            let out = node.out.lock().unwrap().clone();
+            let mut pings = 0;
+            let mut rebased = Vec::new();
+            for (dst, bytes) in out {
+                let raw: RAW = deserialize(&bytes).unwrap();
+                if raw.code == PING::CODE {
+                    pings += 1;
+                } else if raw.code == INSTALL_SNAPSHOT::CODE {
+                    rebased.push((1..3).find(|id| host(*id) == dst).unwrap());
+                }
+            }
+            (pings, rebased)
+        };
+
+        //
+        // - only one peer is rebased at a time, the next one once the previous acknowledged
+        // - everybody keeps getting heartbeats
+        //
+        let mut served = Vec::new();
+        for _ in 0..2 {
+            let (pings, rebased) = heartbeat(&mut node);
+            assert_eq!(pings, 5);
+            assert_eq!(rebased.len(), 1);
+            let (pings, again) = heartbeat(&mut node);
+            assert_eq!(pings, 5);
+            assert!(again.is_empty());
+            let msg = ACK {
+                id: rebased[0],
+                term: 1,
+                ack: 17,
+                learner: false,
+                digest: None,
+            };
+            node.deliver(&msg.to_raw(&host(rebased[0]), &host(0)));
+            served.push(rebased[0]);
+        }
+        served.sort();
+        assert_eq!(served, vec![1, 2]);
+        assert!(node.state == State::LEAD(Default::default()));
+    }
+
+    #[test]
+    fn throughput_counters() {
+
+        //
+        // - force peer #0 to lead at term 1
+        // - append #2 to #5 and have peer #1 acknowledge them
+        //
+        let mut node = Harness::<KV>::new(0, 3, RaftConfig::default(), kv::apply);
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        for n in 0..4u8 {
+            node.post(STORE_ASYNC(Op::SET(vec![n], vec![n]).to_bytes(), channel().0, None));
+        }
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: 5,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        assert_eq!(node.fsm.commit, 5);
+
+        //
+        // - all of this happened within the first second
+        // - one second later the rates must reflect it
+        //
+        let later = Instant::now() + Duration::from_secs(1);
+        assert_eq!(node.fsm.appended.total(), 4);
+        assert_eq!(node.fsm.committed.total(), 4);
+        assert!((node.fsm.appended.rate(later) - 4.0).abs() < ::std::f64::EPSILON);
+        assert!((node.fsm.committed.rate(later) - 4.0).abs() < ::std::f64::EPSILON);
+        let idle = later + Duration::from_secs(WINDOW as u64 + 1);
+        assert!(node.fsm.committed.rate(idle) < ::std::f64::EPSILON);
+    }
+
+    #[test]
+    fn session_reads() {
+
+        //
+        // - force peer #0 to lead at term 1
+        // - store SET a=1 at #2 followed by a blank entry at #3 (the commit offset being
+        //   exclusive) and have peer #1 acknowledge both
+        // - grab the session token
+        //
+        let op = Op::SET(b"a".to_vec(), b"1".to_vec());
+        let mut leader = Harness::<KV>::new(0, 3, RaftConfig::default(), kv::apply);
+        leader.start();
+        leader.fsm.term = 1;
+        leader.state = State::LEAD(Default::default());
+        leader.fsm.pending.fetch_add(1, Ordering::Release);
+        let (tx, rx) = channel();
+        leader.post(STORE_ASYNC(op.to_bytes(), tx, None));
+        let (ntx, _nrx) = channel();
+        leader.post(NOOP(ntx));
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: 3,
+            learner: false,
+            digest: None,
+        };
+        leader.deliver(&msg.to_raw(&host(1), &host(0)));
+        let token = rx.try_recv().unwrap().session_token().unwrap();
+        assert_eq!(token, 3);
+
+        //
+        // - peer #2 follows peer #0 and got both entries but not the commit offset covering
+        //   them yet
+        // - a bounded read with the token must wait
+        //
+        let mut replica = Harness::<KV>::new(2, 3, RaftConfig::default(), kv::apply);
+        replica.start();
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 1,
+            digest: None,
+        };
+        replica.deliver(&msg.to_raw(&host(0), &host(2)));
+        let msg = REPLICATE {
+            id: 0,
+            term: 1,
+            off: 1,
+            age: 0,
+            commit: 1,
+            append: records(2, &[(1, op.to_bytes()), (1, Vec::new())]),
+        };
+        replica.deliver(&msg.to_raw(&host(0), &host(2)));
+        let (tx, rx) = channel();
+        let level = ReadConsistency::Bounded(Duration::from_secs(5));
+        replica.post(READ_AFTER(level, token, Duration::from_secs(5), tx));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(replica.fsm.payload.read().get(b"a"), None);
+
+        //
+        // - once the LEADER commit offset reaches the replica the read goes through
+        // - the payload then includes our write
+        //
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 3,
+            digest: None,
+        };
+        replica.deliver(&msg.to_raw(&host(0), &host(2)));
+        assert_eq!(rx.try_recv().unwrap().unwrap(), 3);
+        assert_eq!(replica.fsm.payload.read().get(b"a"), Some(&b"1"[..]));
+
+        //
+        // - a token we already cover is served right away
+        //
+        let (tx, rx) = channel();
+        replica.post(READ_AFTER(level, 2, Duration::from_secs(5), tx));
+        assert_eq!(rx.try_recv().unwrap().unwrap(), 3);
+    }
+
+    #[test]
+    fn linearizable_read() {
+
+        //
+        // - force peer #0 to lead at term 1
+        // - a linearizable read is not served off the commit offset: it appends a no-op at #2
+        //   and is parked until acknowledged
+        //
+        let mut node = Harness::<Empty>::new(0, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        let (tx, rx) = channel();
+        node.post(READ(ReadConsistency::Linearizable, tx));
+        assert_eq!(node.fsm.head, 2);
+        assert!(rx.try_recv().is_err());
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: 2,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        assert_eq!(rx.try_recv().unwrap().unwrap(), 2);
+
+        //
+        // - same with a session token, but peer #2 got elected meanwhile: we were deposed
+        //   without knowing it and the read must fail instead of being served
+        //
+        let (tx, rx) = channel();
+        let timeout = Duration::from_secs(5);
+        node.post(READ_AFTER(ReadConsistency::Linearizable, 2, timeout, tx));
+        assert_eq!(node.fsm.head, 3);
+        assert!(rx.try_recv().is_err());
+        let msg = PING {
+            id: 2,
+            term: 2,
+            commit: 2,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(2), &host(0)));
+        match rx.try_recv().unwrap() {
+            Err(ReadError::NotLeader(Some(2))) => {}
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn parked_reads() {
+
+        //
+        // - peer #1 follows peer #0 on a manual clock
+        // - park a read for token #3 whose caller gives up after 1s
+        // - past that lapse of time the read is dropped
+        //
+        let clock = Arc::new(ManualClock::new());
+        let mut node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.fsm.clock = clock.clone();
+        node.start();
+        let ping = PING {
+            id: 0,
+            term: 1,
+            commit: 1,
+            digest: None,
+        };
+        node.deliver(&ping.to_raw(&host(0), &host(1)));
+        let level = ReadConsistency::Bounded(Duration::from_secs(5));
+        let (tx, rx) = channel();
+        node.post(READ_AFTER(level, 3, Duration::from_secs(1), tx));
+        assert_eq!(node.fsm.readers.len(), 1);
+        clock.advance(Duration::from_secs(2));
+        node.deliver(&ping.to_raw(&host(0), &host(1)));
+        assert!(node.fsm.readers.is_empty());
+        assert_eq!(rx.try_recv().err(), Some(TryRecvError::Disconnected));
+
+        //
+        // - park another read, this time bounded to 1s since we last heard from peer #0
+        // - it fails with Stale as soon as that lapse is exceeded instead of being served
+        //   from a stale payload later on
+        //
+        let level = ReadConsistency::Bounded(Duration::from_secs(1));
+        let (tx, rx) = channel();
+        node.post(READ_AFTER(level, 3, Duration::from_secs(10), tx));
+        assert!(rx.try_recv().is_err());
+        clock.advance(Duration::from_secs(2));
+        node.post(LOG_HEAD(channel().0));
+        match rx.try_recv().unwrap() {
+            Err(ReadError::Stale) => {}
+            ref res => panic!("unexpected {:?}", res),
+        }
+        assert!(node.fsm.readers.is_empty());
+    }
+
+    #[test]
+    fn disk_usage() {
+
+        //
+        // - force peer #0 to lead at term 1 with a 2KB checkpoint threshold
+        // - each append grows the log usage by one slot
+        //
+        let config = RaftConfig {
+            snapshot_threshold_bytes: Some(2048),
+            ..RaftConfig::default()
+        };
+        let mut node = Harness::<KV>::new(0, 3, config, kv::apply);
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        let slot = <Node<KV>>::SLOT_BYTES as u64;
+        assert_eq!(node.fsm.disk_usage(), slot);
+        for n in 0..4u8 {
+            node.post(STORE_ASYNC(Op::SET(vec![n], vec![7; 900]).to_bytes(), channel().0, None));
+            assert_eq!(node.fsm.disk_usage(), (u64::from(n) + 2) * slot);
+        }
+
+        //
+        // - peer #1 acknowledges #5, which commits 2.7KB and checkpoints at #5
+        // - the log shrinks down to a single slot plus the snapshot
+        //
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: 5,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        assert_eq!(node.fsm.tail, 5);
+        let snapshot = node.fsm.snapshot.len() as u64;
+        assert!(snapshot > 0);
+        assert_eq!(node.fsm.disk_usage(), slot + snapshot);
+        assert!(node.fsm.disk_usage() < 5 * slot);
+    }
+
+    #[test]
+    fn backward_clock() {
+
+        //
+        // - peer #1 follows peer #0 at term 1
+        // - a bounded read goes through
+        //
+        let mut node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 1,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        let level = ReadConsistency::Bounded(Duration::from_secs(5));
+        let (tx, rx) = channel();
+        node.post(READ(level, tx));
+        assert_eq!(rx.recv().unwrap().unwrap(), 1);
+
+        //
+        // - pretend the clock went backwards, e.g the last contact with the LEADER is in the
+        //   future
+        // - the read must be rejected instead of extending the staleness bound
+        //
+        node.fsm.contact = Instant::now() + Duration::from_secs(60);
+        let (tx, rx) = channel();
+        node.post(READ(level, tx));
+        match rx.recv().unwrap() {
+            Err(ReadError::Stale) => {}
+            _ => panic!("stale read served"),
+        }
+    }
+
+    #[test]
+    fn shutdown_reason() {
+
+        //
+        // - drain a healthy peer and a quarantined one
+        // - the reason comes right before the final EXIT
+        //
+        let cases = [
+            (false, ShutdownReason::Drained),
+            (true, ShutdownReason::SafetyViolation),
+        ];
+        for &(quarantined, reason) in &cases {
+            let mut node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+            node.start();
+            node.fsm.quarantined = quarantined;
+            let _ = node.fsm.recv(&node.this, node.state, Opcode::DRAIN);
+            let _ = node.fsm.recv(&node.this, node.state, Opcode::EXIT);
+            let mut notifications = node.notifications();
+            match notifications.pop() {
+                Some(Notification::EXIT) => {}
+                _ => panic!("EXIT is not last"),
+            }
+            match notifications.pop() {
+                Some(Notification::TERMINATED(n)) => assert_eq!(n, reason),
+                _ => panic!("no termination reason"),
+            }
+            assert!(node.sink.next().is_none());
+        }
+    }
+
+    #[test]
+    fn apply_failure() {
+
+        //
+        // - hand an entry over to a dedicated apply thread whose apply closure panics
+        // - draining reports the apply failure right before the final EXIT
+        //
+        let mut node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| {
+            panic!("unable to apply")
+        });
+        let event = Event::new();
+        let guard = event.guard();
+        node.fsm.applier = Some(Applier::spawn(
+            &guard,
+            8,
+            node.fsm.payload.clone(),
+            node.fsm.stage.clone(),
+            node.fsm.pending.clone(),
+            None,
+            Logger::root(Discard, o!()),
+        ));
+        node.start();
+        let _ = node.fsm.applier.as_ref().unwrap().push(Job::Apply(2, vec![], None));
+        let _ = node.fsm.recv(&node.this, node.state, Opcode::DRAIN);
+        let _ = node.fsm.recv(&node.this, node.state, Opcode::EXIT);
+        let mut notifications = node.notifications();
+        match notifications.pop() {
+            Some(Notification::EXIT) => {}
+            _ => panic!("EXIT is not last"),
+        }
+        match notifications.pop() {
+            Some(Notification::TERMINATED(n)) => assert_eq!(n, ShutdownReason::ApplyFailure),
+            _ => panic!("no termination reason"),
+        }
+        drop(guard);
+        event.wait();
+    }
+
+    #[test]
+    fn role_lookup() {
+
+        //
+        // - idle until started, then following peer #0
+        //
+        let mut node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        assert_eq!(Role::load(&node.fsm.role), Role::Idle);
+        node.start();
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 1,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(Role::load(&node.fsm.role), Role::Follower);
+
+        //
+        // - force an election: we are a candidate while pre-voting and running
+        //
+        node.post(CAMPAIGN);
+        assert_eq!(Role::load(&node.fsm.role), Role::Candidate);
+        for id in 0..2 {
+            let msg = AVAILABLE { id: id * 2, term: 1 };
+            node.deliver(&msg.to_raw(&host(id * 2), &host(1)));
+        }
+        assert!(node.state == State::CNDT(Default::default()));
+        assert_eq!(Role::load(&node.fsm.role), Role::Candidate);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn initial_position() {
+
+        //
+        // - peer #1 starts at term 1000 with its log at #1000000
+        //
+        let config = RaftConfig {
+            initial_term: 1000,
+            initial_index: 1_000_000,
+            ..RaftConfig::default()
+        };
+        let mut node = Harness::<Counter>::new(1, 3, config, |p, _| {
+            p.n += 1;
+            Ok(vec![])
+        });
+        node.start();
+        assert_eq!(node.fsm.term, 1000);
+        assert_eq!(node.fsm.head, 1_000_000);
+        assert_eq!(node.fsm.commit, 1_000_000);
+
+        //
+        // - a heartbeat from an older term is rejected with a UPGRADE
+        //
+        let msg = PING {
+            id: 0,
+            term: 999,
+            commit: 1_000_000,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        let out = node.out.lock().unwrap().clone();
+        assert!(out.iter().any(|&(_, ref bytes)| {
+            deserialize::<RAW>(bytes).unwrap().code == UPGRADE::CODE
+        }));
+        assert_eq!(node.fsm.term, 1000);
+
+        //
+        // - replicate and commit #1000001 and #1000002 at term 1000
+        //
+        let msg = REPLICATE {
+            id: 0,
+            term: 1000,
+            off: 1_000_000,
+            age: 0,
+            commit: 1_000_000,
+            append: entries(1_000_001, &[1000, 1000]),
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(node.fsm.head, 1_000_002);
+        let msg = PING {
+            id: 0,
+            term: 1000,
+            commit: 1_000_002,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(node.fsm.commit, 1_000_002);
+        assert_eq!(node.fsm.payload.read().n, 2);
+        assert_eq!(node.fsm.term_at(1_000_001), Some(1000));
+    }
+
+    #[test]
+    fn configuration_lookup() {
+
+        //
+        // - peer #1 in a 4 peers cluster
+        // - all the peers are voters, sorted by id
+        //
+        let mut node = Harness::<Empty>::new(1, 4, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        let (tx, rx) = channel();
+        node.post(CONFIGURATION(tx));
+        let cfg = rx.recv().unwrap();
+        let voters: Vec<_> = (0..4).map(|id| (id, host(id))).collect();
+        assert_eq!(cfg.voters, voters);
+        assert!(cfg.learners.is_empty());
+        assert!(cfg.witnesses.is_empty());
+        assert_eq!(cfg.index, 1);
+
+        //
+        // - drop peer #2 from the configuration
+        //
+        node.fsm.peers.remove(&2);
+        let (tx, rx) = channel();
+        node.post(CONFIGURATION(tx));
+        let ids: Vec<_> = rx.recv().unwrap().voters.iter().map(|v| v.0).collect();
+        assert_eq!(ids, vec![0, 1, 3]);
+    }
+
+    #[test]
+    fn idle_duration() {
+
+        //
+        // - a single peer (with no peers at all) starts on a manual clock
+        // - it is idle and the lapse grows as time goes by
+        //
+        let idle = |node: &mut Harness<Empty>| {
+            let (tx, rx) = channel();
+            node.post(IDLE_DURATION(tx));
+            rx.recv().unwrap()
+        };
+        let clock = Arc::new(ManualClock::new());
+        let mut node = Harness::<Empty>::new(0, 1, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.fsm.clock = clock.clone();
+        node.start();
+        assert!(node.fsm.peers.is_empty());
+        for n in 0..4 {
+            assert_eq!(idle(&mut node), Some(Duration::from_millis(n * 250)));
+            clock.advance(Duration::from_millis(250));
+        }
+
+        //
+        // - same for peer #1 in a 3 peers cluster, not hearing from anybody
+        //
+        let clock = Arc::new(ManualClock::new());
+        let mut node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.fsm.clock = clock.clone();
+        node.start();
+        assert_eq!(idle(&mut node), Some(Duration::from_millis(0)));
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(idle(&mut node), Some(Duration::from_millis(500)));
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(idle(&mut node), Some(Duration::from_secs(1)));
+
+        //
+        // - an election starts: we are not idle while pre-voting
+        //
+        node.post(CAMPAIGN);
+        assert!(node.state == State::PREV(Default::default()));
+        assert!(idle(&mut node).is_none());
+
+        //
+        // - peer #0 is elected and heartbeats: we are not idle either
+        //
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 1,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert!(idle(&mut node).is_none());
+
+        //
+        // - peer #2 shows up at a later term: we drop peer #0 and are idle again, from then on
+        //
+        let msg = UPGRADE { id: 2, term: 2 };
+        node.deliver(&msg.to_raw(&host(2), &host(1)));
+        assert_eq!(idle(&mut node), Some(Duration::from_millis(0)));
+        clock.advance(Duration::from_millis(250));
+        assert_eq!(idle(&mut node), Some(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn pinned_quorum() {
+
+        //
+        // - custom policy: a majority which must include peer #2
+        //
+        struct Pinned(u16);
+
+        impl Quorum for Pinned {
+            fn is_committed(&self, acked: &HashMap<u16, u64>, index: u64) -> bool {
+                acked.get(&self.0).map_or(false, |&off| off >= index) &&
+                    Majority.is_committed(acked, index)
+            }
+        }
+
+        //
+        // - force peer #0 to lead at term 1 in a 4 peers cluster
+        // - append #2 to #5
+        //
+        let mut node = Harness::<Empty>::new(0, 4, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.post(QUORUM(Box::new(Pinned(2))));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        for _ in 0..4 {
+            node.post(STORE_ASYNC(Vec::new(), channel().0, None));
+        }
+
+        //
+        // - peers #1 and #3 acknowledge everything: this is a majority but without peer #2
+        //
+        for &id in &[1, 3] {
+            let msg = ACK {
+                id,
+                term: 1,
+                ack: 5,
+                learner: false,
+                digest: None,
+            };
+            node.deliver(&msg.to_raw(&host(id), &host(0)));
+        }
+        assert_eq!(node.fsm.commit, 1);
+
+        //
+        // - peer #2 acknowledges #3: we can commit up to there
+        //
+        let msg = ACK {
+            id: 2,
+            term: 1,
+            ack: 3,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(2), &host(0)));
+        assert_eq!(node.fsm.commit, 3);
+    }
+
+    #[test]
+    fn intercepted_votes() {
+
+        //
+        // - peer #1 follows peer #0 and drops any VOTE, inbound or outbound
+        //
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let mut node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        {
+            let dropped = dropped.clone();
+            node.post(INTERCEPT(Box::new(move |_, raw: &RAW| {
+                if raw.kind() == "VOTE" {
+                    dropped.fetch_add(1, Ordering::Relaxed);
+                    Action::Drop
+                } else {
+                    Action::Pass
+                }
+            })));
+        }
+        node.start();
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 1,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+
+        //
+        // - force an election and pass the pre-vote
+        //
+        node.post(CAMPAIGN);
+        for id in 0..2 {
+            let msg = AVAILABLE { id: id * 2, term: 1 };
+            node.deliver(&msg.to_raw(&host(id * 2), &host(1)));
+        }
+        assert!(node.state == State::CNDT(Default::default()));
+
+        //
+        // - both peers vote for us: we never see it and thus can't be elected
+        //
+        for id in 0..2 {
+            let msg = VOTE {
+                id: id * 2,
+                term: node.fsm.term,
+            };
+            node.deliver(&msg.to_raw(&host(id * 2), &host(1)));
+        }
+        assert!(node.state == State::CNDT(Default::default()));
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+
+        //
+        // - peer #2 runs for a later term and we grant it our vote: nothing is sent
+        //
+        node.post(VOTE_HOOK(Box::new(|_: &VoteContext| VoteDecision::Grant)));
+        node.out.lock().unwrap().clear();
+        let msg = ADVERTISE {
+            id: 2,
+            term: node.fsm.term + 1,
+            head: 1,
+            age: 0,
+            transfer: false,
+        };
+        node.deliver(&msg.to_raw(&host(2), &host(1)));
+        assert!(node.out.lock().unwrap().is_empty());
+        assert_eq!(dropped.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn leader_wait() {
+
+        //
+        // - peer #1 starts on a manual clock without any LEADER: the request is parked
+        //
+        let clock = Arc::new(ManualClock::new());
+        let mut node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.fsm.clock = clock.clone();
+        node.start();
+        let (tx, rx) = channel();
+        node.post(AWAIT_LEADER(Duration::from_secs(5), tx));
+        assert!(rx.try_recv().is_err());
+
+        //
+        // - another request whose caller gives up after 1s is dropped past that lapse of time
+        //
+        let (tx, stale) = channel();
+        node.post(AWAIT_LEADER(Duration::from_secs(1), tx));
+        assert_eq!(node.fsm.watchers.len(), 2);
+        clock.advance(Duration::from_secs(2));
+        node.post(LOG_HEAD(channel().0));
+        assert_eq!(node.fsm.watchers.len(), 1);
+        assert_eq!(stale.try_recv().err(), Some(TryRecvError::Disconnected));
+
+        //
+        // - peer #0 gets elected and heartbeats: we are told right away
+        // - any further request is answered immediately
+        //
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 1,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(rx.try_recv().unwrap(), 0);
+        let (tx, rx) = channel();
+        node.post(AWAIT_LEADER(Duration::from_secs(5), tx));
+        assert_eq!(rx.try_recv().unwrap(), 0);
+
+        //
+        // - we run and win an election
+        //
+        let (tx, rx) = channel();
+        node.post(CAMPAIGN);
+        node.post(AWAIT_LEADER(Duration::from_secs(5), tx));
+        for id in 0..2 {
+            let msg = AVAILABLE { id: id * 2, term: 1 };
+            node.deliver(&msg.to_raw(&host(id * 2), &host(1)));
+        }
+        for id in 0..2 {
+            let msg = VOTE {
+                id: id * 2,
+                term: node.fsm.term,
+            };
+            node.deliver(&msg.to_raw(&host(id * 2), &host(1)));
+        }
+        assert!(node.state == State::LEAD(Default::default()));
+        assert_eq!(rx.try_recv().unwrap(), 1);
+    }
+
+    #[test]
+    fn corrupted_entries() {
+
+        //
+        // - peer #1 follows peer #0 with entry checksums on
+        // - corrupt the first byte of #3 in transit: the whole REPLICATE is dropped
+        //
+        let config = RaftConfig {
+            entry_checksums: true,
+            ..RaftConfig::default()
+        };
+        let mut node = Harness::<Counter>::new(1, 3, config, |p, _| {
+            p.n += 1;
+            Ok(vec![])
+        });
+        node.start();
+        let slots = vec![(1, vec![1; 16]), (1, vec![2; 16]), (1, vec![3; 16])];
+        let mut msg = REPLICATE {
+            id: 0,
+            term: 1,
+            off: 1,
+            age: 0,
+            commit: 1,
+            append: records(2, &slots),
+        };
+        let width = <Node<Counter>>::SLOT_BYTES;
+        let blob = |slot: usize| slot * width + 25;
+        msg.append[blob(1)] ^= 0xff;
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(node.fsm.head, 1);
+
+        //
+        // - replicate it intact this time
+        // - then corrupt #3 on disk and commit everything
+        // - #2 is applied, #3 triggers a SAFETY_VIOLATION and we stop there
+        //
+        msg.append[blob(1)] ^= 0xff;
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(node.fsm.head, 4);
+        node.tamper(3, |slot| slot[25] ^= 0xff);
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 4,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(node.fsm.commit, 3);
+        assert!(node.fsm.quarantined);
+        assert!(node.notifications().iter().any(|n| match *n {
+            Notification::SAFETY_VIOLATION(3) => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn heartbeat_cadence() {
+
+        //
+        // - force peer #0 to lead at term 1 on a manual clock
+        // - heartbeat: the next one is due after the default interval (750ms)
+        //
+        let clock = Arc::new(ManualClock::new());
+        let mut node = Harness::<Empty>::new(0, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.fsm.clock = clock.clone();
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        let pending = clock.pending();
+        let seq = node.fsm.seq;
+        node.post(TIMEOUT(seq));
+        assert_eq!(clock.pending(), pending + 1);
+        clock.advance(Duration::from_millis(749));
+        assert_eq!(clock.pending(), pending + 1);
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(clock.pending(), pending);
+
+        //
+        // - reconfigure to heartbeat every 50ms and heartbeat again
+        // - the next one is due way earlier
+        //
+        let delta = RaftConfigDelta {
+            heartbeat_interval: Some(Duration::from_millis(50)),
+            ..RaftConfigDelta::default()
+        };
+        let (tx, rx) = channel();
+        node.post(RECONFIGURE(delta, tx));
+        assert!(rx.recv().unwrap().is_ok());
+        node.post(TIMEOUT(seq));
+        assert_eq!(clock.pending(), pending + 1);
+        clock.advance(Duration::from_millis(49));
+        assert_eq!(clock.pending(), pending + 1);
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(clock.pending(), pending);
+
+        //
+        // - invalid changes are rejected
+        //
+        let delta = RaftConfigDelta {
+            heartbeat_interval: Some(Duration::from_secs(5)),
+            ..RaftConfigDelta::default()
+        };
+        let (tx, rx) = channel();
+        node.post(RECONFIGURE(delta, tx));
+        assert!(rx.recv().unwrap().is_err());
+        assert_eq!(node.fsm.config.heartbeat_interval, Duration::from_millis(50));
+    }
+
+    #[test]
+    fn election_progress() {
+
+        //
+        // - peer #1 follows peer #0 in a 5 peers cluster: no election running
+        //
+        let mut node = Harness::<Empty>::new(1, 5, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 1,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        let (tx, rx) = channel();
+        node.post(ELECTION_STATE(tx));
+        assert_eq!(rx.try_recv().unwrap(), None);
+
+        //
+        // - force an election and pass the pre-vote: still nothing until advertised
+        //
+        node.post(CAMPAIGN);
+        for id in &[0, 2, 3] {
+            let msg = AVAILABLE { id: *id, term: 1 };
+            node.deliver(&msg.to_raw(&host(*id), &host(1)));
+        }
+        assert!(node.state == State::CNDT(Default::default()));
+        let (tx, rx) = channel();
+        node.post(ELECTION_STATE(tx));
+        assert_eq!(rx.try_recv().unwrap(), None);
+
+        //
+        // - the election starts, then peer #3 votes for us
+        // - we are short of a quorum, peers #0, #2 and #4 are pending
+        //
+        let seq = node.fsm.seq;
+        node.post(TIMEOUT(seq));
+        let msg = VOTE {
+            id: 3,
+            term: node.fsm.term,
+        };
+        node.deliver(&msg.to_raw(&host(3), &host(1)));
+        assert!(node.state == State::CNDT(Default::default()));
+        let (tx, rx) = channel();
+        node.post(ELECTION_STATE(tx));
+        let state = rx.try_recv().unwrap().unwrap();
+        assert_eq!(state.term, node.fsm.term);
+        assert_eq!(state.granted, vec![1, 3]);
+        assert_eq!(state.pending, vec![0, 2, 4]);
+    }
+
+    #[test]
+    fn store_barrier() {
+
+        //
+        // - force peer #0 to lead at term 1
+        // - store #2, #3 and #4 asynchronously followed by a barrier at #5
+        //
+        let mut node = Harness::<Empty>::new(0, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        let stores: Vec<_> = (0..3u8)
+            .map(|n| {
+                let (tx, rx) = channel();
+                node.post(STORE_ASYNC(vec![n], tx, None));
+                rx
+            })
+            .collect();
+        let (tx, barrier) = channel();
+        node.post(STORE_ASYNC(Vec::new(), tx, None));
+        assert_eq!(node.fsm.head, 5);
+
+        //
+        // - peer #1 acknowledges up to #3: the barrier is still pending
+        //
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: 4,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        assert_eq!(stores[0].try_recv().unwrap(), StoreResult::Committed(2, vec![]));
+        assert_eq!(stores[1].try_recv().unwrap(), StoreResult::Committed(3, vec![]));
+        assert!(barrier.try_recv().is_err());
+
+        //
+        // - peer #2 acknowledges up to the barrier, which commits #4 but not the barrier yet
+        // - store #6 and have peer #1 acknowledge it: the barrier resolves
+        //
+        let msg = ACK {
+            id: 2,
+            term: 1,
+            ack: 5,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(2), &host(0)));
+        assert_eq!(stores[2].try_recv().unwrap(), StoreResult::Committed(4, vec![]));
+        assert!(barrier.try_recv().is_err());
+        node.post(STORE_ASYNC(vec![3], channel().0, None));
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: 6,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        assert_eq!(barrier.try_recv().unwrap(), StoreResult::Committed(5, vec![]));
+    }
+
+    #[test]
+    fn candidate_steps_down() {
+
+        //
+        // - peer #1 passes the pre-vote and runs for term 1
+        //
+        let mut node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        node.post(CAMPAIGN);
+        for id in 0..2 {
+            let msg = AVAILABLE { id: id * 2, term: 0 };
+            node.deliver(&msg.to_raw(&host(id * 2), &host(1)));
+        }
+        let seq = node.fsm.seq;
+        node.post(TIMEOUT(seq));
+        assert!(node.state == State::CNDT(Default::default()));
+        assert_eq!(node.fsm.term, 1);
+
+        //
+        // - peer #2 got elected first for that term and replicates #2 and #3
+        // - we must follow it, append both entries and acknowledge them
+        //
+        node.notifications();
+        node.out.lock().unwrap().clear();
+        let msg = REPLICATE {
+            id: 2,
+            term: 1,
+            off: 1,
+            age: 0,
+            commit: 1,
+            append: entries(2, &[1, 1]),
+        };
+        node.deliver(&msg.to_raw(&host(2), &host(1)));
+        match node.state {
+            State::FLWR(ctx) => assert_eq!(ctx.leader, Some(2)),
+            _ => panic!("expected FOLLOWER"),
+        }
+        assert_eq!(node.fsm.head, 3);
+        assert!(node.notifications().iter().any(|n| match *n {
+            Notification::FOLLOWING => true,
+            _ => false,
+        }));
+        let out = node.out.lock().unwrap().clone();
+        assert!(out.iter().any(|&(dst, ref bytes)| {
+            let raw: RAW = deserialize(bytes).unwrap();
+            dst == host(2) && raw.kind() == "ACK"
+        }));
+    }
+
+    #[test]
+    fn torn_tail() {
+
+        //
+        // - peer #1 follows peer #0 and gets #2 to #5 replicated
+        // - entry checksums are off: slots are still checked upon recovery
+        //
+        let mut node = Harness::<Counter>::new(1, 3, RaftConfig::default(), |p, _| {
+            p.n += 1;
+            Ok(vec![])
+        });
+        node.start();
+        let slots: Vec<_> = (1..5u8).map(|n| (1, vec![n; 16])).collect();
+        let msg = REPLICATE {
+            id: 0,
+            term: 1,
+            off: 1,
+            age: 0,
+            commit: 1,
+            append: records(2, &slots),
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(node.fsm.head, 5);
+
+        //
+        // - simulate a crash while writing #5: the second half of its payload never made it
+        //   to disk (pages are not necessarily flushed in order)
+        // - upon recovery #5 must be truncated, not replayed and without panicking
+        //
+        node.tamper(5, |slot| for byte in &mut slot[33..41] {
+            *byte = 0;
+        });
+        let node = node.restart();
+        assert_eq!(node.fsm.head, 4);
+        assert_eq!(node.fsm.age, 1);
+
+        //
+        // - garbage in place of #4 (e.g not even a valid slot) is handled the same way
+        //
+        let mut node = node;
+        node.tamper(4, |slot| for byte in slot.iter_mut() {
+            *byte = 0xff;
+        });
+        let node = node.restart();
+        assert_eq!(node.fsm.head, 3);
+    }
+
+    /// Storage counting flushes, along with how many messages were sent by then.
+    struct Synced {
+        inner: MemoryStorage,
+        out: Arc<Mutex<Vec<([u8; 32], Vec<u8>)>>>,
+        flushes: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl Storage for Synced {
+        fn append_entries(&mut self, off: u64, bytes: &[u8]) -> io::Result<()> {
+            self.inner.append_entries(off, bytes)
+        }
+
+        fn read_entries(&self, range: Range<u64>, buf: &mut Vec<u8>) -> io::Result<()> {
+            self.inner.read_entries(range, buf)
+        }
+
+        fn truncate_from(&mut self, off: u64) -> io::Result<()> {
+            self.inner.truncate_from(off)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            let sent = self.out.lock().unwrap().len();
+            self.flushes.lock().unwrap().push(sent);
+            Ok(())
+        }
+
+        fn save_meta(&mut self, term: u64, vote: u16) -> io::Result<()> {
+            self.inner.save_meta(term, vote)
+        }
+
+        fn load_meta(&mut self) -> io::Result<Option<(u64, u16)>> {
+            self.inner.load_meta()
+        }
+
+        fn save_snapshot(&mut self, meta: &SnapshotMeta, reader: &mut dyn Read) -> io::Result<()> {
+            self.inner.save_snapshot(meta, reader)
+        }
+
+        fn load_snapshot(&mut self) -> io::Result<Option<(SnapshotMeta, Box<dyn Read>)>> {
+            self.inner.load_snapshot()
+        }
+    }
+
+    #[test]
+    fn flush_before_ack() {
+
+        //
+        // - peer #1 follows peer #0 and gets #2 to #3 replicated
+        // - the log must be flushed before the ACK goes out
+        //
+        let mut node = Harness::<Counter>::new(1, 3, RaftConfig::default(), |p, _| {
+            p.n += 1;
+            Ok(vec![])
+        });
+        let flushes = Arc::new(Mutex::new(Vec::new()));
+        node.fsm.storage = Box::new(Synced {
+            inner: MemoryStorage::new(),
+            out: node.out.clone(),
+            flushes: flushes.clone(),
+        });
+        node.start();
+        let slots: Vec<_> = (1..3u8).map(|n| (1, vec![n; 16])).collect();
+        let msg = REPLICATE {
+            id: 0,
+            term: 1,
+            off: 1,
+            age: 0,
+            commit: 1,
+            append: records(2, &slots),
+        };
+        let sent = node.out.lock().unwrap().len();
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(node.fsm.head, 3);
+        assert_eq!(*flushes.lock().unwrap(), vec![sent]);
+        let out = node.out.lock().unwrap().clone();
+        let raw: RAW = deserialize(&out[out.len() - 1].1).unwrap();
+        assert_eq!(raw.kind(), "ACK");
+
+        //
+        // - a heartbeat bringing nothing new is acknowledged without flushing again
+        //
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 3,
+            digest: None,
+        };
+        let sent = node.out.lock().unwrap().len();
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(node.fsm.commit, 3);
+        assert!(node.out.lock().unwrap().len() > sent);
+        assert_eq!(flushes.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn forced_snapshot() {
+
+        //
+        // - peer #1 follows peer #0 and commits #2 to #4, e.g below any checkpoint boundary
+        //
+        let mut node = Harness::<KV>::new(1, 3, RaftConfig::default(), kv::apply);
+        node.start();
+        let slots: Vec<_> = (0..4u8)
+            .map(|n| (1, Op::SET(vec![n], vec![n]).to_bytes()))
+            .collect();
+        let msg = REPLICATE {
+            id: 0,
+            term: 1,
+            off: 1,
+            age: 0,
+            commit: 5,
+            append: records(2, &slots),
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(node.fsm.commit, 5);
+        assert_eq!(node.fsm.snapshot_off, 1);
+        node.notifications();
+
+        //
+        // - force a snapshot: the log is compacted up to the commit offset
+        //
+        let (tx, rx) = channel();
+        node.post(SNAPSHOT(tx));
+        assert_eq!(rx.try_recv().unwrap(), 5);
+        assert_eq!(node.fsm.tail, 5);
+        assert!(node.notifications().iter().any(|n| match *n {
+            Notification::CHECKPOINT(5) => true,
+            _ => false,
+        }));
+
+        //
+        // - the payload is restored from it after a crash
+        // - a second request with nothing new committed is a no-op
+        //
+        let before = (*node.fsm.payload.read()).clone();
+        let mut node = node.restart();
+        assert_eq!(*node.fsm.payload.read(), before);
+        let (tx, rx) = channel();
+        node.post(SNAPSHOT(tx));
+        assert_eq!(rx.try_recv().unwrap(), 5);
+        assert!(node.notifications().iter().all(|n| match *n {
+            Notification::CHECKPOINT(_) => false,
+            _ => true,
+        }));
+    }
+
+    #[test]
+    fn stale_replication() {
+
+        //
+        // - peer #1 follows peer #0 and commits #2 to #4
+        //
+        let mut node = Harness::<Counter>::new(1, 3, RaftConfig::default(), |p, _| {
+            p.n += 1;
+            Ok(vec![])
+        });
+        node.start();
+        let msg = REPLICATE {
+            id: 0,
+            term: 1,
+            off: 1,
+            age: 0,
+            commit: 5,
+            append: entries(2, &[1, 1, 1, 1]),
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(node.fsm.commit, 5);
+
+        //
+        // - a snapshot at #3 sent earlier shows up late: it must not rewind us
+        //
+        let acked = |node: &Harness<Counter>| {
+            let out = node.out.lock().unwrap().clone();
+            out.iter()
+                .filter_map(|&(_, ref bytes)| {
+                    let raw: RAW = deserialize(bytes).unwrap();
+                    if raw.code == ACK::CODE {
+                        let msg: ACK = deserialize(&raw.msg[..]).unwrap();
+                        Some(msg.ack)
+                    } else {
+                        None
+                    }
+                })
+                .last()
+        };
+        let chunk = |last_index: u64, offset: u64| INSTALL_SNAPSHOT {
+            id: 0,
+            term: 1,
+            last_index,
+            last_term: 1,
+            offset,
+            size: 8,
+            chunk: vec![0; 4],
+            requests: Vec::new(),
+            peers: Vec::new(),
+        };
+        node.out.lock().unwrap().clear();
+        node.deliver(&chunk(3, 0).to_raw(&host(0), &host(1)));
+        node.deliver(&chunk(3, 4).to_raw(&host(0), &host(1)));
+        assert_eq!((node.fsm.tail, node.fsm.head, node.fsm.commit), (1, 5, 5));
+        assert_eq!(acked(&node), Some(5));
+
+        //
+        // - we get rebased at #20 for real, in 2 chunks
+        // - a REPLICATE sent earlier shows up in between: it is processed as usual and does
+        //   not disrupt the snapshot
+        //
+        node.deliver(&chunk(20, 0).to_raw(&host(0), &host(1)));
+        let msg = REPLICATE {
+            id: 0,
+            term: 1,
+            off: 3,
+            age: 1,
+            commit: 5,
+            append: entries(4, &[1]),
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!((node.fsm.tail, node.fsm.head, node.fsm.commit), (1, 5, 5));
+        assert!(node.fsm.installing.is_some());
+        node.deliver(&chunk(20, 4).to_raw(&host(0), &host(1)));
+        assert_eq!((node.fsm.tail, node.fsm.head, node.fsm.commit), (20, 20, 21));
+        assert_eq!(node.fsm.term_at(20), Some(1));
+        assert_eq!(acked(&node), Some(20));
+
+        //
+        // - regular replication from before the snapshot shows up: it points below our tail
+        //   and must be skipped as well
+        //
+        node.out.lock().unwrap().clear();
+        let msg = REPLICATE {
+            id: 0,
+            term: 1,
+            off: 5,
+            age: 1,
+            commit: 5,
+            append: entries(6, &[1]),
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!((node.fsm.tail, node.fsm.head), (20, 20));
+        assert_eq!(acked(&node), Some(20));
+    }
+
+    #[test]
+    fn membership_changes() {
+
+        //
+        // - a FOLLOWER can't change the membership
+        //
+        let mut node = Harness::<Empty>::new(0, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        let (tx, rx) = channel();
+        node.post(CHANGE(MEMBERSHIP::ADD(3, host(3)), tx));
+        assert_eq!(rx.recv().unwrap(), Err(MembershipError::NotLeader));
+
+        //
+        // - force peer #0 to lead at term 1
+        // - adding an existing member is rejected
+        // - add peer #3: the change is appended (along with a no-op) but nothing changes and
+        //   nothing is sent to #3 until it commits
+        //
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        let (tx, rx) = channel();
+        node.post(CHANGE(MEMBERSHIP::ADD(2, host(2)), tx));
+        assert_eq!(rx.recv().unwrap(), Err(MembershipError::Invalid("already a member")));
+        let (tx, rx) = channel();
+        node.post(CHANGE(MEMBERSHIP::ADD(3, host(3)), tx));
+        let off = rx.recv().unwrap().unwrap();
+        assert_eq!((off, node.fsm.head), (2, 3));
+        let heartbeat = |node: &mut Harness<Empty>| -> HashSet<[u8; 32]> {
+            node.out.lock().unwrap().clear();
+            let seq = node.fsm.seq;
+            node.post(TIMEOUT(seq));
+            let out = node.out.lock().unwrap().clone();
+            out.into_iter().map(|(dst, _)| dst).collect()
+        };
+        let voters = |node: &mut Harness<Empty>| -> (Vec<u16>, u64) {
+            let (tx, rx) = channel();
+            node.post(CONFIGURATION(tx));
+            let config = rx.recv().unwrap();
+            (config.voters.iter().map(|&(id, _)| id).collect(), config.index)
+        };
+        assert!(!heartbeat(&mut node).contains(&host(3)));
+        assert_eq!(voters(&mut node), (vec![0, 1, 2], 1));
+
+        //
+        // - peer #1 acknowledges the no-op: the change commits, peer #3 is now a voter and gets
+        //   replicated to
+        //
+        let ack = |id: u16, ack: u64| ACK {
+            id,
+            term: 1,
+            ack,
+            learner: false,
+            digest: None,
+        }.to_raw(&host(id), &host(0));
+        node.deliver(&ack(1, 3));
+        assert_eq!(voters(&mut node), (vec![0, 1, 2, 3], 2));
+        assert!(heartbeat(&mut node).contains(&host(3)));
+
+        //
+        // - remove peer #2: 3 out of 4 voters must now acknowledge, e.g #1 is not enough
+        // - once #3 acknowledges as well nothing is sent to #2 anymore
+        //
+        let (tx, rx) = channel();
+        node.post(CHANGE(MEMBERSHIP::REMOVE(2), tx));
+        assert_eq!(rx.recv().unwrap(), Ok(4));
+        node.deliver(&ack(1, 5));
+        assert!(heartbeat(&mut node).contains(&host(2)));
+        node.deliver(&ack(3, 5));
+        let sent = heartbeat(&mut node);
+        assert!(!sent.contains(&host(2)));
+        assert!(sent.contains(&host(1)) && sent.contains(&host(3)));
+        assert_eq!(voters(&mut node), (vec![0, 1, 3], 4));
+    }
+
+    #[test]
+    fn membership_change_in_progress() {
+
+        //
+        // - force peer #0 to lead at term 1
+        // - add peer #3, then try to remove peer #2 right away: rejected
+        //
+        let mut node = Harness::<Empty>::new(0, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        let change = |node: &mut Harness<Empty>, change: MEMBERSHIP| {
+            let (tx, rx) = channel();
+            node.post(CHANGE(change, tx));
+            rx.recv().unwrap()
+        };
+        assert_eq!(change(&mut node, MEMBERSHIP::ADD(3, host(3))), Ok(2));
+        assert_eq!(
+            change(&mut node, MEMBERSHIP::REMOVE(2)),
+            Err(MembershipError::ConfigChangeInProgress)
+        );
+
+        //
+        // - peer #1 acknowledges the change itself: it is not applied yet (the commit offset
+        //   must go past it), still rejected
+        // - then the no-op chasing it: the change commits and the next one goes through
+        //
+        let ack = |ack: u64| ACK {
+            id: 1,
+            term: 1,
+            ack,
+            learner: false,
+            digest: None,
+        }.to_raw(&host(1), &host(0));
+        node.deliver(&ack(2));
+        assert_eq!(
+            change(&mut node, MEMBERSHIP::REMOVE(2)),
+            Err(MembershipError::ConfigChangeInProgress)
+        );
+        node.deliver(&ack(3));
+        assert_eq!(change(&mut node, MEMBERSHIP::REMOVE(2)), Ok(4));
+
+        //
+        // - peer #1 gets the same change replicated by peer #0 but not committed, then gets
+        //   elected at term 2: the change it inherited is still in progress
+        //
+        let mut node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        let width = <Node<Empty>>::SLOT_BYTES;
+        let bytes = MEMBERSHIP::ADD(3, host(3)).to_bytes(1, 2);
+        let mut append = vec![0; width];
+        append[..bytes.len()].copy_from_slice(&bytes);
+        let msg = REPLICATE {
+            id: 0,
+            term: 1,
+            off: 1,
+            age: 0,
+            commit: 1,
+            append,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(node.fsm.head, 2);
+        node.fsm.term = 2;
+        node.state = State::CNDT(Default::default());
+        for &id in &[0, 2] {
+            let msg = VOTE { id, term: 2 };
+            node.deliver(&msg.to_raw(&host(id), &host(1)));
+        }
+        assert!(node.state == State::LEAD(Default::default()));
+        assert_eq!(
+            change(&mut node, MEMBERSHIP::REMOVE(2)),
+            Err(MembershipError::ConfigChangeInProgress)
+        );
+    }
+
+    #[test]
+    fn membership_heartbeat() {
+
+        //
+        // - force peer #0 to lead at term 1 and add peer #3
+        // - peer #1 acknowledges the change (and its no-op): it commits and peer #3 is
+        //   contacted right away, without waiting for the next heartbeat
+        //
+        let mut node = Harness::<Empty>::new(0, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        let (tx, rx) = channel();
+        node.post(CHANGE(MEMBERSHIP::ADD(3, host(3)), tx));
+        let off = rx.recv().unwrap().unwrap();
+        node.out.lock().unwrap().clear();
+        let seq = node.fsm.seq;
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: off + 1,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        assert!(node.fsm.seq > seq);
+        let out = node.out.lock().unwrap().clone();
+        let codes: Vec<_> = out.iter()
+            .filter(|&&(dst, _)| dst == host(3))
+            .map(|&(_, ref bytes)| deserialize::<RAW>(bytes).unwrap().code)
+            .collect();
+        assert_eq!(codes, vec![PING::CODE, REPLICATE::CODE]);
+
+        //
+        // - so are the other members
+        //
+        for id in 1..3 {
+            assert!(out.iter().any(|&(dst, _)| dst == host(id)));
+        }
+    }
+
+    #[test]
+    fn pre_vote_lease() {
+
+        //
+        // - peer #1 just started and did not hear from anybody yet: no pre-vote
+        //
+        let mut node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        let granted = |node: &mut Harness<Empty>, id: u16| {
+            node.out.lock().unwrap().clear();
+            let msg = PROBE {
+                id,
+                term: 0,
+                head: 1,
+                age: 0,
+            };
+            node.deliver(&msg.to_raw(&host(id), &host(1)));
+            let out = node.out.lock().unwrap().clone();
+            out.iter().any(|&(dst, ref bytes)| {
+                let raw: RAW = deserialize(bytes).unwrap();
+                dst == host(id) && raw.code == AVAILABLE::CODE
+            })
+        };
+        assert!(!granted(&mut node, 0));
+
+        //
+        // - past the election timeout without any LEADER we grant it
+        //
+        node.fsm.contact = Instant::now() - node.fsm.config.election_timeout * 2;
+        assert!(granted(&mut node, 0));
+
+        //
+        // - once following peer #0 we refuse to help peer #2 unseat it
+        //
+        let msg = PING {
+            id: 0,
+            term: 0,
+            commit: 1,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert!(!granted(&mut node, 2));
+    }
+
+    #[test]
+    fn read_index() {
+
+        //
+        // - force peer #0 to lead at term 1
+        // - the read appends a no-op at #2 and is parked until acknowledged
+        //
+        let mut node = Harness::<Empty>::new(0, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        let (tx, rx) = channel();
+        node.post(READ_INDEX(tx));
+        assert_eq!(node.fsm.head, 2);
+        assert!(rx.try_recv().is_err());
+
+        //
+        // - peer #1 acknowledges #2: leadership is confirmed
+        //
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: 2,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        assert_eq!(rx.try_recv().unwrap().unwrap(), 2);
+
+        //
+        // - read again but peer #2 got elected meanwhile: the read fails
+        // - same right away as a FOLLOWER
+        //
+        let (tx, rx) = channel();
+        node.post(READ_INDEX(tx));
+        let msg = PING {
+            id: 2,
+            term: 2,
+            commit: 2,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(2), &host(0)));
+        match rx.try_recv().unwrap() {
+            Err(ReadError::NotLeader(Some(2))) => {}
+            res => panic!("unexpected {:?}", res),
+        }
+        let (tx, rx) = channel();
+        node.post(READ_INDEX(tx));
+        match rx.try_recv().unwrap() {
+            Err(ReadError::NotLeader(Some(2))) => {}
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn raw_display() {
+
+        //
+        // - the enclosed message is decoded, the REPLICATE payload is summarized
+        //
+        let bytes = ACK {
+            id: 1,
+            term: 3,
+            ack: 7,
+            learner: false,
+            digest: None,
+        }.to_raw(&host(1), &host(0));
+        let raw: RAW = deserialize(&bytes).unwrap();
+        let text = format!("{}", raw);
+        assert!(text.contains("ACK"));
+        assert!(text.contains("ack: 7"));
+        let bytes = REPLICATE {
+            id: 0,
+            term: 3,
+            off: 1,
+            age: 0,
+            commit: 1,
+            append: entries(2, &[1, 2]),
+        }.to_raw(&host(0), &host(1));
+        let raw: RAW = deserialize(&bytes).unwrap();
+        let text = format!("{}", raw);
+        assert!(text.contains(&format!("{}B appended", 2 * <Node<Empty>>::SLOT_BYTES)));
+
+        //
+        // - garbage is reported as such
+        //
+        let raw = RAW {
+            version: VERSION,
+            code: ACK::CODE,
+            src: host(0),
+            dst: host(1),
+            msg: vec![1],
+        };
+        assert!(format!("{}", raw).contains("invalid ACK"));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn raw_json() {
+
+        //
+        // - the enclosed message is decoded and rendered along with the envelope
+        // - garbage is not rendered
+        //
+        let bytes = ACK {
+            id: 1,
+            term: 3,
+            ack: 7,
+            learner: false,
+            digest: None,
+        }.to_raw(&host(1), &host(0));
+        let raw: RAW = deserialize(&bytes).unwrap();
+        let json: ::serde_json::Value = ::serde_json::from_str(&raw.to_json().unwrap()).unwrap();
+        assert_eq!(json["kind"], "ACK");
+        assert_eq!(json["src"], "#1");
+        assert_eq!(json["dst"], "#0");
+        assert_eq!(json["msg"]["ack"], 7);
+        assert!(json["msg"]["digest"].is_null());
+        let raw = RAW {
+            version: VERSION,
+            code: ACK::CODE,
+            src: host(0),
+            dst: host(1),
+            msg: vec![1],
+        };
+        assert_eq!(raw.to_json(), None);
+    }
+
+    #[test]
+    fn builder_defaults() {
+
+        //
+        // - nothing set: same as spawn() with a default config
+        //
+        let builder = Raft::builder::<KV>();
+        assert_eq!(builder.id, 0);
+        assert!(builder.peers.is_empty());
+        assert_eq!(builder.config.election_timeout, RaftConfig::default().election_timeout);
+        assert!(!builder.recovering);
+
+        //
+        // - chained setters
+        //
+        let peers: HashMap<_, _> = vec![(0, "a"), (1, "b")].into_iter().collect();
+        let builder = Raft::builder::<KV>()
+            .id(1)
+            .peers(peers)
+            .peer(2, "c")
+            .election_timeout(Duration::from_millis(100))
+            .on_commit(kv::apply)
+            .recover(true);
+        assert_eq!(builder.id, 1);
+        assert_eq!(builder.peers.len(), 3);
+        assert_eq!(builder.peers[&2], "c");
+        assert_eq!(builder.config.election_timeout, Duration::from_millis(100));
+        assert!(builder.apply.is_some() && builder.write.is_none());
+        assert!(builder.recovering);
+    }
+
+    #[test]
+    fn leader_change() {
+
+        //
+        // - peer #0 is heard from first: FOLLOWING then LEADER
+        //
+        let mut node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        node.notifications();
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 1,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        let all = node.notifications();
+        assert!(all.iter().any(|n| match *n {
+            Notification::FOLLOWING => true,
+            _ => false,
+        }));
+        assert!(all.iter().any(|n| match *n {
+            Notification::LEADER(0, ref label) => label == "#0",
+            _ => false,
+        }));
+
+        //
+        // - peer #2 takes over at the next term without us ever campaigning
+        // - exactly one LEADER is emitted, then nothing as long as it stays the same
+        //
+        for _ in 0..2 {
+            let msg = PING {
+                id: 2,
+                term: 2,
+                commit: 1,
+                digest: None,
+            };
+            node.deliver(&msg.to_raw(&host(2), &host(1)));
+        }
+        let all = node.notifications();
+        let n = all.iter()
+            .filter(|n| match **n {
+                Notification::LEADER(2, _) => true,
+                Notification::LEADER(..) | Notification::FOLLOWING => panic!("unexpected {:?}", n),
+                _ => false,
+            })
+            .count();
+        assert_eq!(n, 1);
+    }
+
+    #[test]
+    fn commit_progress() {
+
+        //
+        // - force peer #0 to lead at term 1 and store #2 to #5
+        // - peer #1 acknowledges up to #5, which commits #1 to #4 at once
+        // - the sink gets one COMMIT per entry but a single COMMITTED for the batch
+        //
+        let mut node = Harness::<Empty>::new(0, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        for n in 0..4u8 {
+            let (tx, _) = channel();
+            node.post(STORE_ASYNC(vec![n], tx, None));
+        }
+        node.notifications();
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: 5,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        let all = node.notifications();
+        let commits: Vec<_> = all.iter()
+            .filter_map(|n| match *n {
+                Notification::COMMITTED(off) => Some(off),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(commits, vec![4]);
+        assert_eq!(
+            all.iter()
+                .filter(|n| match **n {
+                    Notification::COMMIT(..) => true,
+                    _ => false,
+                })
+                .count(),
+            4
+        );
+    }
+
+    #[test]
+    fn store_handle() {
+
+        //
+        // - force peer #0 to lead at term 1 and store #2
+        // - its offset is known as soon as it is appended, the outcome only once it either
+        //   commits or is lost
+        //
+        let mut node = Harness::<Empty>::new(0, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        let (tx, rx) = channel();
+        let (appended, off) = channel();
+        node.post(STORE_ASYNC(vec![1], tx, Some(appended)));
+        let mut handle = StoreHandle::new(rx, off);
+        assert!(handle.poll().is_none());
+        assert_eq!(handle.index(), Some(2));
+
+        //
+        // - peer #1 leads at term 2: we step down before #2 commits
+        // - the handle resolves as lost instead of blocking forever
+        //
+        let msg = PING {
+            id: 1,
+            term: 2,
+            commit: 1,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        assert_eq!(handle.poll(), Some(StoreResult::Lost(2)));
+        assert_eq!(handle.index(), Some(2));
+        assert_eq!(handle.wait(), Err(StoreResult::Lost(2)));
+
+        //
+        // - we are not leading anymore: the next store is rejected and never gets an offset
+        //
+        let (tx, rx) = channel();
+        let (appended, off) = channel();
+        node.post(STORE_ASYNC(vec![2], tx, Some(appended)));
+        let handle = StoreHandle::new(rx, off);
+        assert!(handle.index().is_none());
+        assert_eq!(handle.wait(), Err(StoreResult::Rejected));
+    }
+
+    #[test]
+    fn learner_quorum() {
+
+        //
+        // - force peer #0 to lead at term 1 and store #2, #3 and #4
+        // - peer #1 is a learner: its ACK alone commits nothing since peer #2 and
+        //   ourselves are now the only voters
+        //
+        let mut node = Harness::<Empty>::new(0, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        for n in 0..3u8 {
+            node.post(STORE_ASYNC(vec![n], channel().0, None));
+        }
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: 4,
+            learner: true,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        assert_eq!(node.fsm.commit, 1);
+        let (tx, rx) = channel();
+        node.post(CONFIGURATION(tx));
+        let cfg = rx.recv().unwrap();
+        assert_eq!(cfg.learners.iter().map(|m| m.0).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(cfg.voters.len(), 2);
+
+        //
+        // - peer #2 catching up commits everything
+        //
+        let msg = ACK {
+            id: 2,
+            term: 1,
+            ack: 4,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(2), &host(0)));
+        assert_eq!(node.fsm.commit, 4);
+    }
+
+    #[test]
+    fn learner_never_campaigns() {
+
+        //
+        // - peer #1 is a learner following peer #0
+        //
+        let config = RaftConfig {
+            learner: true,
+            ..RaftConfig::default()
+        };
+        let mut node = Harness::<Empty>::new(1, 3, config, |_, _| Ok(vec![]));
+        node.start();
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 1,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(Role::load(&node.fsm.role), Role::Learner);
+
+        //
+        // - peer #0 goes silent: it keeps following upon the liveness timeout and the sink
+        //   only gets IDLE
+        // - it does not grant pre-votes nor campaigns when forced to
+        //
+        node.notifications();
+        node.out.lock().unwrap().clear();
+        let seq = node.fsm.seq;
+        node.post(TIMEOUT(seq));
+        node.post(TIMEOUT(seq));
+        node.fsm.contact = Instant::now() - node.fsm.config.election_timeout * 2;
+        let msg = PROBE {
+            id: 2,
+            term: 1,
+            head: 10,
+            age: 1,
+        };
+        node.deliver(&msg.to_raw(&host(2), &host(1)));
+        node.post(CAMPAIGN);
+        assert!(node.state == State::FLWR(Default::default()));
+        assert!(node.out.lock().unwrap().is_empty());
+        assert!(node.notifications().iter().any(|n| match *n {
+            Notification::IDLE => true,
+            _ => false,
+        }));
+
+        //
+        // - once promoted its ACKs tell the LEADER to count it
+        //
+        let (tx, rx) = channel();
+        node.post(PROMOTE(tx));
+        assert!(rx.recv().unwrap());
+        assert_eq!(Role::load(&node.fsm.role), Role::Follower);
+        let (tx, rx) = channel();
+        node.post(PROMOTE(tx));
+        assert!(!rx.recv().unwrap());
+    }
+
+    #[test]
+    fn status() {
+
+        //
+        // - peer #1 follows peer #0 at term 2 and replicated up to #4
+        //
+        let mut node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        let msg = PING {
+            id: 0,
+            term: 2,
+            commit: 1,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        let msg = REPLICATE {
+            id: 0,
+            term: 2,
+            off: 1,
+            age: 0,
+            commit: 3,
+            append: entries(2, &[2, 2, 2]),
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        let (tx, rx) = channel();
+        node.post(STATUS(tx));
+        let status = rx.recv().unwrap();
+        assert_eq!(status.id, 1);
+        assert_eq!(status.term, 2);
+        assert_eq!(status.role, Role::Follower);
+        assert_eq!(status.leader, Some(0));
+        assert_eq!(status.head, 4);
+        assert_eq!(status.commit, 3);
+    }
+
+    #[test]
+    fn replication_batches() {
+
+        //
+        // - force peer #0 to lead at term 1 with batches of 2 entries and store #2 to #6
+        // - upon the next heartbeat peer #1 gets 3 REPLICATE picking up where the previous
+        //   one ended
+        //
+        let config = RaftConfig {
+            max_batch: Some(2),
+            ..RaftConfig::default()
+        };
+        let mut node = Harness::<Empty>::new(0, 3, config, |_, _| Ok(vec![]));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        for n in 0..5u8 {
+            node.post(STORE_ASYNC(vec![n], channel().0, None));
+        }
+        node.out.lock().unwrap().clear();
+        let seq = node.fsm.seq;
+        node.post(TIMEOUT(seq));
+        let out = node.out.lock().unwrap().clone();
+        let batches: Vec<_> = out.iter()
+            .filter_map(|&(dst, ref bytes)| {
+                let raw: RAW = deserialize(bytes).unwrap();
+                if dst != host(1) || raw.code != REPLICATE::CODE {
+                    return None;
+                }
+                let msg: REPLICATE = deserialize(&raw.msg[..]).unwrap();
+                Some((msg.off, msg.append.len() / <Node<Empty>>::SLOT_BYTES))
+            })
+            .collect();
+        assert_eq!(batches, vec![(1, 2), (3, 2), (5, 1)]);
+        assert_eq!(node.fsm.peers[&1].off, 6);
+    }
+
+    #[test]
+    fn inflight_window() {
+
+        //
+        // - force peer #0 to lead at term 1 with a window of 2 entries and store #2 to #6
+        // - upon the next heartbeat peer #1 only gets #2 and #3
+        //
+        let config = RaftConfig {
+            max_inflight: Some(2),
+            ..RaftConfig::default()
+        };
+        let mut node = Harness::<Empty>::new(0, 3, config, |_, _| Ok(vec![]));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        for n in 0..5u8 {
+            node.post(STORE_ASYNC(vec![n], channel().0, None));
+        }
+        let sent = |node: &mut Harness<Empty>| -> Vec<(u64, usize)> {
+            let out: Vec<_> = node.out.lock().unwrap().drain(..).collect();
+            out.iter()
+                .filter_map(|&(dst, ref bytes)| {
+                    let raw: RAW = deserialize(bytes).unwrap();
+                    if dst != host(1) || raw.code != REPLICATE::CODE {
+                        return None;
+                    }
+                    let msg: REPLICATE = deserialize(&raw.msg[..]).unwrap();
+                    Some((msg.off, msg.append.len() / <Node<Empty>>::SLOT_BYTES))
+                })
+                .collect()
+        };
+        node.out.lock().unwrap().clear();
+        let seq = node.fsm.seq;
+        node.post(TIMEOUT(seq));
+        assert_eq!(sent(&mut node), vec![(1, 2)]);
+
+        //
+        // - peer #1 acknowledges #3: #4 and #5 go out right away
+        // - a late ACK for #2 changes nothing
+        //
+        for &ack in &[3, 2] {
+            let msg = ACK {
+                id: 1,
+                term: 1,
+                ack,
+                learner: false,
+                digest: None,
+            };
+            node.deliver(&msg.to_raw(&host(1), &host(0)));
+        }
+        assert_eq!(sent(&mut node), vec![(3, 2)]);
+        assert_eq!(node.fsm.peers[&1].ack, 3);
+        assert_eq!(node.fsm.peers[&1].off, 5);
+    }
+
+    #[test]
+    fn vote_persistence() {
+
+        //
+        // - persist the log and votes to scratch files (the log file is unlinked right away)
+        // - replicate #2 at term 2 from peer #0, then vote for peer #2 in term 3
+        //
+        let n = FILES.fetch_add(1, Ordering::Relaxed);
+        let log = env::temp_dir().join(format!("rsm.log.{}", n));
+        let path = env::temp_dir().join(format!("rsm.vote.{}", n));
+        let mut node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        let storage = FileStorage::open(log.clone(), path.clone(), Box::new(Nowhere)).unwrap();
+        node.fsm.storage = Box::new(storage);
+        let _ = remove_file(&log);
+        node.start();
+        let msg = REPLICATE {
+            id: 0,
+            term: 2,
+            off: 1,
+            age: 0,
+            commit: 1,
+            append: entries(2, &[2]),
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        let granted = |node: &mut Harness<Empty>, id: u16, term: u64| -> bool {
+            node.state = State::PREV(Default::default());
+            node.out.lock().unwrap().clear();
+            let msg = ADVERTISE {
+                id,
+                term,
+                head: 2,
+                age: 2,
+                transfer: false,
+            };
+            node.deliver(&msg.to_raw(&host(id), &host(1)));
+            let out = node.out.lock().unwrap().clone();
+            out.iter().any(|&(ref dst, ref bytes)| {
+                let raw: RAW = deserialize(bytes).unwrap();
+                *dst == host(id) && raw.code == VOTE::CODE
+            })
+        };
+        assert!(granted(&mut node, 2, 3));
+
+        //
+        // - crash and recover
+        // - the vote and its term are reloaded: peer #0 is denied in term 3, peer #2 is not
+        //
+        let mut node = node.restart();
+        assert_eq!(node.fsm.term, 3);
+        assert_eq!(node.fsm.vote, Some((3, 2)));
+        assert!(!granted(&mut node, 0, 3));
+        assert!(granted(&mut node, 2, 3));
+        assert!(granted(&mut node, 0, 4));
+
+        //
+        // - lose the vote file and recover again
+        // - the term is recovered from the log and we refuse to vote until the next term
+        //
+        let _ = remove_file(&path);
+        let mut node = node.restart();
+        assert_eq!(node.fsm.term, 2);
+        assert!(!granted(&mut node, 0, 2));
+        assert!(granted(&mut node, 0, 3));
+        let _ = remove_file(&path);
+    }
+
+    #[test]
+    fn leadership_transfer() {
+
+        //
+        // - force peer #0 to lead at term 1 and store two entries
+        // - peer #1 is one entry behind, peer #2 is way behind
+        //
+        let mut node = Harness::<Empty>::new(0, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        node.post(STORE_ASYNC(vec![0], channel().0, None));
+        node.post(STORE_ASYNC(vec![0], channel().0, None));
+        let head = node.fsm.head;
+        for &(id, ack) in &[(1, head - 1), (2, 1)] {
+            let msg = ACK {
+                id,
+                term: 1,
+                ack,
+                learner: false,
+                digest: None,
+            };
+            node.deliver(&msg.to_raw(&host(id), &host(0)));
+        }
+        let _ = node.notifications();
+        let timeout_now = |node: &Harness<Empty>| -> Vec<[u8; 32]> {
+            let out = node.out.lock().unwrap().clone();
+            out.iter()
+                .filter(|&&(_, ref bytes)| {
+                    deserialize::<RAW>(bytes).unwrap().code == TIMEOUT_NOW::CODE
+                })
+                .map(|&(dst, _)| dst)
+                .collect()
+        };
+
+        //
+        // - transfer: peer #1 is picked but has to catch up first
+        // - stores are rejected meanwhile
+        //
+        node.out.lock().unwrap().clear();
+        let (tx, rx) = channel();
+        node.post(TRANSFER(tx));
+        assert!(timeout_now(&node).is_empty());
+        let (store, res) = channel();
+        node.post(STORE_ASYNC(vec![1], store, None));
+        assert_eq!(res.recv().unwrap(), StoreResult::Rejected);
+        assert_eq!(node.fsm.head, head);
+
+        //
+        // - peer #1 catches up: it is told to campaign
+        // - we stop heartbeating
+        //
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: head,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        assert_eq!(timeout_now(&node), vec![host(1)]);
+        node.out.lock().unwrap().clear();
+        let seq = node.fsm.seq;
+        node.post(TIMEOUT(seq));
+        assert!(node.out.lock().unwrap().is_empty());
+
+        //
+        // - peer #1 wins and asserts itself
+        // - we step down cleanly (no IDLE) and the transfer completes
+        //
+        let msg = PING {
+            id: 1,
+            term: 2,
+            commit: 1,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        match node.state {
+            State::FLWR(_) => {}
+            _ => panic!("expected FOLLOWER"),
+        }
+        assert_eq!(rx.recv().unwrap(), Some(1));
+        let all = node.notifications();
+        assert!(all.iter().any(|n| match *n {
+            Notification::FOLLOWING => true,
+            _ => false,
+        }));
+        assert!(!all.iter().any(|n| match *n {
+            Notification::IDLE => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn decommission() {
+
+        //
+        // - spawn 3 peers and wait for a leader that commits an entry, e.g one that won't be
+        //   deposed right away by a concurrent election
+        // - a FOLLOWER can't decommission itself
+        //
+        let event = Event::new();
+        let guard = event.guard();
+        let config = RaftConfig {
+            liveness_timeout: Duration::from_millis(500),
+            election_timeout: Duration::from_millis(200),
+            heartbeat_interval: Duration::from_millis(50),
+            transfer_timeout: Duration::from_millis(300),
+            ..RaftConfig::default()
+        };
+        let cluster = cluster::<Empty, _, _>(&guard, 3, config, |_, builder| builder, |_, _| true);
+        let start = Instant::now();
+        let (id, leader) = loop {
+            assert!(start.elapsed() < Duration::from_secs(10), "no stable LEADER");
+            let id = cluster.rafts[0].await_leader(Duration::from_secs(10)).unwrap();
+            let leader = cluster.rafts[id as usize].clone();
+            if let Ok(handle) = leader.store(Vec::new()) {
+                let _ = leader.noop();
+                if handle.wait().is_ok() {
+                    break (id, leader);
+                }
+            }
+        };
+        let successor = (id + 1) % 3;
+        let follower = &cluster.rafts[((id + 2) % 3) as usize];
+        assert_eq!(
+            follower.decommission(successor, Duration::from_secs(1)),
+            Err(MembershipError::NotLeader)
+        );
+
+        //
+        // - decommission the LEADER: it removes itself, hands off to its successor and drains
+        // - the successor leads a 2 voters cluster, though it may only learn the removal
+        //   committed once its own first entry commits
+        //
+        assert_eq!(leader.decommission(successor, Duration::from_secs(10)), Ok(true));
+        assert_eq!(leader.role(), Role::Idle);
+        let raft = &cluster.rafts[successor as usize];
+        assert_eq!(raft.await_leader(Duration::from_secs(10)), Some(successor));
+        assert_eq!(raft.role(), Role::Leader);
+        let start = Instant::now();
+        let voters = loop {
+            let voters: Vec<_> = raft.configuration().voters.iter().map(|&(id, _)| id).collect();
+            if !voters.contains(&id) {
+                break voters;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10), "removal not applied");
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(voters.len(), 2);
+    }
+
+    #[test]
+    fn leadership_takeover() {
+
+        //
+        // - peers #1 and #2 follow peer #0 at term 1
+        //
+        let mut nodes: Vec<_> = (1..3u16)
+            .map(|id| {
+                let config = RaftConfig::default();
+                let mut node = Harness::<Empty>::new(id, 3, config, |_, _| Ok(vec![]));
+                node.start();
+                let msg = PING {
+                    id: 0,
+                    term: 1,
+                    commit: 1,
+                    digest: None,
+                };
+                node.deliver(&msg.to_raw(&host(0), &host(id)));
+                let _ = node.notifications();
+                node
+            })
+            .collect();
+
+        //
+        // - peer #0 hands off to peer #1 which runs for election right away at term 2
+        //
+        let msg = TIMEOUT_NOW { id: 0, term: 1 };
+        nodes[0].deliver(&msg.to_raw(&host(0), &host(1)));
+        match nodes[0].state {
+            State::CNDT(_) => {}
+            _ => panic!("expected CANDIDATE"),
+        }
+        let seq = nodes[0].fsm.seq;
+        nodes[0].post(TIMEOUT(seq));
+        assert_eq!(nodes[0].fsm.term, 2);
+        let out = nodes[0].out.lock().unwrap().clone();
+        let advertise = out.iter()
+            .find(|&&(ref dst, _)| *dst == host(2))
+            .map(|&(_, ref bytes)| bytes.clone())
+            .unwrap();
+        let raw: RAW = deserialize(&advertise).unwrap();
+        assert_eq!(raw.code, ADVERTISE::CODE);
+
+        //
+        // - peer #2 grants its vote even though it still follows peer #0
+        // - it upgrades its term but does not go IDLE
+        //
+        nodes[1].deliver(&advertise);
+        let out = nodes[1].out.lock().unwrap().clone();
+        assert!(out.iter().any(|&(ref dst, ref bytes)| {
+            let raw: RAW = deserialize(bytes).unwrap();
+            *dst == host(1) && raw.code == VOTE::CODE
+        }));
+        assert_eq!(nodes[1].fsm.term, 2);
+        match nodes[1].state {
+            State::FLWR(_) => {}
+            _ => panic!("expected FOLLOWER"),
+        }
+        assert!(nodes[1].notifications().is_empty());
+
+        //
+        // - a regular ADVERTISE is still ignored by a FOLLOWER
+        //
+        nodes[1].out.lock().unwrap().clear();
+        let msg = ADVERTISE {
+            id: 1,
+            term: 3,
+            head: 1,
+            age: 0,
+            transfer: false,
+        };
+        nodes[1].deliver(&msg.to_raw(&host(1), &host(2)));
+        assert!(nodes[1].out.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn protocol_version() {
+
+        //
+        // - wrap our harness in a handle
+        // - a PING from peer #0 running another major version is dropped and counted, while
+        //   a newer minor version goes through
+        //
+        let mut node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        let raft = Raft {
+            fsm: node.this.clone(),
+            pending: Arc::new(AtomicUsize::new(0)),
+            gate: Arc::new(Gate::new(None)),
+            capacity: 1,
+            dropped: Arc::new(AtomicUsize::new(0)),
+            role: Arc::new(AtomicUsize::new(0)),
+            handoff: false,
+            flush_on_drain: false,
+            max_bytes: None,
+            payload: Arc::new(node.fsm.payload.read_only()),
+            logger: Logger::root(Discard, o!()),
+            #[cfg(feature = "auth")]
+            secret: None,
+        };
+        let stamp = |version: u16| -> Vec<u8> {
+            let msg = PING {
+                id: 0,
+                term: 1,
+                commit: 1,
+                digest: None,
+            };
+            let mut raw: RAW = deserialize(&msg.to_raw(&host(0), &host(1))).unwrap();
+            raw.version = version;
+            frame::frame(&serialize(&raw).unwrap())
+        };
+        let version = VERSION + 0x0100;
+        assert_eq!(raft.unpack(&stamp(version)).err(), Some(FeedError::Version(version)));
+        assert_eq!(raft.dropped(), 1);
+        assert!(raft.unpack(&stamp(VERSION + 1)).is_ok());
+        assert_eq!(raft.dropped(), 1);
+
+        //
+        // - the automaton drops it as well
+        // - the newer minor version is accepted
+        //
+        node.start();
+        node.deliver(frame::unframe(&stamp(VERSION + 0x0100)).unwrap());
+        match node.state {
+            State::FLWR(ctx) => assert_eq!(ctx.leader, None),
+            _ => panic!("expected FOLLOWER"),
+        }
+        node.deliver(frame::unframe(&stamp(VERSION + 1)).unwrap());
+        match node.state {
+            State::FLWR(ctx) => assert_eq!(ctx.leader, Some(0)),
+            _ => panic!("expected FOLLOWER"),
+        }
+
+        //
+        // - a message that does not decode is dropped instead of panicking
+        //
+        let raw = RAW {
+            version: VERSION,
+            code: ACK::CODE,
+            src: host(2),
+            dst: host(1),
+            msg: vec![1],
+        };
+        node.deliver(&serialize(&raw).unwrap());
+    }
+
+    #[test]
+    fn corrupted_buffers() {
+
+        //
+        // - wrap our harness in a handle
+        // - a framed PING goes through
+        //
+        let node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        let raft = Raft {
+            fsm: node.this.clone(),
+            pending: Arc::new(AtomicUsize::new(0)),
+            gate: Arc::new(Gate::new(None)),
+            capacity: 1,
+            dropped: Arc::new(AtomicUsize::new(0)),
+            role: Arc::new(AtomicUsize::new(0)),
+            handoff: false,
+            flush_on_drain: false,
+            max_bytes: None,
+            payload: Arc::new(node.fsm.payload.read_only()),
+            logger: Logger::root(Discard, o!()),
+            #[cfg(feature = "auth")]
+            secret: None,
+        };
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 1,
+            digest: None,
+        };
+        let bytes = frame::frame(&msg.to_raw(&host(0), &host(1)));
+        assert!(raft.unpack(&bytes).is_ok());
+
+        //
+        // - flip each bit in turn: every buffer is dropped and counted instead of being decoded
+        //
+        for n in 0..bytes.len() * 8 {
+            let mut corrupted = bytes.clone();
+            corrupted[n / 8] ^= 1 << (n % 8);
+            assert_eq!(raft.unpack(&corrupted).err(), Some(FeedError::Corrupted));
+        }
+        assert_eq!(raft.dropped(), bytes.len() * 8);
+
+        //
+        // - a sound frame holding garbage or some unknown message is rejected as well
+        //
+        let garbage = frame::frame(&[7; 16]);
+        assert_eq!(raft.unpack(&garbage).err(), Some(FeedError::Malformed));
+        let raw = RAW {
+            version: VERSION,
+            code: 42,
+            src: host(0),
+            dst: host(1),
+            msg: Vec::new(),
+        };
+        let unknown = frame::frame(&serialize(&raw).unwrap());
+        assert_eq!(raft.unpack(&unknown).err(), Some(FeedError::UnknownCode(42)));
+    }
+
+    #[test]
+    fn oversized_buffers() {
+
+        //
+        // - accept at most 128B per buffer
+        // - a framed PING goes through
+        //
+        let node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        let raft = Raft {
+            fsm: node.this.clone(),
+            pending: Arc::new(AtomicUsize::new(0)),
+            gate: Arc::new(Gate::new(None)),
+            capacity: 1,
+            dropped: Arc::new(AtomicUsize::new(0)),
+            role: Arc::new(AtomicUsize::new(0)),
+            handoff: false,
+            flush_on_drain: false,
+            max_bytes: Some(128),
+            payload: Arc::new(node.fsm.payload.read_only()),
+            logger: Logger::root(Discard, o!()),
+            #[cfg(feature = "auth")]
+            secret: None,
+        };
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 1,
+            digest: None,
+        };
+        let bytes = frame::frame(&msg.to_raw(&host(0), &host(1)));
+        assert!(raft.unpack(&bytes).is_ok());
+
+        //
+        // - a REPLICATE carrying a few entries is rejected from its header alone
+        // - so is a buffer whose header announces more than the limit (whatever follows)
+        //
+        let msg = REPLICATE {
+            id: 0,
+            term: 1,
+            off: 1,
+            age: 0,
+            commit: 1,
+            append: entries(2, &[1, 1, 1, 1]),
+        };
+        let big = frame::frame(&msg.to_raw(&host(0), &host(1)));
+        let n = big.len() - frame::HEADER_BYTES;
+        assert!(n > 128);
+        assert_eq!(raft.unpack(&big).err(), Some(FeedError::TooLarge(n)));
+        let mut forged = bytes.clone();
+        forged[..4].copy_from_slice(&[0, 0, 1, 0]);
+        assert_eq!(raft.unpack(&forged).err(), Some(FeedError::TooLarge(65536)));
+        assert_eq!(raft.dropped(), 2);
+    }
+
+    #[test]
+    fn replicate_limit() {
+
+        //
+        // - accept at most 2 entries per REPLICATE
+        // - a REPLICATE carrying #2 to #4 is dropped
+        //
+        let config = RaftConfig {
+            max_batch: Some(2),
+            max_replicate_entries: Some(2),
+            ..Default::default()
+        };
+        let mut node = Harness::<Empty>::new(1, 3, config, |_, _| Ok(vec![]));
+        node.start();
+        let msg = REPLICATE {
+            id: 0,
+            term: 1,
+            off: 1,
+            age: 0,
+            commit: 1,
+            append: entries(2, &[1, 1, 1]),
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(node.fsm.head, 1);
+
+        //
+        // - #2 and #3 alone go through
+        //
+        let msg = REPLICATE {
+            append: entries(2, &[1, 1]),
+            ..msg
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(node.fsm.head, 3);
+
+        //
+        // - the configuration must not let us send batches we would reject
+        //
+        let config = RaftConfig {
+            max_replicate_entries: Some(2),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn spawn_errors() {
+
+        //
+        // - an invalid configuration or an id missing from the peers is reported back instead
+        //   of panicking (nothing is started in that case)
+        //
+        let event = Event::new();
+        let guard = event.guard();
+        let peers: HashMap<_, _> = vec![(0, "a"), (1, "b")].into_iter().collect();
+        let config = RaftConfig {
+            max_inflight: Some(0),
+            ..RaftConfig::default()
+        };
+        let res = super::spawn::<_, _, KV, _>(
+            &guard,
+            0,
+            peers.clone(),
+            config,
+            Box::new(Nowhere),
+            |_: &[u8; 32], _: &[u8]| {},
+            kv::apply,
+            Logger::root(Discard, o!()),
+        );
+        match res {
+            Err(RsmError::Config(ConfigError::Invalid(_))) => {}
+            _ => panic!("invalid configuration accepted"),
+        }
+        let res = super::spawn::<_, _, KV, _>(
+            &guard,
+            5,
+            peers,
+            RaftConfig::default(),
+            Box::new(Nowhere),
+            |_: &[u8; 32], _: &[u8]| {},
+            kv::apply,
+            Logger::root(Discard, o!()),
+        );
+        assert!(res.is_err());
+        drop(guard);
+        event.wait();
+    }
+
+    #[test]
+    fn peer_metrics() {
+
+        //
+        // - force peer #0 to lead at term 1: nobody acknowledged anything yet
+        //
+        let mut node = Harness::<Empty>::new(0, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        let metrics = |node: &mut Harness<Empty>| -> Vec<PeerMetric> {
+            let (tx, rx) = channel();
+            node.post(PEER_METRICS(tx));
+            rx.recv().unwrap()
+        };
+        let all = metrics(&mut node);
+        assert_eq!(all.iter().map(|m| m.id).collect::<Vec<_>>(), vec![1, 2]);
+        assert!(all.iter().all(|m| m.acked.is_none() && m.lag.is_none() && m.inflight == 0));
+
+        //
+        // - store one entry and heartbeat: one REPLICATE is in flight to each peer
+        // - peer #1 acknowledges it: it is up to date (zero lag) while peer #2 is still unknown
+        //
+        node.post(STORE_ASYNC(vec![0], channel().0, None));
+        let seq = node.fsm.seq;
+        node.post(TIMEOUT(seq));
+        let head = node.fsm.head;
+        assert!(metrics(&mut node).iter().all(|m| m.inflight == 1));
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: head,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        let all = metrics(&mut node);
+        assert_eq!(all[0].acked, Some(head));
+        assert_eq!(all[0].matched, head);
+        assert_eq!(all[0].lag, Some(0));
+        assert_eq!(all[0].inflight, 0);
+        assert!(all[0].since_ack.is_some());
+        assert_eq!(all[1].lag, None);
+        assert_eq!(all[1].inflight, 1);
+
+        //
+        // - nothing is reported when not leading
+        //
+        node.state = State::FLWR(Default::default());
+        assert!(metrics(&mut node).is_empty());
+    }
+
+    #[test]
+    fn observe() {
+
+        //
+        // - force peer #0 to lead at term 1 and start observing
+        //
+        let mut node = Harness::<Empty>::new(0, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        let (tx, rx) = channel();
+        node.post(OBSERVE(tx));
+        let observer = rx.recv().unwrap();
+        let observations = |observer: &Arc<Sink<Observation>>| -> Vec<Observation> {
+            let mut all = Vec::new();
+            while let Ok(n) = observer.fifo.pop() {
+                all.push(n);
+            }
+            all
+        };
+
+        //
+        // - store and replicate one entry: nothing is observed
+        //
+        node.post(STORE_ASYNC(vec![0], channel().0, None));
+        let seq = node.fsm.seq;
+        node.post(TIMEOUT(seq));
+        assert!(observations(&observer).is_empty());
+
+        //
+        // - peer #1 acknowledges the entry and peer #2 reports being a learner: the membership
+        //   changed and our snapshot now reflects the commit offset
+        //
+        let head = node.fsm.head;
+        for &(id, learner) in &[(1, false), (2, true)] {
+            let msg = ACK {
+                id,
+                term: 1,
+                ack: head,
+                learner,
+                digest: None,
+            };
+            node.deliver(&msg.to_raw(&host(id), &host(0)));
+        }
+        node.post(SNAPSHOT(channel().0));
+        let off = node.fsm.snapshot_off;
+        assert!(off > 1);
+        let all = observations(&observer);
+        assert_eq!(all.len(), 2);
+        match all[0] {
+            Observation::ConfigChanged(ref bytes) => {
+                let config: Configuration = deserialize(bytes).unwrap();
+                assert_eq!(config.learners, vec![(2, host(2))]);
+                assert_eq!(config.voters.len(), 2);
+            }
+            _ => panic!("expected ConfigChanged"),
+        }
+        assert!(match all[1] {
+            Observation::SnapshotTaken(n) => n == off,
+            _ => false,
+        });
+
+        //
+        // - another LEADER at term 2 makes us step down: the new term is reported
+        //
+        let msg = PING {
+            id: 1,
+            term: 2,
+            commit: off,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        let all = observations(&observer);
+        assert_eq!(all.len(), 1);
+        assert!(match all[0] {
+            Observation::TermChanged(2) => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn quorum_loss() {
+
+        //
+        // - a FOLLOWER acknowledges heartbeats
+        //
+        let mut node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        let msg = PING {
+            id: 0,
+            term: 1,
+            commit: 1,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        let out = node.out.lock().unwrap().clone();
+        assert!(out.iter().any(|&(ref dst, ref bytes)| {
+            let raw: RAW = deserialize(bytes).unwrap();
+            *dst == host(0) && raw.code == ACK::CODE
+        }));
+
+        //
+        // - force peer #0 to lead at term 1 with a short liveness timeout and observe it
+        // - peer #1 keeps acknowledging: we still have a quorum and keep leading
+        //
+        let config = RaftConfig {
+            liveness_timeout: Duration::from_millis(50),
+            ..RaftConfig::default()
+        };
+        let mut node = Harness::<Empty>::new(0, 3, config, |_, _| Ok(vec![]));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        let (tx, rx) = channel();
+        node.post(OBSERVE(tx));
+        let observer = rx.recv().unwrap();
+        let ack = ACK {
+            id: 1,
+            term: 1,
+            ack: 1,
+            learner: false,
+            digest: None,
+        };
+        for _ in 0..2 {
+            thread::sleep(Duration::from_millis(30));
+            node.deliver(&ack.to_raw(&host(1), &host(0)));
+        }
+        let seq = node.fsm.seq;
+        node.post(TIMEOUT(seq));
+        assert!(node.state == State::LEAD(Default::default()));
+        let (tx, pending) = channel();
+        node.post(STORE_ASYNC(vec![0], tx, None));
+
+        //
+        // - both peers go silent past the liveness timeout
+        // - stores are rejected right away and the next heartbeat steps us down: the pending
+        //   store is lost, the sink gets IDLE and the observer QuorumLost
+        //
+        let _ = node.notifications();
+        thread::sleep(Duration::from_millis(60));
+        let (tx, rx) = channel();
+        node.post(STORE_ASYNC(vec![1], tx, None));
+        assert_eq!(rx.recv().unwrap(), StoreResult::Rejected);
+        let seq = node.fsm.seq;
+        node.post(TIMEOUT(seq));
+        match node.state {
+            State::FLWR(_) => {}
+            _ => panic!("expected FOLLOWER"),
+        }
+        match pending.recv().unwrap() {
+            StoreResult::Lost(_) => {}
+            res => panic!("unexpected {:?}", res),
+        }
+        assert!(node.notifications().iter().any(|n| match *n {
+            Notification::IDLE => true,
+            _ => false,
+        }));
+        assert!(observer.fifo.pop().ok().map_or(false, |n| match n {
+            Observation::QuorumLost => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn idempotent_stores() {
+
+        //
+        // - force peer #0 to lead at term 1
+        // - store request 7 twice: only #2 is appended, both channels wait on it
+        //
+        let mut node = Harness::<Empty>::new(0, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        let (tx, a) = channel();
+        node.post(STORE_ONCE(7, vec![1], tx, None));
+        let (tx, b) = channel();
+        node.post(STORE_ONCE(7, vec![1], tx, None));
+        let (tx, c) = channel();
+        node.post(STORE_ASYNC(vec![2], tx, None));
+        assert_eq!(node.fsm.head, 3);
+        assert!(a.try_recv().is_err());
+        assert!(b.try_recv().is_err());
+
+        //
+        // - peer #1 acknowledges #3, which commits #2: both channels resolve with it
+        // - a late retry resolves right away without appending anything
+        //
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: 3,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        assert_eq!(a.try_recv().unwrap(), StoreResult::Committed(2, vec![]));
+        assert_eq!(b.try_recv().unwrap(), StoreResult::Committed(2, vec![]));
+        assert!(c.try_recv().is_err());
+        let (tx, d) = channel();
+        node.post(STORE_ONCE(7, vec![1], tx, None));
+        assert_eq!(d.try_recv().unwrap(), StoreResult::Committed(2, vec![]));
+        assert_eq!(node.fsm.head, 3);
+        assert_eq!(node.fsm.requests.get(7), Some(2));
+        assert!(node.fsm.proposed.is_empty());
+    }
+
+    #[test]
+    fn query() {
+
+        //
+        // - wrap our harness in a handle
+        // - the closure reads the payload as applied
+        // - the wrong payload type yields nothing
+        //
+        let node = Harness::<Counter>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        let raft = Raft {
+            fsm: node.this.clone(),
+            pending: Arc::new(AtomicUsize::new(0)),
+            gate: Arc::new(Gate::new(None)),
+            capacity: 1,
+            dropped: Arc::new(AtomicUsize::new(0)),
+            role: Arc::new(AtomicUsize::new(0)),
+            handoff: false,
+            flush_on_drain: false,
+            max_bytes: None,
+            payload: Arc::new(node.fsm.payload.read_only()),
+            logger: Logger::root(Discard, o!()),
+            #[cfg(feature = "auth")]
+            secret: None,
+        };
+        node.fsm.payload.write().n = 3;
+        assert_eq!(raft.query(|payload: &Counter| payload.n), Some(3));
+        assert_eq!(raft.query(|_: &Empty| ()), None);
+    }
+
+    #[test]
+    fn apply_outcomes() {
+
+        //
+        // - force peer #0 to lead at term 1
+        // - store a SET (#2), a CAS whose expectation is not met (#3) and another SET (#4)
+        //   followed by a SET on another key (#5)
+        //
+        let mut node = Harness::<KV>::new(0, 3, RaftConfig::default(), kv::apply);
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        let ops = vec![
+            Op::SET(b"a".to_vec(), b"1".to_vec()),
+            Op::CAS(b"a".to_vec(), None, b"2".to_vec()),
+            Op::SET(b"a".to_vec(), b"3".to_vec()),
+            Op::SET(b"b".to_vec(), b"1".to_vec()),
+        ];
+        let stores: Vec<_> = ops
+            .iter()
+            .map(|op| {
+                let (tx, rx) = channel();
+                node.post(STORE_ASYNC(op.to_bytes(), tx, None));
+                rx
+            })
+            .collect();
+
+        //
+        // - peer #1 acknowledges up to #5, which commits #2 to #4
+        // - the CAS is committed but rejected, each SET returns the previous value
+        //
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: 5,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        assert_eq!(node.fsm.commit, 5);
+        assert_eq!(stores[0].try_recv().unwrap(), StoreResult::Committed(2, vec![]));
+        let failed = stores[1].try_recv().unwrap();
+        match failed {
+            StoreResult::Failed(3, _) => {}
+            ref res => panic!("unexpected {:?}", res),
+        }
+        assert_eq!(failed.session_token(), Some(4));
+        assert_eq!(stores[2].try_recv().unwrap(), StoreResult::Committed(4, b"1".to_vec()));
+        assert_eq!(node.fsm.payload.read().get(b"a"), Some(&b"3"[..]));
+    }
+
+    #[test]
+    fn manual_clock_election() {
+
+        //
+        // - spawn 3 peers wired in-process and sharing a manual clock
+        // - nothing happens until the clock is advanced: no election without time passing
+        //
+        let event = Event::new();
+        let guard = event.guard();
+        let clock = Arc::new(ManualClock::new());
+        let config = RaftConfig {
+            liveness_timeout: Duration::from_millis(300),
+            election_timeout: Duration::from_millis(200),
+            heartbeat_interval: Duration::from_millis(100),
+            transfer_timeout: Duration::from_millis(200),
+            ..RaftConfig::default()
+        };
+        let cluster = cluster::<Empty, _, _>(
+            &guard,
+            3,
+            config,
+            |_, builder| builder.clock(clock.clone()),
+            |_, _| true,
+        );
+        cluster.settle();
+        assert_eq!(cluster.count(Role::Leader), 0);
+
+        //
+        // - step the clock until someone gets elected
+        // - a few heartbeats later there is still exactly one LEADER
+        //
+        let mut steps = 0;
+        while cluster.leader().is_none() {
+            assert!(steps < 400, "no LEADER elected");
+            clock.advance(Duration::from_millis(25));
+            cluster.settle();
+            steps += 1;
+        }
+        for _ in 0..20 {
+            clock.advance(Duration::from_millis(25));
+            cluster.settle();
+        }
+        assert_eq!(cluster.count(Role::Leader), 1);
+        assert_eq!(cluster.count(Role::Follower), 2);
+    }
+
+    #[test]
+    fn reliable_store() {
+
+        //
+        // - spawn 3 peers wired in-process and sharing a manual clock, counting the entries
+        //   they apply
+        // - #1 and #2 drop their own PROBE and ADVERTISE: only #0 may get elected
+        // - #0 drops whatever it sends while cut off
+        //
+        let event = Event::new();
+        let guard = event.guard();
+        let clock = Arc::new(ManualClock::new());
+        let cut = Arc::new(AtomicBool::new(false));
+        let config = RaftConfig {
+            liveness_timeout: Duration::from_millis(300),
+            election_timeout: Duration::from_millis(200),
+            heartbeat_interval: Duration::from_millis(100),
+            transfer_timeout: Duration::from_millis(200),
+            ..RaftConfig::default()
+        };
+        let cluster = cluster::<Counter, _, _>(
+            &guard,
+            3,
+            config,
+            |_, builder| {
+                builder.clock(clock.clone()).on_commit(|p: &mut Counter, bytes: &[u8]| {
+                    if !bytes.is_empty() {
+                        p.n += 1;
+                    }
+                    Ok(vec![])
+                })
+            },
+            |_, _| true,
+        );
+        let rafts = &cluster.rafts;
+        {
+            let cut = cut.clone();
+            rafts[0].intercept(move |direction, _| match direction {
+                Direction::Outbound if cut.load(Ordering::Acquire) => Action::Drop,
+                _ => Action::Pass,
+            });
+        }
+        for raft in &rafts[1..] {
+            raft.intercept(|direction, raw| match (direction, raw.kind()) {
+                (Direction::Outbound, "PROBE") | (Direction::Outbound, "ADVERTISE") => {
+                    Action::Drop
+                }
+                _ => Action::Pass,
+            });
+        }
+        let mut steps = 0;
+        while rafts[0].role() != Role::Leader {
+            assert!(steps < 400, "no LEADER elected");
+            clock.advance(Duration::from_millis(25));
+            cluster.settle();
+            steps += 1;
+        }
+
+        //
+        // - cut #0 off and store from another thread: the entry is appended but #0 steps down
+        //   once it stops hearing from its quorum, before the entry commits
+        // - reconnect #0: it gets elected again and retries with the same request id
+        // - keep stepping the clock and chasing the entries with no-ops until the store
+        //   completes
+        //
+        cut.store(true, Ordering::Release);
+        let head = rafts[0].status().head;
+        let (tx, rx) = channel();
+        {
+            let raft = rafts[0].clone();
+            thread::spawn(move || {
+                let _ = tx.send(raft.store_reliable(7, vec![7], 3, Duration::from_secs(30)));
+            });
+        }
+        while rafts[0].status().head == head {
+            thread::yield_now();
+        }
+        let mut steps = 0;
+        while rafts[0].role() == Role::Leader {
+            assert!(steps < 400, "LEADER did not step down");
+            clock.advance(Duration::from_millis(25));
+            cluster.settle();
+            steps += 1;
+        }
+        cut.store(false, Ordering::Release);
+        let mut steps = 0;
+        let res = loop {
+            assert!(steps < 400, "store did not complete");
+            match rx.try_recv() {
+                Ok(res) => break res,
+                Err(_) => {
+                    clock.advance(Duration::from_millis(25));
+                    cluster.settle();
+                    let _ = rafts[0].noop();
+                    steps += 1;
+                }
+            }
+        };
+        assert!(res.is_ok());
+
+        //
+        // - the entry is applied once on #0 despite being appended twice
+        //
+        assert_eq!(cluster.payloads[0].read().n, 1);
+    }
+
+    #[test]
+    fn reliable_store_handover() {
+
+        //
+        // - spawn 5 peers wired in-process and sharing a manual clock, counting the entries
+        //   they apply (4 of them can elect a LEADER without #0)
+        // - #1 to #4 drop their own PROBE and ADVERTISE (#1 only while pinned): only #0 may
+        //   get elected, then only #1 once unpinned
+        // - #0 drops whatever it sends while cut off
+        //
+        let event = Event::new();
+        let guard = event.guard();
+        let clock = Arc::new(ManualClock::new());
+        let cut = Arc::new(AtomicBool::new(false));
+        let pinned = Arc::new(AtomicBool::new(true));
+        let config = RaftConfig {
+            liveness_timeout: Duration::from_millis(300),
+            election_timeout: Duration::from_millis(200),
+            heartbeat_interval: Duration::from_millis(100),
+            transfer_timeout: Duration::from_millis(200),
+            ..RaftConfig::default()
+        };
+        let cluster = cluster::<Counter, _, _>(
+            &guard,
+            5,
+            config,
+            |_, builder| {
+                builder.clock(clock.clone()).on_commit(|p: &mut Counter, bytes: &[u8]| {
+                    if !bytes.is_empty() {
+                        p.n += 1;
+                    }
+                    Ok(vec![])
+                })
+            },
+            |_, _| true,
+        );
+        let rafts = &cluster.rafts;
+        {
+            let cut = cut.clone();
+            rafts[0].intercept(move |direction, _| match direction {
+                Direction::Outbound if cut.load(Ordering::Acquire) => Action::Drop,
+                _ => Action::Pass,
+            });
+        }
+        for (n, raft) in rafts[1..].iter().enumerate() {
+            let pinned = pinned.clone();
+            raft.intercept(move |direction, raw| match (direction, raw.kind()) {
+                (Direction::Outbound, "PROBE") | (Direction::Outbound, "ADVERTISE")
+                    if n > 0 || pinned.load(Ordering::Acquire) =>
+                {
+                    Action::Drop
+                }
+                _ => Action::Pass,
+            });
+        }
+        let step = || {
+            clock.advance(Duration::from_millis(25));
+            cluster.settle();
+        };
+        let mut steps = 0;
+        while rafts[0].role() != Role::Leader {
+            assert!(steps < 400, "no LEADER elected");
+            step();
+            steps += 1;
+        }
+
+        //
+        // - cut #0 off and store from another thread: the entry is appended but #0 steps down
+        //   once it stops hearing from its quorum, before the entry commits
+        // - unpin #1 so that it takes over, then reconnect #0
+        // - #0 does not lead again: it passes the outcome back instead of retrying
+        //
+        cut.store(true, Ordering::Release);
+        let head = rafts[0].status().head;
+        let (tx, rx) = channel();
+        {
+            let raft = rafts[0].clone();
+            thread::spawn(move || {
+                let _ = tx.send(raft.store_reliable(7, vec![7], 3, Duration::from_secs(30)));
+            });
+        }
+        while rafts[0].status().head == head {
+            thread::yield_now();
+        }
+        pinned.store(false, Ordering::Release);
+        let mut steps = 0;
+        while rafts[1].role() != Role::Leader {
+            assert!(steps < 400, "#1 not elected");
+            step();
+            steps += 1;
+        }
+        cut.store(false, Ordering::Release);
+        let mut steps = 0;
+        let res = loop {
+            assert!(steps < 400, "store did not complete");
+            match rx.try_recv() {
+                Ok(res) => break res,
+                Err(_) => {
+                    step();
+                    steps += 1;
+                }
+            }
+        };
+        match res {
+            Err(StoreResult::Lost(_)) => {}
+            res => panic!("unexpected {:?}", res),
+        }
+
+        //
+        // - store again on the new LEADER with the same request id
+        // - keep stepping the clock and chasing the entries with no-ops until every peer
+        //   applied it, once
+        //
+        let (tx, rx) = channel();
+        {
+            let raft = rafts[1].clone();
+            thread::spawn(move || {
+                let _ = tx.send(raft.store_reliable(7, vec![7], 3, Duration::from_secs(30)));
+            });
+        }
+        let mut steps = 0;
+        let res = loop {
+            assert!(steps < 400, "store did not complete");
+            match rx.try_recv() {
+                Ok(res) => break res,
+                Err(_) => {
+                    step();
+                    let _ = rafts[1].noop();
+                    steps += 1;
+                }
+            }
+        };
+        assert!(res.is_ok());
+        let mut steps = 0;
+        while cluster.payloads.iter().any(|payload| payload.read().n != 1) {
+            assert!(steps < 400, "entry not applied everywhere");
+            step();
+            let _ = rafts[1].noop();
+            steps += 1;
+        }
+    }
+
+    #[test]
+    fn log_range() {
+
+        //
+        // - force peer #0 to lead at term 1 and store 2 entries
+        // - the whole log reads back as the marker at #1 followed by both entries
+        //
+        let mut node = Harness::<Empty>::new(0, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        let (tx, _a) = channel();
+        node.post(STORE_ONCE(7, vec![1], tx, None));
+        let (tx, _b) = channel();
+        node.post(STORE_ASYNC(vec![2], tx, None));
+        let (tx, rx) = channel();
+        node.post(LOG_HEAD(tx));
+        assert_eq!(rx.recv().unwrap(), 3);
+        let (tx, rx) = channel();
+        node.post(LOG_RANGE(0, 100, tx));
+        let all = rx.recv().unwrap();
+        assert_eq!(all.iter().map(|e| e.off).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert!(all[0].marker && !all[1].marker && !all[2].marker);
+        assert_eq!((all[1].term, &all[1].bytes[..], all[1].request), (1, &[1][..], Some(7)));
+        assert_eq!((&all[2].bytes[..], all[2].request), (&[2][..], None));
+
+        //
+        // - once compacted up to #2 only #2 and #3 remain
+        // - an empty or inverted range yields nothing
+        //
+        node.fsm.tail = 2;
+        let (tx, rx) = channel();
+        node.post(LOG_RANGE(1, 3, tx));
+        assert_eq!(rx.recv().unwrap().iter().map(|e| e.off).collect::<Vec<_>>(), vec![2]);
+        let (tx, rx) = channel();
+        node.post(LOG_RANGE(3, 2, tx));
+        assert!(rx.recv().unwrap().is_empty());
+    }
+
+    #[test]
+    fn store_high_water() {
+
+        //
+        // - force peer #0 to lead at term 1 with a high-water mark of 3
+        // - the backlog covers #1 plus 2 entries: writers are held back
+        //
+        let config = RaftConfig {
+            store_high_water: Some(3),
+            ..RaftConfig::default()
+        };
+        let mut node = Harness::<Empty>::new(0, 3, config, |_, _| Ok(vec![]));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        for n in 0..2 {
+            let (tx, _) = channel();
+            node.post(STORE_ASYNC(vec![n], tx, None));
+            assert!(node.fsm.gate.check().is_ok() == (n == 0));
+        }
+        match node.fsm.gate.check() {
+            Err(StoreError::WouldBlock) => {}
+            _ => panic!("store admitted above the high-water mark"),
+        }
+
+        //
+        // - peer #1 acknowledges #3, which commits #2: writers are let through again
+        // - once drained they fail right away instead of blocking
+        //
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: 3,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        assert!(node.fsm.gate.check().is_ok());
+        node.fsm.gate.close();
+        match node.fsm.gate.wait() {
+            Err(StoreError::Unavailable) => {}
+            _ => panic!("blocked writer not released"),
+        }
+    }
+
+    #[test]
+    fn divergent_tails() {
+
+        //
+        // - peer #0 replicates #2 to #6 at term 1 to peer #1, then loses the leadership
+        // - peer #2 gets elected at term 2 with only #2 and #3 from term 1, then appends #4
+        //   and #5 at term 2: our tail from #4 on diverges and is longer than its log
+        //
+        let mut node = Harness::<Empty>::new(1, 3, RaftConfig::default(), |_, _| Ok(vec![]));
+        node.start();
+        let replicate = |term: u64, off: u64, age: u64, terms: &[u64]| REPLICATE {
+            id: if term == 1 { 0 } else { 2 },
+            term,
+            off,
+            age,
+            commit: 1,
+            append: entries(off + 1, terms),
+        };
+        let terms = |node: &Harness<Empty>| -> Vec<u64> {
+            node.fsm.log_range(2, 100).iter().map(|e| e.term).collect()
+        };
+        let msg = replicate(1, 1, 0, &[1, 1, 1, 1, 1]);
+        node.deliver(&msg.to_raw(&host(0), &host(1)));
+        assert_eq!(terms(&node), vec![1, 1, 1, 1, 1]);
+
+        //
+        // - the check mark at #3 matches: everything from the first conflict (#4) on goes,
+        //   including #6 which the new LEADER never sent
+        // - our log is now exactly the LEADER's
+        //
+        let msg = replicate(2, 3, 1, &[2, 2]);
+        node.deliver(&msg.to_raw(&host(2), &host(1)));
+        assert_eq!(node.fsm.head, 5);
+        assert_eq!(terms(&node), vec![1, 1, 2, 2]);
+
+        //
+        // - the LEADER appends #6 and #7, then a copy of the previous REPLICATE shows up late
+        // - nothing conflicts: what the LEADER appended at its term past it is kept
+        //
+        let msg = replicate(2, 5, 2, &[2, 2]);
+        node.deliver(&msg.to_raw(&host(2), &host(1)));
+        let msg = replicate(2, 3, 1, &[2, 2]);
+        node.deliver(&msg.to_raw(&host(2), &host(1)));
+        assert_eq!(node.fsm.head, 7);
+        assert_eq!(terms(&node), vec![1, 1, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn lost_replicate() {
+
+        //
+        // - force peer #0 to lead at term 1 on a manual clock and store #2
+        // - the heartbeat replicates it to both peers but the REPLICATE is lost
+        // - nothing goes out again as long as the resend timeout did not elapse
+        //
+        let config = RaftConfig {
+            resend_timeout: Some(Duration::from_millis(100)),
+            ..RaftConfig::default()
+        };
+        let clock = Arc::new(ManualClock::new());
+        let mut node = Harness::<Empty>::new(0, 3, config, |_, _| Ok(vec![]));
+        node.fsm.clock = clock.clone();
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        node.post(STORE_ASYNC(vec![1], channel().0, None));
+        let heartbeat = |node: &mut Harness<Empty>| -> Vec<(u16, u64)> {
+            node.out.lock().unwrap().clear();
+            let seq = node.fsm.seq;
+            node.post(TIMEOUT(seq));
+            let out: Vec<_> = node.out.lock().unwrap().drain(..).collect();
+            out.iter()
+                .filter_map(|&(dst, ref bytes)| {
+                    let raw: RAW = deserialize(bytes).unwrap();
+                    if raw.code != REPLICATE::CODE {
+                        return None;
+                    }
+                    let msg: REPLICATE = deserialize(&raw.msg[..]).unwrap();
+                    let id = if dst == host(1) { 1 } else { 2 };
+                    Some((id, msg.off))
+                })
+                .collect()
+        };
+        let mut sent = heartbeat(&mut node);
+        sent.sort();
+        assert_eq!(sent, vec![(1, 1), (2, 1)]);
+        assert!(heartbeat(&mut node).is_empty());
+
+        //
+        // - peer #2 acknowledges #2 (it got it after all)
+        // - once the timeout elapses only peer #1 gets #2 again
+        // - the timeout then doubles
+        //
+        let msg = ACK {
+            id: 2,
+            term: 1,
+            ack: 2,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(2), &host(0)));
+        assert!(node.fsm.peers[&2].resend.is_none());
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(heartbeat(&mut node), vec![(1, 1)]);
+        clock.advance(Duration::from_millis(100));
+        assert!(heartbeat(&mut node).is_empty());
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(heartbeat(&mut node), vec![(1, 1)]);
+        assert_eq!(node.fsm.peers[&1].resend.map(|resend| resend.attempts), Some(2));
+
+        //
+        // - peer #1 finally acknowledges #2: the timer is disarmed
+        //
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: 2,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        assert!(node.fsm.peers[&1].resend.is_none());
+        clock.advance(Duration::from_millis(1000));
+        assert!(heartbeat(&mut node).is_empty());
+    }
+
+    #[test]
+    fn lossy_transport() {
+
+        //
+        // - spawn 3 peers wired in-process over a transport dropping 20% of the buffers
+        // - keep storing through whoever leads: every store eventually commits
+        // - the entry at head only commits once something follows it: chase each store with
+        //   a no-op
+        //
+        let event = Event::new();
+        let guard = event.guard();
+        let config = RaftConfig {
+            liveness_timeout: Duration::from_millis(500),
+            election_timeout: Duration::from_millis(200),
+            heartbeat_interval: Duration::from_millis(50),
+            resend_timeout: Some(Duration::from_millis(50)),
+            transfer_timeout: Duration::from_millis(300),
+            ..RaftConfig::default()
+        };
+        let cluster = cluster::<KV, _, _>(
+            &guard,
+            3,
+            config,
+            |_, builder| builder.on_commit(kv::apply),
+            |_, _| thread_rng().gen_range(0, 100) >= 20,
+        );
+        let start = Instant::now();
+        let mut committed = 0;
+        while committed < 50 {
+            assert!(start.elapsed() < Duration::from_secs(30), "no steady progress");
+            if let Some(leader) = cluster.leader() {
+                let op = Op::SET(vec![committed as u8], vec![1]);
+                if let Ok(handle) = leader.store(op.to_bytes()) {
+                    let _ = leader.noop();
+                    if handle.wait().is_ok() {
+                        committed += 1;
+                        continue;
+                    }
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn typed_commands() {
+
+        //
+        // - the apply closure gets decoded commands, empty entries are skipped and garbage is
+        //   rejected
+        //
+        let builder = Raft::builder::<KV>().on_command(kv::execute);
+        let apply = builder.apply.unwrap();
+        let mut payload = KV::default();
+        let op = Op::SET(b"key".to_vec(), b"1".to_vec());
+        assert_eq!(apply(&mut payload, &serialize(&op).unwrap()), Ok(vec![]));
+        assert_eq!(payload.get(b"key"), Some(&b"1"[..]));
+        assert_eq!(apply(&mut payload, &[]), Ok(vec![]));
+        assert!(apply(&mut payload, &[0xff; 3]).is_err());
+
+        //
+        // - spawn 3 peers wired in-process and store a typed command through the LEADER,
+        //   followed by a no-op for it to commit
+        // - it is applied on all of them
+        //
+        let event = Event::new();
+        let guard = event.guard();
+        let config = RaftConfig {
+            liveness_timeout: Duration::from_millis(300),
+            election_timeout: Duration::from_millis(200),
+            heartbeat_interval: Duration::from_millis(50),
+            transfer_timeout: Duration::from_millis(200),
+            ..RaftConfig::default()
+        };
+        let cluster = cluster::<KV, _, _>(
+            &guard,
+            3,
+            config,
+            |_, builder| builder.on_command(kv::execute),
+            |_, _| true,
+        );
+        let payloads = &cluster.payloads;
+        let mut leader = None;
+        for _ in 0..100 {
+            leader = cluster.leader();
+            if leader.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        let leader = leader.expect("no LEADER elected");
+        let op = Op::SET(b"key".to_vec(), b"2".to_vec());
+        let handle = leader.store_command(&op).unwrap();
+        assert!(leader.noop().is_some());
+        assert!(handle.wait().is_ok());
+        for _ in 0..100 {
+            if payloads.iter().all(|payload| payload.read().get(b"key") == Some(&b"2"[..])) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(payloads.iter().all(|payload| payload.read().get(b"key") == Some(&b"2"[..])));
+
+        //
+        // - a command that can't be encoded is refused without storing anything
+        //
+        struct Unencodable;
+        impl Serialize for Unencodable {
+            fn serialize<S: Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+                Err(S::Error::custom("unencodable"))
+            }
+        }
+        let head = leader.log_head();
+        match leader.store_command(&Unencodable).err() {
+            Some(StoreError::Malformed(_)) => {}
+            ref res => panic!("unexpected {:?}", res),
+        }
+        assert_eq!(leader.log_head(), head);
+    }
+
+    #[test]
+    fn dedicated_apply() {
+
+        //
+        // - force peer #0 to lead at term 1 with a slow apply closure running on its own thread
+        // - store #2 to #6 and have peer #1 acknowledge them: the automaton hands #2 to #5
+        //   over and moves on without waiting for them to be applied
+        //
+        let mut node = Harness::<Counter>::new(0, 3, RaftConfig::default(), |p, _| {
+            thread::sleep(Duration::from_millis(50));
+            p.n += 1;
+            Ok(vec![p.n as u8])
+        });
+        let event = Event::new();
+        let guard = event.guard();
+        node.fsm.applier = Some(Applier::spawn(
+            &guard,
+            8,
+            node.fsm.payload.clone(),
+            node.fsm.stage.clone(),
+            node.fsm.pending.clone(),
+            None,
+            Logger::root(Discard, o!()),
+        ));
+        node.start();
+        node.fsm.term = 1;
+        node.state = State::LEAD(Default::default());
+        let mut stores = Vec::new();
+        for n in 0..4u8 {
+            let (tx, rx) = channel();
+            node.post(STORE_ASYNC(vec![n], tx, None));
+            stores.push(rx);
+        }
+        node.post(STORE_ASYNC(vec![4], channel().0, None));
+        let start = Instant::now();
+        let msg = ACK {
+            id: 1,
+            term: 1,
+            ack: 6,
+            learner: false,
+            digest: None,
+        };
+        node.deliver(&msg.to_raw(&host(1), &host(0)));
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert_eq!(node.fsm.commit, 6);
+
+        //
+        // - the stores complete in order with what the apply closure returned (#1 was
+        //   applied first)
+        // - a read is served once the payload reflects the commit offset
+        //
+        for (n, rx) in stores.into_iter().enumerate() {
+            let res = StoreResult::Committed(n as u64 + 2, vec![n as u8 + 2]);
+            assert_eq!(rx.recv().unwrap(), res);
+        }
+        let (tx, rx) = channel();
+        node.post(READ(ReadConsistency::Eventual, tx));
+        assert_eq!(rx.recv().unwrap().unwrap(), 6);
+        assert_eq!(node.fsm.payload.read().n, 5);
+
+        //
+        // - closing lets the apply thread exit
+        //
+        node.fsm.applier.take().unwrap().close();
+        drop(guard);
+        event.wait();
+    }
 }
@@ -19,21 +19,24 @@
 //!
 //!  The automaton maintains some arbitrary user payload and updates it on every commit. Whenever
 //!  the commit offset reaches a new checkpointing window (e.g a fixed number of commits) the
-//!  current payload is serialized into a byte buffer. This buffer is transmitted to any peer
-//!  that needs to be rebased (e.g lags). Please note a read-only lock is passed back to the user
-//!  to access the payload at any time.
+//!  current payload is serialized into a byte buffer. This buffer is transmitted in chunks to
+//!  any peer that needs to be rebased (e.g lags behind our log tail). Please note a read-only
+//!  lock is passed back to the user to access the payload at any time.
 //!
 //!  # Capacity
 //!
 //!   This implementation offers the following properties:
 //!     * log backed up by a circular buffer on disk
 //!     * most of the raft spec is supported
-//!     * up to 64 peers in one cluster
+//!     * up to 65536 peers in one cluster (16 bits ids)
 //!     * all offsets are on 64 bits
 //!     * maximum peer lag up to whatever the underlying file is
-//!     * periodic checkpointing when enough commits went by
+//!     * periodic checkpointing when enough commits went by, or past some entries/bytes
+//!       threshold (see `RaftConfig`)
 //!     * I/O between peers uses byte buffers encoded using bincode
 //!     * pre-vote phase prior to triggering an election
+//!     * single peer membership changes, committed through the log
+//!     * a LEADER cut off from its quorum steps down (heartbeats are acknowledged)
 //!
 //!  # Log implementation
 //!
@@ -46,8 +49,10 @@
 //!
 //!   The automaton maintains a variable log window tracked by a head and tail offsets. The
 //!   commit offset is always located in that window. This window is checkpointed whenever the
-//!   distance between the commit and tail offsets reaches a predefined boundary. The tail offset
-//!   will therefore always be a multiple of this threshold.
+//!   commit offset crosses a predefined boundary (a multiple of a fixed threshold), or earlier
+//!   at the commit offset itself once enough bytes or entries were applied since the last
+//!   checkpoint. The tail offset then moves to that checkpoint, minus whatever entries we are
+//!   configured to retain: it is therefore not aligned on any boundary.
 //!
 //! ```ignore
 //!
@@ -79,21 +84,41 @@
 use bincode::{serialize, deserialize};
 use fsm::automaton::{Automaton, Opcode, Recv};
 use fsm::timer::Timer;
-use memmap::MmapMut;
 use primitives::rwlock::*;
+use raft::applier::{Applier, Job, Stage};
+#[cfg(feature = "auth")]
+use raft::auth;
+use raft::clock::Clock;
+use raft::config::{millis, ConfigError, RaftConfig, RaftConfigDelta, MAX_PRIORITY};
+use raft::dedup::Dedup;
+use raft::frame;
+use raft::gate::Gate;
 use raft::messages::*;
+use raft::metrics::{MessageStats, PeerMetric, RaftMetrics, Throughput};
+use raft::quorum::Quorum;
+use raft::RaftBuilder;
 use raft::sink::*;
 use raft::slots::*;
+use raft::snapshot::SnapshotMeta;
+use raft::storage::{Storage, CAPACITY, ENTRY_BYTES};
 use rand::{Rng, thread_rng};
 use self::Command::*;
 use self::State::*;
+use serde::Serialize;
 use slog::Logger;
+use std::any::Any;
+use std::cell::Cell;
 use std::cmp;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
-use std::str;
-use std::sync::Arc;
-use std::time::Duration;
+use std::mem;
+use std::io::Read;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
 
 macro_rules! display {
     ($self:ident, $fmt:expr $(, $arg:expr)*) => {
@@ -103,18 +128,71 @@ macro_rules! display {
     };
 }
 
-macro_rules! disk {
-    ($off:expr) => {
-        (($off - 1) as usize % FSM::<S, T, U>::RESOLUTION) * FSM::<S, T, U>::SLOT_BYTES
+macro_rules! schedule {
+    ($self:ident, $this:ident, $cmd:expr, $lapse:expr) => {
+        arm(&$self.timer, &*$self.clock, $this, $cmd, $lapse)
+    };
+}
+
+macro_rules! send {
+    ($self:ident, $this:ident, $dst:expr, $msg:expr) => {
+        {
+            //
+            // - wrap the message in its envelope and run the interceptor first if any
+            // - serialize only what actually goes out
+            // - a delayed envelope is handed back to us later via the timer
+            //
+            let dst: [u8; 32] = *$dst;
+            let raw = $msg.envelope(&$self.host, &dst);
+            let action = match $self.interceptor {
+                Some(ref f) => f(Direction::Outbound, &raw),
+                None => Action::Pass,
+            };
+            match action {
+                Action::Pass => {
+                    $self.messages.on_sent(raw.code);
+                    ($self.write)(&dst, &serialize(&raw).expect("RAW is serializable"))
+                }
+                Action::Drop => {}
+                Action::Delay(lapse) => {
+                    schedule!($self, $this, SEND(dst, raw), lapse);
+                }
+            }
+        }
+    };
+}
+
+macro_rules! decode {
+    ($self:ident, $state:ident, $raw:ident) => {
+        {
+            //
+            // - decode the enclosed message
+            // - drop the whole buffer if it does not match what we expect (e.g some other
+            //   protocol version) instead of panicking
+            //
+            match deserialize(&$raw.msg[..]) {
+                Ok(msg) => msg,
+                Err(_) => {
+                    warn!(
+                        &$self.logger,
+                        "               | | dropping invalid {} from {}",
+                        $raw.kind(),
+                        label(&$raw.src)
+                    );
+                    return $state;
+                }
+            }
+        }
     };
 }
 
 macro_rules! read_slot {
     ($self:ident, $off:expr) => {
         {
-            let off = disk!($off);
-            let slot: SLOT =
-                deserialize(&$self.log[off..off + FSM::<S, T, U>::SLOT_BYTES]).unwrap();
+            let off = $off;
+            let mut buf = Vec::with_capacity(FSM::<S, T, U>::SLOT_BYTES);
+            $self.storage.read_entries(off..off + 1, &mut buf).expect("unable to read the log");
+            let slot: SLOT = deserialize(&buf).expect("log slot is valid");
             slot
         }
     };
@@ -123,8 +201,10 @@ macro_rules! read_slot {
 macro_rules! write_slot {
     ($self:ident, $buf:expr, $off:expr) => {
         {
-            let off = disk!($off);
-            $self.log[off..off + $buf.len()].copy_from_slice(&$buf);
+            let buf = $buf;
+            $self.storage.append_entries($off, &buf).expect("unable to write to the log");
+            $self.unsynced = true;
+            $self.reserve_ahead($off);
         }
     };
 }
@@ -132,13 +212,70 @@ macro_rules! write_slot {
 macro_rules! read_range {
     ($self:ident, $buf:ident, $off:expr, $n:expr) => {
         {
-            let start = disk!($off);
-            let end = disk!($off + $n);
-            if end < start {
-                $buf.extend_from_slice(&$self.log[start..]);
-                $buf.extend_from_slice(&$self.log[..end]);
-            } else {
-                $buf.extend_from_slice(&$self.log[start..end]);
+            let off = $off;
+            $self.storage.read_entries(off..off + $n, &mut $buf).expect("unable to read the log");
+        }
+    };
+}
+
+macro_rules! truncate_from {
+    ($self:ident, $off:expr) => {
+        {
+            $self.storage.truncate_from($off).expect("unable to truncate the log");
+        }
+    };
+}
+
+macro_rules! term_at {
+    ($self:ident, $off:expr) => {
+        {
+            //
+            // - term of the entry at that offset, see term_at()
+            // - a rebased peer holds the last entry reflected by our snapshot, which may sit
+            //   right below our tail: use the term at the tail for it (same as the snapshot)
+            //
+            let off = cmp::max($off, $self.tail);
+            read_slot!($self, off).term
+        }
+    };
+}
+
+macro_rules! replicate {
+    ($self:ident, $this:ident, $peer:expr, $upto:expr, $batch:expr) => {
+        {
+            //
+            // - send the entries in ]off, upto] to the peer, at most $batch per REPLICATE, each
+            //   one picking up where the previous one ended
+            // - specify the index+term for the write offset (e.g the offset immediately
+            //   preceding the first replicated entry)
+            // - this will force the FOLLOWER to check its log and flag any conflict
+            // - blank peers will also be able to synch-up this way
+            // - arm the resend timer unless something is already outstanding
+            //
+            if $peer.off < $upto && $peer.resend.is_none() {
+                $peer.resend = Some(Resend {
+                    from: $peer.off,
+                    at: $self.clock.now(),
+                    attempts: 0,
+                });
+            }
+            while $peer.off < $upto {
+                let n = cmp::min($upto - $peer.off, $batch);
+                let mut append = Vec::new();
+                read_range!($self, append, $peer.off + 1, n);
+                let age = term_at!($self, $peer.off);
+                let msg = REPLICATE {
+                    id: $self.id,
+                    term: $self.term,
+                    commit: $self.commit,
+                    off: $peer.off,
+                    age,
+                    append,
+                };
+
+                send!($self, $this, &$peer.host, msg);
+                $peer.off += n;
+                $peer.inflight.push_back($peer.off);
             }
         }
     };
@@ -147,27 +284,22 @@ macro_rules! read_range {
 macro_rules! write_range {
     ($self:ident,$buf:ident, $off:expr, $n:expr) => {
         {
-            let start = disk!($off);
-            let end = disk!($off + $n);
-            if end < start {
-                let cut = FSM::<S, T, U>::RESOLUTION * FSM::<S, T, U>::SLOT_BYTES - start;
-                $self.log[start..].copy_from_slice(&$buf[..cut]);
-                $self.log[..end].copy_from_slice(&$buf[cut..]);
-            } else {
-                $self.log[start..end].copy_from_slice(&$buf);
-            }
+            debug_assert!($buf.len() as u64 <= $n * FSM::<S, T, U>::SLOT_BYTES as u64);
+            $self.storage.append_entries($off, &$buf).expect("unable to write to the log");
+            $self.unsynced = true;
+            $self.reserve_ahead($off + $n - 1);
         }
     };
 }
 
 /// Volatile information maintained while on a given state, typically who we voted
 /// for, who the leader is, etc.
-mod context {
+pub(super) mod context {
 
     #[derive(Copy, Clone, Default, PartialEq)]
     pub struct FLWR {
         pub live: bool,
-        pub leader: Option<u8>,
+        pub leader: Option<u16>,
     }
 
     #[cfg_attr(rustfmt, rustfmt_skip)]
@@ -182,9 +314,9 @@ mod context {
 
     #[derive(Copy, Clone, Default, PartialEq)]
     pub struct CNDT {
-        pub votes: u64,
-        pub pick: Option<u8>,
+        pub pick: Option<u16>,
         pub advertised: bool,
+        pub transfer: bool,
     }
 
     impl super::fmt::Debug for CNDT {
@@ -203,10 +335,449 @@ mod context {
     }
 }
 
+#[allow(non_camel_case_types)]
 pub(super) enum Command {
     BYTES(RAW),
-    STORE(Vec<u8>),
+    STORE_ASYNC(Vec<u8>, Sender<StoreResult>, Option<Sender<u64>>),
+    STORE_ONCE(u64, Vec<u8>, Sender<StoreResult>, Option<Sender<u64>>),
+    NOOP(Sender<Option<u64>>),
+    ADMISSION(Sender<StoreAdmission>),
     TIMEOUT(u64),
+    ACKNOWLEDGE,
+    READ(ReadConsistency, Sender<Result<u64, ReadError>>),
+    READ_AFTER(ReadConsistency, u64, Duration, Sender<Result<u64, ReadError>>),
+    READ_INDEX(Sender<Result<u64, ReadError>>),
+    TERM_AT(u64, Sender<Option<u64>>),
+    LOG_RANGE(u64, u64, Sender<Vec<LogEntry>>),
+    LOG_HEAD(Sender<u64>),
+    METRICS(Sender<RaftMetrics>),
+    PEER_METRICS(Sender<Vec<PeerMetric>>),
+    OBSERVE(Sender<Arc<Sink<Observation>>>),
+    IDLE_DURATION(Sender<Option<Duration>>),
+    AWAIT_LEADER(Duration, Sender<u16>),
+    CONFIGURATION(Sender<Configuration>),
+    SNAPSHOT(Sender<u64>),
+    CHECKPOINTED(u64, Vec<u8>),
+    ELECTION_STATE(Sender<Option<ElectionState>>),
+    STATUS(Sender<Status>),
+    QUORUM(Box<dyn Quorum>),
+    INTERCEPT(Interceptor),
+    RECONFIGURE(RaftConfigDelta, Sender<Result<(), ConfigError>>),
+    DELAYED(RAW),
+    SEND([u8; 32], RAW),
+    PRE_APPLY(Hook),
+    POST_APPLY(Hook),
+    VOTE_HOOK(VoteHook),
+    CAMPAIGN,
+    CLEAR,
+    CHANGE(MEMBERSHIP, Sender<Result<u64, MembershipError>>),
+    DECOMMISSION(u16, Sender<Result<u64, MembershipError>>, Sender<Option<u16>>),
+    PROMOTE(Sender<bool>),
+    TRANSFER(Sender<Option<u16>>),
+}
+
+/// Which way a buffer is going, see `Raft::intercept()`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Direction {
+    /// Received from a peer.
+    Inbound,
+    /// About to be sent to a peer.
+    Outbound,
+}
+
+/// What to do with an intercepted buffer, see `Raft::intercept()`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Action {
+    /// Process or send the buffer as usual.
+    Pass,
+    /// Discard the buffer.
+    Drop,
+    /// Hold the buffer back for the specified lapse of time.
+    Delay(Duration),
+}
+
+/// Handler invoked on every buffer exchanged with the peers, see `Raft::intercept()`.
+pub type Interceptor = Box<dyn Fn(Direction, &RAW) -> Action + Send>;
+
+/// Handler invoked with the offset and bytes of each committed entry, see
+/// `Raft::on_pre_apply()` and `Raft::on_post_apply()`.
+pub type Hook = Box<dyn Fn(u64, &[u8]) + Send>;
+
+fn arm(
+    timer: &Timer<Command>,
+    clock: &dyn Clock,
+    this: &Arc<Automaton<Command>>,
+    cmd: Command,
+    lapse: Duration,
+) -> () {
+
+    //
+    // - arm the timeout on the real timer unless the clock is driven by hand, in which case
+    //   it fires once the clock is advanced past its deadline
+    //
+    match clock.manual() {
+        Some(clock) => {
+            let this = this.clone();
+            let mut cmd = Some(cmd);
+            clock.arm(
+                lapse,
+                Box::new(move || {
+                    if let Some(cmd) = cmd.take() {
+                        let _ = this.post(cmd);
+                    }
+                }),
+            );
+        }
+        None => timer.schedule(this.clone(), cmd, lapse),
+    }
+}
+
+/// Lapse of time elapsed between two instants, None if the second one precedes the first one.
+/// All the timing is based on the monotonic clock (e.g `Instant`, never the wall clock) so that
+/// this should not happen. Anything time bounded (stale reads, etc) must still treat None as
+/// expired: an unreliable clock must never extend a bound.
+fn since(from: Instant, to: Instant) -> Option<Duration> {
+    if to >= from {
+        Some(to - from)
+    } else {
+        None
+    }
+}
+
+pub(super) fn run_hooks(logger: &Logger, hooks: &[Hook], off: u64, bytes: &[u8]) -> () {
+
+    //
+    // - run each hook in registration order
+    // - isolate panics so that a faulty hook can't take the automaton down
+    //
+    for (n, hook) in hooks.iter().enumerate() {
+        if catch_unwind(AssertUnwindSafe(|| hook(off, bytes))).is_err() {
+            warn!(logger, "               | | hook #{} panicked on offset #{}", n, off);
+        }
+    }
+}
+
+/// What a peer knows when processing a vote request, see `Raft::on_vote_request()`.
+#[derive(Debug, Copy, Clone)]
+pub struct VoteContext {
+    /// Id of the candidate.
+    pub candidate: u16,
+    /// Term the candidate is running for.
+    pub term: u64,
+    /// Head offset and term of the candidate's log.
+    pub head: u64,
+    pub age: u64,
+    /// Head offset and term of our log.
+    pub local_head: u64,
+    pub local_age: u64,
+    /// Peer we already voted for in that term, if any.
+    pub pick: Option<u16>,
+    /// Decision taken by the standard raft rule.
+    pub granted: bool,
+}
+
+/// Decision returned by a vote hook.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum VoteDecision {
+    /// Use the standard raft rule.
+    Default,
+    /// Grant the vote no matter what.
+    Grant,
+    /// Deny the vote no matter what.
+    Deny,
+}
+
+/// Handler overriding the vote decision, see `Raft::on_vote_request()`.
+pub type VoteHook = Box<dyn Fn(&VoteContext) -> VoteDecision + Send>;
+
+/// Consistency level requested when reading the payload via `Raft::read()`. Each level trades
+/// latency for freshness. Whatever the level the payload is always read from the local peer.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ReadConsistency {
+    /// Only the LEADER may serve the read, once a quorum acknowledged a no-op appended for it
+    /// (see `Raft::read_index()`). The payload reflects every entry committed by the cluster at
+    /// the time the read was issued. Any other peer, or a LEADER deposed meanwhile, fails with
+    /// `NotLeader`.
+    Linearizable,
+    /// The LEADER or any FOLLOWER that heard from its LEADER within the specified lapse of time
+    /// may serve the read. The payload may lag the cluster by at most that lapse. Candidates and
+    /// peers without a known LEADER fail with `Stale`.
+    Bounded(Duration),
+    /// Any peer serves the read using whatever it has applied so far, regardless of its state.
+    /// The payload may be arbitrarily stale (for instance on a partitioned peer).
+    Eventual,
+}
+
+/// Reasons for `Raft::read()` to fail.
+#[derive(Debug)]
+pub enum ReadError {
+    /// This peer is not leading. The id of the LEADER is passed along if known.
+    NotLeader(Option<u16>),
+    /// This peer cannot guarantee its payload is within the requested staleness bound.
+    Stale,
+    /// The automaton is not running anymore.
+    Unavailable,
+    /// The payload did not reach the requested session token in time, see `Raft::read_after()`.
+    Timeout,
+}
+
+/// Reason for the apply closure to reject an entry (e.g a malformed command), passed on to
+/// whoever stored it, see `StoreResult::Failed`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApplyError(pub String);
+
+/// Outcome of a `Raft::store_async()`, delivered once known.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoreResult {
+    /// The entry was appended at that offset, committed and applied. The bytes are whatever the
+    /// apply closure returned (nothing for a retry deduplicated by `Raft::store_once()`).
+    Committed(u64, Vec<u8>),
+    /// The entry was appended at that offset and committed (e.g it consumed its slot) but the
+    /// apply closure rejected it.
+    Failed(u64, ApplyError),
+    /// The entry was appended at that offset but this peer stopped leading before it committed.
+    /// It may or may not eventually commit.
+    Lost(u64),
+    /// The entry was not appended (this peer is not leading, lost its quorum, its log is full,
+    /// etc).
+    Rejected,
+}
+
+impl StoreResult {
+    /// Commit offset reflecting the entry, to pass to `Raft::read_after()` in order to read
+    /// our own write from any peer. None unless the entry committed (whether the apply closure
+    /// accepted it or not).
+    pub fn session_token(&self) -> Option<u64> {
+        match *self {
+            StoreResult::Committed(off, _) | StoreResult::Failed(off, _) => Some(off + 1),
+            _ => None,
+        }
+    }
+}
+
+/// Outcome of a `Raft::store()`, which is either polled or waited for.
+pub struct StoreHandle {
+    rx: Receiver<StoreResult>,
+    appended: Receiver<u64>,
+    off: Cell<Option<u64>>,
+    res: Option<StoreResult>,
+}
+
+impl StoreHandle {
+    pub(super) fn new(rx: Receiver<StoreResult>, appended: Receiver<u64>) -> Self {
+        StoreHandle {
+            rx,
+            appended,
+            off: Cell::new(None),
+            res: None,
+        }
+    }
+
+    /// Offset the entry was appended at, known as soon as the automaton appends it (e.g well
+    /// before it commits). None until then, or if the entry was rejected.
+    pub fn index(&self) -> Option<u64> {
+        if self.off.get().is_none() {
+            self.off.set(self.appended.try_recv().ok());
+        }
+        self.off.get()
+    }
+
+    /// Returns the outcome if known, without blocking.
+    pub fn poll(&mut self) -> Option<StoreResult> {
+        if self.res.is_none() {
+            match self.rx.try_recv() {
+                Ok(res) => self.res = Some(res),
+                Err(TryRecvError::Disconnected) => self.res = Some(StoreResult::Rejected),
+                Err(TryRecvError::Empty) => {}
+            }
+        }
+        self.res.clone()
+    }
+
+    /// Blocks until the entry is committed and applied, in which case its offset and whatever
+    /// the apply closure returned are passed back. Any other outcome is returned as an error,
+    /// including `Failed` when the apply closure rejected it and `Lost` when this peer stopped
+    /// leading first (the entry may have been truncated). An automaton going down rejects it.
+    pub fn wait(mut self) -> Result<(u64, Vec<u8>), StoreResult> {
+        let res = match self.res.take() {
+            Some(res) => res,
+            None => self.rx.recv().unwrap_or(StoreResult::Rejected),
+        };
+        match res {
+            StoreResult::Committed(off, bytes) => Ok((off, bytes)),
+            res => Err(res),
+        }
+    }
+}
+
+/// Snapshot of what `store()` would do with an entry, see `Raft::can_store()`.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct StoreAdmission {
+    /// This peer is leading.
+    pub leader: bool,
+    /// This peer is quarantined and refuses writes.
+    pub quarantined: bool,
+    /// Number of entries appended but not committed yet.
+    pub backlog: u64,
+    /// Number of entries that can still be appended before the log is full.
+    pub room: u64,
+}
+
+impl StoreAdmission {
+    /// Whether an entry would be appended.
+    pub fn accepted(&self) -> bool {
+        self.leader && !self.quarantined && self.room > 0
+    }
+}
+
+/// Reasons for `Raft::store_async()` to fail right away.
+#[derive(Debug)]
+pub enum StoreError {
+    /// Too many pending stores, try again later.
+    QueueFull,
+    /// Too many entries waiting to commit, try again later (see
+    /// `RaftConfig::store_high_water`).
+    WouldBlock,
+    /// The automaton is not running anymore.
+    Unavailable,
+    /// The command passed to `Raft::store_command()` can't be encoded (the reason is passed
+    /// along).
+    Malformed(String),
+}
+
+/// Reasons for `Raft::add_peer()` and `Raft::remove_peer()` to fail.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MembershipError {
+    /// This peer is not leading.
+    NotLeader,
+    /// A previous membership change did not commit yet, try again later.
+    ConfigChangeInProgress,
+    /// The change does not apply to the current configuration (the reason is passed along).
+    Invalid(&'static str),
+    /// The change could not be appended (the log is full, this peer is quarantined, lost its
+    /// quorum or is transferring its leadership).
+    Rejected,
+    /// The automaton is not running anymore.
+    Unavailable,
+}
+
+/// Reasons for `Raft::feed()` to reject a buffer.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FeedError {
+    /// The buffer got corrupted or truncated in transit (its frame does not check out).
+    Corrupted,
+    /// The buffer is not signed with the cluster secret.
+    Unauthenticated,
+    /// The buffer does not decode into a message.
+    Malformed,
+    /// The message code is unknown (e.g the peer runs a newer protocol version).
+    UnknownCode(u8),
+    /// The peer runs an incompatible protocol version (passed along).
+    Version(u16),
+    /// The buffer is larger than `RaftConfig::max_message_bytes` (its size is passed along).
+    TooLarge(usize),
+    /// The automaton is not running anymore.
+    Unavailable,
+}
+
+/// Cluster membership, see `Raft::configuration()`. Each member is described by its id and
+/// host (padded to 32 bytes).
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Configuration {
+    /// Voting members, including the local peer, sorted by id.
+    pub voters: Vec<(u16, [u8; 32])>,
+    /// Non-voting members replicated to but left out of the quorum, sorted by id (see
+    /// `RaftConfig::learner` and `Raft::promote()`).
+    pub learners: Vec<(u16, [u8; 32])>,
+    /// Voting members not holding the payload (not supported yet).
+    pub witnesses: Vec<(u16, [u8; 32])>,
+    /// Offset of the membership change this configuration reflects (#1 for the one the
+    /// cluster was spawned with).
+    pub index: u64,
+}
+
+/// Progress of the election this peer is running, see `Raft::election_state()`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ElectionState {
+    /// Term the election is held for.
+    pub term: u64,
+    /// Peers who granted their vote, including the local peer, sorted by id.
+    pub granted: Vec<u16>,
+    /// Peers who did not respond yet, sorted by id.
+    pub pending: Vec<u16>,
+}
+
+/// Consistent snapshot of where a peer stands, see `Raft::status()`.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Status {
+    /// Local peer id.
+    pub id: u16,
+    /// Current term.
+    pub term: u64,
+    /// Current commit offset, e.g all the entries below it are applied.
+    pub commit: u64,
+    /// Current role.
+    pub role: Role,
+    /// LEADER this peer knows of, if any (itself when leading).
+    pub leader: Option<u16>,
+    /// Current log head offset, e.g where the next entry is appended.
+    pub head: u64,
+}
+
+/// Entry read back from the log, see `Raft::log_range()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    /// Log offset.
+    pub off: u64,
+    /// Term the entry was appended at.
+    pub term: u64,
+    /// Whether this is a marker written by the automaton itself (e.g at #1), as opposed to an
+    /// entry stored by a client.
+    pub marker: bool,
+    /// Entry bytes (the serialized marker for markers).
+    pub bytes: Vec<u8>,
+    /// Client request id the entry was stored with, if any (see `Raft::store_once()`).
+    pub request: Option<u64>,
+}
+
+/// Current role of a peer, see `Raft::role()`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Role {
+    /// The automaton is not running (not started yet or exited).
+    Idle,
+    Follower,
+    /// Either pre-voting or running for election.
+    Candidate,
+    Leader,
+    /// Non-voting FOLLOWER catching up, see `RaftConfig::learner`.
+    Learner,
+}
+
+impl Default for Role {
+    fn default() -> Self {
+        Role::Idle
+    }
+}
+
+impl Role {
+    fn of(state: State, learner: bool) -> Self {
+        match state {
+            PREV(_) | CNDT(_) => Role::Candidate,
+            FLWR(_) if learner => Role::Learner,
+            FLWR(_) => Role::Follower,
+            LEAD(_) => Role::Leader,
+        }
+    }
+
+    pub(super) fn load(role: &AtomicUsize) -> Self {
+        match role.load(Ordering::Acquire) {
+            1 => Role::Follower,
+            2 => Role::Candidate,
+            3 => Role::Leader,
+            4 => Role::Learner,
+            _ => Role::Idle,
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -250,6 +821,18 @@ impl Default for State {
 /// automaton implementating the protocol is not exposed.
 pub struct Raft {
     pub(super) fsm: Arc<Automaton<Command>>,
+    pub(super) pending: Arc<AtomicUsize>,
+    pub(super) gate: Arc<Gate>,
+    pub(super) capacity: usize,
+    pub(super) dropped: Arc<AtomicUsize>,
+    pub(super) role: Arc<AtomicUsize>,
+    pub(super) handoff: bool,
+    pub(super) flush_on_drain: bool,
+    pub(super) max_bytes: Option<usize>,
+    pub(super) payload: Arc<dyn Any + Send + Sync>,
+    pub(super) logger: Logger,
+    #[cfg(feature = "auth")]
+    pub(super) secret: Option<Vec<u8>>,
 }
 
 pub(super) struct Peer {
@@ -260,37 +843,109 @@ pub(super) struct Peer {
     pub(super) off: u64,
     /// Last acknowledged offset (e.g commit offset).
     pub(super) ack: u64,
+    /// When we last rebased that peer with a snapshot, if it did not acknowledge it yet.
+    pub(super) rebasing: Option<Instant>,
+    /// Whether that peer is a learner, as reported by its latest ACK.
+    pub(super) learner: bool,
+    /// Whether the payload digest carried by its latest comparable ACK differs from ours.
+    pub(super) diverged: bool,
+    /// Offset carried by its latest ACK and when we got it (None until it acknowledges).
+    pub(super) acked: Option<(u64, Instant)>,
+    /// End offsets of the REPLICATE sent to it and not acknowledged yet, oldest first.
+    pub(super) inflight: VecDeque<u64>,
+    /// Resend timer armed while REPLICATE are outstanding, see `RaftConfig::resend_timeout`.
+    pub(super) resend: Option<Resend>,
+}
+
+/// Outstanding replication to a peer, e.g what to send again if it does not acknowledge it.
+#[derive(Copy, Clone)]
+pub(super) struct Resend {
+    /// Write offset to rewind to, e.g the latest offset the peer acknowledged (or where the
+    /// replication started if it did not acknowledge anything since).
+    pub(super) from: u64,
+    /// When the timer was (re-)armed.
+    pub(super) at: Instant,
+    /// Number of resends since the peer last made progress.
+    pub(super) attempts: u32,
+}
+
+impl Peer {
+    fn new(host: [u8; 32]) -> Self {
+        Peer {
+            host,
+            off: 1,
+            ack: 0,
+            rebasing: None,
+            learner: false,
+            diverged: false,
+            acked: None,
+            inflight: VecDeque::new(),
+            resend: None,
+        }
+    }
+}
+
+/// Snapshot received from the LEADER chunk after chunk, see `INSTALL_SNAPSHOT`.
+pub(super) struct Installing {
+    /// Offset of the last entry the snapshot reflects.
+    pub(super) last_index: u64,
+    /// Term of that entry.
+    pub(super) last_term: u64,
+    /// Client request ids the snapshot reflects.
+    pub(super) requests: Vec<(u64, u64)>,
+    /// Cluster membership as of the snapshot.
+    pub(super) peers: Vec<(u16, [u8; 32])>,
+    /// Chunks received so far.
+    pub(super) bytes: Vec<u8>,
+}
+
+/// Leadership transfer in progress, see `Raft::transfer_leadership()`.
+pub(super) struct Transfer {
+    /// Peer we are handing the leadership off to.
+    pub(super) target: u16,
+    /// When to give up and lead again.
+    pub(super) deadline: Instant,
+    /// Whether the target was told to campaign (e.g it caught up).
+    pub(super) sent: bool,
+    /// Completion channel, passed the new LEADER (if any).
+    pub(super) tx: Sender<Option<u16>>,
 }
 
 /// Trait defining the raft automaton payload.
 pub trait Payload {
-    ///
+    /// Serializes the whole payload, invoked upon checkpointing. The log below the checkpoint
+    /// is then compacted.
     fn flush(&self) -> Vec<u8> {
         Vec::new()
     }
 
-    ///
+    /// Restores the payload from the output of `flush()`, invoked upon recovery or when the
+    /// LEADER rebases a peer lagging too far behind.
     fn reset(&mut self, _bytes: &[u8]) -> () {}
+
+    /// Optional digest of the payload, compared across peers at the same commit offset to
+    /// detect divergence. None disables the check.
+    fn checksum(&self) -> Option<u64> {
+        None
+    }
 }
 
-///  todo items:
-///    o) membership add()/remove()
-///    o) the latest vote must be persisted (e.g persist an empty file under /tmp for a
-///       given term and erase it as soon as we're not candidate anymore ?)
 pub(super) struct FSM<S, T, U>
 where
     S: 'static + Send + Fn(&[u8; 32], &[u8]) -> (),
-    T: 'static + Send + Fn(&mut U, &[u8]) -> (),
+    T: 'static + Send + Fn(&mut U, &[u8]) -> Result<Vec<u8>, ApplyError>,
     U: 'static + Send + Default + Payload,
 {
-    /// Local peer index in [0, 64].
-    pub(super) id: u8,
+    /// Local peer index.
+    pub(super) id: u16,
     /// Local network identifier.
     pub(super) host: [u8; 32],
     /// Sequence counter, used to disambiguiate timeouts.
     pub(super) seq: u64,
     /// Current peer term, persisted.
     pub(super) term: u64,
+    /// Latest vote cast (term + candidate id), persisted before being sent out.
+    pub(super) vote: Option<(u64, u16)>,
     /// Last log offset we maintain, starts at #1.
     pub(super) head: u64,
     /// First log offset we maintain, starts at #1.
@@ -299,22 +954,114 @@ where
     pub(super) age: u64,
     /// Current commit offset, as reported by a quorum, starts at #1.
     pub(super) commit: u64,
+    /// Last time we heard from a LEADER (used to bound stale reads).
+    pub(super) contact: Instant,
+    /// Linearizable reads parked until a quorum acknowledges their no-op
+    pub(super) confirming: VecDeque<(u64, Sender<Result<u64, ReadError>>)>,
+    /// Since when we don't know of any LEADER (None if we follow one or lead)
+    pub(super) idle_since: Option<Instant>,
+    /// Last LEADER reported to the sink with `LEADER`
+    pub(super) reported: Option<u16>,
+    /// Leadership transfer in progress, if any
+    pub(super) transfer: Option<Transfer>,
+    /// Channels waiting for a LEADER to be known along with when their caller gives up on
+    /// them, see `Raft::await_leader()`
+    pub(super) watchers: Vec<(Instant, Sender<u16>)>,
+    /// Destination of the next coalesced ACK, if any is pending.
+    pub(super) ack_to: Option<[u8; 32]>,
     /// Map of peer id <-> host + offsets
-    pub(super) peers: HashMap<u8, Peer>,
+    pub(super) peers: HashMap<u16, Peer>,
+    /// Offset of the membership change our configuration reflects (#1 initially)
+    pub(super) configured: u64,
+    /// Offset of the latest membership change in our log while leading (appended by us or
+    /// inherited from the previous LEADER)
+    pub(super) changing: Option<u64>,
+    /// Set once a membership change removing us commits: we then never campaign again
+    pub(super) removed: bool,
+    /// Set once a membership change commits, until the LEADER heartbeats right away
+    pub(super) reconfigured: bool,
+    /// Voter to hand our leadership off to once our removal commits along with the channel
+    /// passed the new LEADER, see `Raft::decommission()`
+    pub(super) successor: Option<(u16, Sender<Option<u16>>)>,
+    /// Peers who granted us their (pre-)vote during the current election cycle
+    pub(super) ballots: HashSet<u16>,
     /// Internal timer automaton used to enforce timeouts
     pub(super) timer: Arc<Timer<Command>>,
-    /// Memory mapped log file on disk, used as a circular buffer
-    pub(super) log: MmapMut,
+    /// Time source, see `Clock`
+    pub(super) clock: Arc<dyn Clock>,
+    /// Where the log, the latest vote and snapshots are persisted
+    pub(super) storage: Box<dyn Storage>,
     /// Notification sink
     pub(super) sink: Arc<Sink>,
+    /// Observation sink, only set once `Raft::observe()` is invoked
+    pub(super) observer: Option<Arc<Sink<Observation>>>,
+    /// Last term reported to the observer
+    pub(super) observed: u64,
+    /// When we last got elected (peers which did not acknowledge anything since count as
+    /// heard from at that point)
+    pub(super) elected: Instant,
     /// Payload updated upon commit, used for checkpointing
     pub(super) payload: Arc<RWLock<U>>,
     /// Latest snapshot, e.g serialized payload at the last checkpointing boundary
     pub(super) snapshot: Vec<u8>,
+    /// Commit offset the latest snapshot reflects (e.g all entries below it are included)
+    pub(super) snapshot_off: u64,
+    /// Snapshot the LEADER is rebasing us with, while its chunks come in
+    pub(super) installing: Option<Installing>,
+    /// Client request ids the latest snapshot reflects, shipped along with it
+    pub(super) snapshot_requests: Vec<(u64, u64)>,
+    /// Whether to recover from the log and latest snapshot upon starting
+    pub(super) recovering: bool,
+    /// Number of bytes applied since the latest snapshot
+    pub(super) uncompacted: usize,
+    /// Number of bytes of the log allocated upfront so far, see `RaftConfig::log_reserve_bytes`
+    pub(super) reserved: u64,
+    /// Whether entries were written to the log since it was last flushed
+    pub(super) unsynced: bool,
+    /// Set once a snapshot threshold is crossed, until the checkpoint is taken
+    pub(super) compaction_due: bool,
+    /// Client request ids reflected by the checkpoint the apply thread is taking, if any
+    pub(super) checkpointing: Option<Vec<(u64, u64)>>,
+    /// Tunables specified upon spawning
+    pub(super) config: RaftConfig,
     /// Network out closure
     pub(super) write: S,
-    /// User payload update closure
-    pub(super) apply: T,
+    /// User payload update closure plus the handlers invoked before and after applying each
+    /// committed entry
+    pub(super) stage: Arc<Mutex<Stage<T>>>,
+    /// Dedicated apply thread, see `RaftConfig::apply_queue`
+    pub(super) applier: Option<Applier>,
+    /// Policy deciding when entries are committed
+    pub(super) quorum: Box<dyn Quorum>,
+    /// Optional handler invoked on every inbound and outbound buffer
+    pub(super) interceptor: Option<Interceptor>,
+    /// Set while processing a buffer the interceptor delayed
+    pub(super) replaying: bool,
+    /// Optional handler overriding vote decisions (testing only)
+    pub(super) vote_hook: Option<VoteHook>,
+    /// Set upon detecting a payload divergence, cleared by the operator
+    pub(super) quarantined: bool,
+    /// Number of asynchronous stores in flight, shared with the handle
+    pub(super) pending: Arc<AtomicUsize>,
+    /// Backlog published to the handle to hold writers back, see `Gate`
+    pub(super) gate: Arc<Gate>,
+    /// Current role, shared with the handle
+    pub(super) role: Arc<AtomicUsize>,
+    /// Completion channels for asynchronous stores, ordered by offset
+    pub(super) waiters: VecDeque<(u64, Sender<StoreResult>)>,
+    /// Client request ids applied lately and their offset, see `Raft::store_once()`
+    pub(super) requests: Dedup,
+    /// Client request ids appended while leading but not yet applied, with their offset
+    pub(super) proposed: HashMap<u64, u64>,
+    /// Reads parked until the commit offset reaches their session token, along with their
+    /// consistency level and when their caller gives up on them
+    pub(super) readers: Vec<(u64, ReadConsistency, Instant, Sender<Result<u64, ReadError>>)>,
+    /// Number of entries appended to the log, with a rolling rate
+    pub(super) appended: Throughput,
+    /// Number of entries committed, with a rolling rate
+    pub(super) committed: Throughput,
+    /// Number of messages sent and received, by type
+    pub(super) messages: MessageStats,
     /// Slog logger
     pub(super) logger: Logger,
 }
@@ -322,133 +1069,1272 @@ where
 impl<S, T, U> FSM<S, T, U>
 where
     S: 'static + Send + Fn(&[u8; 32], &[u8]) -> (),
-    T: 'static + Send + Fn(&mut U, &[u8]) -> (),
+    T: 'static + Send + Fn(&mut U, &[u8]) -> Result<Vec<u8>, ApplyError>,
     U: 'static + Send + Default + Payload,
 {
-    //
-    // - various timeouts in milliseconds
-    //
-    const LIVENESS_TIMEOUT: u64 = 3000;
-    const ELECTION_TIMEOUT: u64 = 750;
-
     //
     // - log topology (slot width, etc.)
     //
-    pub(super) const SLOT_BYTES: usize = 1024;
-    pub(super) const RESOLUTION: usize = 128;
+    pub(super) const SLOT_BYTES: usize = ENTRY_BYTES;
+    pub(super) const RESOLUTION: usize = CAPACITY;
     const CHECKPOINT: usize = 15;
 
-    fn count_votes(&self, votes: &mut u64, id: u8) -> (u8, bool) {
+    fn count_votes(&mut self, id: u16) -> (usize, bool) {
 
         //
-        // - the votes byte is used as a bitmask (1 bit per peer)
-        // - set the bit corresponding to the specified id
-        // - count the set bits
-        // - compare with the quorum
+        // - record the ballot from the specified peer (once per peer)
+        // - count the ballots, we implicitly vote for ourselves
+        // - compare with the quorum, e.g more than half the cluster (2 peers left after a
+        //   decommission must be able to elect one of them)
         //
-        let mut total = 0;
-        *votes |= 1 << id;
-        let mut n = *votes;
-        while n > 0 {
-            n &= n - 1;
-            total += 1;
+        let _ = self.ballots.insert(id);
+        let total = self.ballots.len() + 1;
+        (total, total > (1 + self.peers.len()) >> 1)
+    }
+
+    fn confirm(&mut self, state: State, tx: Sender<Result<u64, ReadError>>) -> () {
+
+        //
+        // - only the LEADER may serve a linearizable read
+        // - append a no-op and park the read until a quorum acknowledges it: this proves we
+        //   were still leading at that point, and our payload then reflects whatever the
+        //   cluster committed before the read was issued
+        // - a LEADER whose log is full can't tell and fails with Stale
+        //
+        let off = match state {
+            LEAD(ref ctx) => self.append(ctx, Vec::new(), None),
+            _ => None,
+        };
+        match off {
+            Some(off) => self.confirming.push_back((off, tx)),
+            None => {
+                let _ = tx.send(self.readable(state, ReadConsistency::Linearizable));
+            }
         }
-        (total + 1, total > (1 + self.peers.len() as u8) >> 1)
     }
-}
 
-impl<S, T, U> Recv<Command, State> for FSM<S, T, U>
-where
-    S: 'static + Send + Fn(&[u8; 32], &[u8]) -> (),
-    T: 'static + Send + Fn(&mut U, &[u8]) -> (),
-    U: 'static + Send + Default + Payload,
-{
-    fn recv(
+    fn append<C: fmt::Debug>(
         &mut self,
-        this: &Arc<Automaton<Command>>,
-        mut state: State,
-        opcode: Opcode<Command, State>,
-    ) -> State {
-        match opcode {
-            Opcode::START => {
-                display!(
-                    self,
-                    "               | | starting ({} peers)",
-                    self.peers.len()
-                );
+        ctx: &C,
+        bytes: Vec<u8>,
+        request: Option<u64>,
+    ) -> Option<u64> {
+        self.append_as(ctx, 255, bytes, request)
+    }
+
+    fn append_as<C: fmt::Debug>(
+        &mut self,
+        ctx: &C,
+        code: u8,
+        bytes: Vec<u8>,
+        request: Option<u64>,
+    ) -> Option<u64> {
+
+        //
+        // - refuse any write while quarantined
+        // - make sure we have enough room in the log
+        //
+        if self.quarantined {
+            display!(self, "{:?}*| discarding record (quarantined)", ctx);
+            None
+        } else if self.head - self.tail == FSM::<S, T, U>::RESOLUTION as u64 - 1 {
+            display!(self, "{:?}*| discarding record (log full)", ctx);
+            None
+        } else {
+
+            //
+            // - increment the head offset
+            // - update the term tracker for the head
+            // - add the entry to the log
+            //
+            self.head += 1;
+            self.age = self.term;
+            self.appended.record(1, self.clock.now());
+            display!(self, "{:?} | appending record ({}B)", ctx, bytes.len());
+            let slot = SLOT {
+                code,
+                term: self.term,
+                off: self.head,
+                crc: Some(crc32(&bytes)),
+                bytes,
+                request,
+            };
+            write_slot!(self, serialize(&slot).expect("slot is serializable"), self.head);
+            Some(self.head)
+        }
+    }
+
+    fn batch(&self) -> u64 {
+
+        //
+        // - maximum number of entries per REPLICATE, as configured in entries and/or bytes
+        //
+        let mut batch = self.config.max_batch.unwrap_or(usize::MAX);
+        if let Some(bytes) = self.config.max_batch_bytes {
+            batch = cmp::min(batch, cmp::max(bytes / FSM::<S, T, U>::SLOT_BYTES, 1));
+        }
+        batch as u64
+    }
+
+    fn release(&mut self, res: StoreResult) -> () {
+        self.pending.fetch_sub(1, Ordering::Release);
+        let _ = self.waiters.pop_front().map(|(_, tx)| tx.send(res));
+    }
+
+    fn commit_to<C: fmt::Debug>(&mut self, ctx: &C, off: u64, notify: bool) -> () {
+
+        //
+        // - apply all entries from our commit offset up to the specified one (excluded)
+        // - each entry is passed to the pre-apply hooks, the apply closure and then the
+        //   post-apply hooks
+        // - if we have a dedicated apply thread hand the entries over instead, along with
+        //   the store waiting on each of them (if any)
+        // - if requested notify the sink with a COMMIT for each entry
+        // - notify the sink once with COMMITTED and the last entry applied
+        // - if applying takes longer than the alarm threshold (or if the apply queue fills
+        //   up) notify the sink once with APPLY_LAG and the number of entries still to apply
+        //
+        debug_assert!(off >= self.tail);
+        let start = self.clock.now();
+        let mut alarm = self.config.apply_lag_alarm;
+        let mut upto = off;
+        let mut outcomes = HashMap::new();
+        let stage = self.stage.clone();
+        let payload = self.payload.clone();
+        let mut inline = match self.applier {
+            None => Some((stage.lock().unwrap(), payload.write())),
+            Some(_) => None,
+        };
+        for n in self.commit..off {
+            if let Some(lapse) = alarm {
+                if since(start, self.clock.now()).map_or(false, |d| d > lapse) {
+                    warn!(&self.logger, "{:?} | applying lags by {} entries", ctx, off - n);
+                    self.sink.push(Notification::APPLY_LAG(off - n));
+                    alarm = None;
+                }
+            }
+            let waiter = if self.applier.is_some() &&
+                self.waiters.front().map_or(false, |w| w.0 == n)
+            {
+                self.waiters.pop_front().map(|w| w.1)
+            } else {
+                None
+            };
+            let mut slot = read_slot!(self, n);
+            if self.config.entry_checksums && !slot.verify() {
 
                 //
-                // - by definition offset #1 is some empty marker
-                // - we use this to avoid having to perform a bunch of == 0 tests
+                // - the entry got corrupted on disk: this is a durability failure
+                // - quarantine ourselves, notify the sink with SAFETY_VIOLATION and stop
+                //   applying right there
                 //
-                debug_assert!(self.head == 1);
-                debug_assert!(self.tail == 1);
-                debug_assert!(self.commit == 1);
-                let slot = NULL {};
-                write_slot!(self, slot.to_bytes(0), self.head);
+                warn!(&self.logger, "{:?} | entry #{} is corrupted on disk", ctx, n);
+                self.quarantined = true;
+                self.sink.push(Notification::SAFETY_VIOLATION(n));
+                if let Some(tx) = waiter {
+                    self.waiters.push_front((n, tx));
+                }
+                upto = n;
+                break;
+            }
+            if slot.code == MEMBERSHIP::CODE {
 
                 //
-                // - we start as a FOLLOWER
-                // - set the first liveness timeout
+                // - membership change: apply it to our configuration right away, the apply
+                //   closure only gets an empty marker
                 //
-                self.timer.schedule(
-                    this.clone(),
-                    TIMEOUT(self.seq),
-                    Duration::from_millis(FSM::<S, T, U>::LIVENESS_TIMEOUT),
-                );
+                if let Ok(change) = deserialize::<MEMBERSHIP>(&slot.bytes) {
+                    self.reconfigure(ctx, n, change);
+                }
+                slot.bytes.clear();
             }
-            Opcode::TRANSITION(prv) => {
-                debug_assert!(state != prv);
-                self.seq += 1;
-                match (prv, state) {
-                    (CNDT(_), PREV(ref ctx)) |
-                    (FLWR(_), PREV(ref ctx)) => {
+            self.uncompacted += slot.bytes.len();
+            if let Some(id) = slot.request {
 
-                        //
-                        // - star the pre-voting cycle right away
-                        //
-                        self.seq += 1;
-                        display!(self, "{:?} | pre-voting", ctx);
-                        let _ = this.post(TIMEOUT(self.seq));
+                //
+                // - the entry carries a client request id: skip it if that request was
+                //   applied already (e.g a retry appended by another LEADER)
+                //
+                let _ = self.proposed.remove(&id);
+                if self.requests.get(id).is_some() {
+                    display!(self, "{:?} | skipping duplicate request {} at #{}", ctx, id, n);
+                    if let (Some(applier), Some(tx)) = (self.applier.as_ref(), waiter) {
+                        let _ = applier.push(Job::Skip(n, tx));
                     }
-                    (PREV(_), CNDT(ref ctx)) => {
+                    continue;
+                }
+                self.requests.record(id, n);
+            }
+            if let Some((ref stage, ref mut guard)) = inline {
+                let res = stage.run(&self.logger, &mut **guard, n, &slot.bytes);
+                if let Err(ref e) = res {
+                    display!(self, "{:?} | entry #{} rejected ({})", ctx, n, e.0);
+                }
+                if !self.waiters.is_empty() {
+                    let _ = outcomes.insert(n, res);
+                }
+            } else if let Some(ref applier) = self.applier {
+                let bytes = if notify { slot.bytes.clone() } else { Vec::new() };
+                let job = Job::Apply(n, mem::replace(&mut slot.bytes, bytes), waiter);
+                if !applier.push(job) && alarm.is_some() {
+                    warn!(&self.logger, "{:?} | apply queue full", ctx);
+                    self.sink.push(Notification::APPLY_LAG(off - n));
+                    alarm = None;
+                }
+            }
+            if notify {
+                self.sink.push(Notification::COMMIT(n, slot.bytes));
+            }
+        }
+        drop(inline);
+        let off = upto;
+        if off > self.commit {
+            self.sink.push(Notification::COMMITTED(off - 1));
+        }
+        self.committed.record(off - self.commit, self.clock.now());
+        self.commit = off;
+        display!(self, "{:?} | offset #{} committed", ctx, off);
 
-                        //
-                        // - we switched to CANDIDATE
-                        // - trigger an election after a random lapse of time
-                        // - the goal is to avoid herding in case multiple peers transition
-                        //   to CANDIDATE at around the same time
-                        //
-                        let ms = thread_rng().gen_range(25, 150);
-                        display!(self, "{:?}*| triggering election in {} ms", ctx, ms);
-                        self.timer.schedule(
-                            this.clone(),
-                            TIMEOUT(self.seq),
-                            Duration::from_millis(ms),
-                        );
-                    }
-                    (CNDT(_), LEAD(ctx)) => {
+        //
+        // - complete any asynchronous store whose entry is now committed, passing along what
+        //   the apply closure returned
+        //
+        while let Some(n) = self.waiters.front().map(|w| w.0) {
+            if n >= off {
+                break;
+            }
+            let res = match outcomes.get(&n) {
+                Some(&Ok(ref bytes)) => StoreResult::Committed(n, bytes.clone()),
+                Some(&Err(ref e)) => StoreResult::Failed(n, e.clone()),
+                None => StoreResult::Committed(n, Vec::new()),
+            };
+            self.release(res);
+        }
 
-                        //
-                        // - we were just elected LEADER
-                        // - reset the next/replication counters for each peer
-                        // - the peer offsets are set to our head offset
-                        //
-                        display!(self, "{:?}*| now leading", ctx);
-                        for peer in &mut self.peers {
-                            debug_assert!(*peer.0 != self.id);
-                            peer.1.off = self.head;
-                            peer.1.ack = 1;
-                        }
+        //
+        // - serve any linearizable read whose no-op reached a quorum: leadership is confirmed
+        //   and everything below it is applied
+        //
+        while let Some(n) = self.confirming.front().map(|r| r.0) {
+            if n > off {
+                break;
+            }
+            if let Some((_, tx)) = self.confirming.pop_front() {
+                self.serve(tx, Ok(off));
+            }
+        }
+
+        //
+        // - if the commit index reached a checkpoint boundary
+        //   reset the tail to that offset (minus whatever we are configured to retain)
+        // - lock the payload and take a snapshot of it
+        // - flush the logfile
+        // - notify the sink with a CHECKPOINT
+        // - with a dedicated apply thread flag a checkpoint at the commit offset instead,
+        //   the thread flushes the payload
+        // - likewise if we applied too many bytes or entries since the last checkpoint: it is
+        //   taken once done processing the current message (e.g after replicating or
+        //   acknowledging), see compact_async()
+        //
+        let boundary = self.commit - (self.commit % FSM::<S, T, U>::CHECKPOINT as u64);
+        if self.applier.is_none() {
+            self.checkpoint(ctx, boundary);
+        } else if boundary > self.snapshot_off {
+            self.compaction_due = true;
+        }
+        let bytes = self.config.snapshot_threshold_bytes;
+        let entries = self.config.snapshot_threshold_entries;
+        if bytes.map_or(false, |limit| self.uncompacted >= limit) {
+            self.compaction_due = true;
+        }
+        if entries.map_or(false, |limit| self.commit - self.snapshot_off >= limit) {
+            self.compaction_due = true;
+        }
+    }
+
+    /// Replies to a read. If we have a dedicated apply thread the reply goes through its queue,
+    /// e.g the payload reflects the commit offset passed back by the time the reader gets it.
+    fn serve(&self, tx: Sender<Result<u64, ReadError>>, res: Result<u64, ReadError>) -> () {
+        match (res, self.applier.as_ref()) {
+            (Ok(commit), Some(applier)) => {
+                let _ = applier.push(Job::Serve(commit, tx));
+            }
+            (res, _) => {
+                let _ = tx.send(res);
+            }
+        }
+    }
+
+    /// Goes over the reads parked until our commit offset reaches their session token and the
+    /// channels waiting for a LEADER.
+    fn unpark(&mut self, state: State) -> () {
+
+        //
+        // - drop any read or LEADER watch whose caller gave up on it (it timed out on its end)
+        // - check again whether our payload may be read at the requested consistency level
+        //   (e.g we may not hear from our LEADER anymore): fail the read right away if not
+        // - serve the read once its session token is covered, keep it parked otherwise
+        //
+        let now = self.clock.now();
+        self.watchers.retain(|&(deadline, _)| now < deadline);
+        if self.readers.is_empty() {
+            return;
+        }
+        for (token, level, deadline, tx) in mem::replace(&mut self.readers, Vec::new()) {
+            if now >= deadline {
+                continue;
+            }
+            match self.readable(state, level) {
+                Ok(commit) if commit < token => self.readers.push((token, level, deadline, tx)),
+                res => self.serve(tx, res),
+            }
+        }
+    }
+
+    /// Whether the dedicated apply thread (if any) is still behind our commit offset.
+    fn applying(&self) -> bool {
+        self.applier.as_ref().map_or(false, |applier| applier.queued() > 0)
+    }
+
+    /// Digest of our payload along with the commit offset it reflects, see `Payload::checksum()`.
+    /// None if the payload does not provide one or while entries are still being applied.
+    fn digest(&self) -> Option<(u64, u64)> {
+        if self.applying() {
+            return None;
+        }
+        self.payload.read().checksum().map(|digest| (self.commit, digest))
+    }
+
+    /// Waits for the dedicated apply thread (if any) to catch up with our commit offset, e.g
+    /// before reading or resetting the payload.
+    fn flush_applier(&self) -> () {
+        if let Some(ref applier) = self.applier {
+            applier.flush();
+        }
+    }
+
+    fn compact(&mut self, state: State) -> () {
+
+        //
+        // - checkpoint at our commit offset, whatever the state
+        //
+        let off = self.commit;
+        match state {
+            PREV(ref ctx) | CNDT(ref ctx) => self.checkpoint(ctx, off),
+            FLWR(ref ctx) => self.checkpoint(ctx, off),
+            LEAD(ref ctx) => self.checkpoint(ctx, off),
+        }
+    }
+
+    /// Allocates the log upfront one chunk ahead of the specified offset, see
+    /// `RaftConfig::log_reserve_bytes`. A failure is not fatal, we just allocate on write.
+    fn reserve_ahead(&mut self, off: u64) -> () {
+        let total = (FSM::<S, T, U>::RESOLUTION * FSM::<S, T, U>::SLOT_BYTES) as u64;
+        let chunk = match self.config.log_reserve_bytes {
+            Some(chunk) if self.reserved < total => chunk,
+            _ => return,
+        };
+        let used = cmp::min(off, FSM::<S, T, U>::RESOLUTION as u64)
+            * FSM::<S, T, U>::SLOT_BYTES as u64;
+        if used + chunk <= self.reserved {
+            return;
+        }
+        let want = cmp::min((used / chunk + 2) * chunk, total);
+        if let Err(e) = self.storage.reserve(want) {
+            warn!(&self.logger, "               | | unable to reserve the log ({})", e);
+        }
+        self.reserved = want;
+    }
+
+    fn compact_async(&mut self, this: &Arc<Automaton<Command>>, state: State) -> () {
+
+        //
+        // - without a dedicated apply thread the payload is updated inline anyway: checkpoint
+        //   right away
+        // - otherwise have the apply thread flush the payload once it applied everything up to
+        //   our commit offset and post the snapshot back (see CHECKPOINTED), e.g a large
+        //   payload does not hold replication up
+        // - the client request ids go along with the snapshot: record them at that offset
+        // - only one such checkpoint at a time, a threshold crossed meanwhile is ignored
+        //
+        if self.applier.is_none() {
+            self.compact(state);
+            return;
+        }
+        if self.checkpointing.is_some() || self.commit <= self.snapshot_off {
+            return;
+        }
+        let off = self.commit;
+        let payload = self.payload.clone();
+        let this = this.clone();
+        if let Some(ref applier) = self.applier {
+            let _ = applier.push(Job::Run(Box::new(move || {
+                let bytes = payload.read().flush();
+                let _ = this.post(CHECKPOINTED(off, bytes));
+            })));
+        }
+        self.checkpointing = Some(self.requests.entries());
+    }
+
+    fn checkpoint<C: fmt::Debug>(&mut self, ctx: &C, boundary: u64) -> () {
+
+        //
+        // - nothing to do unless we applied something since the last snapshot
+        // - wait for the apply thread to catch up with our commit offset, the snapshot
+        //   reflects it
+        //
+        if boundary <= self.snapshot_off {
+            return;
+        }
+        self.flush_applier();
+        let guard = self.payload.read();
+        let bytes = (*guard).flush();
+        drop(guard);
+        let (off, requests) = (self.commit, self.requests.entries());
+        self.install(ctx, boundary, off, requests, bytes);
+    }
+
+    fn install<C: fmt::Debug>(
+        &mut self,
+        ctx: &C,
+        boundary: u64,
+        off: u64,
+        requests: Vec<(u64, u64)>,
+        mut bytes: Vec<u8>,
+    ) -> () {
+
+        //
+        // - adopt a snapshot reflecting the payload at some offset, unless we already hold one
+        //   at least as recent
+        // - compact the log up to the boundary (minus whatever we retain)
+        //
+        if boundary <= self.snapshot_off {
+            return;
+        }
+        self.snapshot.clear();
+        self.snapshot.append(&mut bytes);
+        display!(
+            self,
+            "{:?} | checkpointed [#{} #{}], {}B",
+            ctx,
+            self.tail,
+            boundary,
+            self.snapshot.len()
+        );
+        let _ = self.sync_log();
+        self.sink.push(Notification::CHECKPOINT(boundary));
+        self.observe(Observation::SnapshotTaken(boundary));
+        let retain = cmp::min(
+            self.config.snapshot_retain_entries,
+            FSM::<S, T, U>::RESOLUTION as u64 / 2,
+        );
+        self.truncate_prefix(cmp::max(boundary.saturating_sub(retain), 1));
+        self.snapshot_off = off;
+        self.snapshot_requests = requests;
+        self.uncompacted = 0;
+        self.persist_snapshot();
+    }
+
+    fn hand_off(&mut self, this: &Arc<Automaton<Command>>) -> () {
+
+        //
+        // - if we are transferring our leadership and the target caught up with our head
+        //   tell it to campaign right away with a TIMEOUT_NOW
+        // - from then on we hold our heartbeats back: the target is about to bump its term
+        //   and would otherwise reply with a UPGRADE, turning us IDLE
+        //
+        let target = match self.transfer {
+            Some(ref transfer) if !transfer.sent => transfer.target,
+            _ => return,
+        };
+        let host = match self.peers.get(&target) {
+            Some(peer) if peer.ack >= self.head => peer.host,
+            _ => return,
+        };
+        let msg = TIMEOUT_NOW {
+            id: self.id,
+            term: self.term,
+        };
+        send!(self, this, &host, msg);
+        if let Some(ref mut transfer) = self.transfer {
+            transfer.sent = true;
+        }
+    }
+
+    fn leader_found(&mut self, id: u16) -> () {
+
+        //
+        // - notify the sink with LEADER if we were idle or if this is another LEADER
+        //   than the last one reported
+        //
+        if self.idle_since.is_some() || self.reported != Some(id) {
+            let host = if id == self.id {
+                self.host
+            } else {
+                self.peers.get(&id).map_or([0; 32], |peer| peer.host)
+            };
+            self.sink.push(Notification::LEADER(id, label(&host)));
+            self.reported = Some(id);
+        }
+
+        //
+        // - we are not idle anymore
+        // - wake up whoever is waiting for a LEADER
+        //
+        self.idle_since = None;
+        for (_, tx) in self.watchers.drain(..) {
+            let _ = tx.send(id);
+        }
+    }
+
+    fn probe(&self, off: u64) -> Option<SLOT> {
+
+        //
+        // - same as read_slot!() except that anything which is not a valid slot for that
+        //   offset (left over from a previous lap, torn write, bad checksum) yields None
+        //   instead of panicking
+        // - each slot is written along with its checksum: a slot without one got torn
+        //
+        let mut buf = Vec::with_capacity(FSM::<S, T, U>::SLOT_BYTES);
+        self.storage.read_entries(off..off + 1, &mut buf).ok()?;
+        let slot: SLOT = deserialize(&buf).ok()?;
+        if slot.off == off && slot.sealed() {
+            Some(slot)
+        } else {
+            None
+        }
+    }
+
+    fn sync_log(&mut self) -> bool {
+
+        //
+        // - flush the log if we wrote to it since the last time, e.g before acknowledging:
+        //   a peer restarting must still hold whatever it acknowledged
+        // - on failure keep it flagged and report it, the caller should not acknowledge
+        //
+        if !self.unsynced {
+            return true;
+        }
+        match self.storage.flush() {
+            Ok(_) => {
+                self.unsynced = false;
+                true
+            }
+            Err(e) => {
+                warn!(&self.logger, "               | | unable to flush the log ({})", e);
+                false
+            }
+        }
+    }
+
+    fn intact(buf: &[u8]) -> bool {
+
+        //
+        // - check each slot in a replicated buffer against its CRC32
+        //
+        buf.chunks(FSM::<S, T, U>::SLOT_BYTES).all(|chunk| {
+            deserialize::<SLOT>(chunk).map(|slot| slot.verify()).unwrap_or(false)
+        })
+    }
+
+    fn is_member(&self, id: u16) -> bool {
+        self.peers.contains_key(&id)
+    }
+
+    fn follow_commit<C: fmt::Debug>(&mut self, ctx: &C, commit: u64) -> () {
+
+        //
+        // - the LEADER may be ahead of us: only commit what we actually have in our log
+        // - this is conveyed in the original paper as
+        //
+        //   "If leaderCommit > commitIndex, set commitIndex =
+        //    min(leaderCommit, index of last new entry)"
+        //
+        let next = cmp::min(commit, self.head);
+        if next > self.commit {
+            self.commit_to(ctx, next, false);
+        }
+    }
+
+    fn recover(&mut self) -> bool {
+
+        //
+        // - load the latest snapshot, if any
+        // - reset the payload and the membership with it: this is our new commit offset
+        // - we don't know anything about what followed (e.g the commit offset may have been
+        //   higher), whatever is above will be committed again as instructed by the LEADER
+        //
+        match self.storage.load_snapshot() {
+            Ok(Some((meta, mut reader))) => {
+                let mut bytes = Vec::new();
+                if let Err(e) = reader.read_to_end(&mut bytes) {
+                    warn!(&self.logger, "               | | unable to read snapshot ({})", e);
+                    return false;
+                }
+                let mut guard = self.payload.write();
+                (*guard).reset(&bytes);
+                drop(guard);
+                self.snapshot = bytes;
+                self.snapshot_off = meta.index;
+                self.adopt(meta.index, &meta.peers);
+                self.requests = Dedup::restore(self.config.dedup_window, &meta.requests);
+                self.snapshot_requests = meta.requests;
+                self.commit = meta.index;
+                self.tail = meta.index;
+                self.head = meta.index;
+                self.term = meta.term;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!(&self.logger, "               | | unable to load snapshot ({})", e);
+                return false;
+            }
+        }
+
+        //
+        // - check our tail slot is valid (e.g it does carry its own offset)
+        // - if not write a marker using the snapshot term: any conflict with the LEADER will
+        //   be resolved as usual
+        // - then scan the log forward to find our head, e.g the last contiguous valid slot (a
+        //   slot left over from a previous lap around the log has a different offset)
+        // - a slot torn by a crash mid-write (or failing its checksum) ends the scan as well,
+        //   e.g it is truncated along with whatever follows
+        //
+        if self.probe(self.tail).is_none() {
+            if self.tail == 1 {
+                return false;
+            }
+            let slot = NULL {};
+            write_slot!(self, slot.to_bytes(self.term, self.tail), self.tail);
+        }
+        self.age = read_slot!(self, self.tail).term;
+        while self.head - self.tail < FSM::<S, T, U>::RESOLUTION as u64 - 1 {
+            match self.probe(self.head + 1) {
+                Some(ref slot) if slot.term >= self.age => {
+                    self.head += 1;
+                    self.age = slot.term;
+                }
+                _ => break,
+            }
+        }
+        self.term = cmp::max(self.term, self.age);
+        info!(
+            &self.logger,
+            "               | | recovered [#{} #{}] (#{}), term {}",
+            self.tail,
+            self.head,
+            self.commit,
+            self.term
+        );
+        true
+    }
+
+    fn cast(&mut self, id: u16) -> bool {
+
+        //
+        // - record our vote for the current term
+        // - persist it before it goes out: a peer restarting must never vote twice in the
+        //   same term
+        // - on failure we just don't vote
+        //
+        let vote = (self.term, id);
+        if let Err(e) = self.storage.save_meta(vote.0, vote.1) {
+            warn!(&self.logger, "               | | unable to persist vote ({})", e);
+            return false;
+        }
+        self.vote = Some(vote);
+        true
+    }
+
+    fn load_vote(&mut self, recovered: bool) -> () {
+
+        //
+        // - reload the latest vote, if any, and catch up with its term
+        // - if it is missing while we recovered a log we may have voted in the current term
+        //   already: pretend we voted for ourselves, e.g refuse to vote until the next term
+        //
+        match self.storage.load_meta() {
+            Ok(Some(vote)) => {
+                self.term = cmp::max(self.term, vote.0);
+                self.vote = Some(vote);
+            }
+            Ok(None) => if recovered {
+                self.vote = Some((self.term, self.id));
+            },
+            Err(e) => {
+                warn!(&self.logger, "               | | unable to read vote ({})", e);
+                self.vote = Some((self.term, self.id));
+            }
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    fn reposition(&mut self) -> () {
+
+        //
+        // - start at whatever term and offset we are configured with, as if the cluster had
+        //   been running for a while
+        // - the marker at offset #1 is written at that offset instead
+        //
+        let off = cmp::max(self.config.initial_index, 1);
+        self.term = self.config.initial_term;
+        self.tail = off;
+        self.head = off;
+        self.commit = off;
+        self.snapshot_off = off;
+    }
+
+    fn members(&self) -> Vec<(u16, [u8; 32])> {
+        let mut peers: Vec<_> = self.peers.iter().map(|(id, peer)| (*id, peer.host)).collect();
+        if !self.removed {
+            peers.push((self.id, self.host));
+        }
+        peers.sort();
+        peers
+    }
+
+    fn vet(&self, change: &MEMBERSHIP) -> Result<(), MembershipError> {
+
+        //
+        // - a change must alter our configuration, and can't leave it empty
+        //
+        let member = |id: u16| (id == self.id && !self.removed) || self.peers.contains_key(&id);
+        match *change {
+            MEMBERSHIP::ADD(id, _) if member(id) => {
+                Err(MembershipError::Invalid("already a member"))
+            }
+            MEMBERSHIP::REMOVE(id) if !member(id) => Err(MembershipError::Invalid("not a member")),
+            MEMBERSHIP::REMOVE(_) if self.members().len() == 1 => {
+                Err(MembershipError::Invalid("last member"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn propose(&mut self, state: State, change: MEMBERSHIP) -> Result<u64, MembershipError> {
+
+        //
+        // - only a LEADER changes the membership, one peer at a time: the previous change must
+        //   have committed first (e.g our commit offset went past it)
+        // - append the change as a MEMBERSHIP entry and return its offset, it takes effect on
+        //   each peer once committed
+        // - an entry is only applied once the commit offset goes past it: chase it with a no-op
+        //   so that it does not wait for the next store
+        // - reject it like a store if we can't append it
+        //
+        let res = match state {
+            LEAD(_) if self.changing.map_or(false, |off| off >= self.commit) => {
+                Err(MembershipError::ConfigChangeInProgress)
+            }
+            LEAD(_) if self.quorum_lost(self.clock.now()) => Err(MembershipError::Rejected),
+            LEAD(_) if self.transfer.is_some() => Err(MembershipError::Rejected),
+            LEAD(ref ctx) => match self.vet(&change) {
+                Ok(()) => {
+                    let bytes = serialize(&change).expect("change is serializable");
+                    self.append_as(ctx, MEMBERSHIP::CODE, bytes, None)
+                        .ok_or(MembershipError::Rejected)
+                }
+                Err(e) => Err(e),
+            },
+            _ => Err(MembershipError::NotLeader),
+        };
+        if let (Ok(off), LEAD(ref ctx)) = (res, state) {
+            let _ = self.append(ctx, Vec::new(), None);
+            self.changing = Some(off);
+        }
+        res
+    }
+
+    fn reconfigure<C: fmt::Debug>(&mut self, ctx: &C, off: u64, change: MEMBERSHIP) -> () {
+
+        //
+        // - a membership change committed: add or remove that peer, which starts or stops
+        //   replicating to it (and counting it towards quorums and elections) right away
+        // - we don't know anything about a new peer: start from scratch, as upon a REBASE
+        // - if we are the one removed we stop campaigning (a LEADER steps down upon its next
+        //   heartbeat)
+        // - the same change may commit again (e.g after restarting from a snapshot taken
+        //   past it): applying it twice is harmless
+        // - notify the observer with ConfigChanged
+        // - flag the change: a LEADER heartbeats right away, see recv()
+        //
+        match change {
+            MEMBERSHIP::ADD(id, _) if id == self.id => self.removed = false,
+            MEMBERSHIP::ADD(id, host) => {
+                display!(self, "{:?}*| peer #{} ({}) added at #{}", ctx, id, label(&host), off);
+                self.peers.entry(id).or_insert_with(|| Peer::new(host)).host = host;
+            }
+            MEMBERSHIP::REMOVE(id) if id == self.id => {
+                warn!(&self.logger, "removed from the configuration at #{}", off);
+                self.removed = true;
+            }
+            MEMBERSHIP::REMOVE(id) => {
+                display!(self, "{:?}*| peer #{} removed at #{}", ctx, id, off);
+                let _ = self.peers.remove(&id);
+            }
+        }
+        self.configured = off;
+        self.reconfigured = true;
+        let bytes = serialize(&self.configuration()).expect("configuration is valid");
+        self.observe(Observation::ConfigChanged(bytes));
+    }
+
+    fn adopt(&mut self, off: u64, members: &[(u16, [u8; 32])]) -> () {
+
+        //
+        // - switch to the membership the snapshot at that offset reflects, keeping track of the
+        //   peers we already know
+        // - an empty membership means the snapshot does not carry any: keep ours
+        //
+        if members.is_empty() {
+            return;
+        }
+        let local = self.id;
+        self.peers.retain(|id, _| members.iter().any(|&(n, _)| n == *id));
+        for &(id, host) in members.iter().filter(|&&(id, _)| id != local) {
+            self.peers.entry(id).or_insert_with(|| Peer::new(host)).host = host;
+        }
+        self.removed = members.iter().all(|&(id, _)| id != self.id);
+        self.configured = off;
+    }
+
+    fn configuration(&self) -> Configuration {
+        let (learners, voters) = self.members().into_iter().partition(|&(id, _)| {
+            if id == self.id {
+                self.config.learner
+            } else {
+                self.peers.get(&id).map_or(false, |peer| peer.learner)
+            }
+        });
+        Configuration {
+            voters,
+            learners,
+            witnesses: Vec::new(),
+            index: self.configured,
+        }
+    }
+
+    fn quorum_lost(&self, now: Instant) -> bool {
+
+        //
+        // - sort when we last heard from each voter, most recent first (we always hear from
+        //   ourselves)
+        // - the majority-th one is when we last heard from a quorum
+        //
+        let mut heard: Vec<Instant> = self.peers
+            .values()
+            .filter(|&peer| !peer.learner)
+            .map(|peer| peer.acked.map_or(self.elected, |(_, t)| cmp::max(t, self.elected)))
+            .collect();
+        heard.push(now);
+        heard.sort_by(|a, b| b.cmp(a));
+        let at = heard[heard.len() >> 1];
+        since(at, now).map_or(false, |d| d > self.config.liveness_timeout)
+    }
+
+    fn acknowledge(&mut self, this: &Arc<Automaton<Command>>, dst: [u8; 32]) -> () {
+
+        //
+        // - emit a ACK to acknowledge our head offset, once whatever we wrote is durable
+        // - if coalescing is enabled defer it instead: a single ACK will cover whatever we
+        //   replicate until the coalescing window closes
+        //
+        if self.config.ack_coalesce == Duration::from_millis(0) {
+            if !self.sync_log() {
+                return;
+            }
+            let msg = ACK {
+                id: self.id,
+                term: self.term,
+                ack: self.head,
+                learner: self.config.learner,
+                digest: self.digest(),
+            };
+
+            send!(self, this, &dst, msg);
+
+        } else {
+            if self.ack_to.is_none() {
+                schedule!(self, this, ACKNOWLEDGE, self.config.ack_coalesce);
+            }
+            self.ack_to = Some(dst);
+        }
+    }
+
+    fn observe(&self, observation: Observation) -> () {
+        if let Some(ref observer) = self.observer {
+            observer.push(observation);
+        }
+    }
+
+    fn persist_snapshot(&mut self) -> () {
+
+        //
+        // - describe the snapshot (offset, term and cluster configuration)
+        // - hand it over to the snapshot store
+        //
+        let meta = SnapshotMeta {
+            index: self.snapshot_off,
+            term: self.term_at(self.snapshot_off - 1).unwrap_or(0),
+            peers: self.members(),
+            requests: self.snapshot_requests.clone(),
+        };
+        if let Err(e) = self.storage.save_snapshot(&meta, &mut &self.snapshot[..]) {
+            warn!(
+                &self.logger,
+                "               | | unable to save snapshot #{} ({})",
+                meta.index,
+                e
+            );
+        }
+    }
+
+    fn reconcile(&mut self, off: u64, term: u64, buf: Vec<u8>) -> Option<u64> {
+
+        //
+        // - the entries received from the LEADER go right after its check mark at off, which
+        //   we know matches (same offset, same term)
+        // - compare each of them with whatever we hold at the same offset: the first one
+        //   whose term differs is where our log diverges, it and everything above is stale
+        // - write the entries (those that match are identical) and move our head past them
+        // - if nothing conflicts keep whatever follows only as long as it was appended by that
+        //   same LEADER (e.g a later REPLICATE overtaken by this one): an entry at its term
+        //   can't be stale while anything older may be, so drop the rest
+        // - invariant: once done our log matches the LEADER's up to our head, e.g nothing at
+        //   or above the first conflicting entry survives and the ACK covering our head only
+        //   vouches for entries the LEADER holds as well
+        //
+        let n = (buf.len() / FSM::<S, T, U>::SLOT_BYTES) as u64;
+        let stale = self.head;
+        let conflict = (off + 1..cmp::min(off + n, stale) + 1).find(|&at| {
+            let k = (at - off - 1) as usize * FSM::<S, T, U>::SLOT_BYTES;
+            let theirs: SLOT = deserialize(&buf[k..]).expect("replicated slot is valid");
+            read_slot!(self, at).term != theirs.term
+        });
+        write_range!(self, buf, off + 1, n);
+        self.head = off + n;
+        if conflict.is_none() {
+            while self.head < stale {
+                match self.probe(self.head + 1) {
+                    Some(ref slot) if slot.term == term => self.head += 1,
+                    _ => break,
+                }
+            }
+        }
+        if stale > self.head {
+            truncate_from!(self, self.head + 1);
+        }
+        self.age = read_slot!(self, self.head).term;
+        conflict
+    }
+
+    fn truncate_prefix(&mut self, off: u64) -> () {
+
+        //
+        // - drop all the entries below the specified offset by moving our tail
+        // - the log being a circular buffer there is nothing else to do
+        //
+        debug_assert!(off <= self.head);
+        if off > self.tail {
+            self.tail = off;
+        }
+    }
+
+    fn rebase(&mut self, snapshot: Installing) -> () {
+
+        //
+        // - let the apply thread (if any) catch up, then lock the payload and reset it using
+        //   the snapshot we received
+        //
+        self.flush_applier();
+        let mut guard = self.payload.write();
+        (*guard).reset(&snapshot.bytes);
+        drop(guard);
+
+        //
+        // - if our log holds the last entry the snapshot reflects keep whatever follows it
+        // - otherwise reset our log to a marker at that offset with its term, dropping
+        //   whatever we had past it
+        // - either way our tail moves to that offset
+        // - this is conveyed in the original paper as
+        //
+        //   "If existing log entry has same index and term as snapshot’s last included entry,
+        //    retain log entries following it and reply"
+        //
+        let last = snapshot.last_index;
+        let kept = last >= self.tail && last <= self.head &&
+            read_slot!(self, last).term == snapshot.last_term;
+        if !kept {
+            let stale = self.head;
+            let slot = NULL {};
+            write_slot!(self, slot.to_bytes(snapshot.last_term, last), last);
+            if stale > last {
+                truncate_from!(self, last + 1);
+            }
+            self.head = last;
+            self.age = snapshot.last_term;
+        }
+        self.tail = last;
+
+        //
+        // - our payload now reflects all the entries up to that offset: what follows is our
+        //   new commit offset (e.g past our head until the LEADER replicates it)
+        // - so does the membership shipped along
+        // - keep the snapshot in case we end up leading
+        //
+        self.commit = last + 1;
+        self.snapshot_off = last + 1;
+        self.adopt(last + 1, &snapshot.peers);
+        self.snapshot = snapshot.bytes;
+        self.requests = Dedup::restore(self.config.dedup_window, &snapshot.requests);
+        self.snapshot_requests = snapshot.requests;
+        self.uncompacted = 0;
+        self.persist_snapshot();
+    }
+
+    pub(super) fn startup_delay(&self) -> Duration {
+
+        //
+        // - the first liveness timeout is randomly pushed back by up to the startup jitter
+        //
+        let ms = millis(self.config.startup_jitter);
+        let extra = thread_rng().gen_range(0, ms + 1);
+        self.config.liveness_timeout + Duration::from_millis(extra)
+    }
+
+    pub(super) fn election_delay(&self) -> Duration {
+
+        //
+        // - pick a random delay within the election jitter
+        // - push it back by a fraction of the liveness timeout if our priority is not the
+        //   highest, e.g up to a whole liveness timeout for a priority of 0
+        //
+        let range = &self.config.election_jitter;
+        let ms = thread_rng().gen_range(millis(range.start), millis(range.end));
+        let below = MAX_PRIORITY - cmp::min(self.config.priority, MAX_PRIORITY);
+        Duration::from_millis(ms) + self.config.liveness_timeout * below / MAX_PRIORITY
+    }
+
+    pub(super) fn disk_usage(&self) -> u64 {
+
+        //
+        // - the log has a fixed size: only account for the slots between our tail and
+        //   head, e.g what compaction did not release yet
+        // - add whatever the storage reports for the snapshot
+        //
+        let live = (self.head - self.tail + 1) * FSM::<S, T, U>::SLOT_BYTES as u64;
+        live + self.storage.snapshot_usage()
+    }
+
+    fn readable(&self, state: State, level: ReadConsistency) -> Result<u64, ReadError> {
+
+        //
+        // - a LEADER never serves a linearizable read off its commit offset: it could have been
+        //   deposed without knowing it yet, see confirm()
+        //
+        match (state, level) {
+            (_, ReadConsistency::Eventual) |
+            (LEAD(_), ReadConsistency::Bounded(_)) => Ok(self.commit),
+            (FLWR(ctx), ReadConsistency::Bounded(lapse))
+                if ctx.leader.is_some() &&
+                    since(self.contact, self.clock.now()).map_or(false, |d| d <= lapse) => {
+                Ok(self.commit)
+            }
+            (LEAD(_), ReadConsistency::Linearizable) => Err(ReadError::Stale),
+            (FLWR(ctx), ReadConsistency::Linearizable) => Err(ReadError::NotLeader(ctx.leader)),
+            (_, ReadConsistency::Linearizable) => Err(ReadError::NotLeader(None)),
+            _ => Err(ReadError::Stale),
+        }
+    }
+
+    pub(super) fn term_at(&self, off: u64) -> Option<u64> {
+
+        //
+        // - offsets start at #1 and nothing exists past our head
+        // - anything below the tail has been compacted: use the term at the tail, e.g the
+        //   term of the last entry covered by our latest snapshot
+        //
+        if off == 0 || off > self.head {
+            None
+        } else if off < self.tail {
+            Some(read_slot!(self, self.tail).term)
+        } else {
+            Some(read_slot!(self, off).term)
+        }
+    }
+
+    pub(super) fn log_range(&self, from: u64, to: u64) -> Vec<LogEntry> {
+
+        //
+        // - clamp to [tail, head], anything below the tail has been compacted
+        // - read the whole range at once
+        //
+        let from = cmp::max(from, self.tail);
+        let to = cmp::min(to, self.head + 1);
+        if from >= to {
+            return Vec::new();
+        }
+        let mut buf = Vec::with_capacity((to - from) as usize * FSM::<S, T, U>::SLOT_BYTES);
+        read_range!(self, buf, from, to - from);
+        buf.chunks(FSM::<S, T, U>::SLOT_BYTES)
+            .map(|chunk| {
+                let slot: SLOT = deserialize(chunk).expect("log slot is valid");
+                LogEntry {
+                    off: slot.off,
+                    term: slot.term,
+                    marker: slot.code != 255,
+                    bytes: slot.bytes,
+                    request: slot.request,
+                }
+            })
+            .collect()
+    }
+}
+
+impl<S, T, U> FSM<S, T, U>
+where
+    S: 'static + Send + Fn(&[u8; 32], &[u8]) -> (),
+    T: 'static + Send + Fn(&mut U, &[u8]) -> Result<Vec<u8>, ApplyError>,
+    U: 'static + Send + Default + Payload,
+{
+    fn process(
+        &mut self,
+        this: &Arc<Automaton<Command>>,
+        mut state: State,
+        opcode: Opcode<Command, State>,
+    ) -> State {
+        match opcode {
+            Opcode::START => {
+                display!(
+                    self,
+                    "               | | starting ({} peers)",
+                    self.peers.len()
+                );
+
+                //
+                // - by definition offset #1 is some empty marker
+                // - we use this to avoid having to perform a bunch of == 0 tests
+                // - if recovering try to pick up where we left off instead
+                //
+                debug_assert!(self.head == 1);
+                debug_assert!(self.tail == 1);
+                debug_assert!(self.commit == 1);
+                let recovered = self.recovering && self.recover();
+                if !recovered {
+                    #[cfg(feature = "testing")]
+                    self.reposition();
+                    let slot = NULL {};
+                    write_slot!(self, slot.to_bytes(0, self.head), self.head);
+                }
+                if self.recovering {
+                    self.load_vote(recovered);
+                }
+
+                //
+                // - we start as a FOLLOWER
+                // - set the first liveness timeout, possibly delayed by some jitter
+                //
+                self.role.store(Role::of(state, self.config.learner) as usize, Ordering::Release);
+                self.idle_since = Some(self.clock.now());
+                let delay = self.startup_delay();
+                schedule!(self, this, TIMEOUT(self.seq), delay);
+            }
+            Opcode::TRANSITION(prv) => {
+                debug_assert!(state != prv);
+                self.seq += 1;
+                self.role.store(Role::of(state, self.config.learner) as usize, Ordering::Release);
+
+                //
+                // - we are idle as long as we know of no LEADER and do not campaign: stop
+                //   counting while pre-voting or running for election, start again once back
+                //   to following nobody
+                //
+                match state {
+                    PREV(_) | CNDT(_) => self.idle_since = None,
+                    FLWR(context::FLWR { leader: None, .. }) => {
+                        self.idle_since.get_or_insert(self.clock.now());
+                    }
+                    _ => {}
+                }
+                match (prv, state) {
+                    (CNDT(_), PREV(ref ctx)) |
+                    (FLWR(_), PREV(ref ctx)) => {
+
+                        //
+                        // - star the pre-voting cycle right away, with no ballot
+                        //
+                        self.seq += 1;
+                        self.ballots.clear();
+                        display!(self, "{:?} | pre-voting", ctx);
+                        let _ = this.post(TIMEOUT(self.seq));
+                    }
+                    (PREV(_), CNDT(ref ctx)) => {
+
+                        //
+                        // - we switched to CANDIDATE
+                        // - trigger an election after a random lapse of time
+                        // - the goal is to avoid herding in case multiple peers transition
+                        //   to CANDIDATE at around the same time
+                        //
+                        let delay = self.election_delay();
+                        display!(
+                            self,
+                            "{:?}*| triggering election in {} ms",
+                            ctx,
+                            millis(delay)
+                        );
+                        schedule!(self, this, TIMEOUT(self.seq), delay);
+                    }
+                    (FLWR(_), CNDT(ref ctx)) => {
+
+                        //
+                        // - our LEADER is handing off to us (see TIMEOUT_NOW)
+                        // - skip the pre-vote and the jitter and run for election right away
+                        //
+                        self.ballots.clear();
+                        display!(self, "{:?}*| taking over, triggering election", ctx);
+                        let _ = this.post(TIMEOUT(self.seq));
+                    }
+                    (CNDT(_), LEAD(ctx)) => {
+
+                        //
+                        // - we were just elected LEADER
+                        // - reset the next/replication counters for each peer
+                        // - the peer offsets are set to our head offset
+                        //
+                        display!(self, "{:?}*| now leading", ctx);
+                        for peer in &mut self.peers {
+                            debug_assert!(*peer.0 != self.id);
+                            peer.1.off = self.head;
+                            peer.1.ack = 1;
+                            peer.1.inflight.clear();
+                            peer.1.resend = None;
+                            peer.1.diverged = false;
+                        }
+
+                        //
+                        // - a membership change still uncommitted in our log (e.g appended by the
+                        //   previous LEADER) is in progress: no other change until it commits
+                        //
+                        self.changing = None;
+                        for off in self.commit..self.head + 1 {
+                            if read_slot!(self, off).code == MEMBERSHIP::CODE {
+                                self.changing = Some(off);
+                            }
+                        }
 
                         //
                         // - replicate immediately
                         // - notify the sink with LEADING
                         // - increment the sink semaphore
+                        // - we start with a quorum, e.g the one which elected us
                         //
                         self.seq += 1;
                         let _ = this.post(TIMEOUT(self.seq));
                         self.sink.push(Notification::LEADING);
+                        self.elected = self.clock.now();
+                        let id = self.id;
+                        self.leader_found(id);
 
                     }
                     (PREV(_), FLWR(ctx)) |
@@ -457,14 +2343,27 @@ where
 
                         //
                         // - we got a REPLICATE with a higher term
+                        // - fail any pending linearizable read (only a LEADER has some)
+                        // - fail any pending asynchronous store (only a LEADER has some)
+                        // - complete any leadership transfer (or pending hand-off) with whoever
+                        //   took over
                         // - set our next timeout
                         //
+                        for (_, tx) in self.confirming.drain(..) {
+                            let _ = tx.send(Err(ReadError::NotLeader(ctx.leader)));
+                        }
+                        while let Some(n) = self.waiters.front().map(|w| w.0) {
+                            self.release(StoreResult::Lost(n));
+                        }
+                        self.proposed.clear();
+                        if let Some(transfer) = self.transfer.take() {
+                            let _ = transfer.tx.send(ctx.leader);
+                        }
+                        if let Some((_, tx)) = self.successor.take() {
+                            let _ = tx.send(ctx.leader);
+                        }
                         display!(self, "{:?} | waiting for heartbeats", ctx);
-                        self.timer.schedule(
-                            this.clone(),
-                            TIMEOUT(self.seq),
-                            Duration::from_millis(FSM::<S, T, U>::LIVENESS_TIMEOUT),
-                        );
+                        schedule!(self, this, TIMEOUT(self.seq), self.config.liveness_timeout);
                     }
                     _ => {
                         debug_assert!(false, "invalid state transition");
@@ -492,8 +2391,7 @@ where
                                 head: self.head,
                                 age: self.age,
                             };
-                            let bytes = msg.to_raw(&self.host, &peer.1.host);
-                            (self.write)(&peer.1.host, &bytes);
+                            send!(self, this, &peer.1.host, msg);
                             display!(self, "{:?} | probing peer #{}", ctx, peer.0);
                         }
 
@@ -502,11 +2400,7 @@ where
                         // - we will cycle on PREVOTE as long as we don't get promoted
                         //   to CANDIDATE and don't get quorum
                         //
-                        self.timer.schedule(
-                            this.clone(),
-                            TIMEOUT(self.seq),
-                            Duration::from_millis(FSM::<S, T, U>::ELECTION_TIMEOUT),
-                        );
+                        schedule!(self, this, TIMEOUT(self.seq), self.config.election_timeout);
                     }
                     CNDT(ref mut ctx) => {
                         if ctx.advertised {
@@ -522,21 +2416,25 @@ where
 
                         //
                         // - we went from PREVOTE to CANDIDATE
-                        // - increment our term
-                        // - send a ADVERTISE to all peers
+                        // - increment our term and vote for ourselves
+                        // - send a ADVERTISE to all peers, unless our vote could not be
+                        //   persisted (the election will then just time out)
                         // - wait for VOTE RPCs to come back within the election timeout
                         //
                         self.term += 1;
-                        for peer in &self.peers {
-                            debug_assert!(*peer.0 != self.id);
-                            let msg = ADVERTISE {
-                                id: self.id,
-                                term: self.term,
-                                head: self.head,
-                                age: self.age,
-                            };
-                            let bytes = msg.to_raw(&self.host, &peer.1.host);
-                            (self.write)(&peer.1.host, &bytes);
+                        let id = self.id;
+                        if self.cast(id) {
+                            for peer in &self.peers {
+                                debug_assert!(*peer.0 != self.id);
+                                let msg = ADVERTISE {
+                                    id: self.id,
+                                    term: self.term,
+                                    head: self.head,
+                                    age: self.age,
+                                    transfer: ctx.transfer,
+                                };
+                                send!(self, this, &peer.1.host, msg);
+                            }
                         }
 
                         //
@@ -544,11 +2442,7 @@ where
                         // - set the election timeout
                         //
                         ctx.advertised = true;
-                        self.timer.schedule(
-                            this.clone(),
-                            TIMEOUT(self.seq),
-                            Duration::from_millis(FSM::<S, T, U>::ELECTION_TIMEOUT),
-                        );
+                        schedule!(self, this, TIMEOUT(self.seq), self.config.election_timeout);
                     }
                     FLWR(ref mut ctx) => {
                         if ctx.live {
@@ -559,11 +2453,41 @@ where
                             // - schedule a new timeout
                             //
                             ctx.live = false;
-                            self.timer.schedule(
-                                this.clone(),
-                                TIMEOUT(self.seq),
-                                Duration::from_millis(FSM::<S, T, U>::LIVENESS_TIMEOUT),
-                            );
+                            schedule!(self, this, TIMEOUT(self.seq), self.config.liveness_timeout);
+
+                        } else if self.config.learner {
+
+                            //
+                            // - liveness timeout while learning: never campaign
+                            // - notify the sink with IDLE if we just lost our LEADER
+                            // - keep following and wait for the next one
+                            //
+                            display!(self, "{:?}*| learner, not campaigning", ctx);
+                            if ctx.leader.take().is_some() {
+                                self.sink.push(Notification::IDLE);
+                                self.idle_since.get_or_insert(self.clock.now());
+                            }
+                            schedule!(self, this, TIMEOUT(self.seq), self.config.liveness_timeout);
+
+                        } else if self.quarantined {
+
+                            //
+                            // - liveness timeout while quarantined: never campaign
+                            // - keep following and wait for the operator to clear us
+                            //
+                            display!(self, "{:?}*| quarantined, not campaigning", ctx);
+                            ctx.leader = None;
+                            schedule!(self, this, TIMEOUT(self.seq), self.config.liveness_timeout);
+
+                        } else if self.removed {
+
+                            //
+                            // - liveness timeout once removed from the configuration: never
+                            //   campaign, we are about to be decommissioned
+                            //
+                            display!(self, "{:?}*| removed, not campaigning", ctx);
+                            ctx.leader = None;
+                            schedule!(self, this, TIMEOUT(self.seq), self.config.liveness_timeout);
 
                         } else {
 
@@ -580,142 +2504,748 @@ where
                     }
                     LEAD(ref ctx) => {
 
+                        //
+                        // - step down once removed from the configuration, unless handing our
+                        //   leadership off: if decommissioning start transferring it to our
+                        //   successor
+                        // - notify the sink with IDLE
+                        //
+                        if self.removed && self.transfer.is_none() {
+                            match self.successor.take() {
+                                Some((target, tx)) => {
+                                    warn!(
+                                        &self.logger,
+                                        "handing leadership off to peer #{}",
+                                        target
+                                    );
+                                    self.transfer = Some(Transfer {
+                                        target,
+                                        deadline: self.clock.now() + self.config.transfer_timeout,
+                                        sent: false,
+                                        tx,
+                                    });
+                                    self.hand_off(this);
+                                }
+                                None => {
+                                    display!(self, "{:?}*| removed, stepping down", ctx);
+                                    self.sink.push(Notification::IDLE);
+                                    self.idle_since.get_or_insert(self.clock.now());
+                                    return FLWR(context::FLWR {
+                                        live: false,
+                                        leader: None,
+                                    });
+                                }
+                            }
+                        }
+
                         //
                         // - assert our authority by sending a PING to all our peers
                         // - any peer receiving those will turn into a FOLLOWER if not already
                         //   the case
                         //
+                        // - attach the payload digest at our commit offset
+                        // - work out how many entries at most go in one REPLICATE
+                        //
                         // @todo better manager idle times vs. dirty state
                         //
+                        // - if we did not hear from a majority of voters for longer than the
+                        //   liveness timeout we are most likely partitioned away: step down
+                        //   instead of accepting writes that can't commit
+                        // - notify the sink with IDLE and the observer with QuorumLost
+                        //
+                        // - if transferring our leadership give up once the timeout elapses
+                        //   (we keep leading), otherwise skip the heartbeat if the target was
+                        //   told to campaign
+                        //
+                        let now = self.clock.now();
+                        if self.quorum_lost(now) {
+                            display!(self, "{:?}*| quorum lost, stepping down", ctx);
+                            self.sink.push(Notification::IDLE);
+                            self.idle_since.get_or_insert(now);
+                            self.observe(Observation::QuorumLost);
+                            return FLWR(context::FLWR {
+                                live: false,
+                                leader: None,
+                            });
+                        }
+                        if self.transfer.as_ref().map_or(false, |t| now >= t.deadline) {
+                            let transfer = self.transfer.take().expect("transfer pending");
+                            warn!(
+                                &self.logger,
+                                "leadership transfer to peer #{} timed out",
+                                transfer.target
+                            );
+                            let _ = transfer.tx.send(None);
+                        } else if self.removed && self.transfer.as_ref().map_or(false, |t| t.sent) {
+
+                            //
+                            // - once removed we won't hear from our successor anymore (we are
+                            //   not a member of its configuration): consider the hand-off done
+                            //   as soon as it has been told to campaign and step down
+                            //
+                            let transfer = self.transfer.take().expect("transfer pending");
+                            display!(
+                                self,
+                                "{:?}*| handed off to #{}, stepping down",
+                                ctx,
+                                transfer.target
+                            );
+                            let _ = transfer.tx.send(Some(transfer.target));
+                            self.sink.push(Notification::IDLE);
+                            self.idle_since.get_or_insert(now);
+                            return FLWR(context::FLWR {
+                                live: false,
+                                leader: None,
+                            });
+                        } else if self.transfer.as_ref().map_or(false, |t| t.sent) {
+                            let lapse = self.config.heartbeat_interval;
+                            schedule!(self, this, TIMEOUT(self.seq), lapse);
+                            return state;
+                        }
+                        let digest = self.digest().map(|(_, digest)| digest);
+                        let lapse = self.config.liveness_timeout;
+                        let rebasing = |peer: &Peer| {
+                            peer.rebasing.and_then(|t| since(t, now)).map_or(false, |d| d < lapse)
+                        };
+                        let mut inflight = self.peers
+                            .values()
+                            .filter(|&peer| rebasing(peer))
+                            .count();
+                        let members = self.members();
+                        let batch = self.batch();
                         for peer in &mut self.peers {
                             debug_assert!(*peer.0 != self.id);
                             let msg = PING {
                                 id: self.id,
                                 term: self.term,
                                 commit: self.commit,
+                                digest,
                             };
-                            let bytes = msg.to_raw(&self.host, &peer.1.host);
-                            (self.write)(&peer.1.host, &bytes);
+                            send!(self, this, &peer.1.host, msg);
                             debug_assert!(peer.1.off <= self.head);
-                            if self.head > peer.1.off {
+
+                            //
+                            // - if what we replicated to that peer was not acknowledged in time
+                            //   assume the REPLICATE (or its ACK) got lost: rewind to its last
+                            //   acknowledged offset and send again
+                            // - the timeout doubles upon each attempt (up to the liveness
+                            //   timeout) and resets as soon as the peer makes progress
+                            //
+                            if let (Some(timeout), Some(resend)) =
+                                (self.config.resend_timeout, peer.1.resend)
+                            {
+                                let lapse = cmp::min(
+                                    timeout * (1 << cmp::min(resend.attempts, 16)),
+                                    self.config.liveness_timeout,
+                                );
+                                if since(resend.at, now).map_or(false, |d| d >= lapse) {
+                                    display!(
+                                        self,
+                                        "{:?}*| resending from #{} to peer #{} (attempt {})",
+                                        ctx,
+                                        resend.from,
+                                        peer.0,
+                                        resend.attempts + 1
+                                    );
+                                    peer.1.off = resend.from;
+                                    peer.1.inflight.clear();
+                                    peer.1.resend = Some(Resend {
+                                        from: resend.from,
+                                        at: now,
+                                        attempts: resend.attempts + 1,
+                                    });
+                                }
+                            }
+
+                            //
+                            // - if the peer needs to be rebased and we are already sending too
+                            //   many snapshots skip it for now
+                            // - it still gets heartbeats and will be rebased later on
+                            // - a rebase is considered in flight until the peer acknowledges it
+                            //   or until the liveness timeout elapses
+                            //
+                            if peer.1.off + 1 < self.tail && !rebasing(&*peer.1) {
+                                if let Some(limit) = self.config.max_concurrent_snapshots {
+                                    if inflight >= limit {
+                                        display!(
+                                            self,
+                                            "{:?} | peer #{} queued for rebasing",
+                                            ctx,
+                                            peer.0
+                                        );
+                                        continue;
+                                    }
+                                }
+                                inflight += 1;
+                                peer.1.rebasing = Some(self.clock.now());
+                            }
+
+                            //
+                            // - hold back whatever does not fit in the in-flight window, e.g
+                            //   what was sent but not acknowledged yet (the ACK will resume)
+                            // - a rebase is never held back
+                            //
+                            let upto = match self.config.max_inflight {
+                                Some(window) if peer.1.off + 1 >= self.tail => {
+                                    cmp::min(self.head, peer.1.ack + window as u64)
+                                }
+                                _ => self.head,
+                            };
+                            if upto > peer.1.off {
 
                                 //
                                 // - we have entries to replicate
-                                // - prep a append buffer
-                                // - check if we need to replicate
                                 //
-                                let mut append = Vec::new();
                                 debug_assert!(self.head >= peer.1.ack);
                                 debug_assert!(self.head >= peer.1.off);
                                 debug_assert!(
                                     peer.1.off >= peer.1.ack,
                                     format!("id {} off {} ack {}", peer.0, peer.1.off, peer.1.ack)
                                 );
-                                let snapshot = if peer.1.off < self.tail {
+                                if peer.1.off + 1 < self.tail {
 
                                     //
-                                    // - if the peer is behind our log window bump it
-                                    // - the rebase flag will force it to align with us
-                                    // - copy the whole log window to the append buffer
+                                    // - if the peer is behind our log window rebase it with our
+                                    //   snapshot, split in chunks sent back to back
+                                    // - the snapshot reflects every entry below its offset: the
+                                    //   peer then holds the last one (and its term) and regular
+                                    //   replication picks up from there
                                     // - please note 1+ commit notifications will thus be lost
-                                    //   on that peer (at least the peer will notify it was
-                                    //   rebased)
+                                    //   on that peer
                                     //
                                     display!(
                                         self,
-                                        "{:?}*| bumping peer #{} to [#{} #{}] (lag ?)",
+                                        "{:?}*| installing snapshot #{} on peer #{} (lag ?)",
                                         ctx,
-                                        peer.0,
-                                        self.tail,
-                                        self.head
+                                        self.snapshot_off,
+                                        peer.0
                                     );
-                                    peer.1.off = self.tail;
-                                    let n = self.head - self.tail + 1;
-                                    read_range!(self, append, self.tail, n);
-                                    self.snapshot.clone()
+                                    let last_index = self.snapshot_off - 1;
+                                    let last_term = term_at!(self, last_index);
+                                    let size = self.snapshot.len();
+                                    let mut offset = 0;
+                                    loop {
+                                        let end = cmp::min(
+                                            offset + self.config.snapshot_chunk_bytes,
+                                            size,
+                                        );
+                                        let (requests, peers) = if offset == 0 {
+                                            (self.snapshot_requests.clone(), members.clone())
+                                        } else {
+                                            (Vec::new(), Vec::new())
+                                        };
+                                        let msg = INSTALL_SNAPSHOT {
+                                            id: self.id,
+                                            term: self.term,
+                                            last_index,
+                                            last_term,
+                                            offset: offset as u64,
+                                            size: size as u64,
+                                            chunk: self.snapshot[offset..end].to_vec(),
+                                            requests,
+                                            peers,
+                                        };
+
+                                        send!(self, this, &peer.1.host, msg);
+                                        offset = end;
+                                        if offset == size {
+                                            break;
+                                        }
+                                    }
+                                    peer.1.off = last_index;
+                                    peer.1.inflight.clear();
+                                    peer.1.inflight.push_back(last_index);
+                                    peer.1.resend = None;
 
                                 } else {
 
                                     //
                                     // - we have 1+ log entries to replicate
-                                    // - copy entries from [off + 1, head] to the append buffer
                                     //
                                     display!(
                                         self,
                                         "{:?} | replicating [#{} .. #{}] to peer #{}",
                                         ctx,
                                         peer.1.off + 1,
-                                        self.head,
+                                        upto,
                                         peer.0
                                     );
-                                    let n = self.head - peer.1.off;
-                                    read_range!(self, append, peer.1.off + 1, n);
-                                    Vec::new()
-                                };
-
-                                //
-                                // - specify the index+term for the write offset (e.g the offset
-                                //   immediately preceding the first replicated entry)
-                                // - even if we have no entries to replicate this will force the
-                                //   FOLLOWER to check its log and flag any conflict
-                                // - blank peers will also be able to synch-up this way
-                                // - emit a REPLICATE
-                                //
-                                debug_assert!(!append.is_empty());
-                                let slot = read_slot!(self, peer.1.off);
-                                let msg = REPLICATE {
-                                    id: self.id,
-                                    term: self.term,
-                                    commit: self.commit,
-                                    off: peer.1.off,
-                                    age: slot.term,
-                                    append,
-                                    snapshot,
-                                };
-
-                                let bytes = msg.to_raw(&self.host, &peer.1.host);
-                                (self.write)(&peer.1.host, &bytes);
-                                peer.1.off = self.head;
+                                    replicate!(self, this, peer.1, upto, batch);
+                                }
                             }
                         }
 
                         //
                         // - schedule a new heartbeat timeout
-                        // - note we use a fraction of the liveness timeout duration
+                        // - note the heartbeat interval is a fraction of the liveness timeout
                         // - this is to be safe
                         //
-                        self.timer.schedule(
-                            this.clone(),
-                            TIMEOUT(self.seq),
-                            Duration::from_millis(FSM::<S, T, U>::LIVENESS_TIMEOUT / 4),
-                        );
+                        schedule!(self, this, TIMEOUT(self.seq), self.config.heartbeat_interval);
+                    }
+                }
+            }
+            Opcode::CMD(STORE_ASYNC(bytes, tx, appended)) => {
+
+                //
+                // - append if we are leading and park the completion channel until the entry
+                //   commits
+                // - pass the offset back right away if asked to (e.g for a StoreHandle)
+                // - reject right away if we can't append, if we did not hear from a quorum
+                //   lately (we are about to step down) or if we are transferring our
+                //   leadership (the target must catch up with our head)
+                //
+                let off = match state {
+                    LEAD(_) if self.quorum_lost(self.clock.now()) => None,
+                    LEAD(ref ctx) if self.transfer.is_none() => self.append(ctx, bytes, None),
+                    _ => None,
+                };
+                match off {
+                    Some(off) => {
+                        if let Some(appended) = appended {
+                            let _ = appended.send(off);
+                        }
+                        self.waiters.push_back((off, tx));
+                    }
+                    None => {
+                        self.pending.fetch_sub(1, Ordering::Release);
+                        let _ = tx.send(StoreResult::Rejected);
+                    }
+                }
+            }
+            Opcode::CMD(STORE_ONCE(id, bytes, tx, appended)) => {
+
+                //
+                // - if that request was applied already reply right away with its offset
+                // - if we appended it already and it is still in flight park the completion
+                //   channel next to the original one
+                // - otherwise append it along with its id, as we do for STORE_ASYNC
+                // - either way the offset is passed back as soon as we know it if asked to
+                //
+                if let Some(off) = self.requests.get(id) {
+                    if let Some(appended) = appended {
+                        let _ = appended.send(off);
+                    }
+                    self.pending.fetch_sub(1, Ordering::Release);
+                    let _ = tx.send(StoreResult::Committed(off, Vec::new()));
+                } else if let Some(off) = self.proposed.get(&id).cloned() {
+                    if let Some(appended) = appended {
+                        let _ = appended.send(off);
+                    }
+                    let n = self.waiters.iter().take_while(|w| w.0 <= off).count();
+                    self.waiters.insert(n, (off, tx));
+                } else {
+                    let off = match state {
+                        LEAD(_) if self.quorum_lost(self.clock.now()) => None,
+                        LEAD(ref ctx) if self.transfer.is_none() => {
+                            self.append(ctx, bytes, Some(id))
+                        }
+                        _ => None,
+                    };
+                    match off {
+                        Some(off) => {
+                            if let Some(appended) = appended {
+                                let _ = appended.send(off);
+                            }
+                            let _ = self.proposed.insert(id, off);
+                            self.waiters.push_back((off, tx));
+                        }
+                        None => {
+                            self.pending.fetch_sub(1, Ordering::Release);
+                            let _ = tx.send(StoreResult::Rejected);
+                        }
+                    }
+                }
+            }
+            Opcode::CMD(NOOP(tx)) => {
+
+                //
+                // - append an empty entry, which goes through the log like any other (it is
+                //   replicated, committed, checkpointed and passed to the apply closure)
+                // - reply with its offset
+                //
+                let off = match state {
+                    LEAD(ref ctx) => self.append(ctx, Vec::new(), None),
+                    _ => None,
+                };
+                let _ = tx.send(off);
+            }
+            Opcode::CMD(ADMISSION(tx)) => {
+                let used = self.head - self.tail;
+                let res = StoreAdmission {
+                    leader: match state {
+                        LEAD(_) => true,
+                        _ => false,
+                    },
+                    quarantined: self.quarantined,
+                    backlog: self.head + 1 - self.commit,
+                    room: FSM::<S, T, U>::RESOLUTION as u64 - 1 - used,
+                };
+                let _ = tx.send(res);
+            }
+            Opcode::CMD(ACKNOWLEDGE) => {
+
+                //
+                // - the ACK coalescing window closed
+                // - emit a single ACK for our current head offset if we are still following,
+                //   once whatever we wrote is durable
+                //
+                if let Some(dst) = self.ack_to.take() {
+                    if let FLWR(_) = state {
+                        if !self.sync_log() {
+                            return state;
+                        }
+                        let msg = ACK {
+                            id: self.id,
+                            term: self.term,
+                            ack: self.head,
+                            learner: self.config.learner,
+                            digest: self.digest(),
+                        };
+
+                        send!(self, this, &dst, msg);
+                    }
+                }
+            }
+            Opcode::CMD(READ(level, tx)) => {
+
+                //
+                // - check whether our payload may be read at the requested consistency level
+                // - reply with the commit offset it reflects
+                // - a linearizable read is confirmed through the log first
+                //
+                match level {
+                    ReadConsistency::Linearizable => self.confirm(state, tx),
+                    _ => {
+                        let res = self.readable(state, level);
+                        self.serve(tx, res);
+                    }
+                }
+            }
+            Opcode::CMD(READ_AFTER(level, token, timeout, tx)) => {
+
+                //
+                // - same as READ except the payload must also reflect the session token
+                // - if we are lagging park the read until we commit up to it, or until its
+                //   caller gives up on it
+                // - a confirmed linearizable read covers any token handed out so far (e.g the
+                //   LEADER commits everything the cluster committed before the no-op)
+                //
+                if level == ReadConsistency::Linearizable {
+                    self.confirm(state, tx);
+                    return state;
+                }
+                match self.readable(state, level) {
+                    Ok(commit) if commit < token => {
+                        let deadline = self.clock.now() + timeout;
+                        self.readers.push((token, level, deadline, tx));
+                    }
+                    res => self.serve(tx, res),
+                }
+            }
+            Opcode::CMD(READ_INDEX(tx)) => {
+
+                //
+                // - same as READ at the linearizable level
+                //
+                self.confirm(state, tx);
+            }
+            Opcode::CMD(TERM_AT(off, tx)) => {
+                let _ = tx.send(self.term_at(off));
+            }
+            Opcode::CMD(LOG_RANGE(from, to, tx)) => {
+                let _ = tx.send(self.log_range(from, to));
+            }
+            Opcode::CMD(LOG_HEAD(tx)) => {
+                let _ = tx.send(self.head);
+            }
+            Opcode::CMD(CONFIGURATION(tx)) => {
+
+                //
+                // - the membership is set upon spawning, then changes whenever a MEMBERSHIP
+                //   entry commits (see `Raft::add_peer()`)
+                // - learners are known locally or from their ACKs (e.g only the LEADER knows
+                //   about all of them)
+                //
+                let _ = tx.send(self.configuration());
+            }
+            Opcode::CMD(SNAPSHOT(tx)) => {
+
+                //
+                // - checkpoint right away at our commit offset instead of waiting for the next
+                //   boundary
+                // - reply with the offset the latest snapshot reflects
+                //
+                self.compact(state);
+                let _ = tx.send(self.snapshot_off);
+            }
+            Opcode::CMD(CHECKPOINTED(off, bytes)) => {
+
+                //
+                // - the apply thread flushed the payload at that offset, see compact_async()
+                // - adopt the snapshot unless a more recent one was taken meanwhile (e.g at a
+                //   checkpoint boundary or upon being rebased)
+                //
+                if let Some(requests) = self.checkpointing.take() {
+                    match state {
+                        PREV(ref ctx) | CNDT(ref ctx) => {
+                            self.install(ctx, off, off, requests, bytes)
+                        }
+                        FLWR(ref ctx) => self.install(ctx, off, off, requests, bytes),
+                        LEAD(ref ctx) => self.install(ctx, off, off, requests, bytes),
+                    }
+                }
+            }
+            Opcode::CMD(ELECTION_STATE(tx)) => {
+
+                //
+                // - only report the real election, e.g once the ADVERTISE went out
+                // - we implicitly vote for ourselves
+                //
+                let res = match state {
+                    CNDT(ctx) if ctx.advertised => {
+                        let mut granted = vec![self.id];
+                        let mut pending = Vec::new();
+                        for id in self.peers.keys() {
+                            if self.ballots.contains(id) {
+                                granted.push(*id);
+                            } else {
+                                pending.push(*id);
+                            }
+                        }
+                        granted.sort();
+                        pending.sort();
+                        Some(ElectionState {
+                            term: self.term,
+                            granted,
+                            pending,
+                        })
+                    }
+                    _ => None,
+                };
+                let _ = tx.send(res);
+            }
+            Opcode::CMD(STATUS(tx)) => {
+                let leader = match state {
+                    LEAD(_) => Some(self.id),
+                    FLWR(ctx) => ctx.leader,
+                    _ => None,
+                };
+                let _ = tx.send(Status {
+                    id: self.id,
+                    term: self.term,
+                    commit: self.commit,
+                    role: Role::of(state, self.config.learner),
+                    leader,
+                    head: self.head,
+                });
+            }
+            Opcode::CMD(AWAIT_LEADER(timeout, tx)) => {
+
+                //
+                // - reply right away if we know the LEADER, otherwise wait for one (or until
+                //   the caller gives up)
+                //
+                match state {
+                    LEAD(_) => {
+                        let _ = tx.send(self.id);
                     }
+                    FLWR(context::FLWR { leader: Some(id), .. }) => {
+                        let _ = tx.send(id);
+                    }
+                    _ => self.watchers.push((self.clock.now() + timeout, tx)),
+                }
+            }
+            Opcode::CMD(IDLE_DURATION(tx)) => {
+                let _ = tx.send(self.idle_since.and_then(|t| since(t, self.clock.now())));
+            }
+            Opcode::CMD(METRICS(tx)) => {
+                let now = self.clock.now();
+                let _ = tx.send(RaftMetrics {
+                    appended: self.appended.total(),
+                    committed: self.committed.total(),
+                    append_rate: self.appended.rate(now),
+                    commit_rate: self.committed.rate(now),
+                    disk_usage: self.disk_usage(),
+                    role: Role::of(state, self.config.learner),
+                    dropped: 0,
+                    messages: self.messages,
+                });
+            }
+            Opcode::CMD(PEER_METRICS(tx)) => {
+
+                //
+                // - only the LEADER tracks how far its peers are
+                // - a peer which never acknowledged anything has an unknown lag
+                //
+                let now = self.clock.now();
+                let mut all: Vec<_> = match state {
+                    LEAD(_) => self.peers
+                        .iter()
+                        .map(|(id, peer)| PeerMetric {
+                            id: *id,
+                            acked: peer.acked.map(|(off, _)| off),
+                            matched: peer.ack,
+                            lag: peer.acked.map(|_| self.head.saturating_sub(peer.ack)),
+                            inflight: peer.inflight.len(),
+                            since_ack: peer.acked.and_then(|(_, t)| since(t, now)),
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                all.sort_by_key(|metric| metric.id);
+                let _ = tx.send(all);
+            }
+            Opcode::CMD(OBSERVE(tx)) => {
+
+                //
+                // - lazily create the observation sink, subsequent calls share it
+                // - only report terms from now on
+                //
+                if self.observer.is_none() {
+                    self.observer = Some(Arc::new(Sink::new()));
+                    self.observed = self.term;
                 }
+                let _ = tx.send(self.observer.clone().expect("observer is set"));
+            }
+            Opcode::CMD(PRE_APPLY(hook)) => {
+                self.stage.lock().unwrap().pre_apply.push(hook);
+            }
+            Opcode::CMD(POST_APPLY(hook)) => {
+                self.stage.lock().unwrap().post_apply.push(hook);
+            }
+            Opcode::CMD(RECONFIGURE(delta, tx)) => {
+
+                //
+                // - validate and swap our configuration
+                // - the new timeouts kick in the next time we re-arm the timer
+                //
+                let res = self.config.apply(&delta).map(|config| {
+                    info!(&self.logger, "               | | reconfigured ({:?})", delta);
+                    self.config = config;
+                });
+                let _ = tx.send(res);
+            }
+            Opcode::CMD(INTERCEPT(f)) => {
+                self.interceptor = Some(f);
+            }
+            Opcode::CMD(DELAYED(raw)) => {
+
+                //
+                // - an inbound buffer was held back by the interceptor: process it now
+                //
+                self.replaying = true;
+                return self.process(this, state, Opcode::CMD(BYTES(raw)));
+            }
+            Opcode::CMD(SEND(dst, raw)) => {
+
+                //
+                // - an outbound buffer was held back by the interceptor: send it now
+                //
+                self.messages.on_sent(raw.code);
+                (self.write)(&dst, &serialize(&raw).expect("RAW is serializable"));
+            }
+            Opcode::CMD(QUORUM(quorum)) => {
+                self.quorum = quorum;
             }
-            Opcode::CMD(STORE(bytes)) => {
-                if let LEAD(ref ctx) = state {
+            Opcode::CMD(VOTE_HOOK(hook)) => {
+                self.vote_hook = Some(hook);
+            }
+            Opcode::CMD(CAMPAIGN) => {
+                if let FLWR(ref ctx) = state {
 
                     //
-                    // - make sure we have enough room in the log
+                    // - forced election: same as a liveness timeout except we don't wait for it
+                    // - notify the sink with IDLE
+                    // - switch to PREVOTE, the election may very well fail
+                    // - a quarantined, learning or removed peer never campaigns
                     //
-                    if self.head - self.tail == FSM::<S, T, U>::RESOLUTION as u64 - 1 {
-                        display!(self, "{:?}*| discarding record (log full)", ctx);
+                    if self.quarantined {
+                        display!(self, "{:?}*| quarantined, not campaigning", ctx);
+                    } else if self.config.learner {
+                        display!(self, "{:?}*| learner, not campaigning", ctx);
+                    } else if self.removed {
+                        display!(self, "{:?}*| removed, not campaigning", ctx);
                     } else {
+                        display!(self, "{:?}*| forcing an election", ctx);
+                        self.sink.push(Notification::IDLE);
+                        return PREV(context::CNDT::default());
+                    }
+                }
+            }
+            Opcode::CMD(CLEAR) => {
+                if self.quarantined {
+                    warn!(&self.logger, "quarantine cleared by operator");
+                    self.quarantined = false;
+                }
+            }
+            Opcode::CMD(CHANGE(change, tx)) => {
+                let _ = tx.send(self.propose(state, change));
+            }
+            Opcode::CMD(DECOMMISSION(successor, tx, handoff)) => {
 
-                        //
-                        // - increment the head offset
-                        // - update the term tracker for the head
-                        // - add the entry to the log
-                        //
-                        self.head += 1;
-                        self.age = self.term;
-                        display!(self, "{:?} | appending record ({}B)", ctx, bytes.len());
-                        let slot = SLOT {
-                            code: 255,
-                            term: self.term,
-                            bytes,
-                        };
-                        write_slot!(self, serialize(&slot).unwrap(), self.head);
+                //
+                // - same as removing ourselves, except that once the removal commits we hand
+                //   our leadership off to the specified voter instead of merely stepping down
+                //   (see the heartbeat)
+                //
+                let voter = self.peers.get(&successor).map_or(false, |peer| !peer.learner);
+                let res = match state {
+                    LEAD(_) if !voter => Err(MembershipError::Invalid("successor is not a voter")),
+                    _ => {
+                        let id = self.id;
+                        self.propose(state, MEMBERSHIP::REMOVE(id))
+                    }
+                };
+                if res.is_ok() {
+                    self.successor = Some((successor, handoff));
+                }
+                let _ = tx.send(res);
+            }
+            Opcode::CMD(PROMOTE(tx)) => {
+
+                //
+                // - turn into a voter: our next ACK will tell the LEADER to count us
+                // - reply whether we were a learner
+                //
+                let learner = self.config.learner;
+                if learner {
+                    warn!(&self.logger, "promoted to voter");
+                    self.config.learner = false;
+                    self.role.store(Role::of(state, false) as usize, Ordering::Release);
+                    let bytes = serialize(&self.configuration()).expect("configuration is valid");
+                    self.observe(Observation::ConfigChanged(bytes));
+                }
+                let _ = tx.send(learner);
+            }
+            Opcode::CMD(TRANSFER(tx)) => {
+
+                //
+                // - only a LEADER transfers, one transfer at a time
+                // - pick the most up-to-date voter, e.g the one with the highest acknowledged
+                //   offset
+                // - hand off right away if it already caught up, otherwise upon its ACKs
+                //
+                let target = match state {
+                    LEAD(_) if self.transfer.is_none() => self.peers
+                        .iter()
+                        .filter(|&(_, peer)| !peer.learner)
+                        .max_by_key(|&(_, peer)| peer.ack)
+                        .map(|(id, _)| *id),
+                    _ => None,
+                };
+                match target {
+                    Some(target) => {
+                        warn!(&self.logger, "transferring leadership to peer #{}", target);
+                        self.transfer = Some(Transfer {
+                            target,
+                            deadline: self.clock.now() + self.config.transfer_timeout,
+                            sent: false,
+                            tx,
+                        });
+                        self.hand_off(this);
+                    }
+                    None => {
+                        let _ = tx.send(None);
                     }
                 }
             }
@@ -724,9 +3254,42 @@ where
                     &self.logger,
                     "<- {}B from {} (code #{})",
                     raw.msg.len(),
-                    str::from_utf8(&raw.src).unwrap(),
+                    label(&raw.src),
                     raw.code
                 );
+
+                //
+                // - drop buffers from peers running an incompatible protocol version
+                //
+                if !compatible(raw.version) {
+                    warn!(
+                        &self.logger,
+                        "               | | dropping {} from {} (version {:#06x})",
+                        raw.kind(),
+                        label(&raw.src),
+                        raw.version
+                    );
+                    return state;
+                }
+
+                //
+                // - count the message, unless this is a buffer we process again
+                // - run the interceptor first if any (unless it already delayed that buffer)
+                //
+                if !mem::replace(&mut self.replaying, false) {
+                    self.messages.on_received(raw.code);
+                    if let Some(ref f) = self.interceptor {
+                        let action = f(Direction::Inbound, &raw);
+                        match action {
+                            Action::Pass => {}
+                            Action::Drop => return state,
+                            Action::Delay(lapse) => {
+                                schedule!(self, this, DELAYED(raw), lapse);
+                                return state;
+                            }
+                        }
+                    }
+                }
                 #[cfg(feature = "chaos")]
                 {
                     match raw.code {
@@ -740,7 +3303,7 @@ where
                 }
                 match raw.code {
                     UPGRADE::CODE => {
-                        let msg: UPGRADE = deserialize(&raw.msg[..]).unwrap();
+                        let msg: UPGRADE = decode!(self, state, raw);
                         debug_assert!(msg.id != self.id);
                         if msg.term > self.term {
 
@@ -756,6 +3319,7 @@ where
                             //
                             self.term = msg.term;
                             self.sink.push(Notification::IDLE);
+                            self.idle_since.get_or_insert(self.clock.now());
                             return FLWR(context::FLWR {
                                 live: false,
                                 leader: None,
@@ -763,7 +3327,7 @@ where
                         }
                     }
                     PING::CODE => {
-                        let mut msg: PING = deserialize(&raw.msg[..]).unwrap();
+                        let mut msg: PING = decode!(self, state, raw);
                         debug_assert!(msg.id != self.id);
                         if msg.term < self.term {
 
@@ -774,10 +3338,14 @@ where
                                 id: self.id,
                                 term: self.term,
                             };
-                            let bytes = msg.to_raw(&self.host, &raw.src);
-                            (self.write)(&raw.src, &bytes);
+                            send!(self, this, &raw.src, msg);
 
                         } else {
+
+                            //
+                            // - keep track of when we last heard from a LEADER
+                            //
+                            self.contact = self.clock.now();
                             match state {
                                 PREV(_) | CNDT(_) => {
 
@@ -790,6 +3358,7 @@ where
                                     //
                                     self.term = msg.term;
                                     self.sink.push(Notification::FOLLOWING);
+                                    self.leader_found(msg.id);
                                     return FLWR(context::FLWR {
                                         live: false,
                                         leader: Some(msg.id),
@@ -802,14 +3371,19 @@ where
                                         ctx,
                                         msg.id
                                     );
-                                    if ctx.leader.is_none() {
+                                    if ctx.leader != Some(msg.id) {
 
                                         //
                                         // - notify the sink with FOLLOWING in case we went
                                         //   from no leader to one (e.g we started for instance)
                                         // - increment the sink semaphore
+                                        // - a switch from one LEADER to another one only
+                                        //   notifies with LEADER
                                         //
-                                        self.sink.push(Notification::FOLLOWING);
+                                        if ctx.leader.is_none() {
+                                            self.sink.push(Notification::FOLLOWING);
+                                        }
+                                        self.leader_found(msg.id);
                                     }
 
                                     //
@@ -817,54 +3391,40 @@ where
                                     // - upgrade our term if we are stale
                                     // - make sure we update our leader id if needed
                                     // - check if we need to update the commit offset
+                                    // - acknowledge our head offset, which tells the LEADER
+                                    //   it still has a quorum
                                     //
                                     ctx.live = true;
                                     self.term = msg.term;
                                     ctx.leader = Some(msg.id);
-                                    let next = cmp::min(msg.commit, self.head);
-                                    if next > self.commit {
-                                        debug_assert!(next >= self.tail);
-                                        let mut guard = self.payload.write();
-                                        for n in self.commit..next {
-                                            let slot = read_slot!(self, n);
-                                            (self.apply)(&mut guard, &slot.bytes);
-                                        }
-                                        drop(guard);
-                                        self.commit = next;
-                                        display!(
-                                            self,
-                                            "{:?} | offset #{} committed",
-                                            ctx,
-                                            self.commit
-                                        );
+                                    self.follow_commit(&*ctx, msg.commit);
+                                    self.acknowledge(this, raw.src);
 
-                                        //
-                                        // - if the commit index reached a checkpoint boundary
-                                        //   reset the tail to that offset
-                                        // - lock the payload and take a snapshot of it
-                                        // - flush the logfile
-                                        // - notify the sink with a CHECKPOINT
-                                        // - signal the sink event
-                                        //
-                                        let boundary = self.commit -
-                                            (self.commit % FSM::<S, T, U>::CHECKPOINT as u64);
-                                        if boundary > self.tail {
-                                            let guard = self.payload.read();
-                                            let mut bytes = (*guard).flush();
-                                            drop(guard);
-                                            self.snapshot.clear();
-                                            self.snapshot.append(&mut bytes);
-                                            display!(
-                                                self,
-                                                "{:?} | snapshot [#{} #{}], {}B",
-                                                ctx,
-                                                self.tail,
-                                                boundary,
-                                                self.snapshot.len()
-                                            );
-                                            self.log.flush().unwrap();
-                                            self.sink.push(Notification::CHECKPOINT(boundary));
-                                            self.tail = boundary;
+                                    //
+                                    // - if we applied exactly what the leader applied compare
+                                    //   both payload digests
+                                    // - any mismatch means our state machine diverged: quarantine
+                                    //   ourselves, notify the sink with SAFETY_VIOLATION and
+                                    //   stop taking part in elections until cleared
+                                    //
+                                    if !self.quarantined && self.commit == msg.commit &&
+                                        !self.applying()
+                                    {
+                                        let local = self.payload.read().checksum();
+                                        if let (Some(a), Some(b)) = (local, msg.digest) {
+                                            if a != b {
+                                                warn!(
+                                                    &self.logger,
+                                                    "payload divergence at #{} ({:x} vs. {:x})",
+                                                    self.commit,
+                                                    a,
+                                                    b
+                                                );
+                                                self.quarantined = true;
+                                                self.sink.push(
+                                                    Notification::SAFETY_VIOLATION(self.commit),
+                                                );
+                                            }
                                         }
                                     }
                                 }
@@ -880,6 +3440,7 @@ where
                                     self.term = msg.term;
                                     display!(self, "{:?}*| stepping down", ctx);
                                     self.sink.push(Notification::FOLLOWING);
+                                    self.leader_found(msg.id);
 
                                     return FLWR(context::FLWR {
                                         live: false,
@@ -890,8 +3451,36 @@ where
                         }
                     }
                     REPLICATE::CODE => {
-                        let mut msg: REPLICATE = deserialize(&raw.msg[..]).unwrap();
+                        let mut msg: REPLICATE = decode!(self, state, raw);
                         debug_assert!(msg.id != self.id);
+                        if self.config.entry_checksums && !FSM::<S, T, U>::intact(&msg.append) {
+
+                            //
+                            // - some entry got corrupted in transit: drop the whole message
+                            // - the LEADER will replicate again on its next heartbeat
+                            //
+                            warn!(
+                                &self.logger,
+                                "               | | dropping corrupted REPLICATE from peer #{}",
+                                msg.id
+                            );
+                            return state;
+                        }
+                        let n = msg.append.len() / FSM::<S, T, U>::SLOT_BYTES;
+                        if self.config.max_replicate_entries.map_or(false, |cap| n > cap) {
+
+                            //
+                            // - the LEADER sends more entries at once than we accept: drop the
+                            //   whole message (its batch limit is probably misconfigured)
+                            //
+                            warn!(
+                                &self.logger,
+                                "               | | dropping REPLICATE from peer #{} ({} entries)",
+                                msg.id,
+                                n
+                            );
+                            return state;
+                        }
                         if msg.term < self.term {
 
                             //
@@ -901,53 +3490,30 @@ where
                                 id: self.id,
                                 term: self.term,
                             };
-                            let bytes = msg.to_raw(&self.host, &raw.src);
-                            (self.write)(&raw.src, &bytes);
+                            send!(self, this, &raw.src, msg);
 
                         } else {
-                            let rebase = !msg.snapshot.is_empty();
                             let n = (msg.append.len() / FSM::<S, T, U>::SLOT_BYTES) as u64;
                             debug_assert!(n > 0);
                             match state {
-                                FLWR(ref mut ctx) if rebase => {
-
-                                    //
-                                    // - reset our log using the append buffer
-                                    // - override our tail and head offsets
-                                    //
-                                    self.tail = msg.off;
-                                    self.head = self.tail + n as u64 - 1;
-                                    let buf = msg.append;
-                                    write_range!(self, buf, msg.off, n);
-                                    let slot = read_slot!(self, self.head);
-                                    self.age = slot.term;
-                                    display!(
-                                        self,
-                                        "{:?} | rebased into [#{} #{}]",
-                                        ctx,
-                                        self.tail,
-                                        self.head
-                                    );
-
-                                    //
-                                    // - lock the payload
-                                    // - deserialize and use the snapshot we received
-                                    //
-                                    let mut guard = self.payload.write();
-                                    (*guard).reset(&msg.snapshot);
-                                    drop(guard);
+                                FLWR(ref ctx) if msg.off < self.tail => {
 
                                     //
-                                    // - emit a ACK to acknowledge our new head offset
+                                    // - stale message sent before we got ahead (e.g regular
+                                    //   replication delayed past a snapshot install)
+                                    // - applying it would point below our tail: drop it and
+                                    //   re-acknowledge our head instead
                                     //
+                                    display!(self, "{:?} | skipping stale REPLICATE", ctx);
                                     let msg = ACK {
                                         id: self.id,
                                         term: self.term,
                                         ack: self.head,
+                                        learner: self.config.learner,
+                                        digest: self.digest(),
                                     };
 
-                                    let bytes = msg.to_raw(&self.host, &raw.src);
-                                    (self.write)(&raw.src, &bytes);
+                                    send!(self, this, &raw.src, msg);
                                 }
                                 FLWR(ref mut ctx) => {
 
@@ -966,7 +3532,6 @@ where
                                     //    prevLogIndex whose term matches prevLogTerm (§5.3)"
                                     //
                                     let mut conflict = true;
-                                    debug_assert!(msg.off >= self.tail);
                                     if msg.off <= self.head {
 
                                         let slot = read_slot!(self, msg.off);
@@ -975,8 +3540,8 @@ where
                                             //
                                             // - the specified log offset matches
                                             // - now this offset may not be our head, therefore
-                                            //   truncate() to drop all subsequent entries
-                                            // - then transfer the entries to append at the head
+                                            //   reconcile() the entries that follow with ours,
+                                            //   dropping everything from the first conflict on
                                             // - this is conveyed in the original paper as
                                             //
                                             //   "If an existing entry conflicts with a new one
@@ -984,14 +3549,20 @@ where
                                             //    existing entry and all that follow it (§5.3)
                                             //    Append any new entries not already in the log"
                                             //
-                                            // - truncate/append at the specified offset
-                                            // - udpate our head offset
+                                            // - udpate our head offset and its term
+                                            // - check if we need to update the commit offset
                                             //
-                                            let buf = msg.append;
-                                            write_range!(self, buf, msg.off + 1, n);
-                                            let slot = read_slot!(self, self.head);
-                                            self.head = msg.off + n as u64;
-                                            self.age = slot.term;
+                                            let diverged =
+                                                self.reconcile(msg.off, msg.term, msg.append);
+                                            if let Some(at) = diverged {
+                                                display!(
+                                                    self,
+                                                    "{:?}*| conflict at #{}, truncating",
+                                                    ctx,
+                                                    at
+                                                );
+                                            }
+                                            self.appended.record(n, self.clock.now());
                                             display!(
                                                 self,
                                                 "{:?} | replicated [#{} #{}]",
@@ -999,18 +3570,12 @@ where
                                                 msg.off + 1,
                                                 self.head
                                             );
+                                            self.follow_commit(&*ctx, msg.commit);
 
                                             //
-                                            // - emit a ACK to acknowledge our new head offset
+                                            // - acknowledge our new head offset
                                             //
-                                            let msg = ACK {
-                                                id: self.id,
-                                                term: self.term,
-                                                ack: self.head,
-                                            };
-
-                                            let bytes = msg.to_raw(&self.host, &raw.src);
-                                            (self.write)(&raw.src, &bytes);
+                                            self.acknowledge(this, raw.src);
                                             conflict = false;
                                         }
                                     }
@@ -1039,16 +3604,164 @@ where
                                             term: self.term,
                                         };
 
-                                        let bytes = msg.to_raw(&self.host, &raw.src);
-                                        (self.write)(&raw.src, &bytes);
+                                        send!(self, this, &raw.src, msg);
                                     }
                                 }
-                                _ => {}
+                                LEAD(_) if msg.term == self.term => {}
+                                _ => {
+
+                                    //
+                                    // - a LEADER is active at our term or later while we are
+                                    //   running an election (or are a stale LEADER)
+                                    // - step down right away, same as upon a PING
+                                    // - then process the REPLICATE as a FOLLOWER instead of
+                                    //   dropping the entries
+                                    //
+                                    self.term = msg.term;
+                                    self.contact = self.clock.now();
+                                    self.sink.push(Notification::FOLLOWING);
+                                    self.leader_found(msg.id);
+                                    self.replaying = true;
+                                    let next = FLWR(context::FLWR {
+                                        live: false,
+                                        leader: Some(msg.id),
+                                    });
+                                    return self.process(this, next, Opcode::CMD(BYTES(raw)));
+                                }
+                            }
+                        }
+                    }
+                    INSTALL_SNAPSHOT::CODE => {
+                        let msg: INSTALL_SNAPSHOT = decode!(self, state, raw);
+                        debug_assert!(msg.id != self.id);
+                        if msg.term < self.term {
+
+                            //
+                            // - stale peer: send back a UPGRADE
+                            //
+                            let msg = UPGRADE {
+                                id: self.id,
+                                term: self.term,
+                            };
+                            send!(self, this, &raw.src, msg);
+
+                        } else {
+                            match state {
+                                FLWR(ref ctx) if msg.last_index < self.commit => {
+
+                                    //
+                                    // - stale snapshot sent before we got ahead (e.g delayed
+                                    //   past regular replication)
+                                    // - applying it would rewind our payload: drop it and
+                                    //   re-acknowledge our head instead (once per snapshot)
+                                    //
+                                    if msg.offset == 0 {
+                                        display!(self, "{:?} | skipping stale snapshot", ctx);
+                                        let msg = ACK {
+                                            id: self.id,
+                                            term: self.term,
+                                            ack: self.head,
+                                            learner: self.config.learner,
+                                            digest: self.digest(),
+                                        };
+
+                                        send!(self, this, &raw.src, msg);
+                                    }
+                                }
+                                FLWR(ref ctx) => {
+
+                                    //
+                                    // - a first chunk starts a new snapshot
+                                    // - any other chunk must follow what we got so far for
+                                    //   that same snapshot: otherwise drop what we have (the
+                                    //   LEADER will rebase us again)
+                                    //
+                                    if msg.offset == 0 {
+                                        self.installing = Some(Installing {
+                                            last_index: msg.last_index,
+                                            last_term: msg.last_term,
+                                            requests: msg.requests,
+                                            peers: msg.peers,
+                                            bytes: Vec::new(),
+                                        });
+                                    }
+                                    let next = match self.installing {
+                                        Some(ref mut snapshot)
+                                            if snapshot.last_index == msg.last_index &&
+                                                snapshot.last_term == msg.last_term &&
+                                                snapshot.bytes.len() as u64 == msg.offset => {
+                                            snapshot.bytes.extend_from_slice(&msg.chunk);
+                                            Some(snapshot.bytes.len() as u64 >= msg.size)
+                                        }
+                                        _ => None,
+                                    };
+                                    match next {
+                                        Some(false) => {}
+                                        Some(true) => {
+
+                                            //
+                                            // - we got the whole snapshot: rebase on it
+                                            // - emit a ACK to acknowledge our new head
+                                            //   offset, once it is durable
+                                            //
+                                            let snapshot =
+                                                self.installing.take().expect("snapshot");
+                                            self.rebase(snapshot);
+                                            display!(
+                                                self,
+                                                "{:?} | rebased into [#{} #{}]",
+                                                ctx,
+                                                self.tail,
+                                                self.head
+                                            );
+                                            if !self.sync_log() {
+                                                return state;
+                                            }
+                                            let msg = ACK {
+                                                id: self.id,
+                                                term: self.term,
+                                                ack: self.head,
+                                                learner: self.config.learner,
+                                                digest: self.digest(),
+                                            };
+
+                                            send!(self, this, &raw.src, msg);
+                                        }
+                                        None => {
+                                            display!(
+                                                self,
+                                                "{:?}*| dropping snapshot chunk at {}B",
+                                                ctx,
+                                                msg.offset
+                                            );
+                                            self.installing = None;
+                                        }
+                                    }
+                                }
+                                LEAD(_) if msg.term == self.term => {}
+                                _ => {
+
+                                    //
+                                    // - same as upon a REPLICATE: a LEADER is active at our
+                                    //   term or later, step down and process the chunk as a
+                                    //   FOLLOWER
+                                    //
+                                    self.term = msg.term;
+                                    self.contact = self.clock.now();
+                                    self.sink.push(Notification::FOLLOWING);
+                                    self.leader_found(msg.id);
+                                    self.replaying = true;
+                                    let next = FLWR(context::FLWR {
+                                        live: false,
+                                        leader: Some(msg.id),
+                                    });
+                                    return self.process(this, next, Opcode::CMD(BYTES(raw)));
+                                }
                             }
                         }
                     }
                     ACK::CODE => {
-                        let msg: ACK = deserialize(&raw.msg[..]).unwrap();
+                        let msg: ACK = decode!(self, state, raw);
                         debug_assert!(msg.id != self.id);
                         if msg.term < self.term {
 
@@ -1059,8 +3772,7 @@ where
                                 id: self.id,
                                 term: self.term,
                             };
-                            let bytes = msg.to_raw(&self.host, &raw.src);
-                            (self.write)(&raw.src, &bytes);
+                            send!(self, this, &raw.src, msg);
 
                         } else if let LEAD(ref mut ctx) = state {
 
@@ -1076,20 +3788,28 @@ where
                             //     log[N].term == currentTerm:
                             //     set commitIndex = N (§5.3, §5.4)."
                             //
-                            // - note the quorum count is initialized at 1 since we have to
-                            //   count ourselves (we are maintaining the log)
+                            // - whether a given offset has quorum is up to our policy (a simple
+                            //   majority by default)
                             //
-                            let mut n = 1;
-                            let mut smallest = <u64>::max_value();
                             debug_assert!(
                                 msg.ack <= self.head,
                                 format!("ack {} head {}", msg.ack, self.head)
                             );
+                            let batch = self.batch();
+                            let changed = self.peers
+                                .get(&msg.id)
+                                .map_or(false, |peer| peer.learner != msg.learner);
+                            let digest = self.digest();
                             for peer in &mut self.peers {
                                 debug_assert!(*peer.0 != self.id);
 
                                 //
-                                // - first, update the acknowledged offset for that peer
+                                // - first, update the acknowledged offset for that peer as well
+                                //   as whether it is a learner
+                                // - ACKs may arrive out of order: never move it backwards (a
+                                //   REBASE resets it instead)
+                                // - an ACK may also confirm entries we rewound past (e.g upon
+                                //   resending or rebasing): resume right after them
                                 //
                                 if *peer.0 == msg.id {
                                     display!(
@@ -1099,78 +3819,136 @@ where
                                         peer.0,
                                         msg.ack
                                     );
-                                    peer.1.ack = msg.ack;
-                                }
+                                    let progress = msg.ack > peer.1.ack;
+                                    peer.1.ack = cmp::max(peer.1.ack, msg.ack);
+                                    peer.1.off = cmp::max(peer.1.off, peer.1.ack);
+                                    peer.1.acked = Some((msg.ack, self.clock.now()));
+                                    let ack = peer.1.ack;
+                                    while peer.1.inflight.front().map_or(false, |&off| off <= ack) {
+                                        let _ = peer.1.inflight.pop_front();
+                                    }
 
-                                //
-                                // - any peer whose confirmed replicated offset is > to
-                                //   our commit is part of the quorum set
-                                // - keep the smallest of those offsets
-                                //
-                                if peer.1.ack > self.commit {
-                                    if peer.1.ack < smallest {
-                                        smallest = peer.1.ack;
+                                    //
+                                    // - disarm the resend timer once everything is acknowledged,
+                                    //   re-arm it from the new offset upon progress
+                                    //
+                                    if peer.1.inflight.is_empty() {
+                                        peer.1.resend = None;
+                                    } else if progress {
+                                        peer.1.resend = Some(Resend {
+                                            from: ack,
+                                            at: self.clock.now(),
+                                            attempts: 0,
+                                        });
+                                    }
+                                    peer.1.learner = msg.learner;
+                                    if msg.ack + 1 >= self.tail {
+                                        peer.1.rebasing = None;
+                                    }
+
+                                    //
+                                    // - if that peer applied exactly what we applied compare both
+                                    //   payload digests and remember whether they differ
+                                    //
+                                    if let (Some(ours), Some(theirs)) = (digest, msg.digest) {
+                                        if ours.0 == theirs.0 {
+                                            peer.1.diverged = ours.1 != theirs.1;
+                                        }
+                                    }
+
+                                    //
+                                    // - if we are holding entries back because of the in-flight
+                                    //   window resume right away instead of waiting for the next
+                                    //   heartbeat
+                                    //
+                                    if let Some(window) = self.config.max_inflight {
+                                        let upto =
+                                            cmp::min(self.head, peer.1.ack + window as u64);
+                                        if peer.1.off + 1 >= self.tail && upto > peer.1.off {
+                                            replicate!(self, this, peer.1, upto, batch);
+                                        }
                                     }
-                                    n += 1;
                                 }
                             }
+                            if changed {
+                                let bytes = serialize(&self.configuration())
+                                    .expect("configuration is valid");
+                                self.observe(Observation::ConfigChanged(bytes));
+                            }
 
                             //
-                            // - do we have quorum ?
+                            // - if a majority of voters disagrees with our payload digest we
+                            //   are the ones who diverged: quarantine ourselves, notify the
+                            //   sink with SAFETY_VIOLATION and step down (we won't campaign
+                            //   until cleared), notifying the sink with IDLE
+                            // - a minority disagreeing quarantines itself upon our next PING
                             //
-                            if n > self.peers.len() >> 1 {
+                            let voters = self.peers.values().filter(|peer| !peer.learner).count();
+                            let diverged = self.peers
+                                .values()
+                                .filter(|peer| !peer.learner && peer.diverged)
+                                .count();
+                            if !self.quarantined && 2 * diverged > voters + 1 {
+                                warn!(
+                                    &self.logger,
+                                    "payload divergence at #{} ({} of {} peers disagree)",
+                                    self.commit,
+                                    diverged,
+                                    voters
+                                );
+                                self.quarantined = true;
+                                self.sink.push(Notification::SAFETY_VIOLATION(self.commit));
+                                self.sink.push(Notification::IDLE);
+                                self.idle_since.get_or_insert(self.clock.now());
+                                return FLWR(context::FLWR {
+                                    live: false,
+                                    leader: None,
+                                });
+                            }
 
-                                //
-                                // - notify the sink with a COMMIT for each entry
-                                // - update our commit offset to the smallest replicated
-                                //   offset reported by the quorum peers
-                                // - signal the sink event
-                                //
-                                debug_assert!(smallest >= self.tail);
-                                let mut guard = self.payload.write();
-                                for n in self.commit..smallest {
-                                    let slot = read_slot!(self, n);
-                                    (self.apply)(&mut guard, &slot.bytes);
-                                    self.sink.push(Notification::COMMIT(n, slot.bytes));
-                                }
-                                drop(guard);
-                                self.commit = smallest;
-                                display!(self, "{:?} | offset #{} committed", ctx, smallest);
+                            //
+                            // - gather the acknowledged offsets, counting ourselves at our
+                            //   head (we are maintaining the log, unless removed) and leaving
+                            //   learners out
+                            // - any of those offsets that is > to our commit is a candidate
+                            // - try them from the highest down and keep the first one that
+                            //   has quorum
+                            //
+                            let mut acked: HashMap<u16, u64> = self.peers
+                                .iter()
+                                .filter(|&(_, peer)| !peer.learner)
+                                .map(|(id, peer)| (*id, peer.ack))
+                                .collect();
+                            if !self.removed {
+                                acked.insert(self.id, self.head);
+                            }
+                            let mut offsets: Vec<u64> = acked
+                                .values()
+                                .cloned()
+                                .filter(|&off| off > self.commit)
+                                .collect();
+                            offsets.sort_unstable_by(|a, b| b.cmp(a));
+                            offsets.dedup();
+                            let next = offsets
+                                .into_iter()
+                                .find(|&off| self.quorum.is_committed(&acked, off));
+                            if let Some(off) = next {
 
                                 //
-                                // - if the commit index reached a checkpoint boundary
-                                // - reset the tail to that offset
-                                // - lock the payload and take a snapshot of it
-                                // - flush the log
-                                // - notify the sink with a CHECKPOINT
-                                // - signal the sink event
+                                // - apply and notify the sink with a COMMIT for each entry
+                                // - update our commit offset
                                 //
-                                let boundary = self.commit -
-                                    (self.commit % FSM::<S, T, U>::CHECKPOINT as u64);
-                                if boundary > self.tail {
-                                    display!(
-                                        self,
-                                        "{:?} | checkpointed [#{} #{}] ",
-                                        ctx,
-                                        self.tail,
-                                        boundary
-                                    );
-
-                                    let guard = self.payload.read();
-                                    let mut bytes = (*guard).flush();
-                                    drop(guard);
-                                    self.snapshot.clear();
-                                    self.snapshot.append(&mut bytes);
-                                    self.log.flush().unwrap();
-                                    self.sink.push(Notification::CHECKPOINT(boundary));
-                                    self.tail = boundary;
-                                }
+                                self.commit_to(&*ctx, off, true);
                             }
 
+                            //
+                            // - hand off if we are transferring to that peer and it caught up
+                            //
+                            self.hand_off(this);
                         }
                     }
                     REBASE::CODE => {
-                        let msg: REBASE = deserialize(&raw.msg[..]).unwrap();
+                        let msg: REBASE = decode!(self, state, raw);
                         debug_assert!(msg.id != self.id);
                         if msg.term < self.term {
 
@@ -1181,8 +3959,7 @@ where
                                 id: self.id,
                                 term: self.term,
                             };
-                            let bytes = msg.to_raw(&self.host, &raw.src);
-                            (self.write)(&raw.src, &bytes);
+                            send!(self, this, &raw.src, msg);
 
                         } else if let LEAD(_) = state {
 
@@ -1191,16 +3968,31 @@ where
                             //   specified during replication: reset the peer offsets
                             // - we will rebase it upon the next heartbeat
                             //
-                            let mut peer = self.peers.get_mut(&msg.id).unwrap();
-                            peer.off = 1;
-                            peer.ack = 0;
+                            if let Some(peer) = self.peers.get_mut(&msg.id) {
+                                peer.off = 1;
+                                peer.ack = 0;
+                                peer.inflight.clear();
+                                peer.resend = None;
+                            }
 
                         }
                     }
                     PROBE::CODE => {
-                        let msg: PROBE = deserialize(&raw.msg[..]).unwrap();
+                        let msg: PROBE = decode!(self, state, raw);
                         debug_assert!(msg.id != self.id);
-                        if msg.term < self.term {
+                        if !self.is_member(msg.id) {
+
+                            //
+                            // - pre-vote request from a peer which is not part of our configuration
+                            //   (removed or not known yet): ignore it
+                            //
+                            warn!(
+                                &self.logger,
+                                "               | | ignoring PROBE from unknown peer #{}",
+                                msg.id
+                            );
+
+                        } else if msg.term < self.term {
 
                             //
                             // - stale peer: send back a UPGRADE
@@ -1209,37 +4001,53 @@ where
                                 id: self.id,
                                 term: self.term,
                             };
-                            let bytes = msg.to_raw(&self.host, &raw.src);
-                            (self.write)(&raw.src, &bytes);
+                            send!(self, this, &raw.src, msg);
 
                         } else {
-                            match state {
-                                PREV(_) | CNDT(_) => {
 
-                                    //
-                                    // - always grant the pre-vote as long as we are not
-                                    //   either FOLLOWER or LEADER and as long as the peer log
-                                    //   is at least as up-to-date as ours
-                                    //
-                                    if msg.age < self.age || msg.head < self.head {
-                                    } else {
-
-                                        let msg = AVAILABLE {
-                                            id: self.id,
-                                            term: self.term,
-                                        };
-                                        let bytes = msg.to_raw(&self.host, &raw.src);
-                                        (self.write)(&raw.src, &bytes);
-                                    }
-                                }
-                                _ => {}
+                            //
+                            // - grant the pre-vote unless we heard from a LEADER recently:
+                            //   either we lead or follow one, or we are a FOLLOWER without
+                            //   LEADER whose last contact is within the election timeout
+                            // - an unreliable clock counts as recent
+                            // - the peer log must also be at least as up-to-date as ours
+                            // - this way a peer coming back from a partition can't disrupt
+                            //   a healthy cluster by bumping the term
+                            //
+                            let recent = since(self.contact, self.clock.now())
+                                .map_or(true, |lapse| lapse < self.config.election_timeout);
+                            let lease = match state {
+                                PREV(_) | CNDT(_) => false,
+                                FLWR(ctx) => ctx.leader.is_some() || recent,
+                                LEAD(_) => true,
+                            };
+                            if !lease && !self.config.learner && msg.age >= self.age &&
+                                msg.head >= self.head
+                            {
+                                let msg = AVAILABLE {
+                                    id: self.id,
+                                    term: self.term,
+                                };
+                                send!(self, this, &raw.src, msg);
                             }
                         }
                     }
                     AVAILABLE::CODE => {
-                        let msg: AVAILABLE = deserialize(&raw.msg[..]).unwrap();
+                        let msg: AVAILABLE = decode!(self, state, raw);
                         debug_assert!(msg.id != self.id);
-                        if msg.term < self.term {
+                        if !self.is_member(msg.id) {
+
+                            //
+                            // - pre-vote from a peer which is not part of our configuration
+                            //   (removed or not known yet): ignore it
+                            //
+                            warn!(
+                                &self.logger,
+                                "               | | ignoring AVAILABLE from unknown peer #{}",
+                                msg.id
+                            );
+
+                        } else if msg.term < self.term {
 
                             //
                             // - stale peer: send back a UPGRADE
@@ -1248,8 +4056,7 @@ where
                                 id: self.id,
                                 term: self.term,
                             };
-                            let bytes = msg.to_raw(&self.host, &raw.src);
-                            (self.write)(&raw.src, &bytes);
+                            send!(self, this, &raw.src, msg);
                         } else if let PREV(ref mut ctx) = state {
                             //
                             // - we are in the pre-voting phase
@@ -1257,11 +4064,11 @@ where
                             //   check if we can reach quorum or not
                             // - this is like testing for reachability
                             // - if we get quorum transition to CANDIDATE to trigger the
-                            //   real election (and reset the votes bit array)
+                            //   real election (and reset the ballots)
                             //
-                            let (n, granted) = self.count_votes(&mut ctx.votes, msg.id);
+                            let (n, granted) = self.count_votes(msg.id);
                             if granted {
-                                ctx.votes = 0;
+                                self.ballots.clear();
                                 display!(
                                     self,
                                     "{:?} | probing quorum reached ({}/{})",
@@ -1274,9 +4081,21 @@ where
                         }
                     }
                     ADVERTISE::CODE => {
-                        let msg: ADVERTISE = deserialize(&raw.msg[..]).unwrap();
+                        let msg: ADVERTISE = decode!(self, state, raw);
                         debug_assert!(msg.id != self.id);
-                        if msg.term < self.term {
+                        if !self.is_member(msg.id) {
+
+                            //
+                            // - vote request from a peer which is not part of our configuration
+                            //   (removed or not known yet): ignore it
+                            //
+                            warn!(
+                                &self.logger,
+                                "               | | ignoring ADVERTISE from unknown peer #{}",
+                                msg.id
+                            );
+
+                        } else if msg.term < self.term {
 
                             //
                             // - stale peer: send back a UPGRADE
@@ -1285,8 +4104,7 @@ where
                                 id: self.id,
                                 term: self.term,
                             };
-                            let bytes = msg.to_raw(&self.host, &raw.src);
-                            (self.write)(&raw.src, &bytes);
+                            send!(self, this, &raw.src, msg);
                         } else {
                             match state {
                                 CNDT(ref mut ctx) |
@@ -1305,31 +4123,84 @@ where
                                     //
                                     // - grant if a) we haven't picked someone yet or b) if
                                     //   we already picked this peer (duplicate message ?)
+                                    // - never grant if we persisted a vote for another peer
+                                    //   in that term (e.g before restarting)
                                     // - this is also condition to the candidate having its
                                     //   log at least as up-to-date as ours (see above)
                                     //
-                                    let granted = match ctx.pick {
+                                    let mut granted = match ctx.pick {
                                         _ if msg.age < self.age || msg.head < self.head => false,
+                                        _ if self.vote.map_or(false, |(term, id)| {
+                                            term == msg.term && id != msg.id
+                                        }) => false,
                                         Some(id) if id == msg.id => true,
                                         None => true,
                                         _ => false,
                                     };
 
-                                    if granted {
+                                    //
+                                    // - let the vote hook, if any, override the decision
+                                    //
+                                    if let Some(ref hook) = self.vote_hook {
+                                        let vote = VoteContext {
+                                            candidate: msg.id,
+                                            term: msg.term,
+                                            head: msg.head,
+                                            age: msg.age,
+                                            local_head: self.head,
+                                            local_age: self.age,
+                                            pick: ctx.pick,
+                                            granted,
+                                        };
+                                        match hook(&vote) {
+                                            VoteDecision::Grant => granted = true,
+                                            VoteDecision::Deny => granted = false,
+                                            VoteDecision::Default => {}
+                                        }
+                                    }
+
+                                    //
+                                    // - a learner never votes
+                                    // - persist the vote before replying (no vote on failure)
+                                    //
+                                    if granted && !self.config.learner && self.cast(msg.id) {
                                         ctx.pick = Some(msg.id);
                                         display!(self, "{:?} | voted for peer #{}", ctx, msg.id);
-
-                                        //
-                                        // - the vote is granted
-                                        // - reply with a VOTE RPC
-                                        //
+
+                                        //
+                                        // - the vote is granted
+                                        // - reply with a VOTE RPC
+                                        //
+                                        let msg = VOTE {
+                                            id: self.id,
+                                            term: self.term,
+                                        };
+
+                                        send!(self, this, &raw.src, msg);
+                                    }
+                                }
+                                FLWR(ref ctx) if msg.transfer => {
+
+                                    //
+                                    // - the LEADER is handing off to that candidate: vote even
+                                    //   though we still follow it
+                                    // - the usual rules apply otherwise (up-to-date log and one
+                                    //   vote per term)
+                                    // - upgrade our term but keep following until the new
+                                    //   LEADER asserts itself, e.g don't go IDLE
+                                    //
+                                    let granted = msg.age >= self.age && msg.head >= self.head &&
+                                        self.vote.map_or(true, |(term, id)| {
+                                            term < msg.term || id == msg.id
+                                        });
+                                    self.term = msg.term;
+                                    if granted && !self.config.learner && self.cast(msg.id) {
+                                        display!(self, "{:?} | voted for peer #{}", ctx, msg.id);
                                         let msg = VOTE {
                                             id: self.id,
                                             term: self.term,
                                         };
-
-                                        let bytes = msg.to_raw(&self.host, &raw.src);
-                                        (self.write)(&raw.src, &bytes);
+                                        send!(self, this, &raw.src, msg);
                                     }
                                 }
                                 _ => {}
@@ -1337,9 +4208,21 @@ where
                         }
                     }
                     VOTE::CODE => {
-                        let msg: VOTE = deserialize(&raw.msg[..]).unwrap();
+                        let msg: VOTE = decode!(self, state, raw);
                         debug_assert!(msg.id != self.id);
-                        if msg.term < self.term {
+                        if !self.is_member(msg.id) {
+
+                            //
+                            // - vote from a peer which is not part of our configuration
+                            //   (removed or not known yet): ignore it
+                            //
+                            warn!(
+                                &self.logger,
+                                "               | | ignoring VOTE from unknown peer #{}",
+                                msg.id
+                            );
+
+                        } else if msg.term < self.term {
 
                             //
                             // - stale peer: send back a UPGRADE
@@ -1348,8 +4231,7 @@ where
                                 id: self.id,
                                 term: self.term,
                             };
-                            let bytes = msg.to_raw(&self.host, &raw.src);
-                            (self.write)(&raw.src, &bytes);
+                            send!(self, this, &raw.src, msg);
                         } else if let CNDT(ref mut ctx) = state {
 
                             //
@@ -1357,9 +4239,9 @@ where
                             // - if we reach quorum this time transition to LEADER
                             //
                             display!(self, "{:?} | vote received from peer #{}", ctx, msg.id);
-                            let (n, granted) = self.count_votes(&mut ctx.votes, msg.id);
+                            let (n, granted) = self.count_votes(msg.id);
                             if granted {
-                                ctx.votes = 0;
+                                self.ballots.clear();
                                 display!(
                                     self,
                                     "{:?} | voting quorum reached ({}/{})",
@@ -1372,6 +4254,41 @@ where
 
                         }
                     }
+                    TIMEOUT_NOW::CODE => {
+                        let msg: TIMEOUT_NOW = decode!(self, state, raw);
+                        debug_assert!(msg.id != self.id);
+                        if msg.term < self.term {
+
+                            //
+                            // - stale peer: send back a UPGRADE
+                            //
+                            let msg = UPGRADE {
+                                id: self.id,
+                                term: self.term,
+                            };
+                            send!(self, this, &raw.src, msg);
+
+                        } else if let FLWR(ref ctx) = state {
+
+                            //
+                            // - our LEADER is handing off to us: switch to CANDIDATE right
+                            //   away, the other peers will vote even though they still follow
+                            //   it (see ADVERTISE)
+                            // - ignore it if it does not come from our current LEADER, or if
+                            //   we are quarantined, a learner or removed (the transfer will
+                            //   time out)
+                            //
+                            if msg.term == self.term && ctx.leader == Some(msg.id) &&
+                                !self.quarantined && !self.config.learner && !self.removed
+                            {
+                                display!(self, "{:?}*| LEADER handing off to us", ctx);
+                                return CNDT(context::CNDT {
+                                    transfer: true,
+                                    ..Default::default()
+                                });
+                            }
+                        }
+                    }
                     _ => {
                         display!(self, "warning, skipping invalid RPC");
                         debug_assert!(false, "invalid RPC");
@@ -1384,14 +4301,37 @@ where
             Opcode::EXIT => {
 
                 //
-                // - flush the log file
-                // - send a last notification to our sink
+                // - let the apply thread (if any) process what is left and exit
+                // - flush the log
+                // - tell our sink why we are going down, followed by a last notification
                 // - disable the sink semaphore which will force the consuming thread to pop
                 //   all pending notifications and then move on
+                // - same for the observer if any
                 //
-                self.log.flush().unwrap();
+                let applied = match self.applier {
+                    Some(ref mut applier) => applier.close(),
+                    None => true,
+                };
+                let reason = if self.quarantined {
+                    ShutdownReason::SafetyViolation
+                } else if !applied {
+                    ShutdownReason::ApplyFailure
+                } else if this.is_aborted() {
+                    ShutdownReason::Aborted
+                } else {
+                    ShutdownReason::Drained
+                };
+                if let Err(e) = self.storage.flush() {
+                    warn!(&self.logger, "               | | unable to flush the log ({})", e);
+                }
+                self.sink.push(Notification::TERMINATED(reason));
                 self.sink.push(Notification::EXIT);
                 self.sink.sem.disable();
+                if let Some(ref observer) = self.observer {
+                    observer.sem.disable();
+                }
+                self.gate.close();
+                self.role.store(Role::Idle as usize, Ordering::Release);
             }
             _ => {}
         };
@@ -1399,22 +4339,806 @@ where
     }
 }
 
+impl<S, T, U> Recv<Command, State> for FSM<S, T, U>
+where
+    S: 'static + Send + Fn(&[u8; 32], &[u8]) -> (),
+    T: 'static + Send + Fn(&mut U, &[u8]) -> Result<Vec<u8>, ApplyError>,
+    U: 'static + Send + Default + Payload,
+{
+    fn recv(
+        &mut self,
+        this: &Arc<Automaton<Command>>,
+        state: State,
+        opcode: Opcode<Command, State>,
+    ) -> State {
+
+        //
+        // - process the opcode
+        // - if leading and a membership change just committed heartbeat right away instead of
+        //   waiting for the next timeout, e.g new voters hear from us before timing out (which
+        //   would disrupt the cluster)
+        // - go over the parked reads and LEADER watches
+        // - checkpoint if a snapshot threshold was crossed meanwhile: several crossings
+        //   while processing the same opcode result in a single checkpoint, taken by the
+        //   apply thread if we have one
+        // - if observed report any term upgrade that happened meanwhile
+        // - publish our backlog to hold writers back if need be (only while leading)
+        //
+        let mut next = self.process(this, state, opcode);
+        if mem::replace(&mut self.reconfigured, false) {
+            if let LEAD(_) = next {
+                self.seq += 1;
+                let seq = self.seq;
+                next = self.process(this, next, Opcode::CMD(TIMEOUT(seq)));
+            }
+        }
+        self.unpark(next);
+        if self.compaction_due {
+            self.compaction_due = false;
+            self.compact_async(this, next);
+        }
+        self.gate.publish(match next {
+            LEAD(_) => self.head + 1 - self.commit,
+            _ => 0,
+        });
+        if self.observer.is_some() && self.term != self.observed {
+            self.observed = self.term;
+            self.observe(Observation::TermChanged(self.term));
+        }
+        next
+    }
+}
+
 impl Raft {
 
+    /// Returns a builder to set up and spawn a new automaton, see `RaftBuilder`.
+    #[allow(dead_code)]
+    pub fn builder<U>() -> RaftBuilder<U>
+    where
+        U: 'static + Send + Default + Payload,
+    {
+        RaftBuilder::new()
+    }
+
+    /// Shuts the automaton down. If `RaftConfig::transfer_on_drain` is set and this peer is
+    /// leading it first hands its leadership off (see `transfer_leadership()`), and drains
+    /// anyway if that fails. Writers blocked in `store()` are released right away. If
+    /// `RaftConfig::apply_queue` is set this blocks until every committed entry is applied and
+    /// must therefore not be invoked from within the `apply` closure.
     #[allow(dead_code)]
     pub fn drain(&self) -> () {
+        self.gate.close();
+        if self.handoff && self.role() == Role::Leader {
+            let _ = self.transfer_leadership();
+        }
+        self.fsm.drain();
+        if self.flush_on_drain {
+            self.fsm.wait();
+        }
+    }
+
+    /// Adds a voter to the cluster, described by its id and host (clipped to 32 bytes, as upon
+    /// spawning). The change goes through the log: this peer must be leading and any previous
+    /// change must have committed. Returns the offset the change was appended at. It takes
+    /// effect on each peer once committed (see `configuration()`): the LEADER then replicates
+    /// to the new peer, which must have been spawned with the new membership.
+    #[allow(dead_code)]
+    pub fn add_peer(&self, id: u16, host: &str) -> Result<u64, MembershipError> {
+        let mut buf = [0; 32];
+        let n = cmp::min(host.len(), 32);
+        buf[..n].copy_from_slice(&host.as_bytes()[..n]);
+        self.change(MEMBERSHIP::ADD(id, buf))
+    }
+
+    /// Removes a peer from the cluster, possibly this one, same as `add_peer()`. Once the change
+    /// commits nothing is sent to the removed peer anymore. A LEADER removing itself steps down
+    /// and never campaigns again.
+    #[allow(dead_code)]
+    pub fn remove_peer(&self, id: u16) -> Result<u64, MembershipError> {
+        self.change(MEMBERSHIP::REMOVE(id))
+    }
+
+    /// Takes this LEADER out of the cluster: it removes itself (see `remove_peer()`), hands its
+    /// leadership off to the specified voter once the removal commits and then drains, all
+    /// within the specified lapse of time. Fails right away if the removal can't be appended.
+    /// Returns true if the successor took over and the automaton drained cleanly.
+    #[allow(dead_code)]
+    pub fn decommission(&self, successor: u16, timeout: Duration) -> Result<bool, MembershipError> {
+        let deadline = Instant::now() + timeout;
+        let (tx, rx) = channel();
+        let (handoff, done) = channel();
+        if self.fsm.post(DECOMMISSION(successor, tx, handoff)).is_err() {
+            return Err(MembershipError::Unavailable);
+        }
+        let _ = rx.recv().unwrap_or(Err(MembershipError::Unavailable))?;
+        let handed = done.recv_timeout(timeout).ok().and_then(|leader| leader) == Some(successor);
+        let now = Instant::now();
+        let left = if now < deadline { deadline - now } else { Duration::from_millis(0) };
+        Ok(self.drain_timeout(left) && handed)
+    }
+
+    fn change(&self, change: MEMBERSHIP) -> Result<u64, MembershipError> {
+        let (tx, rx) = channel();
+        if self.fsm.post(CHANGE(change, tx)).is_err() {
+            return Err(MembershipError::Unavailable);
+        }
+        rx.recv().unwrap_or(Err(MembershipError::Unavailable))
+    }
+
+    /// Same as `drain()` but bounded: the leadership transfer (if any) and the draining itself
+    /// must complete within the specified lapse of time. Past it the automaton is aborted, e.g
+    /// its pending commands are dropped and its guard is released right away so that whoever
+    /// waits on the event can proceed. Returns true if the automaton drained cleanly, false if it
+    /// had to be aborted.
+    #[allow(dead_code)]
+    pub fn drain_timeout(&self, timeout: Duration) -> bool {
+
+        //
+        // - close the gate and hand the leadership off if need be, giving up at the deadline
+        // - drain and wait for the event loop to exit, abort it otherwise
+        //
+        let deadline = Instant::now() + timeout;
+        self.gate.close();
+        if self.handoff && self.role() == Role::Leader {
+            let (tx, rx) = channel();
+            if self.fsm.post(TRANSFER(tx)).is_ok() {
+                let _ = rx.recv_timeout(timeout);
+            }
+        }
         self.fsm.drain();
+        let now = Instant::now();
+        let left = if now < deadline { deadline - now } else { Duration::from_millis(0) };
+        if self.fsm.wait_timeout(left) {
+            return true;
+        }
+        warn!(&self.logger, "unable to drain within {:?}, aborting", timeout);
+        self.fsm.abort();
+        false
+    }
+
+    /// Hands the leadership off to the most up-to-date FOLLOWER, e.g before taking this peer
+    /// down. The FOLLOWER is told to campaign right away as soon as it caught up with our log
+    /// (stores are rejected meanwhile) and we step down once it asserts itself. Blocks until
+    /// then and returns the new LEADER. None if this peer is not leading (or not running), or
+    /// if the transfer did not complete within `RaftConfig::transfer_timeout`, in which case
+    /// we keep leading.
+    #[allow(dead_code)]
+    pub fn transfer_leadership(&self) -> Option<u16> {
+        let (tx, rx) = channel();
+        if self.fsm.post(TRANSFER(tx)).is_err() {
+            return None;
+        }
+        rx.recv().unwrap_or(None)
+    }
+
+    /// Passes a byte buffer received from a peer (e.g as handed to its `write` closure) to the
+    /// automaton. Buffers that got corrupted in transit (see `frame`), can't be decoded, fail
+    /// authentication if a cluster secret is set or come from an incompatible peer are rejected
+    /// with the reason why, and counted (see `dropped()`).
+    #[allow(dead_code)]
+    pub fn feed(&self, bytes: &[u8]) -> Result<(), FeedError> {
+        let raw = self.unpack(bytes)?;
+        self.fsm.post(BYTES(raw)).map_err(|_| FeedError::Unavailable)
+    }
+
+    /// Returns the cluster membership as known by this peer, e.g as of the latest membership
+    /// change it committed. The configuration is empty if the automaton is not running anymore.
+    #[allow(dead_code)]
+    pub fn configuration(&self) -> Configuration {
+        let (tx, rx) = channel();
+        if self.fsm.post(CONFIGURATION(tx)).is_err() {
+            return Configuration::default();
+        }
+        rx.recv().unwrap_or_default()
+    }
+
+    /// Takes a snapshot of the payload at the current commit offset (see `Payload::flush()`)
+    /// and compacts the log below it, as if a checkpoint boundary was reached. Stores keep
+    /// being appended meanwhile. Returns the offset the latest snapshot reflects, or None if
+    /// the automaton is not running anymore.
+    #[allow(dead_code)]
+    pub fn snapshot(&self) -> Option<u64> {
+        let (tx, rx) = channel();
+        if self.fsm.post(SNAPSHOT(tx)).is_err() {
+            return None;
+        }
+        rx.recv().ok()
+    }
+
+    /// Returns the progress of the election this peer is running as a CANDIDATE: which peers
+    /// granted their vote and which did not respond yet. A peer stuck in pending across
+    /// several elections is most likely partitioned. None if this peer is not a CANDIDATE
+    /// (pre-voting does not count) or if the automaton is not running anymore.
+    #[allow(dead_code)]
+    pub fn election_state(&self) -> Option<ElectionState> {
+        let (tx, rx) = channel();
+        if self.fsm.post(ELECTION_STATE(tx)).is_err() {
+            return None;
+        }
+        rx.recv().unwrap_or(None)
+    }
+
+    /// Returns the id, term, commit offset, role, known LEADER and head offset of this peer,
+    /// all captured at once. The status is defaulted (e.g `Idle`) if the automaton is not
+    /// running anymore.
+    #[allow(dead_code)]
+    pub fn status(&self) -> Status {
+        let (tx, rx) = channel();
+        if self.fsm.post(STATUS(tx)).is_err() {
+            return Status::default();
+        }
+        rx.recv().unwrap_or_default()
+    }
+
+    /// Blocks until this peer knows of a LEADER (itself or another peer) and returns its id.
+    /// Returns None if no LEADER shows up within the specified lapse of time or if the
+    /// automaton is not running anymore.
+    #[allow(dead_code)]
+    pub fn await_leader(&self, timeout: Duration) -> Option<u16> {
+        let (tx, rx) = channel();
+        if self.fsm.post(AWAIT_LEADER(timeout, tx)).is_err() {
+            return None;
+        }
+        rx.recv_timeout(timeout).ok()
+    }
+
+    /// Returns for how long this peer has been idle, e.g without knowing of any LEADER nor
+    /// campaigning, since it started or last stopped campaigning. None if it currently follows
+    /// a LEADER, leads, pre-votes, runs for election or if the automaton is not running anymore.
+    /// A supervisor may use this to detect a peer left without LEADER, and `election_state()`
+    /// to detect one stuck campaigning.
+    #[allow(dead_code)]
+    pub fn idle_duration(&self) -> Option<Duration> {
+        let (tx, rx) = channel();
+        if self.fsm.post(IDLE_DURATION(tx)).is_err() {
+            return None;
+        }
+        rx.recv().unwrap_or(None)
+    }
+
+    /// Updates the runtime tunables of the automaton (timeouts, etc) without restarting it. The
+    /// new values apply the next time the automaton arms its timer. Anything that can't change
+    /// at runtime (peer id, storage, etc) is not part of the delta. Fails if the resulting
+    /// configuration is invalid, in which case nothing changes.
+    #[allow(dead_code)]
+    pub fn reconfigure(&self, delta: RaftConfigDelta) -> Result<(), ConfigError> {
+        let (tx, rx) = channel();
+        if self.fsm.post(RECONFIGURE(delta, tx)).is_err() {
+            return Err(ConfigError::Unavailable);
+        }
+        rx.recv().unwrap_or(Err(ConfigError::Unavailable))
+    }
+
+    /// Installs a handler invoked on every buffer received from (e.g passed to `feed()`) or
+    /// sent to (e.g passed to the `write` closure) a peer. The handler decides whether to pass
+    /// the buffer through, drop it or delay it, which is handy for fault injection or proxies.
+    /// Installing a new handler replaces the previous one. Nothing is intercepted by default,
+    /// in which case this costs nothing.
+    #[allow(dead_code)]
+    pub fn intercept<F>(&self, f: F) -> ()
+    where
+        F: 'static + Send + Fn(Direction, &RAW) -> Action,
+    {
+        let _ = self.fsm.post(INTERCEPT(Box::new(f)));
+    }
+
+    /// Installs the policy deciding when entries are committed, see `Quorum`. This should be
+    /// done right after spawning and using the same policy on all the peers. The default is a
+    /// simple majority.
+    #[allow(dead_code)]
+    pub fn use_quorum<Q>(&self, quorum: Q) -> ()
+    where
+        Q: 'static + Quorum,
+    {
+        let _ = self.fsm.post(QUORUM(Box::new(quorum)));
+    }
+
+    /// Current role of this peer. This is a simple atomic read and never blocks.
+    #[allow(dead_code)]
+    pub fn role(&self) -> Role {
+        Role::load(&self.role)
+    }
+
+    /// Number of incoming buffers dropped by `feed()` so far.
+    #[allow(dead_code)]
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn unpack(&self, bytes: &[u8]) -> Result<RAW, FeedError> {
+
+        //
+        // - reject the buffer right away if its frame announces more than we accept, logging
+        //   whoever it claims to come from (this does not decode anything)
+        // - strip and check the frame header: reject the buffer if it got corrupted in transit
+        // - strip and check the MAC trailer if we have a secret
+        // - decode the RAW and check its protocol version and message code
+        // - log and count whatever we reject
+        //
+        if let Some(limit) = self.max_bytes {
+            let n = if bytes.len() < frame::HEADER_BYTES {
+                bytes.len()
+            } else {
+                cmp::max(frame::length(bytes), bytes.len() - frame::HEADER_BYTES)
+            };
+            if n > limit {
+                let src = RAW::peek_src(&bytes[cmp::min(bytes.len(), frame::HEADER_BYTES)..]);
+                warn!(
+                    &self.logger,
+                    "dropping {}B buffer from {} (limit is {}B)",
+                    n,
+                    src.map_or("?".to_string(), |src| label(&src)),
+                    limit
+                );
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return Err(FeedError::TooLarge(n));
+            }
+        }
+        let res = frame::unframe(bytes)
+            .ok_or(FeedError::Corrupted)
+            .and_then(|bytes| self.verify(bytes))
+            .and_then(|bytes| deserialize::<RAW>(bytes).map_err(|_| FeedError::Malformed))
+            .and_then(|raw| match raw {
+                ref raw if !compatible(raw.version) => Err(FeedError::Version(raw.version)),
+                ref raw if !raw.known() => Err(FeedError::UnknownCode(raw.code)),
+                raw => Ok(raw),
+            });
+        if let Err(ref e) = res {
+            warn!(&self.logger, "dropping {}B buffer ({:?})", bytes.len(), e);
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        res
+    }
+
+    /// Number of bytes an envelope takes once handed over to the `write` closure, e.g its
+    /// encoded length plus the frame header and, if a cluster secret is set, the MAC.
+    pub fn wire_len(&self, raw: &RAW) -> usize {
+        frame::HEADER_BYTES + raw.encoded_len() + self.mac_len()
+    }
+
+    #[cfg(feature = "auth")]
+    fn mac_len(&self) -> usize {
+        self.secret.as_ref().map_or(0, |_| auth::MAC_BYTES)
+    }
+
+    #[cfg(not(feature = "auth"))]
+    fn mac_len(&self) -> usize {
+        0
+    }
+
+    #[cfg(feature = "auth")]
+    fn verify<'a>(&self, bytes: &'a [u8]) -> Result<&'a [u8], FeedError> {
+        match self.secret {
+            Some(ref secret) => auth::verify(secret, bytes).ok_or(FeedError::Unauthenticated),
+            None => Ok(bytes),
+        }
+    }
+
+    #[cfg(not(feature = "auth"))]
+    fn verify<'a>(&self, bytes: &'a [u8]) -> Result<&'a [u8], FeedError> {
+        Ok(bytes)
+    }
+
+    /// Appends an entry if this peer is leading and returns a handle resolving once the outcome
+    /// is known (the handle may just be dropped). This is `store_async()` wrapped into a
+    /// `StoreHandle`. Entries stored from one thread are appended in call order (e.g `store(a)`
+    /// then `store(b)` puts a at a lower offset than b). Entries stored concurrently from several
+    /// threads are appended in some unspecified order, which is the same on all peers. If
+    /// `RaftConfig::store_high_water` is set this blocks while too many entries are waiting to
+    /// commit, and fails with `Unavailable` if the automaton is drained meanwhile: it must
+    /// therefore not be invoked from within the `apply` closure.
+    #[allow(dead_code)]
+    pub fn store(&self, bytes: Vec<u8>) -> Result<StoreHandle, StoreError> {
+        let (appended, off) = channel();
+        self.submit(true, |tx| STORE_ASYNC(bytes, tx, Some(appended)))
+            .map(|rx| StoreHandle::new(rx, off))
+    }
+
+    /// Same as `store()` but encodes a typed command first, e.g the counterpart of
+    /// `RaftBuilder::on_command()` which decodes it upon commit. Fails with `Malformed` if the
+    /// command can't be encoded, in which case nothing is stored.
+    #[allow(dead_code)]
+    pub fn store_command<C>(&self, cmd: &C) -> Result<StoreHandle, StoreError>
+    where
+        C: Serialize,
+    {
+        let bytes = serialize(cmd).map_err(|e| StoreError::Malformed(e.to_string()))?;
+        self.store(bytes)
+    }
+
+    /// Appends an empty entry and returns its offset, or None if this peer is not leading (or
+    /// can't append). Empty entries are valid no-ops: they advance the log and commit offsets
+    /// like any other entry and are passed as an empty slice to the apply closure, which must
+    /// tolerate them. This blocks until the automaton processes the request and must therefore
+    /// not be invoked from within the `apply` closure.
+    #[allow(dead_code)]
+    pub fn noop(&self) -> Option<u64> {
+        let (tx, rx) = channel();
+        if self.fsm.post(NOOP(tx)).is_err() {
+            return None;
+        }
+        rx.recv().unwrap_or(None)
+    }
+
+    /// Checks whether `store()` would currently append an entry, e.g to avoid serializing a large
+    /// payload for nothing. This is advisory only: the state may change before the entry is
+    /// actually stored. An automaton that is not running anymore rejects everything. This blocks
+    /// until the automaton processes the request and must therefore not be invoked from within
+    /// the `apply` closure.
+    #[allow(dead_code)]
+    pub fn can_store(&self) -> StoreAdmission {
+        let (tx, rx) = channel();
+        if self.fsm.post(ADMISSION(tx)).is_err() {
+            return StoreAdmission::default();
+        }
+        rx.recv().unwrap_or_default()
+    }
+
+    /// Non-blocking variant of `store()` returning a channel on which the outcome is delivered
+    /// once known, e.g when the entry commits or is rejected. The number of pending stores is
+    /// bounded by `RaftConfig::store_queue` and `QueueFull` is returned beyond that. Likewise
+    /// `WouldBlock` is returned while the backlog is above `RaftConfig::store_high_water`.
+    #[allow(dead_code)]
+    pub fn store_async(&self, bytes: Vec<u8>) -> Result<Receiver<StoreResult>, StoreError> {
+        self.submit(false, |tx| STORE_ASYNC(bytes, tx, None))
+    }
+
+    /// Same as `store()` except the entry is tagged with a client request id: storing again
+    /// with the same id (e.g retrying after a timeout or a `Lost`) does not append a second
+    /// entry, the handle resolves with the offset of the original one instead. The ids applied
+    /// lately are remembered on all the peers, up to `RaftConfig::dedup_window` of them, so that
+    /// a retry sent to a new LEADER is deduplicated as well. A retry racing with the original
+    /// entry across an election may still be appended: it is then committed but not applied.
+    #[allow(dead_code)]
+    pub fn store_once(&self, request: u64, bytes: Vec<u8>) -> Result<StoreHandle, StoreError> {
+        let (appended, off) = channel();
+        self.submit(true, |tx| STORE_ONCE(request, bytes, tx, Some(appended)))
+            .map(|rx| StoreHandle::new(rx, off))
+    }
+
+    /// Non-blocking variant of `store_once()`, see `store_async()`.
+    #[allow(dead_code)]
+    pub fn store_async_once(
+        &self,
+        request: u64,
+        bytes: Vec<u8>,
+    ) -> Result<Receiver<StoreResult>, StoreError> {
+        self.submit(false, |tx| STORE_ONCE(request, bytes, tx, None))
+    }
+
+    /// Same as `store_once()` but resubmits the entry, up to the specified number of times, if
+    /// it did not make it (e.g `Lost` because this peer stopped leading before it committed, or
+    /// `Rejected` during an election). Each retry first waits up to the specified lapse of time
+    /// for a LEADER and only goes ahead if this peer leads again: the request id is the same so
+    /// the entry is applied at most once. A full store queue is likewise retried once that lapse
+    /// of time elapsed. The retries are local only, e.g nothing is forwarded to another peer: if
+    /// another peer (or no peer) leads by then the last outcome is passed back and the caller
+    /// may store the entry on the new LEADER (see `await_leader()`) with the same request id.
+    /// An automaton that is not running anymore is not retried. This blocks until the entry
+    /// commits and must therefore not be invoked from within the `apply` closure.
+    #[allow(dead_code)]
+    pub fn store_reliable(
+        &self,
+        request: u64,
+        bytes: Vec<u8>,
+        retries: usize,
+        timeout: Duration,
+    ) -> Result<(u64, Vec<u8>), StoreResult> {
+        let mut left = retries;
+        loop {
+
+            //
+            // - store and wait for the outcome
+            // - a full queue is transient: back off and retry
+            // - any other failure to submit is final and counts as a rejection
+            // - give up once out of retries, or if another peer (or no peer) leads by then
+            //
+            let res = match self.store_once(request, bytes.clone()) {
+                Ok(handle) => handle.wait(),
+                Err(StoreError::QueueFull) if left > 0 => {
+                    left -= 1;
+                    debug!(
+                        &self.logger,
+                        "queue full, retrying request {} ({} left)",
+                        request,
+                        left
+                    );
+                    thread::sleep(timeout);
+                    continue;
+                }
+                Err(_) => return Err(StoreResult::Rejected),
+            };
+            match res {
+                Err(StoreResult::Lost(_)) | Err(StoreResult::Rejected) if left > 0 => {
+                    left -= 1;
+                    let _ = self.await_leader(timeout);
+                    if self.role() != Role::Leader {
+                        return res;
+                    }
+                    debug!(&self.logger, "retrying request {} ({} left)", request, left);
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn submit<F>(&self, block: bool, cmd: F) -> Result<Receiver<StoreResult>, StoreError>
+    where
+        F: FnOnce(Sender<StoreResult>) -> Command,
+    {
+
+        //
+        // - wait for (or check) the backlog first, then make sure the queue is not full
+        //
+        if block {
+            self.gate.wait()?;
+        } else {
+            self.gate.check()?;
+        }
+        if self.pending.fetch_add(1, Ordering::Acquire) >= self.capacity {
+            self.pending.fetch_sub(1, Ordering::Release);
+            return Err(StoreError::QueueFull);
+        }
+
+        let (tx, rx) = channel();
+        match self.fsm.post(cmd(tx)) {
+            Ok(_) => Ok(rx),
+            Err(_) => {
+                self.pending.fetch_sub(1, Ordering::Release);
+                Err(StoreError::Unavailable)
+            }
+        }
+    }
+
+    /// Appends an empty entry via `store_async()`. Since the log commits in order the barrier
+    /// resolving as `Committed` implies every entry stored before it also committed, e.g there
+    /// is no need to track each individual store. `Lost` or `Rejected` on the other hand tell
+    /// nothing about the prior entries.
+    #[allow(dead_code)]
+    pub fn barrier(&self) -> Result<Receiver<StoreResult>, StoreError> {
+        self.store_async(Vec::new())
+    }
+
+    /// Checks whether the payload may be read at the specified consistency level and returns
+    /// the commit offset it reflects. The payload itself is accessed via the read-only lock
+    /// returned upon spawning. Please note this blocks until the automaton processes the
+    /// request (and, for a linearizable read, until a quorum confirms it or this peer steps
+    /// down) and must therefore not be invoked from within the `apply` closure.
+    #[allow(dead_code)]
+    pub fn read(&self, level: ReadConsistency) -> Result<u64, ReadError> {
+        let (tx, rx) = channel();
+        if self.fsm.post(READ(level, tx)).is_err() {
+            return Err(ReadError::Unavailable);
+        }
+        rx.recv().unwrap_or(Err(ReadError::Unavailable))
+    }
+
+    /// Same as `read()` except the payload must also reflect the specified session token (see
+    /// `StoreResult::session_token()`), e.g include a write acknowledged by any other peer. If
+    /// this peer lags it waits for its commit offset to catch up, for up to the specified lapse
+    /// of time, and fails with `Timeout` otherwise.
+    #[allow(dead_code)]
+    pub fn read_after(
+        &self,
+        level: ReadConsistency,
+        token: u64,
+        timeout: Duration,
+    ) -> Result<u64, ReadError> {
+        let (tx, rx) = channel();
+        if self.fsm.post(READ_AFTER(level, token, timeout, tx)).is_err() {
+            return Err(ReadError::Unavailable);
+        }
+        match rx.recv_timeout(timeout) {
+            Ok(res) => res,
+            Err(RecvTimeoutError::Timeout) => Err(ReadError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => Err(ReadError::Unavailable),
+        }
+    }
+
+    /// Linearizable read confirmed through the log: the LEADER appends a no-op and the read
+    /// completes once a quorum acknowledges it, returning the commit offset the payload then
+    /// reflects. A peer deposed without knowing it yet fails with `NotLeader` once it finds
+    /// out. Any other peer fails right away with `NotLeader`. Same as `read(Linearizable)`
+    /// except this fails with `Timeout` if the no-op is not acknowledged within the specified
+    /// lapse of time.
+    #[allow(dead_code)]
+    pub fn read_index(&self, timeout: Duration) -> Result<u64, ReadError> {
+        let (tx, rx) = channel();
+        if self.fsm.post(READ_INDEX(tx)).is_err() {
+            return Err(ReadError::Unavailable);
+        }
+        match rx.recv_timeout(timeout) {
+            Ok(res) => res,
+            Err(RecvTimeoutError::Timeout) => Err(ReadError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => Err(ReadError::Unavailable),
+        }
+    }
+
+    /// Runs a closure against the payload while holding its read lock and returns its result,
+    /// or None if `P` is not the payload type this automaton was spawned with. The payload is
+    /// read as applied locally: use `read()` or `read_index()` first to pick a consistency
+    /// level. The apply closure waits meanwhile, so the closure should be short and must not
+    /// invoke the automaton. This does not hold for entries applied in parallel, which the
+    /// closure may see half applied (see `RaftBuilder::on_commit_parallel()`).
+    #[allow(dead_code)]
+    pub fn query<P, R, F>(&self, f: F) -> Option<R>
+    where
+        P: 'static,
+        F: FnOnce(&P) -> R,
+    {
+        let payload: &dyn Any = &*self.payload;
+        let lock = payload.downcast_ref::<ROLock<P>>()?;
+        let guard = lock.read();
+        Some(f(&*guard))
+    }
+
+    /// Returns the term of the log entry at the specified offset. Offsets below the log tail
+    /// (e.g compacted) report the term of the latest snapshot. Offsets past the log head (or
+    /// if the automaton is not running anymore) return None.
+    #[allow(dead_code)]
+    pub fn term_at(&self, off: u64) -> Option<u64> {
+        let (tx, rx) = channel();
+        if self.fsm.post(TERM_AT(off, tx)).is_err() {
+            return None;
+        }
+        rx.recv().unwrap_or(None)
+    }
+
+    /// Returns the log entries within [from, to), read at once by the automaton (e.g this is a
+    /// consistent view of its log). The range is clamped to what the log retains: entries below
+    /// the log tail are gone once compacted and nothing exists past the log head. This is meant
+    /// for debugging (e.g comparing the committed prefix across peers) and returns nothing if
+    /// the automaton is not running anymore.
+    #[allow(dead_code)]
+    pub fn log_range(&self, from: u64, to: u64) -> Vec<LogEntry> {
+        let (tx, rx) = channel();
+        if self.fsm.post(LOG_RANGE(from, to, tx)).is_err() {
+            return Vec::new();
+        }
+        rx.recv().unwrap_or_default()
+    }
+
+    /// Returns the log head offset, e.g the offset of the latest entry appended (0 if the
+    /// automaton is not running anymore).
+    #[allow(dead_code)]
+    pub fn log_head(&self) -> u64 {
+        let (tx, rx) = channel();
+        if self.fsm.post(LOG_HEAD(tx)).is_err() {
+            return 0;
+        }
+        rx.recv().unwrap_or(0)
+    }
+
+    /// Returns the automaton counters (appended and committed entries along with their rate
+    /// over the last few seconds, disk usage) plus the number of dropped buffers. The counters
+    /// are zeroed if the automaton is not running anymore.
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> RaftMetrics {
+        let (tx, rx) = channel();
+        let mut metrics = if self.fsm.post(METRICS(tx)).is_err() {
+            RaftMetrics::default()
+        } else {
+            rx.recv().unwrap_or_default()
+        };
+        metrics.dropped = self.dropped();
+        metrics
+    }
+
+    /// Returns how many messages of each type were sent and received so far (all zero if the
+    /// automaton is not running anymore).
+    #[allow(dead_code)]
+    pub fn message_stats(&self) -> MessageStats {
+        self.metrics().messages
+    }
+
+    /// Returns a sink streaming lower frequency events (term upgrades, membership changes,
+    /// snapshots and quorum loss), see `Observation`. Nothing is tracked until this is invoked
+    /// and subsequent calls return the same sink. It has its own capacity: a slow observer
+    /// only loses observations. None if the automaton is not running anymore.
+    #[allow(dead_code)]
+    pub fn observe(&self) -> Option<Arc<Sink<Observation>>> {
+        let (tx, rx) = channel();
+        if self.fsm.post(OBSERVE(tx)).is_err() {
+            return None;
+        }
+        rx.recv().ok()
+    }
+
+    /// Returns the replication progress of each peer, sorted by id. This is only tracked while
+    /// leading: the list is empty otherwise (or if the automaton is not running anymore).
+    #[allow(dead_code)]
+    pub fn peer_metrics(&self) -> Vec<PeerMetric> {
+        let (tx, rx) = channel();
+        if self.fsm.post(PEER_METRICS(tx)).is_err() {
+            Vec::new()
+        } else {
+            rx.recv().unwrap_or_default()
+        }
+    }
+
+    /// Registers a handler invoked with the offset and bytes of each committed entry right
+    /// before it is applied to the payload. Handlers run in registration order on the automaton
+    /// thread and any panic they raise is caught and logged.
+    #[allow(dead_code)]
+    pub fn on_pre_apply<F>(&self, f: F) -> ()
+    where
+        F: 'static + Send + Fn(u64, &[u8]) -> (),
+    {
+        let _ = self.fsm.post(PRE_APPLY(Box::new(f)));
+    }
+
+    /// Same as `on_pre_apply()` except the handler is invoked right after the entry is applied.
+    #[allow(dead_code)]
+    pub fn on_post_apply<F>(&self, f: F) -> ()
+    where
+        F: 'static + Send + Fn(u64, &[u8]) -> (),
+    {
+        let _ = self.fsm.post(POST_APPLY(Box::new(f)));
+    }
+
+    /// Forces this peer to start an election right away if it is following, e.g without waiting
+    /// for its liveness timeout. The usual pre-vote phase and election rules apply: the peer
+    /// may very well lose. Please note this will depose a perfectly healthy LEADER if the peer
+    /// manages to get elected (and even if it doesn't peers bump their term), causing a short
+    /// write unavailability. Use for operational recovery only.
+    #[allow(dead_code)]
+    pub fn campaign(&self) -> () {
+        let _ = self.fsm.post(CAMPAIGN);
+    }
+
+    /// Registers a handler invoked upon each vote request with the context of the decision. The
+    /// handler may force the vote to be granted or denied.
+    ///
+    /// **Dangerous**: this is meant for testing only. Overriding the standard raft rule breaks
+    /// the protocol safety guarantees (e.g a peer with a stale log could get elected).
+    #[allow(dead_code)]
+    pub fn on_vote_request<F>(&self, f: F) -> ()
+    where
+        F: 'static + Send + Fn(&VoteContext) -> VoteDecision,
+    {
+        let _ = self.fsm.post(VOTE_HOOK(Box::new(f)));
+    }
+
+    /// Lifts the quarantine put in place after a `SAFETY_VIOLATION`, allowing the peer to
+    /// accept writes and campaign again. This is meant to be invoked by an operator once the
+    /// divergence has been investigated.
+    #[allow(dead_code)]
+    pub fn clear_quarantine(&self) -> () {
+        let _ = self.fsm.post(CLEAR);
     }
 
+    /// Turns this learner into a regular voter, typically once it caught up with the LEADER.
+    /// Returns false if it was not a learner (or is not running). The LEADER starts counting
+    /// it towards the commit quorum upon its next ACK. Please note the membership itself is
+    /// fixed: the peer must have been declared upon spawning on all the other peers. Promote
+    /// learners one at a time.
     #[allow(dead_code)]
-    pub fn store(&self, bytes: Vec<u8>) -> () {
-        let _ = self.fsm.post(STORE(bytes));
+    pub fn promote(&self) -> bool {
+        let (tx, rx) = channel();
+        if self.fsm.post(PROMOTE(tx)).is_err() {
+            return false;
+        }
+        rx.recv().unwrap_or(false)
     }
 }
 
 impl Clone for Raft {
     fn clone(&self) -> Self {
-        Self { fsm: self.fsm.clone() }
+        Self {
+            fsm: self.fsm.clone(),
+            pending: self.pending.clone(),
+            gate: self.gate.clone(),
+            capacity: self.capacity,
+            dropped: self.dropped.clone(),
+            role: self.role.clone(),
+            handoff: self.handoff,
+            flush_on_drain: self.flush_on_drain,
+            max_bytes: self.max_bytes,
+            payload: self.payload.clone(),
+            logger: self.logger.clone(),
+            #[cfg(feature = "auth")]
+            secret: self.secret.clone(),
+        }
     }
 }
 
@@ -0,0 +1,56 @@
+//! Commit policies. The LEADER consults a `Quorum` to decide whether it may advance its commit
+//! offset given what each peer acknowledged. The default is a simple majority, custom policies
+//! (for instance requiring one acknowledgement per availability zone) may be installed via
+//! `Raft::use_quorum()`.
+//!
+//! # Safety
+//!
+//! Any set of peers accepted by a policy must intersect any election majority: otherwise a new
+//! LEADER could be elected without a committed entry and overwrite it. In practice a custom
+//! policy should always require a majority plus whatever extra constraint it wants to enforce.
+use std::collections::HashMap;
+
+/// Trait defining when an offset is committed.
+pub trait Quorum: Send {
+    /// Whether the specified commit offset (e.g all the entries below it) is durable. The
+    /// acknowledged offsets are keyed by peer id and include the LEADER itself.
+    fn is_committed(&self, acked: &HashMap<u16, u64>, index: u64) -> bool;
+}
+
+/// Default policy: more than half the peers acknowledged the offset.
+pub struct Majority;
+
+impl Quorum for Majority {
+    fn is_committed(&self, acked: &HashMap<u16, u64>, index: u64) -> bool {
+        let n = acked.values().filter(|&&off| off >= index).count();
+        n > acked.len() >> 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn majority() {
+
+        //
+        // - 4 peers: 3 are needed
+        //
+        let mut acked = HashMap::new();
+        acked.insert(0, 10);
+        acked.insert(1, 8);
+        acked.insert(2, 5);
+        acked.insert(3, 2);
+        assert!(Majority.is_committed(&acked, 5));
+        assert!(!Majority.is_committed(&acked, 6));
+
+        //
+        // - 5 peers: 3 are still enough
+        //
+        acked.insert(4, 9);
+        assert!(Majority.is_committed(&acked, 8));
+        assert!(!Majority.is_committed(&acked, 9));
+    }
+}
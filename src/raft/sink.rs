@@ -7,29 +7,76 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 
 /// Events emitted by the state machine. Those are available for the user to react
 /// to membership changes, commits, etc.
+#[allow(non_camel_case_types)]
 #[derive(Debug)]
 pub enum Notification {
     FOLLOWING,
     LEADING,
+    /// The peer does not know of any LEADER anymore (liveness timeout, forced election, term
+    /// upgrade or a LEADER losing its quorum) and is not leading. Until notified with
+    /// `FOLLOWING` or `LEADING` it is idle whenever it does not campaign (pre-vote included),
+    /// see `Raft::idle_duration()`.
     IDLE,
+    /// The known LEADER changed (its id and host label), e.g to redirect clients. This follows
+    /// `FOLLOWING` or `LEADING` and is also emitted when a FOLLOWER switches directly to another
+    /// LEADER.
+    LEADER(u16, String),
     COMMIT(u64, Vec<u8>),
+    /// The commit offset advanced: every entry up to this offset (included) is committed and
+    /// applied. This is emitted on all the peers, once per batch of entries applied (e.g not
+    /// once per entry).
+    COMMITTED(u64),
     CHECKPOINT(u64),
+    SAFETY_VIOLATION(u64),
+    APPLY_LAG(u64),
     EXIT,
+    TERMINATED(ShutdownReason),
+}
+
+/// Lower frequency events streamed to an observer, see `Raft::observe()`. They are pushed to a
+/// sink of their own (e.g a slow observer does not get in the way of notifications).
+#[derive(Debug)]
+pub enum Observation {
+    /// The peer moved to a new term.
+    TermChanged(u64),
+    /// The membership changed (e.g a learner was promoted). This carries the bincode encoded
+    /// `Configuration`, as returned by `Raft::configuration()`.
+    ConfigChanged(Vec<u8>),
+    /// A snapshot was taken at this commit offset.
+    SnapshotTaken(u64),
+    /// The peer was leading but did not hear from a majority of voters within the liveness
+    /// timeout and stepped down (the sink gets `IDLE`).
+    QuorumLost,
+}
+
+/// Why the automaton went down, passed along with `TERMINATED` right before the final `EXIT`
+/// notification.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ShutdownReason {
+    /// The automaton was drained, e.g shut down on purpose.
+    Drained,
+    /// The automaton was shut down while quarantined after a `SAFETY_VIOLATION`.
+    SafetyViolation,
+    /// The automaton did not drain in time and was aborted, see `Raft::drain_timeout()`.
+    Aborted,
+    /// The dedicated apply thread died (e.g the apply closure panicked), see
+    /// `RaftConfig::apply_queue`.
+    ApplyFailure,
 }
 
 /// Simple blocking notification sink consuming from a MPSC. Once signaled with no
 /// content the sink will disable itself and always fail.
-pub struct Sink {
+pub struct Sink<T = Notification> {
     pub(super) sem: Semaphore,
-    pub(super) fifo: MPSC<Notification>,
+    pub(super) fifo: MPSC<T>,
     len: AtomicUsize,
 }
 
-impl Sink {
+impl<T> Sink<T> {
     const CAPACITY: usize = 4096;
 
     #[allow(dead_code)]
-    pub fn next(&self) -> Option<Notification> {
+    pub fn next(&self) -> Option<T> {
 
         //
         // - wait/pop, this will fast-fail on None as soon as we disable the semaphore, e.g
@@ -49,7 +96,7 @@ impl Sink {
         }
     }
 
-    pub(super) fn push(&self, n: Notification) -> () {
+    pub(super) fn push(&self, n: T) -> () {
         if self.len.load(Ordering::Relaxed) < Self::CAPACITY {
 
             //
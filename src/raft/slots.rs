@@ -4,26 +4,105 @@ macro_rules! declare {
     ($code:expr, $msg:ident) => {
         impl $msg {
             pub(super) const CODE: u8 = $code;
-            pub(super) fn to_bytes(&self, term: u64) -> Vec<u8> {
+            #[allow(dead_code)]
+            pub(super) fn to_bytes(&self, term: u64, off: u64) -> Vec<u8> {
+                let bytes = serialize(&self).expect("entry is serializable");
                 let slot = SLOT {
                     code: $msg::CODE,
                     term,
-                    bytes: serialize(&self).unwrap(),
+                    off,
+                    crc: Some(crc32(&bytes)),
+                    bytes,
+                    request: None,
                 };
-                serialize(&slot).unwrap()
+                serialize(&slot).expect("slot is serializable")
             }
         }
     };
 }
 
 declare!(0, NULL);
+declare!(1, MEMBERSHIP);
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(super) struct SLOT {
     pub(super) term: u64,
+    pub(super) off: u64,
     pub(super) code: u8,
     pub(super) bytes: Vec<u8>,
+    /// CRC32 of the bytes, always written (slots from older logs may lack it).
+    pub(super) crc: Option<u32>,
+    /// Optional client request id, used to deduplicate retries (see `Raft::store_once()`).
+    pub(super) request: Option<u64>,
+}
+
+impl SLOT {
+    /// Whether the bytes match their CRC32, if any.
+    pub(super) fn verify(&self) -> bool {
+        self.crc.map_or(true, |crc| crc == crc32(&self.bytes))
+    }
+
+    /// Whether the slot carries a CRC32 and the bytes match it, e.g it was fully written.
+    pub(super) fn sealed(&self) -> bool {
+        self.crc.is_some() && self.verify()
+    }
+}
+
+/// CRC32 (IEEE 802.3) of a byte buffer, computed bitwise to avoid carrying a table around.
+pub(super) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in bytes {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(super) struct NULL {}
+
+/// Single peer membership change, see `Raft::add_peer()` and `Raft::remove_peer()`. It takes
+/// effect on each peer once committed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) enum MEMBERSHIP {
+    /// Adds that peer (id and host padded to 32 bytes) as a voter.
+    ADD(u16, [u8; 32]),
+    /// Removes that peer.
+    REMOVE(u16),
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn checksums() {
+
+        //
+        // - standard check value
+        // - a slot without checksum always verifies but is not sealed
+        //
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(&[]), 0);
+        let mut slot = SLOT {
+            term: 1,
+            off: 2,
+            code: 255,
+            bytes: b"abc".to_vec(),
+            crc: None,
+            request: None,
+        };
+        assert!(slot.verify());
+        assert!(!slot.sealed());
+        slot.crc = Some(crc32(b"abc"));
+        assert!(slot.verify());
+        assert!(slot.sealed());
+        slot.bytes[0] = b'x';
+        assert!(!slot.verify());
+        assert!(!slot.sealed());
+    }
+}
@@ -0,0 +1,200 @@
+//! Snapshot persistence. Snapshots are large opaque blobs written once per checkpoint, as
+//! opposed to the log which is made of small appends. They are therefore stored separately via
+//! a `SnapshotStore` passed upon spawning. The default implementation stores them on the local
+//! filesystem.
+use bincode::{deserialize_from, serialize_into};
+use std::fs::{metadata, rename, File, OpenOptions};
+use std::io::{self, copy, ErrorKind, Read, Write};
+use std::path::PathBuf;
+
+/// Leading bytes of each snapshot file.
+const MAGIC: [u8; 4] = *b"RSMS";
+
+/// Version of the snapshot file layout, to bump whenever the metadata or the way the content
+/// is laid out changes. Version 2 added the client request ids.
+const VERSION: u16 = 2;
+
+/// Describes what a snapshot reflects.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotMeta {
+    /// Offset the snapshot reflects, e.g all the entries below were applied.
+    pub index: u64,
+    /// Term of the last applied entry.
+    pub term: u64,
+    /// Peers (id + host) making up the cluster when the snapshot was taken.
+    pub peers: Vec<(u16, [u8; 32])>,
+    /// Client request ids (and their offset) applied lately, oldest first, used to keep
+    /// deduplicating retries after restoring the snapshot.
+    pub requests: Vec<(u64, u64)>,
+}
+
+/// Trait defining where snapshots are persisted. Only the latest snapshot matters: saving a new
+/// one may discard the previous one.
+pub trait SnapshotStore: Send {
+    /// Persists a snapshot whose content is read from the specified reader.
+    fn save(&mut self, meta: &SnapshotMeta, reader: &mut dyn Read) -> io::Result<()>;
+
+    /// Returns the latest snapshot if any.
+    fn load(&mut self) -> io::Result<Option<(SnapshotMeta, Box<dyn Read>)>>;
+
+    /// Number of bytes used to store the latest snapshot (approximate for non persistent
+    /// stores). Defaults to 0.
+    fn disk_usage(&self) -> u64 {
+        0
+    }
+}
+
+/// Default store, each snapshot being written to a single file holding a small header (magic
+/// plus format version), the metadata and then the content. The file is replaced atomically.
+/// Loading a file with an unexpected header fails with `InvalidData` instead of misreading it.
+pub struct FileSnapshotStore {
+    path: PathBuf,
+}
+
+impl FileSnapshotStore {
+    /// Stores snapshots under the specified path.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        FileSnapshotStore { path: path.into() }
+    }
+
+    /// Stores snapshots for a given peer in the current directory, e.g next to its log file.
+    pub fn for_peer(id: u16) -> Self {
+        Self::new(format!("snapshot.{}", id))
+    }
+}
+
+impl SnapshotStore for FileSnapshotStore {
+    fn save(&mut self, meta: &SnapshotMeta, reader: &mut dyn Read) -> io::Result<()> {
+
+        //
+        // - write to a scratch file first and then rename it
+        // - this way we never end up with a partial snapshot
+        //
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&tmp)?;
+            file.write_all(&MAGIC)?;
+            serialize_into(&mut file, &VERSION).map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+            serialize_into(&mut file, meta).map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+            copy(reader, &mut file)?;
+            file.sync_all()?;
+        }
+        rename(&tmp, &self.path)
+    }
+
+    fn load(&mut self) -> io::Result<Option<(SnapshotMeta, Box<dyn Read>)>> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        //
+        // - check the header before anything else
+        // - a file written by another version can't be trusted to deserialize properly
+        //
+        let mut magic = [0; 4];
+        file.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a snapshot file"));
+        }
+        let version: u16 =
+            deserialize_from(&mut file).map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+        if version != VERSION {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported snapshot format v{} (expected v{})", version, VERSION),
+            ));
+        }
+        let meta = deserialize_from(&mut file).map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+        Ok(Some((meta, Box::new(file))))
+    }
+
+    fn disk_usage(&self) -> u64 {
+        metadata(&self.path).map(|meta| meta.len()).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::env;
+    use std::fs::remove_file;
+    use super::*;
+
+    #[test]
+    fn file_roundtrip() {
+
+        //
+        // - nothing to load initially
+        //
+        let path = env::temp_dir().join("rsm.snapshot.roundtrip");
+        let _ = remove_file(&path);
+        let mut store = FileSnapshotStore::new(path.clone());
+        assert!(store.load().unwrap().is_none());
+        assert_eq!(store.disk_usage(), 0);
+
+        //
+        // - save two snapshots in a row, the latest one must be loaded back
+        //
+        let mut meta = SnapshotMeta {
+            index: 15,
+            term: 2,
+            peers: vec![(0, [1; 32]), (1, [2; 32])],
+            requests: vec![(7, 3), (9, 12)],
+        };
+        store.save(&meta, &mut &b"first"[..]).unwrap();
+        meta.index = 30;
+        let blob: Vec<u8> = (0..4096).map(|n| n as u8).collect();
+        store.save(&meta, &mut &blob[..]).unwrap();
+
+        let (loaded, mut reader) = store.load().unwrap().unwrap();
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).unwrap();
+        assert_eq!(loaded, meta);
+        assert_eq!(bytes, blob);
+        assert!(store.disk_usage() > blob.len() as u64);
+        let _ = remove_file(&path);
+    }
+
+    #[test]
+    fn header_mismatch() {
+
+        //
+        // - save a snapshot then bump its format version in place
+        // - loading it must fail with InvalidData
+        //
+        let path = env::temp_dir().join("rsm.snapshot.header");
+        let _ = remove_file(&path);
+        let mut store = FileSnapshotStore::new(path.clone());
+        let meta = SnapshotMeta {
+            index: 15,
+            term: 2,
+            peers: vec![(0, [1; 32])],
+            requests: Vec::new(),
+        };
+        store.save(&meta, &mut &b"content"[..]).unwrap();
+        let mut bytes = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+        bytes[MAGIC.len()] += 1;
+        File::create(&path).unwrap().write_all(&bytes).unwrap();
+        match store.load() {
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => {}
+            _ => panic!("expected InvalidData"),
+        }
+
+        //
+        // - same with a file which is not a snapshot at all
+        //
+        File::create(&path).unwrap().write_all(b"garbage").unwrap();
+        match store.load() {
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => {}
+            _ => panic!("expected InvalidData"),
+        }
+        let _ = remove_file(&path);
+    }
+}
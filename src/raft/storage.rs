@@ -0,0 +1,408 @@
+//! Log and metadata persistence. The automaton does not care how its entries, its vote and its
+//! snapshots are stored as long as a `Storage` passed upon spawning holds onto them. Two
+//! implementations are provided: `FileStorage` (the default, a memory mapped log file next to a
+//! vote file and a snapshot store) and `MemoryStorage` (nothing survives the process, which is
+//! handy for tests).
+use bincode::{deserialize, serialize};
+use memmap::MmapMut;
+use raft::snapshot::{SnapshotMeta, SnapshotStore};
+use std::cmp;
+use std::fs::{read, rename, OpenOptions};
+use std::io::{self, Cursor, ErrorKind, Read, Write};
+use std::ops::{Deref, DerefMut, Range};
+use std::path::PathBuf;
+use std::ptr;
+
+/// Number of bytes each log entry takes, e.g its slot width (entries are padded to it).
+pub const ENTRY_BYTES: usize = 1024;
+
+/// Number of entries the log holds. The automaton never reads an entry more than that many
+/// offsets below its head, e.g the log can be laid out as a ring.
+pub const CAPACITY: usize = 128;
+
+/// Trait defining where the log, the vote and snapshots are persisted. Log offsets start at #1
+/// and entries are always `ENTRY_BYTES` wide. Failing to read or write entries is fatal to the
+/// automaton while failing to persist a vote or a snapshot is not.
+pub trait Storage: Send {
+    /// Writes consecutive entries starting at the specified offset, overwriting whatever was
+    /// there. The last entry may be shorter than `ENTRY_BYTES`.
+    fn append_entries(&mut self, off: u64, bytes: &[u8]) -> io::Result<()>;
+
+    /// Appends the entries within the specified range to the buffer. An entry that was never
+    /// written reads as zeroes.
+    fn read_entries(&self, range: Range<u64>, buf: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Discards the entries at or above the specified offset (e.g upon a conflict with the
+    /// LEADER). They must not be read back after a restart.
+    fn truncate_from(&mut self, off: u64) -> io::Result<()>;
+
+    /// Makes whatever was written so far durable. Defaults to a no-op.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Allocates room upfront for the first bytes of the log (bounded by its size) without
+    /// altering what they hold, e.g so that appending later on does not have to. Defaults to a
+    /// no-op.
+    fn reserve(&mut self, _bytes: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Persists the vote cast in a given term. This must be durable once it returns as a peer
+    /// restarting must never vote twice in the same term.
+    fn save_meta(&mut self, term: u64, vote: u16) -> io::Result<()>;
+
+    /// Returns the latest term and vote if any.
+    fn load_meta(&mut self) -> io::Result<Option<(u64, u16)>>;
+
+    /// Persists a snapshot, see `SnapshotStore::save()`.
+    fn save_snapshot(&mut self, meta: &SnapshotMeta, reader: &mut dyn Read) -> io::Result<()>;
+
+    /// Returns the latest snapshot if any, see `SnapshotStore::load()`.
+    fn load_snapshot(&mut self) -> io::Result<Option<(SnapshotMeta, Box<dyn Read>)>>;
+
+    /// Number of bytes used to store the latest snapshot. Defaults to 0.
+    fn snapshot_usage(&self) -> u64 {
+        0
+    }
+}
+
+/// Fixed size buffer holding the last `CAPACITY` entries, each offset mapping to a given slot.
+struct Ring<B> {
+    bytes: B,
+    head: u64,
+    reserved: usize,
+}
+
+impl<B> Ring<B>
+where
+    B: Deref<Target = [u8]> + DerefMut,
+{
+    fn at(off: u64) -> usize {
+        ((off - 1) as usize % CAPACITY) * ENTRY_BYTES
+    }
+
+    fn write(&mut self, off: u64, bytes: &[u8]) -> () {
+        let mut n = 0;
+        for chunk in bytes.chunks(ENTRY_BYTES) {
+            let at = Self::at(off + n);
+            self.bytes[at..at + chunk.len()].copy_from_slice(chunk);
+            n += 1;
+        }
+        self.head = cmp::max(self.head, off + n - 1);
+    }
+
+    fn read(&self, range: Range<u64>, buf: &mut Vec<u8>) -> () {
+        for off in range {
+            let at = Self::at(off);
+            buf.extend_from_slice(&self.bytes[at..at + ENTRY_BYTES]);
+        }
+    }
+
+    fn truncate(&mut self, off: u64) -> () {
+
+        //
+        // - zero whatever we wrote from that offset on (at least one slot, the highest offset
+        //   written is not known after a restart)
+        // - a zeroed slot does not carry its offset, which ends the scan upon recovery
+        //
+        let end = cmp::min(cmp::max(self.head, off), off + CAPACITY as u64 - 1);
+        for n in off..end + 1 {
+            let at = Self::at(n);
+            for byte in &mut self.bytes[at..at + ENTRY_BYTES] {
+                *byte = 0;
+            }
+        }
+        self.head = cmp::min(self.head, off - 1);
+    }
+
+    fn reserve(&mut self, bytes: u64) -> () {
+
+        //
+        // - touch each page we did not reserve yet by writing back what it holds: this faults
+        //   it in (allocating its block if the buffer is a sparse file) and leaves it as is
+        // - volatile accesses make sure this is not optimized away
+        //
+        let end = cmp::min(bytes, self.bytes.len() as u64) as usize;
+        for at in (self.reserved..end).step_by(PAGE_BYTES) {
+            unsafe {
+                let byte = &mut self.bytes[at] as *mut u8;
+                ptr::write_volatile(byte, ptr::read_volatile(byte));
+            }
+        }
+        self.reserved = cmp::max(self.reserved, end);
+    }
+}
+
+/// Granularity at which `Ring::reserve()` touches the buffer, e.g the smallest page size.
+const PAGE_BYTES: usize = 4096;
+
+/// Default storage: the log is a memory mapped file laid out as a ring, the vote is written to
+/// a small file replaced atomically and snapshots go to the specified store.
+pub struct FileStorage {
+    ring: Ring<MmapMut>,
+    meta: PathBuf,
+    snapshots: Box<dyn SnapshotStore>,
+}
+
+impl FileStorage {
+    /// Maps the specified log file (created and sized if need be) and persists the vote to the
+    /// specified path.
+    pub fn open<P: Into<PathBuf>>(
+        log: P,
+        meta: P,
+        snapshots: Box<dyn SnapshotStore>,
+    ) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(log.into())?;
+        file.set_len((CAPACITY * ENTRY_BYTES) as u64)?;
+        let bytes = unsafe { MmapMut::map_mut(&file)? };
+        Ok(FileStorage {
+            ring: Ring {
+                bytes,
+                head: 0,
+                reserved: 0,
+            },
+            meta: meta.into(),
+            snapshots,
+        })
+    }
+
+    /// Storage for a given peer in the current directory, e.g `log.<id>` and `vote.<id>`.
+    pub fn for_peer(id: u16, snapshots: Box<dyn SnapshotStore>) -> io::Result<Self> {
+        Self::open(format!("log.{}", id), format!("vote.{}", id), snapshots)
+    }
+}
+
+impl Storage for FileStorage {
+    fn append_entries(&mut self, off: u64, bytes: &[u8]) -> io::Result<()> {
+        self.ring.write(off, bytes);
+        Ok(())
+    }
+
+    fn read_entries(&self, range: Range<u64>, buf: &mut Vec<u8>) -> io::Result<()> {
+        self.ring.read(range, buf);
+        Ok(())
+    }
+
+    fn truncate_from(&mut self, off: u64) -> io::Result<()> {
+        self.ring.truncate(off);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.ring.bytes.flush()
+    }
+
+    fn reserve(&mut self, bytes: u64) -> io::Result<()> {
+        self.ring.reserve(bytes);
+        Ok(())
+    }
+
+    fn save_meta(&mut self, term: u64, vote: u16) -> io::Result<()> {
+
+        //
+        // - write to a scratch file first, fsync it and then rename it
+        //
+        let tmp = self.meta.with_extension("tmp");
+        {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&tmp)?;
+            file.write_all(&serialize(&(term, vote)).expect("vote is serializable"))?;
+            file.sync_all()?;
+        }
+        rename(&tmp, &self.meta)
+    }
+
+    fn load_meta(&mut self) -> io::Result<Option<(u64, u16)>> {
+        match read(&self.meta) {
+            Ok(bytes) => deserialize(&bytes[..])
+                .map(Some)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e)),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save_snapshot(&mut self, meta: &SnapshotMeta, reader: &mut dyn Read) -> io::Result<()> {
+        self.snapshots.save(meta, reader)
+    }
+
+    fn load_snapshot(&mut self) -> io::Result<Option<(SnapshotMeta, Box<dyn Read>)>> {
+        self.snapshots.load()
+    }
+
+    fn snapshot_usage(&self) -> u64 {
+        self.snapshots.disk_usage()
+    }
+}
+
+/// Volatile storage keeping everything in memory.
+pub struct MemoryStorage {
+    ring: Ring<Vec<u8>>,
+    meta: Option<(u64, u16)>,
+    snapshot: Option<(SnapshotMeta, Vec<u8>)>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage {
+            ring: Ring {
+                bytes: vec![0; CAPACITY * ENTRY_BYTES],
+                head: 0,
+                reserved: 0,
+            },
+            meta: None,
+            snapshot: None,
+        }
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn append_entries(&mut self, off: u64, bytes: &[u8]) -> io::Result<()> {
+        self.ring.write(off, bytes);
+        Ok(())
+    }
+
+    fn read_entries(&self, range: Range<u64>, buf: &mut Vec<u8>) -> io::Result<()> {
+        self.ring.read(range, buf);
+        Ok(())
+    }
+
+    fn truncate_from(&mut self, off: u64) -> io::Result<()> {
+        self.ring.truncate(off);
+        Ok(())
+    }
+
+    fn reserve(&mut self, bytes: u64) -> io::Result<()> {
+        self.ring.reserve(bytes);
+        Ok(())
+    }
+
+    fn save_meta(&mut self, term: u64, vote: u16) -> io::Result<()> {
+        self.meta = Some((term, vote));
+        Ok(())
+    }
+
+    fn load_meta(&mut self) -> io::Result<Option<(u64, u16)>> {
+        Ok(self.meta)
+    }
+
+    fn save_snapshot(&mut self, meta: &SnapshotMeta, reader: &mut dyn Read) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        self.snapshot = Some((meta.clone(), bytes));
+        Ok(())
+    }
+
+    fn load_snapshot(&mut self) -> io::Result<Option<(SnapshotMeta, Box<dyn Read>)>> {
+        Ok(self.snapshot.clone().map(|(meta, bytes)| {
+            (meta, Box::new(Cursor::new(bytes)) as Box<dyn Read>)
+        }))
+    }
+
+    fn snapshot_usage(&self) -> u64 {
+        self.snapshot.as_ref().map_or(0, |&(_, ref bytes)| bytes.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use raft::snapshot::FileSnapshotStore;
+    use std::env;
+    use std::fs::remove_file;
+    use super::*;
+
+    #[test]
+    fn ring() {
+
+        //
+        // - write #1 to #3 then wrap around: #CAPACITY + 1 lands where #1 was
+        //
+        let mut storage = MemoryStorage::new();
+        let bytes: Vec<u8> = (1..4u8).flat_map(|n| vec![n; ENTRY_BYTES]).collect();
+        storage.append_entries(1, &bytes).unwrap();
+        let mut buf = Vec::new();
+        storage.read_entries(2..4, &mut buf).unwrap();
+        assert_eq!(&buf[..], &bytes[ENTRY_BYTES..]);
+        storage.append_entries(CAPACITY as u64 + 1, &[9; 16]).unwrap();
+        let mut buf = Vec::new();
+        storage.read_entries(1..2, &mut buf).unwrap();
+        assert_eq!(&buf[..16], &[9; 16]);
+        assert_eq!(buf[16], 1);
+
+        //
+        // - truncating from #2 zeroes #2 and #3
+        //
+        storage.truncate_from(2).unwrap();
+        let mut buf = Vec::new();
+        storage.read_entries(2..4, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn reserve() {
+
+        //
+        // - write #1 to a fresh log file and reserve more than the whole log
+        // - #1 is left as is and the log reads the same once mapped again
+        //
+        let log = env::temp_dir().join("rsm.reserve.log");
+        let meta = env::temp_dir().join("rsm.reserve.vote");
+        let _ = remove_file(&log);
+        let snapshots = Box::new(FileSnapshotStore::new(env::temp_dir().join("rsm.reserve.snap")));
+        let mut storage = FileStorage::open(log.clone(), meta.clone(), snapshots).unwrap();
+        storage.append_entries(1, &[7; 16]).unwrap();
+        storage.reserve(2 * (CAPACITY * ENTRY_BYTES) as u64).unwrap();
+        assert_eq!(storage.ring.reserved, CAPACITY * ENTRY_BYTES);
+        storage.flush().unwrap();
+        drop(storage);
+        let snapshots = Box::new(FileSnapshotStore::new(env::temp_dir().join("rsm.reserve.snap")));
+        let storage = FileStorage::open(log.clone(), meta.clone(), snapshots).unwrap();
+        let mut buf = Vec::new();
+        storage.read_entries(1..3, &mut buf).unwrap();
+        assert_eq!(&buf[..16], &[7; 16]);
+        assert!(buf[16..].iter().all(|&b| b == 0));
+        let _ = remove_file(&log);
+    }
+
+    #[test]
+    fn file_meta() {
+
+        //
+        // - nothing to load initially, then the latest vote is loaded back
+        // - garbage in place of the vote file fails with InvalidData
+        //
+        let log = env::temp_dir().join("rsm.storage.log");
+        let meta = env::temp_dir().join("rsm.storage.vote");
+        let _ = remove_file(&meta);
+        let snapshots = Box::new(FileSnapshotStore::new(env::temp_dir().join("rsm.storage.snap")));
+        let mut storage = FileStorage::open(log.clone(), meta.clone(), snapshots).unwrap();
+        assert!(storage.load_meta().unwrap().is_none());
+        storage.save_meta(3, 2).unwrap();
+        storage.save_meta(4, 1).unwrap();
+        assert_eq!(storage.load_meta().unwrap(), Some((4, 1)));
+        OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(&meta)
+            .and_then(|mut file| file.write_all(&[1]))
+            .unwrap();
+        assert_eq!(storage.load_meta().unwrap_err().kind(), ErrorKind::InvalidData);
+        let _ = remove_file(&log);
+        let _ = remove_file(&meta);
+    }
+}
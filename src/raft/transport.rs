@@ -0,0 +1,390 @@
+//! TCP transport moving the buffers exchanged by the automata across processes (or machines).
+//! Each peer is designated by a `host:port` label which is both where it listens and how the
+//! others reach it. Outgoing buffers (see `send()`) are queued per destination and written by a
+//! dedicated thread over one connection, established upon the first buffer and re-established
+//! whenever it breaks. Incoming buffers are read off each accepted connection and passed to
+//! `Raft::feed()`.
+//!
+//! The buffers are already framed (see `frame`), e.g length prefixed, so they are written as is
+//! and the frame header is enough to delimit them on the receiving end. The transport is cheap to
+//! clone and can be shared by several automata running in the same process. The writing threads
+//! exit once the last clone is dropped.
+use raft::frame::{self, HEADER_BYTES};
+use raft::messages::label;
+use raft::protocol::{FeedError, Raft};
+use slog::Logger;
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Largest frame accepted off a connection. Anything announcing more is deemed garbage and the
+/// connection is closed. Frames within it but above `RaftConfig::max_message_bytes` are skipped
+/// without being buffered.
+pub const MAX_FRAME_BYTES: usize = 1 << 30;
+
+/// Lapse of time in milliseconds after which connecting to a peer is given up.
+const CONNECT_TIMEOUT: u64 = 250;
+
+/// Lapse of time in milliseconds after which a write that does not go through (e.g the peer
+/// stopped reading) is given up and the connection closed.
+const WRITE_TIMEOUT: u64 = 1000;
+
+/// Lapse of time in milliseconds during which a peer we failed to connect to is not tried
+/// again (its buffers are dropped meanwhile, the automaton retries on its own).
+const RECONNECT_DELAY: u64 = 500;
+
+/// Number of buffers queued for a given peer past which new ones are dropped, e.g while its
+/// connection is stuck.
+const QUEUE_DEPTH: usize = 1024;
+
+/// Transport shared by the automata of a process, see the module documentation.
+#[derive(Clone)]
+pub struct TcpTransport {
+    links: Arc<Mutex<HashMap<[u8; 32], SyncSender<Vec<u8>>>>>,
+    logger: Logger,
+}
+
+/// Outgoing connection to a given peer, if any, plus when to try connecting again. Owned by the
+/// thread writing to that peer.
+struct Link {
+    host: [u8; 32],
+    stream: Option<TcpStream>,
+    retry: Instant,
+}
+
+impl TcpTransport {
+    pub fn new(logger: Logger) -> Self {
+        TcpTransport {
+            links: Arc::new(Mutex::new(HashMap::new())),
+            logger,
+        }
+    }
+
+    /// Queues a buffer for the specified peer (its `host:port` label padded to 32 bytes), e.g
+    /// this is what to invoke from the automaton `write` closure. This never blocks: the buffer
+    /// is dropped if too many are already queued for that peer. The thread writing to that peer
+    /// is started upon the first buffer.
+    pub fn send(&self, host: &[u8; 32], bytes: &[u8]) -> () {
+
+        //
+        // - only hold the lock to look the queue up (or create it along with its thread)
+        // - drop the buffer if the queue is full, the automaton retries on its own
+        //
+        let mut links = self.links.lock().unwrap();
+        let logger = &self.logger;
+        let tx = links.entry(*host).or_insert_with(|| {
+            let (tx, rx) = sync_channel(QUEUE_DEPTH);
+            let mut link = Link {
+                host: *host,
+                stream: None,
+                retry: Instant::now(),
+            };
+            let logger = logger.clone();
+            let _ = thread::spawn(move || link.drain(&rx, &logger));
+            tx
+        });
+        if let Err(TrySendError::Full(_)) = tx.try_send(bytes.to_vec()) {
+            debug!(&self.logger, "dropping buffer to {} (queue full)", label(host));
+        }
+    }
+
+    /// Listens on the specified address (typically the local peer label) and passes whatever
+    /// is received to the automaton. Each connection is served by its own thread, which exits
+    /// once the connection closes or the automaton is not running anymore. The listening thread
+    /// is blocked accepting connections: once one of them found the automaton gone it exits
+    /// (releasing the address) upon the next connection attempt, e.g the next time a peer
+    /// tries to reach us.
+    pub fn listen(&self, addr: &str, raft: Arc<Raft>) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        let logger = self.logger.clone();
+        let limit = raft.max_bytes.map_or(MAX_FRAME_BYTES, |n| cmp::min(n, MAX_FRAME_BYTES));
+        let gone = Arc::new(AtomicBool::new(false));
+        let _ = thread::spawn(move || for stream in listener.incoming() {
+            if gone.load(Ordering::Acquire) {
+                debug!(&logger, "automaton gone, no longer listening");
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    let raft = raft.clone();
+                    let gone = gone.clone();
+                    let logger = logger.clone();
+                    let _ = thread::spawn(move || serve(stream, &raft, limit, &gone, &logger));
+                }
+                Err(e) => warn!(&logger, "unable to accept a connection ({})", e),
+            }
+        });
+        Ok(())
+    }
+}
+
+impl Link {
+    /// Writes whatever gets queued for that peer until the transport is dropped.
+    fn drain(&mut self, rx: &Receiver<Vec<u8>>, logger: &Logger) -> () {
+        for bytes in rx.iter() {
+            self.write(&bytes, logger);
+        }
+    }
+
+    /// Writes a buffer, connecting if need be. Any failure drops the buffer and the connection,
+    /// which is re-established later on.
+    fn write(&mut self, bytes: &[u8], logger: &Logger) -> () {
+
+        //
+        // - connect if we have no connection to that peer, unless we failed lately
+        // - write the buffer: upon failure close the connection and retry once right away
+        //   (the peer may have restarted since we last wrote to it)
+        //
+        for _ in 0..2 {
+            if self.stream.is_none() {
+                if Instant::now() < self.retry {
+                    return;
+                }
+                match connect(&self.host) {
+                    Ok(stream) => self.stream = Some(stream),
+                    Err(e) => {
+                        debug!(logger, "unable to connect to {} ({})", label(&self.host), e);
+                        self.retry = Instant::now() + Duration::from_millis(RECONNECT_DELAY);
+                        return;
+                    }
+                }
+            }
+            let res = match self.stream {
+                Some(ref mut stream) => stream.write_all(bytes),
+                None => unreachable!(),
+            };
+            match res {
+                Ok(_) => return,
+                Err(e) => {
+                    debug!(logger, "connection to {} lost ({})", label(&self.host), e);
+                    self.stream = None;
+                }
+            }
+        }
+    }
+}
+
+fn connect(host: &[u8; 32]) -> io::Result<TcpStream> {
+    let addr = label(host)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address"))?;
+    let stream = TcpStream::connect_timeout(&addr, Duration::from_millis(CONNECT_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    stream.set_write_timeout(Some(Duration::from_millis(WRITE_TIMEOUT)))?;
+    Ok(stream)
+}
+
+fn serve(
+    mut stream: TcpStream,
+    raft: &Raft,
+    limit: usize,
+    gone: &AtomicBool,
+    logger: &Logger,
+) -> () {
+
+    //
+    // - read one frame at a time: its header first, which tells how much follows
+    // - a frame above the automaton limit is skipped and counted as dropped before anything
+    //   gets allocated for it
+    // - feed the whole frame, the automaton checks it (a corrupted one is just dropped)
+    // - stop upon any read error, a bogus length or once the automaton is gone, in which case
+    //   the listening thread is told to stop as well
+    //
+    loop {
+        let mut buf = vec![0; HEADER_BYTES];
+        if stream.read_exact(&mut buf).is_err() {
+            break;
+        }
+        let n = frame::length(&buf);
+        if n > MAX_FRAME_BYTES {
+            warn!(logger, "closing connection (bogus {}B frame)", n);
+            break;
+        }
+        if n > limit {
+            warn!(logger, "skipping {}B frame (limit is {}B)", n, limit);
+            raft.dropped.fetch_add(1, Ordering::Relaxed);
+            let skipped = io::copy(&mut (&mut stream).take(n as u64), &mut io::sink());
+            if skipped.ok() != Some(n as u64) {
+                break;
+            }
+            continue;
+        }
+        buf.resize(HEADER_BYTES + n, 0);
+        if stream.read_exact(&mut buf[HEADER_BYTES..]).is_err() {
+            break;
+        }
+        if let Err(FeedError::Unavailable) = raft.feed(&buf) {
+            gone.store(true, Ordering::Release);
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use primitives::event::*;
+    use raft::config::RaftConfig;
+    use raft::kv::{self, KV};
+    use raft::messages::*;
+    use raft::protocol::*;
+    use raft::storage::MemoryStorage;
+    use slog::Discard;
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use super::*;
+
+    #[test]
+    fn loopback() {
+
+        //
+        // - grab 3 free ports and spawn 3 peers talking over loopback TCP
+        // - a LEADER gets elected and a store commits once a no-op follows it
+        //
+        let ports: Vec<_> = (0..3)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port())
+            .collect();
+        let labels: Vec<_> = ports.iter().map(|port| format!("127.0.0.1:{}", port)).collect();
+        let peers: HashMap<_, _> = labels.iter()
+            .enumerate()
+            .map(|(n, label)| (n as u16, label.as_str()))
+            .collect();
+        let event = Event::new();
+        let guard = event.guard();
+        let transport = TcpTransport::new(Logger::root(Discard, o!()));
+        let config = RaftConfig {
+            liveness_timeout: Duration::from_millis(400),
+            election_timeout: Duration::from_millis(200),
+            heartbeat_interval: Duration::from_millis(100),
+            transfer_timeout: Duration::from_millis(200),
+            ..RaftConfig::default()
+        };
+        let mut rafts = Vec::new();
+        for id in 0..3u16 {
+            let link = transport.clone();
+            let (raft, _, _) = Raft::builder::<KV>()
+                .id(id)
+                .peers(peers.clone())
+                .config(config.clone())
+                .storage(Box::new(MemoryStorage::new()))
+                .on_send(move |host: &[u8; 32], bytes: &[u8]| link.send(host, bytes))
+                .on_commit(kv::apply)
+                .spawn(&guard)
+                .unwrap();
+            transport.listen(&labels[id as usize], raft.clone()).unwrap();
+            rafts.push(raft);
+        }
+        let mut leader = None;
+        for _ in 0..100 {
+            leader = rafts.iter().find(|raft| raft.role() == Role::Leader).cloned();
+            if leader.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        let leader = leader.expect("no LEADER elected");
+        let op = kv::Op::SET(b"key".to_vec(), b"value".to_vec());
+        let handle = leader.store(op.to_bytes()).unwrap();
+        assert!(leader.noop().is_some());
+        assert!(handle.wait().is_ok());
+        for raft in &rafts {
+            raft.drain();
+        }
+
+        //
+        // - once drained keep pushing a valid buffer to each peer over fresh connections: the
+        //   connection finds the automaton gone and the listener stops upon the next one,
+        //   releasing its port
+        //
+        let ping = PING {
+            id: 1,
+            term: 1,
+            commit: 1,
+            digest: None,
+        };
+        for label in &labels {
+            let mut host = [0; 32];
+            host[..label.len()].copy_from_slice(label.as_bytes());
+            let bytes = frame::frame(&ping.to_raw(&host, &host));
+            let start = Instant::now();
+            while let Ok(mut stream) = TcpStream::connect(label) {
+                assert!(start.elapsed() < Duration::from_secs(5), "still listening");
+                let _ = stream.write_all(&bytes);
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+
+    #[test]
+    fn stuck_peer() {
+
+        //
+        // - listen without ever reading and push 2 queues worth of 64KB buffers to it
+        // - the writes end up stalling but send() never blocks, whatever does not fit in the
+        //   queue is dropped
+        //
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut host = [0; 32];
+        let label = listener.local_addr().unwrap().to_string();
+        host[..label.len()].copy_from_slice(label.as_bytes());
+        let transport = TcpTransport::new(Logger::root(Discard, o!()));
+        let bytes = vec![0; 1 << 16];
+        let start = Instant::now();
+        for _ in 0..2 * QUEUE_DEPTH {
+            transport.send(&host, &bytes);
+        }
+        assert!(start.elapsed() < Duration::from_millis(WRITE_TIMEOUT));
+    }
+
+    #[test]
+    fn oversized_frames() {
+
+        //
+        // - spawn a peer accepting at most 1KB per buffer and listening over loopback TCP
+        // - push 2 frames announcing 1MB each through the same connection
+        // - both are skipped and counted as dropped, the connection remains usable
+        //
+        let label = format!(
+            "127.0.0.1:{}",
+            TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+        );
+        let peers: HashMap<_, _> = vec![(0u16, label.as_str())].into_iter().collect();
+        let event = Event::new();
+        let guard = event.guard();
+        let transport = TcpTransport::new(Logger::root(Discard, o!()));
+        let config = RaftConfig {
+            max_message_bytes: Some(1024),
+            ..RaftConfig::default()
+        };
+        let (raft, _, _) = Raft::builder::<KV>()
+            .id(0)
+            .peers(peers)
+            .config(config)
+            .storage(Box::new(MemoryStorage::new()))
+            .on_commit(kv::apply)
+            .spawn(&guard)
+            .unwrap();
+        transport.listen(&label, raft.clone()).unwrap();
+        let mut stream = TcpStream::connect(&label).unwrap();
+        for _ in 0..2 {
+            stream.write_all(&frame::frame(&vec![0; 1 << 20])).unwrap();
+        }
+        for _ in 0..100 {
+            if raft.dropped() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(raft.dropped(), 2);
+        raft.drain();
+    }
+}